@@ -0,0 +1,68 @@
+// Queries per second of the forwarding and host record paths, the upstream
+// answers from memory so only the server is measured
+// cargo bench --bench forward
+
+use futures_util::future::{BoxFuture, FutureExt};
+use std::{
+    io::Result,
+    time::{Duration, Instant},
+};
+use updns::{config::Config, upstream::Static, QueryType, Server};
+
+const TARGET: Duration = Duration::from_secs(1);
+
+fn parse(text: &str) -> Config {
+    let import = |_: &str, _: &Config| -> BoxFuture<'static, Result<Config>> {
+        async { Ok(Config::new()) }.boxed()
+    };
+    Config::parse_str(text, import)
+        .now_or_never()
+        .unwrap()
+        .unwrap()
+}
+
+// The query with one A record pointing at the question
+fn answer(query: &[u8]) -> Result<Vec<u8>> {
+    let mut answer = query.to_vec();
+    answer[2] |= 0x80;
+    answer[7] = 1;
+    answer.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+    Ok(answer)
+}
+
+// Resolve `name` repeatedly for about `TARGET` and print the rate
+fn bench(rt: &tokio::runtime::Runtime, label: &str, config: &str, name: &str) {
+    let server = Server::with_upstream(parse(config), Static::new("memory", answer));
+    let (queries, elapsed) = rt.block_on(async {
+        let mut queries = 0u32;
+        let start = Instant::now();
+        while start.elapsed() < TARGET {
+            server.resolve(name, QueryType::A).await.unwrap();
+            queries += 1;
+        }
+        (queries, start.elapsed())
+    });
+    println!(
+        "{:<40} {:>12.0} queries/s",
+        label,
+        queries as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    // Printing would dominate
+    updns::logger::set_filter("!all").unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    bench(&rt, "forward", "", "www.example.com");
+    bench(
+        &rt,
+        "forward/rebind_protection+bogus_nx",
+        "rebind_protection true\nbogus-nx 198.51.100.1",
+        "www.example.com",
+    );
+    bench(&rt, "forward/ecs", "ecs set /24", "www.example.com");
+    bench(&rt, "hosts", "www.example.com 1.1.1.1", "www.example.com");
+}
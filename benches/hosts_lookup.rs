@@ -0,0 +1,98 @@
+// Lookup and parsing time of large host lists, also as an import
+// cargo bench --bench hosts_lookup
+
+use futures_util::future::{BoxFuture, FutureExt};
+use std::{
+    hint::black_box,
+    io::Result,
+    time::{Duration, Instant},
+};
+use updns::{concurrent::ConcurrentHosts, config::Config};
+
+const SIZES: [usize; 4] = [100, 1_000, 10_000, 100_000];
+const TARGET: Duration = Duration::from_secs(1);
+
+// Mostly plain names like an ad-blocking list, with some wildcard and regex entries
+fn config_text(size: usize) -> String {
+    let mut text = String::new();
+    for i in 0..size {
+        let line = match i % 20 {
+            0 => format!("~^ads{}\\.[a-z]+\\.com$ 0.0.0.0\n", i),
+            1..=3 => format!("*.tracker{}.net 0.0.0.0\n", i),
+            _ => format!("host{}.example.com 0.0.0.0\n", i),
+        };
+        text.push_str(&line);
+    }
+    text
+}
+
+fn parse(text: &str) -> Config {
+    let import = |_: &str, _: &Config| -> BoxFuture<'static, Result<Config>> {
+        async { Ok(Config::new()) }.boxed()
+    };
+    Config::parse_str(text, import)
+        .now_or_never()
+        .unwrap()
+        .unwrap()
+}
+
+// `text` as a blocklist imported by the config
+fn parse_import(text: &str) -> Config {
+    let import = |_: &str, _: &Config| -> BoxFuture<'static, Result<Config>> {
+        let text = text.to_string();
+        async move { Ok(parse(&text)) }.boxed()
+    };
+    Config::parse_str("import blocklist.conf", import)
+        .now_or_never()
+        .unwrap()
+        .unwrap()
+}
+
+// Run `f` repeatedly for about `TARGET` and print the mean time
+fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    let mut iters = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < TARGET {
+        black_box(f());
+        iters += 1;
+    }
+    println!("{:<40} {:>12.3?}/iter", name, start.elapsed() / iters);
+}
+
+fn main() {
+    for &size in &SIZES {
+        let text = config_text(size);
+        bench(&format!("parse_str/{}", size), || parse(&text));
+        bench(&format!("parse_str/{}/import", size), || {
+            parse_import(&text)
+        });
+
+        let config = parse(&text);
+        let last = format!("host{}.example.com", size - 1);
+        let wildcard = format!("cdn.tracker{}.net", size - 19);
+        let regex = format!("ads{}.example.com", size - 20);
+        bench(&format!("get/{}/text_first", size), || {
+            config.hosts().get("host4.example.com").is_some()
+        });
+        bench(&format!("get/{}/text_last", size), || {
+            config.hosts().get(&last).is_some()
+        });
+        bench(&format!("get/{}/wildcard", size), || {
+            config.hosts().get(&wildcard).is_some()
+        });
+        bench(&format!("get/{}/regex", size), || {
+            config.hosts().get(&regex).is_some()
+        });
+        bench(&format!("get/{}/miss", size), || {
+            config.hosts().get("not.found.org").is_some()
+        });
+
+        let concurrent = ConcurrentHosts::from(parse(&text).hosts().clone());
+        bench(&format!("concurrent_get/{}/text_last", size), || {
+            concurrent.get(&last).is_some()
+        });
+        bench(&format!("concurrent_get/{}/wildcard", size), || {
+            concurrent.get(&wildcard).is_some()
+        });
+    }
+}
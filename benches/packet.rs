@@ -0,0 +1,66 @@
+// Encoding and decoding time of DNS messages
+// cargo bench --bench packet
+
+use std::{
+    hint::black_box,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+use updns::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+const TARGET: Duration = Duration::from_secs(1);
+
+// A question and `answers` A records
+fn packet(answers: usize) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 1;
+    packet.header.response = answers > 0;
+    packet.questions.push(DnsQuestion::new(
+        "www.example.com".to_string(),
+        QueryType::A,
+    ));
+    for i in 0..answers {
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(93, 184, 216, i as u8),
+            ttl: 300,
+        });
+    }
+    packet
+}
+
+fn encode(packet: &mut DnsPacket) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.buf[..buffer.pos()].to_vec()
+}
+
+fn decode(data: &[u8]) -> DnsPacket {
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data)).unwrap()
+}
+
+// Run `f` repeatedly for about `TARGET` and print the mean time
+fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    let mut iters = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < TARGET {
+        black_box(f());
+        iters += 1;
+    }
+    println!("{:<40} {:>12.3?}/iter", name, start.elapsed() / iters);
+}
+
+fn main() {
+    for &answers in &[0, 1, 8] {
+        let mut message = packet(answers);
+        let data = encode(&mut message);
+        bench(&format!("encode/{}", answers), || encode(&mut message));
+        bench(&format!("decode/{}", answers), || decode(&data));
+        bench(&format!("round_trip/{}", answers), || {
+            encode(&mut decode(&data))
+        });
+        bench(&format!("records/{}", answers), || {
+            BytePacketBuffer::from_bytes(&data).records().unwrap().len()
+        });
+    }
+}
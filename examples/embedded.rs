@@ -0,0 +1,67 @@
+// An in-process resolver answering from its own host records
+// cargo run --example embedded -- app.internal
+// cargo run --example embedded -- --serve
+
+use futures_util::future::FutureExt;
+use std::{env, time::Duration};
+use updns::{config::Config, DnsRecord, QueryType, Server};
+
+const CONFIG: &str = "
+*.internal   10.0.0.1
+app.internal 10.0.0.2
+bind         127.0.0.1:5353
+proxy        1.1.1.1:53
+timeout      1s
+";
+
+#[tokio::main]
+async fn main() {
+    // No `import` lines, nothing to load
+    let config = Config::parse_str(CONFIG, |_, _| async { Ok(Config::new()) }.boxed())
+        .await
+        .expect("parse config");
+    let server = Server::new(config);
+
+    let mut names = env::args().skip(1).collect::<Vec<_>>();
+    let serve = names.first().map(String::as_str) == Some("--serve");
+    if serve {
+        names.remove(0);
+    }
+    let names = match names.is_empty() {
+        true => vec!["app.internal".to_string(), "db.internal".to_string()],
+        false => names,
+    };
+    for name in &names {
+        let answer = match server.resolve(name, QueryType::A).await {
+            Ok(answer) => answer,
+            Err(err) => {
+                println!("{}: {}", name, err);
+                continue;
+            }
+        };
+        let addrs = answer
+            .packet()
+            .expect("decode answer")
+            .answers
+            .into_iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(addr.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{} -> [{}] {} by {}",
+            name,
+            addrs.join(", "),
+            answer.outcome().as_str(),
+            answer.source().unwrap_or("-")
+        );
+    }
+    println!("{}", server.stats().summary());
+
+    // The same records over UDP, `dig @127.0.0.1 -p 5353 app.internal`
+    if serve {
+        let shutdown = tokio::time::sleep(Duration::from_secs(60));
+        server.serve(shutdown).await.expect("serve");
+    }
+}
@@ -0,0 +1,16 @@
+#![no_main]
+
+use futures_util::future::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use updns::config::Config;
+
+fuzz_target!(|data: &[u8]| {
+    let content = String::from_utf8_lossy(data);
+
+    // Imports never touch the file system
+    let config = Config::parse_str(&content, |_, _| async { Ok(Config::new()) }.boxed())
+        .now_or_never()
+        .expect("Parsing without imports should not wait");
+
+    let _ = config;
+});
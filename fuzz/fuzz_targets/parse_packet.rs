@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use updns::{BytePacketBuffer, DnsPacket};
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are fine, panics and hangs are not
+    let _ = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data));
+    let _ = BytePacketBuffer::from_bytes(data).questions_end();
+    let _ = BytePacketBuffer::from_bytes(data).records();
+});
@@ -0,0 +1,589 @@
+use crate::{records, reload_config, ADMIN_KEY, SERVER};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use updns::{
+    config::{Parser, Record},
+    matcher::Matcher,
+    querylog::json_string,
+    remote::RemoteImports,
+    Outcome,
+};
+use updns::{error, info};
+
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// The config file written by `POST /hosts`
+pub struct Admin {
+    path: PathBuf,
+    remote: RemoteImports,
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    // Percent-decoded, without the query string
+    path: String,
+    // Percent-decoded query parameters
+    query: HashMap<String, String>,
+    authorization: Option<String>,
+    body: String,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, body: String) -> Response {
+        Response { status, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response::new(status, format!(r#"{{"error":{}}}"#, json_string(msg)))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        };
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason,
+            self.body.len()
+        );
+        if self.status == 401 {
+            head.push_str("WWW-Authenticate: Bearer\r\n");
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(self.body.as_bytes());
+        bytes
+    }
+}
+
+impl Admin {
+    pub fn new(path: PathBuf, remote: RemoteImports) -> Admin {
+        Admin {
+            path,
+            remote,
+            started: Instant::now(),
+        }
+    }
+
+    // One request per connection
+    pub async fn serve(self, listener: TcpListener) {
+        let admin = Arc::new(self);
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!("Failed to accept admin connection {:?}", err);
+                    continue;
+                }
+            };
+            let admin = admin.clone();
+            tokio::spawn(async move {
+                if let Err(err) = admin.connection(stream).await {
+                    error!("Admin connection failed {:?}", err);
+                }
+            });
+        }
+    }
+
+    async fn connection(&self, mut stream: TcpStream) -> Result<()> {
+        let request = match timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+            Ok(request) => request,
+            Err(_) => return Ok(()),
+        };
+        let response = match request {
+            Ok(request) => self.route(request).await,
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                Response::error(413, &err.to_string())
+            }
+            Err(err) => Response::error(400, &err.to_string()),
+        };
+        stream.write_all(&response.to_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    async fn route(&self, req: Request) -> Response {
+        let mutating = req.method != "GET";
+        if mutating {
+            let key = ADMIN_KEY.read().await;
+            if !authorized(key.as_deref(), req.authorization.as_deref()) {
+                return Response::error(401, "Missing or wrong admin key");
+            }
+        }
+
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/hosts") => self.list(req.query.get("ip")).await,
+            ("POST", "/hosts") => self.add(&req.body).await,
+            ("DELETE", path) if path.starts_with("/hosts/") => {
+                self.remove(&path["/hosts/".len()..]).await
+            }
+            ("POST", "/reload") => self.reload().await,
+            ("GET", "/stats") => self.stats().await,
+            (_, "/hosts") | (_, "/reload") | (_, "/stats") => {
+                Response::error(405, "Method not allowed")
+            }
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    // `?ip=` keeps the records mapped to an address
+    async fn list(&self, ip: Option<&String>) -> Response {
+        let ip = match ip.map(|ip| ip.parse::<IpAddr>()) {
+            Some(Ok(ip)) => Some(ip),
+            Some(Err(_)) => return Response::error(400, "Invalid ip address"),
+            None => None,
+        };
+        let hosts = SERVER.hosts();
+        let records = hosts
+            .records()
+            .filter(|record| ip.is_none_or(|ip| *record.ip == ip))
+            .map(|record| records::to_json(&record))
+            .collect::<Vec<_>>();
+        Response::new(200, format!("[{}]", records.join(",")))
+    }
+
+    // Written to the config file, then added to the live records
+    async fn add(&self, body: &str) -> Response {
+        let fields = match parse_object(body) {
+            Some(fields) => fields,
+            None => return Response::error(400, "Expected a JSON object"),
+        };
+        let (pattern, ip) = match (fields.get("pattern"), fields.get("ip")) {
+            (Some(pattern), Some(ip)) => (pattern.as_str(), ip.as_str()),
+            _ => return Response::error(400, "Expected 'pattern' and 'ip'"),
+        };
+
+        let written = match crate::open_config(&self.path).await {
+            Ok(mut parser) => parser.add(pattern, ip).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            return match err.kind() {
+                ErrorKind::InvalidInput => Response::error(400, &err.to_string()),
+                _ => Response::error(500, &err.to_string()),
+            };
+        }
+
+        // Both were checked by `add`
+        let matcher = Matcher::new(pattern).unwrap();
+        let ip = ip.parse::<IpAddr>().unwrap();
+        let source = self.path.display().to_string();
+        let body = records::to_json(&Record {
+            matcher: &matcher,
+            ip: &ip,
+            source: Some(&source),
+            line: None,
+        });
+        SERVER.edit_hosts(|hosts| hosts.insert((matcher, ip), Some(Arc::from(source.as_str()))));
+        Response::new(201, body)
+    }
+
+    // Removed from the local files defining it, then from the live records.
+    // Records of remote imports come back with the next reload
+    async fn remove(&self, pattern: &str) -> Response {
+        let mut sources = SERVER
+            .hosts()
+            .records()
+            .filter(|record| record.matcher.to_string() == pattern)
+            .map(|record| record.source.map(str::to_string))
+            .collect::<Vec<_>>();
+        if sources.is_empty() {
+            return Response::error(404, "No record of this pattern");
+        }
+        sources.sort();
+        sources.dedup();
+
+        for source in sources.into_iter().flatten() {
+            if source.starts_with("http://") || source.starts_with("https://") {
+                continue;
+            }
+            let parser = match Path::new(&source) == self.path {
+                true => crate::open_config(&self.path).await,
+                false => Parser::new(&source).await,
+            };
+            let removed = match parser {
+                Ok(mut parser) => parser.remove(pattern).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = removed {
+                return Response::error(500, &format!("Failed to write {}: {}", source, err));
+            }
+        }
+
+        let removed = SERVER.edit_hosts(|hosts| hosts.remove(pattern).len());
+        Response::new(200, format!(r#"{{"removed":{}}}"#, removed))
+    }
+
+    async fn reload(&self) -> Response {
+        info!(
+            "Reload the configuration file from the admin api: {:?}",
+            &self.path
+        );
+        match reload_config(&self.path, self.remote).await {
+            Ok(_) => Response::new(200, r#"{"reloaded":true}"#.to_string()),
+            Err(err) => Response::error(500, &err.to_string()),
+        }
+    }
+
+    async fn stats(&self) -> Response {
+        let outcomes = Outcome::ALL
+            .iter()
+            .map(|outcome| {
+                let count = SERVER.stats().outcome(*outcome);
+                format!(r#""{}":{}"#, outcome.as_str(), count)
+            })
+            .collect::<Vec<_>>();
+        Response::new(
+            200,
+            format!(
+                r#"{{"uptime_secs":{},"queries":{},"outcomes":{{{}}},"rate_limited":{},"overloaded":{},"inflight":{},"hosts":{}}}"#,
+                self.started.elapsed().as_secs(),
+                SERVER.stats().queries(),
+                outcomes.join(","),
+                SERVER.stats().dropped(),
+                SERVER.stats().overloaded(),
+                SERVER.inflight(),
+                SERVER.hosts().records().count()
+            ),
+        )
+    }
+}
+
+// `POST /reload` as a client, used by `updns add`
+pub async fn request_reload(addr: SocketAddr, key: Option<&str>) -> Result<()> {
+    let mut request = format!("POST /reload HTTP/1.1\r\nHost: {}\r\n", addr);
+    if let Some(key) = key {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", key));
+    }
+    request.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, Error>(response)
+    };
+    let response = timeout(CLIENT_TIMEOUT, exchange)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "No response from the admin api"))??;
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split(' ').nth(1).unwrap_or_default();
+    match status {
+        "200" => Ok(()),
+        _ => {
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+            Err(Error::other(format!("Status {} {}", status, body)))
+        }
+    }
+}
+
+// Without a key every client of the listener may change the records
+fn authorized(key: Option<&str>, authorization: Option<&str>) -> bool {
+    let key = match key {
+        Some(key) => key,
+        None => return true,
+    };
+    let token = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(token) => token.trim(),
+        None => return false,
+    };
+    // Compare every byte so the time doesn't tell how much matched
+    token.len() == key.len()
+        && token
+            .bytes()
+            .zip(key.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Request> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(Error::new(ErrorKind::InvalidData, "Request head too large"));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(invalid("Incomplete request"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = str::from_utf8(&buf[..end]).map_err(|_| invalid("Request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut words = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) if !method.is_empty() => (method.to_string(), target),
+        _ => return Err(invalid("Invalid request line")),
+    };
+    let (target, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(target).ok_or_else(|| invalid("Invalid path"))?;
+    let query = parse_query(query).ok_or_else(|| invalid("Invalid query"))?;
+
+    let mut authorization = None;
+    let mut length = 0;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(invalid("Invalid header")),
+        };
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value
+                .parse::<usize>()
+                .map_err(|_| invalid("Invalid Content-Length"))?;
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, "Request body too large"));
+    }
+
+    let mut body = buf.split_off(end + 4);
+    body.truncate(length);
+    if body.len() < length {
+        let start = body.len();
+        body.resize(length, 0);
+        reader.read_exact(&mut body[start..]).await?;
+    }
+    let body = String::from_utf8(body).map_err(|_| invalid("Request body is not UTF-8"))?;
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        authorization,
+        body,
+    })
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// a=1&b=2, `+` is a space
+fn parse_query(text: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for pair in text.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode = |text: &str| percent_decode(&text.replace('+', " "));
+        params.insert(decode(name)?, decode(value)?);
+    }
+    Some(params)
+}
+
+// A flat object of string values, which is all the API takes
+fn parse_object(text: &str) -> Option<HashMap<String, String>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut fields = HashMap::new();
+
+    let skip_space = |chars: &mut std::iter::Peekable<str::Chars>| {
+        while chars.peek().is_some_and(|ch| ch.is_whitespace()) {
+            chars.next();
+        }
+    };
+    let string = |chars: &mut std::iter::Peekable<str::Chars>| -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut out = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => match chars.next()? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let hex = (0..4).map(|_| chars.next()).collect::<Option<String>>()?;
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    ch @ ('"' | '\\' | '/') => out.push(ch),
+                    _ => return None,
+                },
+                ch => out.push(ch),
+            }
+        }
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_space(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_space(&mut chars);
+            let key = string(&mut chars)?;
+            skip_space(&mut chars);
+            if chars.next()? != ':' {
+                return None;
+            }
+            skip_space(&mut chars);
+            let value = string(&mut chars)?;
+            fields.insert(key, value);
+            skip_space(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    match chars.next() {
+        Some(_) => None,
+        None => Some(fields),
+    }
+}
+
+#[cfg(test)]
+mod test_admin {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut raw: &[u8] = b"POST /hosts/%2A.example.com?x=1 HTTP/1.1\r\nHost: localhost\r\nauthorization: Bearer key\r\nContent-Length: 4\r\n\r\nbodyextra";
+        let req = read_request(&mut raw).await.unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/hosts/*.example.com");
+        assert_eq!(req.query["x"], "1");
+        assert_eq!(req.authorization.as_deref(), Some("Bearer key"));
+        assert_eq!(req.body, "body");
+
+        let mut raw: &[u8] = b"GET /stats HTTP/1.1\r\n\r\n";
+        let req = read_request(&mut raw).await.unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("GET", "/stats"));
+        assert_eq!(req.body, "");
+
+        let mut raw: &[u8] = b"POST /hosts HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(read_request(&mut raw).await.is_err());
+
+        let mut raw: &[u8] = b"POST /hosts HTTP/1.1\r\nContent-Length: 100000\r\n\r\n";
+        let err = read_request(&mut raw).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Ab").as_deref(), Some("a*b"));
+        assert_eq!(percent_decode("~%5E%5Cw%2B").as_deref(), Some("~^\\w+"));
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("ip=1.2.3.4&a=b+c%21&flag").unwrap();
+        assert_eq!(query["ip"], "1.2.3.4");
+        assert_eq!(query["a"], "b c!");
+        assert_eq!(query["flag"], "");
+        assert!(parse_query("").unwrap().is_empty());
+        assert_eq!(parse_query("a=%zz"), None);
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let fields = parse_object(r#" { "pattern": "~^\\w+\\.lan$", "ip" : "1.1.1.1" } "#).unwrap();
+        assert_eq!(fields["pattern"], "~^\\w+\\.lan$");
+        assert_eq!(fields["ip"], "1.1.1.1");
+        assert_eq!(parse_object("{}"), Some(HashMap::new()));
+        assert_eq!(parse_object(r#"{"a":"A"}"#).unwrap()["a"], "A");
+
+        assert_eq!(parse_object(r#"{"a":1}"#), None);
+        assert_eq!(parse_object(r#"{"a":"b""#), None);
+        assert_eq!(parse_object(r#"{"a":"b"} x"#), None);
+        assert_eq!(parse_object("[]"), None);
+    }
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(None, None));
+        assert!(authorized(Some("key"), Some("Bearer key")));
+        assert!(!authorized(Some("key"), Some("Bearer other")));
+        assert!(!authorized(Some("key"), Some("Basic key")));
+        assert!(!authorized(Some("key"), None));
+    }
+
+    #[tokio::test]
+    async fn test_request_reload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in &[200, 401] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let req = read_request(&mut stream).await.unwrap();
+                assert_eq!(
+                    (req.method.as_str(), req.path.as_str()),
+                    ("POST", "/reload")
+                );
+                assert_eq!(req.authorization.as_deref(), Some("Bearer key"));
+                let res = Response::error(*status, "no");
+                stream.write_all(&res.to_bytes()).await.unwrap();
+            }
+        });
+
+        request_reload(addr, Some("key")).await.unwrap();
+        let err = request_reload(addr, Some("key")).await.unwrap_err();
+        assert_eq!(err.to_string(), r#"Status 401 {"error":"no"}"#);
+    }
+
+    #[test]
+    fn test_response() {
+        let bytes = Response::error(401, "no").to_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(text.contains("WWW-Authenticate: Bearer\r\n"));
+        assert!(text.ends_with("\r\n\r\n{\"error\":\"no\"}"));
+    }
+}
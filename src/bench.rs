@@ -0,0 +1,335 @@
+use regex::Regex;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io::Result, net::UdpSocket, sync::oneshot, time::timeout};
+use updns::{
+    config::{Config, Hosts},
+    matcher::Pattern,
+    upstream::Static,
+    BytePacketBuffer, DnsPacket, DnsQuestion, QueryType, Server,
+};
+
+// A query without an answer in this time counts as an error
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+// Keys of the JSON report, compared to a baseline in this order
+const METRICS: [&str; 5] = ["qps", "mean_us", "p50_us", "p99_us", "p999_us"];
+
+// xorshift64, only used to pick the query names
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        Rng(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn ratio(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+// Names answered by the host records, text patterns as written and
+// wildcards with a random label. Regexes can't be sampled
+fn hits(hosts: &Hosts, rng: &mut Rng) -> Vec<String> {
+    hosts
+        .records()
+        .filter_map(|record| match record.matcher.as_pattern() {
+            Pattern::Text(text) => Some(text.to_string()),
+            Pattern::Wildcard(raw) => Some(raw.replace('*', &format!("b{:x}", rng.next()))),
+            Pattern::Regex(_) => None,
+        })
+        .filter(|domain| hosts.get(domain).is_some())
+        .collect()
+}
+
+// `count` names, about `hit_ratio` of them answered by the host records
+// and the others forwarded
+fn domains(hosts: &Hosts, count: usize, hit_ratio: f64, rng: &mut Rng) -> Vec<(String, bool)> {
+    let hits = hits(hosts, rng);
+    (0..count)
+        .map(|_| {
+            if !hits.is_empty() && rng.ratio() < hit_ratio {
+                return (hits[rng.next() as usize % hits.len()].clone(), true);
+            }
+            loop {
+                let domain = format!("miss-{:x}.updns-bench.test", rng.next());
+                if hosts.get(&domain).is_none() {
+                    return (domain, false);
+                }
+            }
+        })
+        .collect()
+}
+
+fn query(id: u16, domain: &str) -> Result<Vec<u8>> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = id;
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(domain.to_string(), QueryType::A));
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    Ok(buffer.buf[..buffer.pos()].to_vec())
+}
+
+#[derive(Debug)]
+pub struct Report {
+    concurrency: usize,
+    hits: usize,
+    errors: usize,
+    elapsed: Duration,
+    // Of the answered queries, sorted
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn queries(&self) -> usize {
+        self.latencies.len() + self.errors
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        match self.latencies.len() {
+            0 => Duration::ZERO,
+            n => self.latencies[((n as f64 * p).ceil() as usize).clamp(1, n) - 1],
+        }
+    }
+
+    fn metrics(&self) -> [(&'static str, f64); 5] {
+        let micros = |d: Duration| d.as_secs_f64() * 1e6;
+        let mean = match self.latencies.len() {
+            0 => Duration::ZERO,
+            n => self.latencies.iter().sum::<Duration>() / n as u32,
+        };
+        [
+            (
+                METRICS[0],
+                self.latencies.len() as f64 / self.elapsed.as_secs_f64().max(1e-9),
+            ),
+            (METRICS[1], micros(mean)),
+            (METRICS[2], micros(self.percentile(0.5))),
+            (METRICS[3], micros(self.percentile(0.99))),
+            (METRICS[4], micros(self.percentile(0.999))),
+        ]
+    }
+
+    // One line, saved as the baseline of a later run
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"queries\":{},\"concurrency\":{},\"hits\":{},\"errors\":{}",
+            self.queries(),
+            self.concurrency,
+            self.hits,
+            self.errors
+        );
+        for (name, value) in self.metrics().iter() {
+            out += &format!(",\"{}\":{:.1}", name, value);
+        }
+        out + "}"
+    }
+
+    // The change of every metric from a saved report
+    pub fn compare(&self, baseline: &str) -> Vec<String> {
+        let number = Regex::new(r#""(\w+)":\s*(-?[0-9.]+)"#).unwrap();
+        let saved = number
+            .captures_iter(baseline)
+            .filter_map(|cap| Some((cap[1].to_string(), cap[2].parse::<f64>().ok()?)))
+            .collect::<Vec<_>>();
+        self.metrics()
+            .iter()
+            .map(
+                |(name, value)| match saved.iter().find(|(saved, _)| saved == name) {
+                    Some((_, base)) if *base != 0.0 => format!(
+                        "{} {:.1} -> {:.1} ({:+.1}%)",
+                        name,
+                        base,
+                        value,
+                        (value - base) / base * 100.0
+                    ),
+                    _ => format!("{} {:.1}, not in the baseline", name, value),
+                },
+            )
+            .collect()
+    }
+}
+
+// Serve the config on a local port and send it `queries` queries from
+// `concurrency` sockets, each waiting for its answer before the next query.
+// The forwarded queries get an NXDOMAIN without leaving the process
+pub async fn run(
+    mut config: Config,
+    queries: usize,
+    concurrency: usize,
+    hit_ratio: f64,
+) -> Result<Report> {
+    // They would drop the load or measure the disk
+    config.clear_limits_and_logs();
+
+    let domains = domains(config.hosts(), queries, hit_ratio, &mut Rng::new());
+    let hits = domains.iter().filter(|(_, hit)| *hit).count();
+    let upstream = Static::new("bench", |query: &[u8]| {
+        let mut answer = query.to_vec();
+        // QR and RA, NXDOMAIN
+        answer[2] |= 0x80;
+        answer[3] = 0x83;
+        Ok(answer)
+    });
+    let server = Server::with_upstream(config, upstream);
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let serving = tokio::spawn({
+        let server = server.clone();
+        async move {
+            server
+                .serve_sockets(vec![socket], async {
+                    let _ = stopped.await;
+                })
+                .await
+        }
+    });
+
+    let domains = Arc::new(domains);
+    let next = Arc::new(AtomicUsize::new(0));
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(queries)));
+    let errors = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers = (0..concurrency.max(1))
+        .map(|_| {
+            let (domains, next) = (domains.clone(), next.clone());
+            let (latencies, errors) = (latencies.clone(), errors.clone());
+            tokio::spawn(async move {
+                let socket = UdpSocket::bind("127.0.0.1:0").await?;
+                socket.connect(addr).await?;
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let (domain, _) = match domains.get(i) {
+                        Some(domain) => domain,
+                        None => return Result::Ok(()),
+                    };
+                    match send(&socket, i as u16, domain).await {
+                        Some(latency) => latencies.lock().unwrap().push(latency),
+                        None => {
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.await.unwrap_or_else(|err| Err(err.into()))?;
+    }
+    let elapsed = start.elapsed();
+
+    let _ = stop.send(());
+    let _ = serving.await;
+    server.drain().await;
+
+    let mut latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    latencies.sort();
+    Ok(Report {
+        concurrency,
+        hits,
+        errors: errors.load(Ordering::Relaxed),
+        elapsed,
+        latencies,
+    })
+}
+
+// The time to the answer of the query, `None` when it fails or times out.
+// Late answers of an earlier query are skipped by their id
+async fn send(socket: &UdpSocket, id: u16, domain: &str) -> Option<Duration> {
+    let query = query(id, domain).ok()?;
+    let start = Instant::now();
+    socket.send(&query).await.ok()?;
+    timeout(QUERY_TIMEOUT, async {
+        let mut buf = [0; 512];
+        loop {
+            let len = socket.recv(&mut buf).await.ok()?;
+            if len >= 2 && buf[..2] == id.to_be_bytes() {
+                return Some(start.elapsed());
+            }
+        }
+    })
+    .await
+    .ok()?
+}
+
+#[cfg(test)]
+mod test_bench {
+    use super::*;
+    use crate::parse_test_config;
+
+    #[test]
+    fn test_domains() {
+        let config = parse_test_config("a.com 1.1.1.1\n*.b.com 2.2.2.2\n~^c[0-9]+\\.com$ 3.3.3.3");
+        let mut rng = Rng::new();
+        let hits = hits(config.hosts(), &mut rng);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0], "a.com");
+        assert!(hits[1].ends_with(".b.com"));
+
+        let names = domains(config.hosts(), 1000, 0.3, &mut rng);
+        assert_eq!(names.len(), 1000);
+        for (domain, hit) in &names {
+            assert_eq!(config.hosts().get(domain).is_some(), *hit);
+        }
+        let count = names.iter().filter(|(_, hit)| *hit).count();
+        assert!((200..400).contains(&count), "{}", count);
+
+        // Without any record to hit
+        let names = domains(&Hosts::new(), 10, 1.0, &mut rng);
+        assert!(names.iter().all(|(_, hit)| !hit));
+    }
+
+    #[test]
+    fn test_report() {
+        let report = Report {
+            concurrency: 2,
+            hits: 3,
+            errors: 1,
+            elapsed: Duration::from_secs(2),
+            latencies: (1..=1000).map(Duration::from_micros).collect(),
+        };
+        assert_eq!(report.percentile(0.5), Duration::from_micros(500));
+        assert_eq!(report.percentile(0.999), Duration::from_micros(999));
+        assert_eq!(
+            report.to_json(),
+            "{\"queries\":1001,\"concurrency\":2,\"hits\":3,\"errors\":1,\"qps\":500.0,\
+             \"mean_us\":500.5,\"p50_us\":500.0,\"p99_us\":990.0,\"p999_us\":999.0}"
+        );
+        assert_eq!(
+            report.compare("{\"qps\":400.0,\"p50_us\": 1000}"),
+            vec![
+                "qps 400.0 -> 500.0 (+25.0%)",
+                "mean_us 500.5, not in the baseline",
+                "p50_us 1000.0 -> 500.0 (-50.0%)",
+                "p99_us 990.0, not in the baseline",
+                "p999_us 999.0, not in the baseline",
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run() {
+        let config = parse_test_config("a.com 1.1.1.1");
+        let report = run(config, 200, 4, 0.5).await.unwrap();
+        assert_eq!(report.queries(), 200);
+        assert_eq!(report.errors, 0);
+        assert!(report.hits > 0 && report.hits < 200);
+    }
+}
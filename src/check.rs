@@ -0,0 +1,209 @@
+use updns::{
+    config::{Config, Invalid, DEFAULT_BIND},
+    querylog::json_string,
+    server::DEFAULT_PROXY,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+// Something `updns check` reports, `file` is empty when unknown
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub level: Level,
+    pub file: String,
+    pub line: Option<usize>,
+    pub source: String,
+    pub message: String,
+}
+
+impl Finding {
+    fn from_invalid(level: Level, invalid: &Invalid) -> Finding {
+        Finding {
+            level,
+            file: invalid.file.display().to_string(),
+            line: Some(invalid.line),
+            source: invalid.source.clone(),
+            message: invalid.kind.description().to_string(),
+        }
+    }
+
+    fn location(&self) -> String {
+        match (self.file.is_empty(), self.line) {
+            (false, Some(line)) => format!("{}:{}", self.file, line),
+            (false, None) => self.file.clone(),
+            (true, Some(line)) => format!("line:{}", line),
+            (true, None) => "config".to_string(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"level":"{}","file":{},"line":{},"source":{},"message":{}}}"#,
+            self.level.as_str(),
+            json_string(&self.file),
+            match self.line {
+                Some(line) => line.to_string(),
+                None => "null".to_string(),
+            },
+            json_string(&self.source),
+            json_string(&self.message)
+        )
+    }
+}
+
+// Invalid lines first, then legal but suspicious things. Without
+// `default_bind` a config must have a `bind` line, as with --no-default-bind
+pub fn check(config: &Config, default_bind: bool) -> Vec<Finding> {
+    let mut findings = config
+        .invalid()
+        .iter()
+        .map(|invalid| Finding::from_invalid(Level::Error, invalid))
+        .collect::<Vec<_>>();
+    findings.extend(
+        config
+            .warnings()
+            .iter()
+            .map(|invalid| Finding::from_invalid(Level::Warning, invalid)),
+    );
+
+    for (record, by) in config.hosts().shadowed() {
+        let by_location = match (by.source, by.line) {
+            (Some(source), Some(line)) => format!(" at {}:{}", source, line),
+            (None, Some(line)) => format!(" at line {}", line),
+            (Some(source), None) => format!(" in {}", source),
+            (None, None) => String::new(),
+        };
+        findings.push(Finding {
+            level: Level::Warning,
+            file: record.source.unwrap_or_default().to_string(),
+            line: record.line,
+            source: format!("{} {}", record.matcher, record.ip),
+            message: format!("Never used, shadowed by `{}`{}", by.matcher, by_location),
+        });
+    }
+
+    if config.bind().is_empty() {
+        findings.push(Finding {
+            level: match default_bind {
+                true => Level::Warning,
+                false => Level::Error,
+            },
+            file: String::new(),
+            line: None,
+            source: String::new(),
+            message: match default_bind {
+                true => format!("No bind address, only serving {}", DEFAULT_BIND),
+                false => "No bind address and --no-default-bind is set".to_string(),
+            },
+        });
+    }
+    if config.proxy_count() == 0 {
+        findings.push(Finding {
+            level: Level::Warning,
+            file: String::new(),
+            line: None,
+            source: String::new(),
+            message: format!("No proxy configured, using {}", DEFAULT_PROXY.join(", ")),
+        });
+    }
+    findings
+}
+
+pub fn has_errors(findings: &[Finding]) -> bool {
+    findings.iter().any(|f| f.level == Level::Error)
+}
+
+// Output of `updns check`, one finding per line or a JSON array
+pub fn format(findings: &[Finding], json: bool) -> String {
+    if json {
+        let findings = findings.iter().map(Finding::to_json).collect::<Vec<_>>();
+        return format!("[{}]\n", findings.join(","));
+    }
+    let mut out = String::new();
+    for finding in findings {
+        out += &format!(
+            "{}: [{}] {}",
+            finding.level.as_str(),
+            finding.location(),
+            finding.message
+        );
+        if !finding.source.is_empty() {
+            out += &format!(" `{}`", finding.source);
+        }
+        out += "\n";
+    }
+    out
+}
+
+#[cfg(test)]
+mod test_check {
+    use super::*;
+    use crate::parse_test_config;
+
+    #[test]
+    fn test_check() {
+        let config = parse_test_config(
+            "
+            bind 0.0.0.0:53
+            bind 0.0.0.0:53
+            proxy 1.1.1.1:53
+            a.com 1.1.1.1
+            a.com 2.2.2.2
+            b.com 1.1.1.1.1
+            ",
+        );
+        let findings = check(&config, true);
+        assert!(has_errors(&findings));
+        assert_eq!(findings.len(), 3);
+        assert_eq!(findings[0].level, Level::Error);
+        assert_eq!(findings[0].line, Some(3));
+        assert_eq!(findings[1].line, Some(7));
+        assert_eq!(
+            format(&findings[2..], false),
+            "warning: [line:6] Never used, shadowed by `a.com` at line 5 `a.com 2.2.2.2`\n"
+        );
+        assert_eq!(
+            format(&findings[2..], true),
+            r#"[{"level":"warning","file":"","line":6,"source":"a.com 2.2.2.2","message":"Never used, shadowed by `a.com` at line 5"}]"#
+                .to_string()
+                + "\n"
+        );
+
+        let findings = check(&parse_test_config("a.com 1.1.1.1"), true);
+        assert!(!has_errors(&findings));
+        assert_eq!(
+            format(&findings, false),
+            format!(
+                "warning: [config] No bind address, only serving {}\n\
+                 warning: [config] No proxy configured, using {}\n",
+                DEFAULT_BIND,
+                DEFAULT_PROXY.join(", ")
+            )
+        );
+
+        // An empty config is an error with --no-default-bind
+        let findings = check(&parse_test_config(""), false);
+        assert!(has_errors(&findings));
+        assert_eq!(
+            findings[0].message,
+            "No bind address and --no-default-bind is set"
+        );
+        assert!(!has_errors(&check(
+            &parse_test_config("bind 0.0.0.0:53"),
+            false
+        )));
+    }
+}
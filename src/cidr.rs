@@ -0,0 +1,165 @@
+use std::{fmt, net::IpAddr};
+
+// Address block such as 10.0.0.0/8, a bare address covers only itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Option<Cidr> {
+        let (addr, prefix) = match text.find('/') {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (text, None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?;
+        let max = Self::max_prefix(addr);
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|n| *n <= max)?,
+            None => max,
+        };
+
+        Some(Cidr { addr, prefix })
+    }
+
+    fn max_prefix(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                Self::mask(u32::from(net) as u128, 32, self.prefix)
+                    == Self::mask(u32::from(ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                Self::mask(u128::from(net), 128, self.prefix)
+                    == Self::mask(u128::from(ip), 128, self.prefix)
+            }
+            // IPv4 clients of a dual stack socket
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+
+    // Keep the first `prefix` bits of a `bits` wide address
+    fn mask(n: u128, bits: u8, prefix: u8) -> u128 {
+        if prefix == 0 {
+            0
+        } else {
+            n >> (bits - prefix)
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Access rule of the client addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acl {
+    Allow(Cidr),
+    Deny(Cidr),
+}
+
+// The first matching rule decides, clients matching no rule are allowed
+pub fn is_allowed(acl: &[Acl], ip: IpAddr) -> bool {
+    for rule in acl {
+        match rule {
+            Acl::Allow(cidr) if cidr.contains(ip) => return true,
+            Acl::Deny(cidr) if cidr.contains(ip) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test_cidr {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Cidr::parse("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(Cidr::parse("10.1.2.3").unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().to_string(), "fd00::/8");
+        assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+
+        for text in &[
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "/8",
+            "",
+        ] {
+            assert!(Cidr::parse(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains("10.255.1.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::a00:1".parse().unwrap()));
+
+        let cidr = Cidr::parse("192.168.1.128/25").unwrap();
+        assert!(cidr.contains("192.168.1.200".parse().unwrap()));
+        assert!(!cidr.contains("192.168.1.127".parse().unwrap()));
+
+        let cidr = Cidr::parse("fd00::/8").unwrap();
+        assert!(cidr.contains("fd12::1".parse().unwrap()));
+        assert!(!cidr.contains("fe80::1".parse().unwrap()));
+        assert!(!cidr.contains("10.0.0.1".parse().unwrap()));
+
+        let cidr = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(cidr.contains("8.8.8.8".parse().unwrap()));
+
+        let cidr = Cidr::parse("1.1.1.1").unwrap();
+        assert!(cidr.contains("1.1.1.1".parse().unwrap()));
+        assert!(!cidr.contains("1.1.1.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_acl() {
+        let allowed = |acl: &[Acl], ip: &str| is_allowed(acl, ip.parse().unwrap());
+        let allow = |s| Acl::Allow(Cidr::parse(s).unwrap());
+        let deny = |s| Acl::Deny(Cidr::parse(s).unwrap());
+
+        // No rules
+        assert!(allowed(&[], "8.8.8.8"));
+        assert!(allowed(&[], "::1"));
+
+        let acl = [
+            deny("192.168.1.100"),
+            allow("192.168.0.0/16"),
+            allow("fd00::/8"),
+            deny("0.0.0.0/0"),
+        ];
+        assert!(!allowed(&acl, "192.168.1.100"));
+        assert!(allowed(&acl, "192.168.1.101"));
+        assert!(allowed(&acl, "fd00::1"));
+        assert!(!allowed(&acl, "8.8.8.8"));
+        // Not covered by any rule
+        assert!(allowed(&acl, "2001:db8::1"));
+
+        // Earlier rules win over overlapping later ones
+        let acl = [allow("10.0.0.0/8"), deny("10.1.0.0/16")];
+        assert!(allowed(&acl, "10.1.2.3"));
+        let acl = [deny("10.1.0.0/16"), allow("10.0.0.0/8")];
+        assert!(!allowed(&acl, "10.1.2.3"));
+        assert!(allowed(&acl, "10.2.2.3"));
+    }
+}
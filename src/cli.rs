@@ -1,32 +1,123 @@
-use crate::{config::try_parse_duration, exit, CONFIG_FILE, WATCH_INTERVAL};
-use clap::{crate_name, crate_version, App, AppSettings, Arg, SubCommand};
-use logs::LogConfig;
-use regex::Regex;
-use std::{net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+use crate::{dryrun, exit, export, man, records::Format, WATCH_INTERVAL};
+use clap::{
+    crate_description, crate_name, crate_version, App, AppSettings, Arg, ArgGroup, Shell,
+    SubCommand,
+};
+use std::{io, path::PathBuf, time::Duration};
+use updns::{
+    config::{try_parse_duration, ConfigFormat, LogFormat, LogLevel, Parser},
+    logger,
+    remote::RemoteImports,
+    QueryType,
+};
 
 pub enum AppRunType {
     AddRecord {
         path: PathBuf,
         ip: String,
         host: String,
+        remote: RemoteImports,
+    },
+    RemoveRecord {
+        path: PathBuf,
+        pattern: String,
+        remote: RemoteImports,
     },
     PrintRecord {
         path: PathBuf,
+        remote: RemoteImports,
+        format: Format,
+        // Regular expression of the patterns to print
+        filter: Option<String>,
+    },
+    Check {
+        path: PathBuf,
+        remote: RemoteImports,
+        json: bool,
+        no_default_bind: bool,
+    },
+    Console {
+        path: PathBuf,
+        remote: RemoteImports,
+    },
+    ExportConfig {
+        path: PathBuf,
+        remote: RemoteImports,
+        format: export::Format,
+    },
+    // Config lines from a JSON export, `-` for stdin
+    ImportConfig {
+        file: PathBuf,
+    },
+    Bench {
+        path: PathBuf,
+        remote: RemoteImports,
+        queries: usize,
+        concurrency: usize,
+        // Of the queries answered by the host records
+        hit_ratio: f64,
+        baseline: Option<PathBuf>,
+    },
+    Test {
+        path: PathBuf,
+        remote: RemoteImports,
+        domains: Vec<String>,
+        qtype: QueryType,
+        // Also send the queries to the first upstream
+        upstream: bool,
     },
     EditConfig {
         path: PathBuf,
+        remote: RemoteImports,
     },
     PrintPath {
         path: PathBuf,
     },
+    Init {
+        path: PathBuf,
+        force: bool,
+    },
+    Completions {
+        shell: Shell,
+    },
+    Man,
+    #[cfg(windows)]
+    Service {
+        command: ServiceCommand,
+        path: PathBuf,
+        duration: Duration,
+        remote: RemoteImports,
+    },
+    Stop {
+        pid_file: PathBuf,
+    },
     Run {
         path: PathBuf,
         duration: Duration,
+        remote: RemoteImports,
+        verbose: bool,
+        no_query_log: bool,
+        daemon: bool,
+        pid_file: Option<PathBuf>,
+        no_default_bind: bool,
+        strict: bool,
+        user: Option<String>,
+        group: Option<String>,
+        // The levels are set by the command line, over `log_level`
+        log_override: bool,
+        log_format: Option<LogFormat>,
     },
 }
 
-pub fn parse_args() -> AppRunType {
-    let app = App::new(crate_name!())
+#[cfg(windows)]
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+    Run,
+}
+
+fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .global_setting(AppSettings::ColoredHelp)
         .setting(AppSettings::VersionlessSubcommands)
@@ -46,7 +137,135 @@ pub fn parse_args() -> AppRunType {
                 )
         )
         .subcommand(
-            SubCommand::with_name("ls").about("Print all configured DNS records")
+            SubCommand::with_name("rm")
+                .about("Remove a DNS record from the config files defining it")
+                .arg(
+                    Arg::with_name("pattern")
+                        .value_name("PATTERN")
+                        .required(true)
+                        .help("Domain of the DNS record, as written in the config")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .alias("list")
+                .about("Print all configured DNS records, sorted by pattern")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["table", "json", "csv"])
+                        .help("Output format [default: table]")
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with("format")
+                        .help("Print a JSON array, same as --format json")
+                )
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .value_name("REGEX")
+                        .help("Only print the patterns matching the regular expression")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the config and its imports for errors and unused rules")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print a JSON array")
+                )
+                .arg(
+                    Arg::with_name("no-default-bind")
+                        .long("no-default-bind")
+                        .help("Report a config without a `bind` line as an error")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .alias("query")
+                .about("Print how the server would answer domains, without querying")
+                .arg(
+                    Arg::with_name("domain")
+                        .value_name("DOMAIN")
+                        .required(true)
+                        .multiple(true)
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .short("t")
+                        .long("type")
+                        .value_name("TYPE")
+                        .default_value("A")
+                        .help("Query type: A, AAAA, MX, TYPE65...")
+                )
+                .arg(
+                    Arg::with_name("upstream")
+                        .long("upstream")
+                        .help("Also query the first proxy and print its answer")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Export the resolved config as structured data, or import it back into config lines")
+                .arg(
+                    Arg::with_name("export")
+                        .long("export")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "toml"])
+                        .help("Print the config and its imports")
+                )
+                .arg(
+                    Arg::with_name("import")
+                        .long("import")
+                        .value_name("FILE")
+                        .help("Print the config lines of a JSON export, - for stdin")
+                )
+                .group(
+                    ArgGroup::with_name("action")
+                        .args(&["export", "import"])
+                        .required(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Measure the latency and throughput of the config, served in-process")
+                .arg(
+                    Arg::with_name("queries")
+                        .short("n")
+                        .long("queries")
+                        .value_name("N")
+                        .default_value("10000")
+                        .help("Number of queries to send")
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .short("c")
+                        .long("concurrency")
+                        .value_name("N")
+                        .default_value("50")
+                        .help("Number of sockets sending queries at the same time")
+                )
+                .arg(
+                    Arg::with_name("hit-ratio")
+                        .long("hit-ratio")
+                        .value_name("RATIO")
+                        .default_value("0.5")
+                        .help("Share of the names answered by the host records, from 0 to 1")
+                )
+                .arg(
+                    Arg::with_name("baseline")
+                        .long("baseline")
+                        .value_name("FILE")
+                        .help("Compare with the output of an earlier run")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("console")
+                .about("Resolve the typed domains like the server and trace the answers")
         )
         .subcommand(
             SubCommand::with_name("edit").about("Call 'vim' to edit the configuration file")
@@ -54,6 +273,31 @@ pub fn parse_args() -> AppRunType {
         .subcommand(
             SubCommand::with_name("path").about("Print related directories")
         )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print the completion script of a shell")
+                .arg(
+                    Arg::with_name("shell")
+                        .value_name("SHELL")
+                        .required(true)
+                        .possible_values(&Shell::variants())
+                )
+        )
+        .subcommand(SubCommand::with_name("man").about("Print the man page"))
+        .subcommand(
+            SubCommand::with_name("stop")
+                .about("Stop the server of a pid file")
+                .arg(pid_file_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Create a config file with examples")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrite an existing config file")
+                )
+        )
         .arg(
             Arg::with_name("config")
                 .short("c")
@@ -62,6 +306,14 @@ pub fn parse_args() -> AppRunType {
                 .takes_value(true)
                 .help("Specify a config file"),
         )
+        .arg(
+            Arg::with_name("config-format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "toml"])
+                .help("Syntax of the config file, TOML for a .toml file otherwise (default: text)"),
+        )
         .arg(
             Arg::with_name("duration")
                 .short("d")
@@ -70,6 +322,59 @@ pub fn parse_args() -> AppRunType {
                 .takes_value(true)
                 .help("Check the interval time of the configuration file\nformat: 1ms, 1s, 1m, 1h, 1d"),
         )
+        .arg(
+            Arg::with_name("allow-remote-imports")
+                .long("allow-remote-imports")
+                .help("Allow importing config files over https, which is not available yet"),
+        )
+        .arg(
+            Arg::with_name("allow-http-imports")
+                .long("allow-http-imports")
+                .requires("allow-remote-imports")
+                .help("Also allow plain http imports, which anyone on the network path can alter"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Log every query, same as `log_queries true`, and each config line as it is parsed"),
+        )
+        .arg(
+            Arg::with_name("no-query-log")
+                .long("no-query-log")
+                .conflicts_with("verbose")
+                .help("Don't log the queries, whatever `log_queries` says"),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Run in the background once the config is loaded and the sockets are bound"),
+        )
+        .arg(pid_file_arg())
+        .arg(
+            Arg::with_name("no-default-bind")
+                .long("no-default-bind")
+                .help("Fail without a `bind` line instead of binding 127.0.0.1:53"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Fail to start when a proxy host name cannot be resolved"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .value_name("USER")
+                .takes_value(true)
+                .help("Switch to this user once the sockets are bound, over `user` of the config"),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .value_name("GROUP")
+                .takes_value(true)
+                .help("Switch to this group once the sockets are bound, over `group` of the config"),
+        )
         .arg(
             Arg::with_name("log")
                 .short("l")
@@ -79,58 +384,372 @@ pub fn parse_args() -> AppRunType {
                 .default_value("all,!trace,!debug")
                 .help("Set logs enable"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .conflicts_with("log")
+                .help("Log this level and the ones above, over the `log_level` of the config"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "json", "compact"])
+                .help("Format of the server log and the query log, over the `log_format` of the config"),
+        )
+        .subcommands(platform_subcommands())
+}
+
+fn pid_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("pid-file")
+        .long("pid-file")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("Write the pid of the server, removed when it stops")
+}
+
+#[cfg(windows)]
+fn platform_subcommands() -> Vec<App<'static, 'static>> {
+    vec![SubCommand::with_name("service")
+        .about("Manage the Windows service")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Register updns as a service starting with the system"),
+        )
+        .subcommand(SubCommand::with_name("uninstall").about("Remove the service"))
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Entry of the service control manager")
+                .setting(AppSettings::Hidden),
+        )]
+}
 
-    LogConfig::from_str(app.value_of("log").unwrap())
-        .unwrap_or_else(|msg| exit!("Log value error: '{}'", msg))
-        .build();
+#[cfg(not(windows))]
+fn platform_subcommands() -> Vec<App<'static, 'static>> {
+    Vec::new()
+}
+
+// Generated from the compiled-in arguments
+pub fn print_completions(shell: Shell) {
+    app().gen_completions_to(crate_name!(), shell, &mut io::stdout());
+}
+
+pub fn print_man() {
+    print!("{}", man::render(&app(), crate_description!()));
+}
+
+pub fn parse_args() -> AppRunType {
+    let app = app().get_matches();
+
+    let env = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|value| env_log(&value));
+    let level = app.value_of("log-level").and_then(LogLevel::parse);
+    let log_override = level.is_some() || env.is_some() || app.occurrences_of("log") > 0;
+    let mut log = match (level, env) {
+        (Some(level), _) => level.enabled().to_string(),
+        (None, Some(log)) if app.occurrences_of("log") == 0 => log,
+        _ => app.value_of("log").unwrap().to_string(),
+    };
+    // Every query is logged as info, `bench` would measure the terminal
+    if app.is_present("bench") && app.occurrences_of("log") == 0 {
+        log += ",!info";
+    }
+    // The config lines are logged as trace
+    if app.is_present("verbose") {
+        log += ",trace";
+    }
+    logger::set_filter(&log).unwrap_or_else(|msg| exit!("Log value error: '{}'", msg));
+    let log_format = app.value_of("log-format").and_then(LogFormat::parse);
+    if let Some(format) = log_format {
+        logger::init(format);
+    }
 
     let path = match app.value_of("config") {
         Some(s) => PathBuf::from(s),
-        None => match dirs::home_dir() {
-            Some(p) => p.join(CONFIG_FILE[0]).join(CONFIG_FILE[1]),
+        // The service is not run by a user
+        #[cfg(windows)]
+        None if app.is_present("service") => crate::service::default_path(),
+        None => match Parser::default_path() {
+            Some(p) => p,
             None => exit!("Can't get home directory"),
         },
     };
 
     let duration = match app.value_of("duration") {
-        Some(s) => try_parse_duration(s).unwrap_or_else(|_| {
+        Some(s) => try_parse_duration(s).unwrap_or_else(|| {
             exit!(
-                "Cannot resolve '{}' to interval time, format: 1ms, 1s, 1m, 1h, 1d",
+                "Cannot resolve '{}' to interval time, format: 1ms, 1s, 1m, 1h, 1d or seconds",
                 s
             )
         }),
         None => WATCH_INTERVAL,
     };
 
+    let remote = match (
+        app.is_present("allow-remote-imports"),
+        app.is_present("allow-http-imports"),
+    ) {
+        (false, _) => RemoteImports::Deny,
+        (true, false) => RemoteImports::Https,
+        (true, true) => RemoteImports::Http,
+    };
+    if let Some(format) = app.value_of("config-format") {
+        *crate::CONFIG_FORMAT.lock().unwrap() = ConfigFormat::parse(format);
+    }
+
     if let Some(add) = app.subcommand_matches("add") {
         let host = add.value_of("host").unwrap().to_string();
         let ip = add.value_of("ip").unwrap().to_string();
-        // check
-        if let Err(err) = Regex::new(&host) {
-            exit!(
-                "Cannot resolve host '{}' to regular expression\n{:?}",
-                host,
-                err
-            );
-        }
-        if ip.parse::<IpAddr>().is_err() {
-            exit!("Cannot resolve '{}' to ip address", ip);
+        // Checked by `Parser::add`
+        return AppRunType::AddRecord {
+            path,
+            ip,
+            host,
+            remote,
+        };
+    }
+
+    if let Some(rm) = app.subcommand_matches("rm") {
+        let pattern = rm.value_of("pattern").unwrap().to_string();
+        return AppRunType::RemoveRecord {
+            path,
+            pattern,
+            remote,
+        };
+    }
+
+    if let Some(ls) = app.subcommand_matches("ls") {
+        let format = match ls.value_of("format") {
+            Some(format) => Format::parse(format).unwrap(),
+            None if ls.is_present("json") => Format::Json,
+            None => Format::Table,
+        };
+        let filter = ls.value_of("filter").map(str::to_string);
+        return AppRunType::PrintRecord {
+            path,
+            remote,
+            format,
+            filter,
+        };
+    }
+
+    if let Some(check) = app.subcommand_matches("check") {
+        let json = check.is_present("json");
+        let no_default_bind = check.is_present("no-default-bind");
+        return AppRunType::Check {
+            path,
+            remote,
+            json,
+            no_default_bind,
+        };
+    }
+
+    if let Some(test) = app.subcommand_matches("test") {
+        let qtype = test.value_of("type").unwrap();
+        let qtype = dryrun::parse_type(qtype).unwrap_or_else(|| {
+            updns::error!("Cannot parse query type '{}'", qtype);
+            std::process::exit(crate::EXIT_PARSE)
+        });
+        let domains = test
+            .values_of("domain")
+            .unwrap()
+            .map(|domain| domain.strip_suffix('.').unwrap_or(domain).to_string())
+            .collect();
+        return AppRunType::Test {
+            path,
+            remote,
+            domains,
+            qtype,
+            upstream: test.is_present("upstream"),
+        };
+    }
+
+    if let Some(config) = app.subcommand_matches("config") {
+        if let Some(file) = config.value_of("import") {
+            return AppRunType::ImportConfig {
+                file: PathBuf::from(file),
+            };
         }
-        return AppRunType::AddRecord { path, ip, host };
+        let format = export::Format::parse(config.value_of("export").unwrap()).unwrap();
+        return AppRunType::ExportConfig {
+            path,
+            remote,
+            format,
+        };
+    }
+
+    if let Some(bench) = app.subcommand_matches("bench") {
+        let number = |name: &str| {
+            let value = bench.value_of(name).unwrap();
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .unwrap_or_else(|| exit!("Invalid --{} '{}'", name, value))
+        };
+        let hit_ratio = bench.value_of("hit-ratio").unwrap();
+        let hit_ratio = hit_ratio
+            .parse::<f64>()
+            .ok()
+            .filter(|ratio| (0.0..=1.0).contains(ratio))
+            .unwrap_or_else(|| exit!("Invalid --hit-ratio '{}', from 0 to 1", hit_ratio));
+        return AppRunType::Bench {
+            path,
+            remote,
+            queries: number("queries"),
+            concurrency: number("concurrency"),
+            hit_ratio,
+            baseline: bench.value_of("baseline").map(PathBuf::from),
+        };
     }
 
-    if app.is_present("ls") {
-        return AppRunType::PrintRecord { path };
+    if app.is_present("console") {
+        return AppRunType::Console { path, remote };
     }
 
     if app.is_present("edit") {
-        return AppRunType::EditConfig { path };
+        return AppRunType::EditConfig { path, remote };
+    }
+
+    if let Some(completions) = app.subcommand_matches("completions") {
+        let shell = completions.value_of("shell").unwrap().parse().unwrap();
+        return AppRunType::Completions { shell };
+    }
+
+    if app.is_present("man") {
+        return AppRunType::Man;
+    }
+
+    if let Some(init) = app.subcommand_matches("init") {
+        let force = init.is_present("force");
+        return AppRunType::Init { path, force };
+    }
+
+    #[cfg(windows)]
+    if let Some(service) = app.subcommand_matches("service") {
+        let command = match service.subcommand_name() {
+            Some("install") => ServiceCommand::Install,
+            Some("uninstall") => ServiceCommand::Uninstall,
+            _ => ServiceCommand::Run,
+        };
+        return AppRunType::Service {
+            command,
+            path,
+            duration,
+            remote,
+        };
+    }
+
+    if let Some(stop) = app.subcommand_matches("stop") {
+        let pid_file = stop
+            .value_of("pid-file")
+            .or_else(|| app.value_of("pid-file"))
+            .unwrap_or_else(|| exit!("'stop' needs the --pid-file of the server"));
+        return AppRunType::Stop {
+            pid_file: PathBuf::from(pid_file),
+        };
     }
 
     if app.is_present("path") {
         return AppRunType::PrintPath { path };
     }
 
-    AppRunType::Run { path, duration }
+    AppRunType::Run {
+        path,
+        duration,
+        remote,
+        verbose: app.is_present("verbose"),
+        no_query_log: app.is_present("no-query-log"),
+        daemon: app.is_present("daemon"),
+        pid_file: app.value_of("pid-file").map(PathBuf::from),
+        no_default_bind: app.is_present("no-default-bind"),
+        strict: app.is_present("strict"),
+        user: app.value_of("user").map(str::to_string),
+        group: app.value_of("group").map(str::to_string),
+        log_override,
+        log_format,
+    }
+}
+
+// The `--log` value of `RUST_LOG=updns=debug`, or of a bare level
+fn env_log(value: &str) -> Option<String> {
+    let mut items = value.split(',');
+    let level = items
+        .clone()
+        .find_map(|item| match item.split_once('=') {
+            Some((target, level)) if target.trim() == crate_name!() => Some(level),
+            _ => None,
+        })
+        .or_else(|| items.rfind(|item| !item.contains('=')))?;
+    match level.trim().to_lowercase().as_str() {
+        "off" => Some("!all".to_string()),
+        level => LogLevel::parse(level).map(|level| level.enabled().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use super::*;
+
+    #[test]
+    fn test_completions() {
+        for shell in &Shell::variants() {
+            let mut script = Vec::new();
+            app().gen_completions_to("updns", shell.parse().unwrap(), &mut script);
+            let script = String::from_utf8(script).unwrap();
+            for word in &["init", "completions", "config", "allow-remote-imports"] {
+                assert!(script.contains(word), "{} {}", shell, word);
+            }
+        }
+    }
+
+    #[test]
+    fn test_env_log() {
+        assert_eq!(env_log("updns=debug").as_deref(), Some("all"));
+        assert_eq!(
+            env_log("updns=info,warn").as_deref(),
+            Some("error,warn,info")
+        );
+        assert_eq!(env_log("Error").as_deref(), Some("error"));
+        assert_eq!(env_log("hyper=debug"), None);
+        assert_eq!(env_log("updns=loud"), None);
+    }
+
+    #[test]
+    fn test_man() {
+        let page = man::render(&app(), crate_description!());
+        assert!(page.starts_with(&format!(
+            ".TH UPDNS 1 \"\" \"updns {}\"\n.SH NAME\nupdns \\- DNS proxy tool\n",
+            crate_version!()
+        )));
+        // The subcommands and their flags are documented
+        for sub in &[
+            "add", "bench", "check", "config", "init", "ls", "rm", "test",
+        ] {
+            assert!(page.contains(&format!(".SS updns {}", sub)), "{}", sub);
+        }
+        for long in &[
+            "config",
+            "duration",
+            "allow\\-remote\\-imports",
+            "allow\\-http\\-imports",
+            "verbose",
+            "log",
+            "baseline",
+            "export",
+            "json",
+        ] {
+            assert!(
+                page.contains(&format!("\\fB\\-\\-{}\\fR", long)),
+                "{}",
+                long
+            );
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::Mutex};
+use tokio::{
+    io::{Error, ErrorKind, Result},
+    sync::oneshot,
+};
+
+type Shared<T> = std::result::Result<T, (ErrorKind, String)>;
+
+// Run identical concurrent requests only once, the first caller (leader)
+// runs the future and every other caller with the same key receives a copy
+pub struct Coalesce<K, T> {
+    waiting: Mutex<HashMap<K, Vec<oneshot::Sender<Shared<T>>>>>,
+}
+
+impl<K: Hash + Eq + Clone, T: Clone> Coalesce<K, T> {
+    pub fn new() -> Self {
+        Coalesce {
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F>(&self, key: K, f: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let receiver = {
+            let mut waiting = self.waiting.lock().unwrap();
+            match waiting.get_mut(&key) {
+                Some(senders) => {
+                    let (sender, receiver) = oneshot::channel();
+                    senders.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    waiting.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = receiver {
+            return match receiver.await {
+                Ok(Ok(data)) => Ok(data),
+                Ok(Err((kind, msg))) => Err(Error::new(kind, msg)),
+                // The leader was dropped before finishing
+                Err(_) => Err(Error::other("Coalesced request was cancelled")),
+            };
+        }
+
+        let mut leader = Leader {
+            coalesce: self,
+            key: Some(key),
+        };
+        let result = f.await;
+
+        let shared = match &result {
+            Ok(data) => Ok(data.clone()),
+            Err(err) => Err((err.kind(), err.to_string())),
+        };
+        for sender in leader.finish() {
+            let _ = sender.send(shared.clone());
+        }
+
+        result
+    }
+
+    fn remove(&self, key: &K) -> Vec<oneshot::Sender<Shared<T>>> {
+        self.waiting.lock().unwrap().remove(key).unwrap_or_default()
+    }
+}
+
+// Removes the in-flight entry even if the leader is cancelled,
+// dropping the senders wakes the waiters with an error
+struct Leader<'a, K: Hash + Eq + Clone, T: Clone> {
+    coalesce: &'a Coalesce<K, T>,
+    key: Option<K>,
+}
+
+impl<K: Hash + Eq + Clone, T: Clone> Leader<'_, K, T> {
+    fn finish(&mut self) -> Vec<oneshot::Sender<Shared<T>>> {
+        match self.key.take() {
+            Some(key) => self.coalesce.remove(&key),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, T: Clone> Drop for Leader<'_, K, T> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod test_coalesce {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_share_result() {
+        let coalesce = Arc::new(Coalesce::new());
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..10)
+            .map(|_| {
+                let coalesce = coalesce.clone();
+                let count = count.clone();
+                tokio::spawn(async move {
+                    coalesce
+                        .run("key", async {
+                            count.fetch_add(1, Ordering::SeqCst);
+                            sleep(Duration::from_millis(100)).await;
+                            Ok(vec![1, 2, 3])
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), vec![1, 2, 3]);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(coalesce.waiting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_share_error() {
+        let coalesce = Arc::new(Coalesce::new());
+
+        let leader = {
+            let coalesce = coalesce.clone();
+            tokio::spawn(async move {
+                coalesce
+                    .run("key", async {
+                        sleep(Duration::from_millis(100)).await;
+                        Err(Error::new(ErrorKind::TimedOut, "timeout"))
+                    })
+                    .await
+            })
+        };
+        sleep(Duration::from_millis(10)).await;
+
+        let err = coalesce
+            .run("key", async { Ok(Vec::<u8>::new()) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            leader.await.unwrap().unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[tokio::test]
+    async fn test_leader_cancelled() {
+        let coalesce = Arc::new(Coalesce::new());
+
+        let leader = {
+            let coalesce = coalesce.clone();
+            tokio::spawn(async move {
+                coalesce
+                    .run("key", async {
+                        sleep(Duration::from_secs(10)).await;
+                        Ok(vec![])
+                    })
+                    .await
+            })
+        };
+        sleep(Duration::from_millis(10)).await;
+
+        let waiter = {
+            let coalesce = coalesce.clone();
+            tokio::spawn(async move { coalesce.run("key", async { Ok(Vec::<u8>::new()) }).await })
+        };
+        sleep(Duration::from_millis(10)).await;
+
+        leader.abort();
+        assert!(waiter.await.unwrap().is_err());
+        assert!(coalesce.waiting.lock().unwrap().is_empty());
+    }
+}
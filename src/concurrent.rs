@@ -0,0 +1,206 @@
+use crate::{
+    config::Hosts,
+    matcher::{Matcher, Pattern},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::RwLock,
+};
+
+// Power of two, the text records are spread over the shards by hash
+const SHARDS: usize = 16;
+
+// Host records shared between threads without an outer lock.
+// The plain text records live in sharded maps so concurrent lookups
+// rarely wait on the same lock, the wildcard and regex records change
+// rarely and sit behind a single read-mostly lock
+#[derive(Debug)]
+pub struct ConcurrentHosts {
+    text: Vec<RwLock<HashMap<String, IpAddr>>>,
+    patterns: RwLock<Vec<(Matcher, IpAddr)>>,
+}
+
+impl Default for ConcurrentHosts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConcurrentHosts {
+    pub fn new() -> ConcurrentHosts {
+        ConcurrentHosts {
+            text: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            patterns: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn shard(&self, domain: &str) -> &RwLock<HashMap<String, IpAddr>> {
+        let mut hasher = DefaultHasher::new();
+        domain.hash(&mut hasher);
+        &self.text[hasher.finish() as usize & (SHARDS - 1)]
+    }
+
+    // Same answer as `Hosts::get`, a fully qualified domain included
+    pub fn get(&self, domain: &str) -> Option<IpAddr> {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        if let Some(ip) = self.shard(domain).read().unwrap().get(domain) {
+            return Some(*ip);
+        }
+        self.patterns
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(domain))
+            .map(|(_, ip)| *ip)
+    }
+
+    // Like a record after the existing ones, the first record of a domain wins
+    pub fn insert(&self, matcher: Matcher, ip: IpAddr) {
+        match matcher.as_pattern() {
+            Pattern::Text(domain) => {
+                self.shard(domain)
+                    .write()
+                    .unwrap()
+                    .entry(domain.to_string())
+                    .or_insert(ip);
+            }
+            _ => self.patterns.write().unwrap().push((matcher, ip)),
+        }
+    }
+
+    // Remove the records written as `pattern`, returns how many
+    pub fn remove(&self, pattern: &str) -> usize {
+        let matcher = match Matcher::new(pattern) {
+            Ok(matcher) => matcher,
+            Err(_) => return 0,
+        };
+        match matcher.as_pattern() {
+            Pattern::Text(domain) => {
+                let removed = self.shard(domain).write().unwrap().remove(domain);
+                removed.is_some() as usize
+            }
+            _ => {
+                let mut patterns = self.patterns.write().unwrap();
+                let len = patterns.len();
+                patterns.retain(|(matcher, _)| matcher.to_string() != pattern);
+                len - patterns.len()
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let text = self
+            .text
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum::<usize>();
+        text + self.patterns.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<Hosts> for ConcurrentHosts {
+    fn from(hosts: Hosts) -> Self {
+        let concurrent = ConcurrentHosts::new();
+        for (matcher, ip) in hosts.into_records() {
+            concurrent.insert(matcher, ip);
+        }
+        concurrent
+    }
+}
+
+#[cfg(test)]
+mod test_concurrent {
+    use super::*;
+    use crate::config::parse_test_config;
+    use std::{sync::Arc, thread};
+
+    fn hosts(content: &str) -> Hosts {
+        parse_test_config(content).hosts
+    }
+
+    #[test]
+    fn test_from_hosts() {
+        let content = "
+            *.example.com 1.1.1.1
+            www.example.com 2.2.2.2
+            www.example.com 3.3.3.3
+            ~^\\w+\\.test$ 4.4.4.4
+            a.test 5.5.5.5
+        ";
+        let expected = hosts(content);
+        let concurrent = ConcurrentHosts::from(hosts(content));
+        for domain in &[
+            "www.example.com",
+            "api.example.com",
+            "a.test",
+            "b.test",
+            "example.org",
+            "www.example.com.",
+            "a.test.",
+        ] {
+            assert_eq!(
+                concurrent.get(domain),
+                expected.get(domain).copied(),
+                "{}",
+                domain
+            );
+        }
+        // The duplicated domain keeps its first record
+        assert_eq!(concurrent.len(), 4);
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let concurrent = ConcurrentHosts::from(hosts("a.com 1.1.1.1\n*.a.com 2.2.2.2"));
+        concurrent.insert(Matcher::new("a.com").unwrap(), "9.9.9.9".parse().unwrap());
+        assert_eq!(concurrent.get("a.com"), Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(concurrent.get("a.com."), Some("1.1.1.1".parse().unwrap()));
+
+        assert_eq!(concurrent.remove("a.com"), 1);
+        assert_eq!(concurrent.get("a.com"), None);
+        assert_eq!(concurrent.remove("*.a.com"), 1);
+        assert_eq!(concurrent.get("www.a.com"), None);
+        assert_eq!(concurrent.remove("~["), 0);
+        assert!(concurrent.is_empty());
+    }
+
+    #[test]
+    fn test_shared() {
+        let concurrent = Arc::new(ConcurrentHosts::new());
+        let writer = {
+            let concurrent = concurrent.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let matcher = Matcher::new(&format!("host{}.com", i)).unwrap();
+                    concurrent.insert(matcher, "1.1.1.1".parse().unwrap());
+                }
+            })
+        };
+        let readers = (0..4)
+            .map(|_| {
+                let concurrent = concurrent.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let _ = concurrent.get(&format!("host{}.com", i));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(concurrent.len(), 1000);
+        assert_eq!(
+            concurrent.get("host999.com"),
+            Some("1.1.1.1".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{dryrun, force_get_config, reload_config, update_config, SERVER};
+use std::{io::Write, path::Path, time::Instant};
+use tokio::io::{self, AsyncBufReadExt, BufReader, ErrorKind, Result};
+use updns::{remote::RemoteImports, server::answers_type, Answer, DnsRecord, QueryType};
+
+const HELP: &str = "Type a domain and an optional type (a.com AAAA), :reload, :stats or :quit";
+
+// A line typed at the prompt
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Query(String, QueryType),
+    Reload,
+    Stats,
+    Help,
+    Quit,
+    Empty,
+}
+
+fn parse_line(line: &str) -> std::result::Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let first = match words.next() {
+        Some(word) => word,
+        None => return Ok(Command::Empty),
+    };
+    let command = match first {
+        ":reload" | ":r" => Command::Reload,
+        ":stats" | ":s" => Command::Stats,
+        ":help" | ":h" | ":?" => Command::Help,
+        ":quit" | ":q" | ":exit" => Command::Quit,
+        _ if first.starts_with(':') => return Err(format!("Unknown command '{}'", first)),
+        domain => {
+            let qtype = match words.next() {
+                Some(text) => dryrun::parse_type(text)
+                    .ok_or_else(|| format!("Cannot parse query type '{}'", text))?,
+                None => QueryType::A,
+            };
+            let domain = domain.strip_suffix('.').unwrap_or(domain);
+            Command::Query(domain.to_string(), qtype)
+        }
+    };
+    match words.next() {
+        Some(extra) => Err(format!("Unexpected '{}'", extra)),
+        None => Ok(command),
+    }
+}
+
+// Load the config like the server and answer the typed names with `handle`
+pub async fn run(path: &Path, remote: RemoteImports) {
+    update_config(force_get_config(path, remote).await).await;
+    println!("{}", HELP);
+
+    let mut lines = BufReader::new(io::stdin()).lines();
+    loop {
+        print!("> ");
+        let _ = std::io::stdout().flush();
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            // Ctrl-D
+            Ok(None) => break println!(),
+            Err(err) => break eprintln!("Failed to read input {:?}", err),
+        };
+        match parse_line(&line) {
+            Ok(Command::Query(domain, qtype)) => {
+                for line in trace(&domain, qtype).await {
+                    println!("{}", line);
+                }
+            }
+            Ok(Command::Reload) => match reload_config(path, remote).await {
+                Ok(_) => println!("Reloaded {}", path.display()),
+                Err(err) => println!("Failed to reload {}: {}", path.display(), err),
+            },
+            Ok(Command::Stats) => println!("{}", SERVER.stats().summary()),
+            Ok(Command::Help) => println!("{}", HELP),
+            Ok(Command::Quit) => break,
+            Ok(Command::Empty) => {}
+            Err(msg) => println!("{}", msg),
+        }
+    }
+}
+
+// The host lookup, how the query was answered and the decoded answer
+async fn trace(domain: &str, qtype: QueryType) -> Vec<String> {
+    let mut out = Vec::new();
+    match SERVER.hosts().find_record(domain) {
+        Some(record) if answers_type(qtype, record.ip) => {
+            out.push(format!("hosts: matched {}", dryrun::origin(&record)))
+        }
+        Some(record) => out.push(format!(
+            "hosts: {} has no {} address",
+            dryrun::origin(&record),
+            dryrun::type_name(qtype)
+        )),
+        None => out.push("hosts: no record".to_string()),
+    }
+
+    let start = Instant::now();
+    let res = SERVER.resolve(domain, qtype).await;
+    let elapsed = start.elapsed();
+    let answer = match res {
+        Ok(answer) => answer,
+        Err(err) if err.kind() == ErrorKind::TimedOut => {
+            out.push(format!("timed out after {:?}", elapsed));
+            return out;
+        }
+        Err(err) => {
+            out.push(format!("failed after {:?}: {}", elapsed, err));
+            return out;
+        }
+    };
+    out.push(describe(&answer, elapsed));
+    match decode(&answer) {
+        Ok(lines) => out.extend(lines),
+        Err(err) => out.push(format!("cannot decode the answer: {}", err)),
+    }
+    out
+}
+
+fn describe(answer: &Answer, elapsed: std::time::Duration) -> String {
+    let source = match answer.source() {
+        Some(source) => format!(" by {}", source),
+        None => String::new(),
+    };
+    format!("{}{} in {:?}", answer.outcome().as_str(), source, elapsed)
+}
+
+// Result code and one zone file line per record
+pub fn decode(answer: &Answer) -> Result<Vec<String>> {
+    let packet = answer.packet()?;
+    let mut out = vec![format!(
+        "{:?}, {} answers",
+        packet.header.rescode,
+        packet.answers.len()
+    )];
+    let records = packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.resources);
+    out.extend(records.map(|record| format!("  {}", format_record(record))));
+    Ok(out)
+}
+
+fn format_record(record: &DnsRecord) -> String {
+    match record {
+        DnsRecord::A { domain, addr, ttl } => format!("{} {} A {}", domain, ttl, addr),
+        DnsRecord::AAAA { domain, addr, ttl } => format!("{} {} AAAA {}", domain, ttl, addr),
+        DnsRecord::NS { domain, host, ttl } => format!("{} {} NS {}", domain, ttl, host),
+        DnsRecord::CNAME { domain, host, ttl } => format!("{} {} CNAME {}", domain, ttl, host),
+        DnsRecord::MX {
+            domain,
+            priority,
+            host,
+            ttl,
+        } => format!("{} {} MX {} {}", domain, ttl, priority, host),
+        DnsRecord::UNKNOWN {
+            domain,
+            qtype,
+            data_len,
+            ttl,
+        } => format!("{} {} TYPE{} ({} bytes)", domain, ttl, qtype, data_len),
+    }
+}
+
+#[cfg(test)]
+mod test_console {
+    use super::*;
+    use crate::parse_test_config;
+    use updns::Server;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("a.com."),
+            Ok(Command::Query("a.com".into(), QueryType::A))
+        );
+        assert_eq!(
+            parse_line("  a.com aaaa "),
+            Ok(Command::Query("a.com".into(), QueryType::AAAA))
+        );
+        assert_eq!(parse_line(":q"), Ok(Command::Quit));
+        assert_eq!(parse_line(":reload"), Ok(Command::Reload));
+        assert_eq!(parse_line(":stats"), Ok(Command::Stats));
+        assert_eq!(parse_line(""), Ok(Command::Empty));
+        assert!(parse_line(":x").is_err());
+        assert!(parse_line("a.com TXTX").is_err());
+        assert!(parse_line("a.com A b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode() {
+        let answer = Server::new(parse_test_config("a.com 1.2.3.4"))
+            .resolve("a.com", QueryType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            decode(&answer).unwrap(),
+            vec!["NOERROR, 1 answers", "  a.com 3600 A 1.2.3.4"]
+        );
+        assert_eq!(
+            describe(&answer, std::time::Duration::from_millis(2)),
+            "hosts by a.com in 2ms"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+// Set in the environment of the process running in the background
+const CHILD: &str = "UPDNS_DAEMON";
+// Written by the child once it serves, the foreground process exits then
+const READY: &[u8] = b"\0updns-ready\n";
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The pid file of the running server
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    // Refuses when the file names a live process, a stale one is overwritten.
+    // Written to a temporary file first so it's never seen half written
+    pub fn create(path: &Path) -> Result<PidFile> {
+        check(path)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", process::id()));
+        fs::write(&tmp, format!("{}\n", process::id()))?;
+        if let Err(err) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Unless another process took it over
+    pub fn remove(self) {
+        if read_pid(&self.path).ok() == Some(process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Result<u32> {
+    let text = fs::read_to_string(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => Error::new(
+            ErrorKind::NotFound,
+            format!("No pid file {:?}, updns is not running", path),
+        ),
+        _ => err,
+    })?;
+    text.trim().parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} is not a pid file", path),
+        )
+    })
+}
+
+// Fails when the pid file names a live process
+pub fn check(path: &Path) -> Result<()> {
+    match read_pid(path) {
+        Ok(pid) if pid != process::id() && imp::is_alive(pid) => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("updns is already running with pid {}, see {:?}", pid, path),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Send SIGTERM to the process of the pid file and wait for it to exit
+pub fn stop(path: &Path) -> Result<u32> {
+    let pid = read_pid(path)?;
+    if !imp::is_alive(pid) {
+        let _ = fs::remove_file(path);
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "updns is not running, removed the stale pid file {:?}",
+                path
+            ),
+        ));
+    }
+    imp::terminate(pid)?;
+
+    let start = Instant::now();
+    while imp::is_alive(pid) {
+        if start.elapsed() > STOP_TIMEOUT {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("updns with pid {} is still running", pid),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(pid)
+}
+
+// Whether this process is the one started in the background
+pub fn is_child() -> bool {
+    std::env::var_os(CHILD).is_some()
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::{
+        env,
+        fs::File,
+        io::{Read, Write},
+        os::unix::{
+            io::{AsRawFd, FromRawFd, OwnedFd},
+            process::CommandExt,
+        },
+        process::{Command, Stdio},
+    };
+
+    pub fn is_alive(pid: u32) -> bool {
+        let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        ret == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    pub fn terminate(pid: u32) -> Result<()> {
+        match unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    // Run the same command again in a new session. Its output is shown here
+    // until it serves, then this process exits. Forking is not an option,
+    // the runtime's threads would not survive it
+    pub fn spawn() -> Result<u32> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let read = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let write = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+        // Only passed on as stdout and stderr, the child closing them is the
+        // end of the output
+        for fd in &fds {
+            unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        let mut command = Command::new(env::current_exe()?);
+        command
+            .args(env::args_os().skip(1))
+            .env(CHILD, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(write.try_clone()?))
+            .stderr(Stdio::from(write));
+        unsafe {
+            command.pre_exec(|| match libc::setsid() {
+                -1 => Err(Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+        let mut child = command.spawn()?;
+        // Drop the write ends held by `command`
+        drop(command);
+
+        let mut output = Vec::new();
+        File::from(read).read_to_end(&mut output)?;
+        let ready = output.ends_with(READY);
+        if ready {
+            output.truncate(output.len() - READY.len());
+        }
+        std::io::stderr().write_all(&output)?;
+
+        if ready {
+            return Ok(child.id());
+        }
+        let status = child.wait()?;
+        Err(Error::other(format!("updns failed to start, {}", status)))
+    }
+
+    // Tell the foreground process we serve and let go of its terminal
+    pub fn detach() -> Result<()> {
+        std::io::stdout().flush()?;
+        std::io::stderr().write_all(READY)?;
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        for fd in 0..3 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn is_alive(_pid: u32) -> bool {
+        false
+    }
+
+    pub fn terminate(_pid: u32) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn spawn() -> Result<u32> {
+        Err(unsupported())
+    }
+
+    pub fn detach() -> Result<()> {
+        Ok(())
+    }
+
+    fn unsupported() -> Error {
+        Error::new(
+            ErrorKind::Unsupported,
+            "Running in the background is only supported on unix, use 'updns service'",
+        )
+    }
+}
+
+pub use imp::{detach, spawn};
+
+#[cfg(all(test, unix))]
+mod test_daemon {
+    use super::*;
+    use std::process::Command;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("updns-{}-{}.pid", name, process::id()))
+    }
+
+    #[test]
+    fn test_pid_file() {
+        let path = temp("pid");
+        let pid = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), process::id());
+        // Our own pid is not another server
+        check(&path).unwrap();
+
+        // A live process
+        let mut sleep = Command::new("sleep").arg("10").spawn().unwrap();
+        fs::write(&path, format!("{}\n", sleep.id())).unwrap();
+        let err = PidFile::create(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        // Not ours anymore
+        pid.remove();
+        assert!(path.exists());
+
+        // Stale after the process exits
+        sleep.kill().unwrap();
+        sleep.wait().unwrap();
+        PidFile::create(&path).unwrap().remove();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stop() {
+        let path = temp("stop");
+        let mut sleep = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = sleep.id();
+        fs::write(&path, format!("{}\n", pid)).unwrap();
+        // Reap it so it's gone once terminated
+        let waiter = std::thread::spawn(move || sleep.wait().unwrap());
+
+        assert_eq!(stop(&path).unwrap(), pid);
+        assert!(!waiter.join().unwrap().success());
+
+        let err = stop(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!path.exists());
+
+        fs::write(&path, "x").unwrap();
+        assert_eq!(stop(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
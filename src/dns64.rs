@@ -0,0 +1,92 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+// Prefix lengths of RFC 6052, the well-known prefix is 64:ff9b::/96
+const LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+// The prefix IPv4 addresses are embedded into for IPv6-only clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Prefix {
+    // `64:ff9b::/96`, a bare address is a /96
+    pub fn parse(text: &str) -> Option<Prefix> {
+        let (addr, len) = match text.split_once('/') {
+            Some((addr, len)) => (addr, len.parse::<u8>().ok()?),
+            None => (text, 96),
+        };
+        if !LENGTHS.contains(&len) {
+            return None;
+        }
+        let addr = addr.parse::<Ipv6Addr>().ok()?;
+        let bits = u128::from(addr) & !(u128::MAX >> len);
+
+        Some(Prefix {
+            addr: bits.into(),
+            len,
+        })
+    }
+
+    // The address follows the prefix, skipping bits 64 to 71 which stay
+    // zero (RFC 6052 section 2.2)
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.addr.octets();
+        let mut i = self.len as usize / 8;
+        for byte in ip.octets() {
+            if i == 8 {
+                i += 1;
+            }
+            octets[i] = byte;
+            i += 1;
+        }
+        octets.into()
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[cfg(test)]
+mod test_dns64 {
+    use super::*;
+
+    #[test]
+    fn test_embed() {
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        // The examples of RFC 6052 section 2.4
+        let cases = [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::", "64:ff9b::192.0.2.33"),
+        ];
+        for (prefix, addr) in &cases {
+            let prefix = Prefix::parse(prefix).unwrap();
+            assert_eq!(prefix.embed(ip), addr.parse::<Ipv6Addr>().unwrap());
+        }
+
+        assert_eq!(
+            Prefix::parse("64:ff9b::").unwrap().to_string(),
+            "64:ff9b::/96"
+        );
+        // Host bits are dropped
+        assert_eq!(
+            Prefix::parse("64:ff9b::1:2/96").unwrap().to_string(),
+            "64:ff9b::/96"
+        );
+        assert_eq!(Prefix::parse("64:ff9b::/80"), None);
+        assert_eq!(Prefix::parse("192.0.2.0/96"), None);
+        assert_eq!(Prefix::parse("64:ff9b::/x"), None);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+// Frames waiting for the collector, newer ones are dropped when it's full
+const QUEUE: usize = 1024;
+const RECONNECT: Duration = Duration::from_secs(1);
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// dnstap.Message.Type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+}
+
+pub struct Message<'a> {
+    pub kind: Kind,
+    // The client, or updns for resolver messages
+    pub query_address: SocketAddr,
+    // updns, or the upstream for resolver messages
+    pub response_address: SocketAddr,
+    // Over TCP instead of UDP
+    pub tcp: bool,
+    pub query_time: SystemTime,
+    pub query: Option<&'a [u8]>,
+    pub response_time: Option<SystemTime>,
+    pub response: Option<&'a [u8]>,
+}
+
+impl Message<'_> {
+    // A dnstap.Dnstap protobuf
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(128);
+        field_varint(&mut message, 1, self.kind as u64);
+
+        // Both addresses in the family of the response address
+        let v6 = self.response_address.is_ipv6();
+        field_varint(&mut message, 2, if v6 { 2 } else { 1 });
+        // SocketProtocol, UDP or TCP
+        field_varint(&mut message, 3, if self.tcp { 2 } else { 1 });
+        field_bytes(&mut message, 4, &address(self.query_address.ip(), v6));
+        field_bytes(&mut message, 5, &address(self.response_address.ip(), v6));
+        field_varint(&mut message, 6, self.query_address.port() as u64);
+        field_varint(&mut message, 7, self.response_address.port() as u64);
+
+        let (sec, nsec) = timestamp(self.query_time);
+        field_varint(&mut message, 8, sec);
+        field_fixed32(&mut message, 9, nsec);
+        if let Some(query) = self.query {
+            field_bytes(&mut message, 10, query);
+        }
+        if let Some(time) = self.response_time {
+            let (sec, nsec) = timestamp(time);
+            field_varint(&mut message, 12, sec);
+            field_fixed32(&mut message, 13, nsec);
+        }
+        if let Some(response) = self.response {
+            field_bytes(&mut message, 14, response);
+        }
+
+        let mut dnstap = Vec::with_capacity(message.len() + 32);
+        field_bytes(&mut dnstap, 1, b"updns");
+        field_bytes(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        field_bytes(&mut dnstap, 14, &message);
+        // Type MESSAGE
+        field_varint(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+fn address(ip: IpAddr, v6: bool) -> Vec<u8> {
+    match (ip, v6) {
+        (IpAddr::V4(ip), false) => ip.octets().to_vec(),
+        (IpAddr::V4(ip), true) => ip.to_ipv6_mapped().octets().to_vec(),
+        (IpAddr::V6(ip), true) => ip.octets().to_vec(),
+        (IpAddr::V6(ip), false) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.octets().to_vec(),
+            None => ip.octets().to_vec(),
+        },
+    }
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+fn varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u64, n: u64) {
+    varint(buf, field << 3);
+    varint(buf, n);
+}
+
+fn field_fixed32(buf: &mut Vec<u8>, field: u64, n: u32) {
+    varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// Streams the messages to a Frame Streams collector on a unix socket,
+// sending never waits for the collector
+pub struct Dnstap {
+    path: PathBuf,
+    sender: mpsc::Sender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    pub fn new(path: PathBuf) -> Dnstap {
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(imp::writer(path.clone(), receiver, dropped.clone()));
+        Dnstap {
+            path,
+            sender,
+            dropped,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn send(&self, message: &Message) {
+        if self.sender.try_send(message.encode()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(test)]
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use crate::{info, warn};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
+        net::UnixStream,
+        time::{sleep, timeout},
+    };
+
+    const ACCEPT: u32 = 0x01;
+    const START: u32 = 0x02;
+    const STOP: u32 = 0x03;
+    const READY: u32 = 0x04;
+    const FIELD_CONTENT_TYPE: u32 = 0x01;
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub async fn writer(
+        path: PathBuf,
+        mut receiver: mpsc::Receiver<Vec<u8>>,
+        dropped: Arc<AtomicU64>,
+    ) {
+        // Only the first failure of a row is logged
+        let mut failing = false;
+        loop {
+            match connect(&path).await {
+                Ok(mut stream) => {
+                    info!("Connected to dnstap collector {:?}", path);
+                    failing = false;
+                    match stream_frames(&mut stream, &mut receiver).await {
+                        // The writer was replaced or the server stopped
+                        Ok(()) => return,
+                        Err(err) => warn!("Lost dnstap collector {:?}\n{:?}", path, err),
+                    }
+                }
+                Err(err) if !failing => {
+                    warn!(
+                        "Failed to connect to dnstap collector {:?}\n{:?}",
+                        path, err
+                    );
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+
+            // Discard the frames until the next attempt
+            let retry = sleep(RECONNECT);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    _ = &mut retry => break,
+                    frame = receiver.recv() => match frame {
+                        Some(_) => {
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => return,
+                    },
+                }
+            }
+        }
+    }
+
+    // Bidirectional handshake: READY, ACCEPT, START
+    async fn connect(path: &PathBuf) -> Result<UnixStream> {
+        let mut stream = UnixStream::connect(path).await?;
+        timeout(HANDSHAKE_TIMEOUT, async {
+            stream.write_all(&control(READY, true)).await?;
+            if read_control(&mut stream).await? != ACCEPT {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Collector did not accept",
+                ));
+            }
+            stream.write_all(&control(START, true)).await
+        })
+        .await??;
+        Ok(stream)
+    }
+
+    async fn stream_frames(
+        stream: &mut UnixStream,
+        receiver: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> Result<()> {
+        while let Some(frame) = receiver.recv().await {
+            let mut data = Vec::with_capacity(frame.len() + 4);
+            data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            data.extend(frame);
+            stream.write_all(&data).await?;
+        }
+        stream.write_all(&control(STOP, false)).await
+    }
+
+    pub(super) fn control(kind: u32, content_type: bool) -> Vec<u8> {
+        let mut frame = kind.to_be_bytes().to_vec();
+        if content_type {
+            frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+            frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+            frame.extend_from_slice(CONTENT_TYPE);
+        }
+        // Escape, then the length of the control frame
+        let mut data = vec![0; 4];
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend(frame);
+        data
+    }
+
+    pub(super) async fn read_control(stream: &mut UnixStream) -> Result<u32> {
+        if stream.read_u32().await? != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Expect a control frame"));
+        }
+        let len = stream.read_u32().await? as usize;
+        if !(4..=512).contains(&len) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid control frame"));
+        }
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame).await?;
+        Ok(u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]))
+    }
+}
+
+// No unix sockets, the frames are discarded
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub async fn writer(
+        path: PathBuf,
+        mut receiver: mpsc::Receiver<Vec<u8>>,
+        dropped: Arc<AtomicU64>,
+    ) {
+        crate::warn!("dnstap {:?} needs unix sockets", path);
+        while receiver.recv().await.is_some() {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_dnstap {
+    use super::*;
+
+    // Top level fields of a protobuf message
+    fn fields(mut buf: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut n = 0;
+            for shift in (0..).step_by(7) {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                n |= ((byte & 0x7F) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            n
+        }
+
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let value = match key & 7 {
+                0 => varint(&mut buf).to_le_bytes().to_vec(),
+                5 => {
+                    let value = buf[..4].to_vec();
+                    buf = &buf[4..];
+                    value
+                }
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let value = buf[..len].to_vec();
+                    buf = &buf[len..];
+                    value
+                }
+                wire => panic!("unexpected wire type {}", wire),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn field(fields: &[(u64, Vec<u8>)], n: u64) -> Vec<u8> {
+        fields.iter().find(|(i, _)| *i == n).unwrap().1.clone()
+    }
+
+    fn message(kind: Kind) -> Message<'static> {
+        Message {
+            kind,
+            query_address: "192.0.2.1:5353".parse().unwrap(),
+            response_address: "192.0.2.53:53".parse().unwrap(),
+            tcp: false,
+            query_time: UNIX_EPOCH + Duration::new(1_600_000_000, 42),
+            query: Some(b"query"),
+            response_time: None,
+            response: None,
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let dnstap = fields(&message(Kind::ClientQuery).encode());
+        assert_eq!(field(&dnstap, 1), b"updns");
+        assert_eq!(field(&dnstap, 15)[0], 1);
+        let tcp = Message {
+            tcp: true,
+            ..message(Kind::ClientQuery)
+        };
+        let tcp = fields(&field(&fields(&tcp.encode()), 14));
+        assert_eq!(field(&tcp, 3)[0], 2);
+
+        let message = fields(&field(&dnstap, 14));
+        assert_eq!(field(&message, 1)[0], Kind::ClientQuery as u8);
+        assert_eq!(field(&message, 2)[0], 1);
+        assert_eq!(field(&message, 3)[0], 1);
+        assert_eq!(field(&message, 4), vec![192, 0, 2, 1]);
+        assert_eq!(field(&message, 5), vec![192, 0, 2, 53]);
+        assert_eq!(field(&message, 6)[..2], 5353_u16.to_le_bytes());
+        assert_eq!(field(&message, 9), 42_u32.to_le_bytes());
+        assert_eq!(field(&message, 10), b"query");
+        assert!(message.iter().all(|(i, _)| *i != 14));
+    }
+
+    #[test]
+    fn test_address_family() {
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(address(mapped, false), vec![192, 0, 2, 1]);
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(address(v4, true), address(mapped, true));
+    }
+
+    // In-process collector: handshake, frames and reconnection
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_collector() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{UnixListener, UnixStream},
+            time::{sleep, timeout},
+        };
+
+        async fn accept(listener: &UnixListener) -> UnixStream {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(imp::read_control(&mut stream).await.unwrap(), 0x04);
+            stream.write_all(&imp::control(0x01, true)).await.unwrap();
+            assert_eq!(imp::read_control(&mut stream).await.unwrap(), 0x02);
+            stream
+        }
+
+        async fn read_frame(stream: &mut UnixStream) -> Vec<u8> {
+            let len = stream.read_u32().await.unwrap();
+            let mut frame = vec![0; len as usize];
+            stream.read_exact(&mut frame).await.unwrap();
+            frame
+        }
+
+        let dir = std::env::temp_dir().join(format!("updns-dnstap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let tap = Dnstap::new(path.clone());
+        let mut stream = timeout(Duration::from_secs(5), accept(&listener))
+            .await
+            .unwrap();
+
+        let kinds = [
+            Kind::ClientQuery,
+            Kind::ResolverQuery,
+            Kind::ResolverResponse,
+            Kind::ClientResponse,
+        ];
+        for kind in &kinds {
+            tap.send(&message(*kind));
+        }
+        for kind in &kinds {
+            let frame = timeout(Duration::from_secs(5), read_frame(&mut stream))
+                .await
+                .unwrap();
+            let message = fields(&field(&fields(&frame), 14));
+            assert_eq!(field(&message, 1)[0], *kind as u8);
+        }
+
+        // The collector restarts
+        drop(stream);
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let reconnect = async {
+            let mut stream = accept(&listener).await;
+            loop {
+                tap.send(&message(Kind::ClientQuery));
+                if let Ok(frame) = timeout(Duration::from_millis(50), read_frame(&mut stream)).await
+                {
+                    return frame;
+                }
+            }
+        };
+        let sender = async {
+            // Frames sent while the collector is away are discarded
+            for _ in 0..10 {
+                tap.send(&message(Kind::ClientQuery));
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let (frame, _) = timeout(
+            Duration::from_secs(5),
+            futures_util::future::join(reconnect, sender),
+        )
+        .await
+        .unwrap();
+        assert!(!frame.is_empty());
+        assert!(tap.dropped() > 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
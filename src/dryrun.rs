@@ -0,0 +1,235 @@
+use crate::console;
+use std::net::SocketAddr;
+use updns::{
+    config::{Config, Proxy, ProxyOptions, Record},
+    server::{answers_type, clamp_ttl, DEFAULT_PROXY, DEFAULT_TIMEOUT, DEFAULT_TTL},
+    upstream::Udp,
+    QueryType, Server,
+};
+
+// What the server would do with a query
+#[derive(Debug)]
+pub enum Decision<'a> {
+    Local {
+        record: Record<'a>,
+        ttl: u32,
+    },
+    Forward {
+        upstreams: Vec<SocketAddr>,
+        // A record matched without an address of the queried type
+        skipped: Option<Record<'a>>,
+    },
+}
+
+impl Decision<'_> {
+    pub fn is_local(&self) -> bool {
+        matches!(self, Decision::Local { .. })
+    }
+}
+
+// The same lookup as `handle` and `get_answer`
+pub fn decide<'a>(config: &'a Config, domain: &str, qtype: QueryType) -> Decision<'a> {
+    let record = config.hosts().find_record(domain);
+    match record {
+        Some(record) if answers_type(qtype, record.ip) => Decision::Local {
+            record,
+            ttl: clamp_ttl(DEFAULT_TTL, config.ttl_min(), None),
+        },
+        skipped => Decision::Forward {
+            upstreams: upstreams(config),
+            skipped,
+        },
+    }
+}
+
+// The names which didn't resolve are left out
+fn upstreams(config: &Config) -> Vec<SocketAddr> {
+    let addrs = config.proxy().iter().flat_map(Proxy::addrs);
+    match addrs.copied().collect::<Vec<_>>() {
+        addrs if addrs.is_empty() => DEFAULT_PROXY.iter().map(|p| p.parse().unwrap()).collect(),
+        addrs => addrs,
+    }
+}
+
+// The answer of the first upstream to the query, whatever the host records.
+// Validated like the server does, without the rewrites of the config
+pub async fn ask_upstream(config: &Config, domain: &str, qtype: QueryType) -> Vec<String> {
+    let addr = upstreams(config)[0];
+    let timeout = match config.proxy_options().get(&addr) {
+        Some(ProxyOptions {
+            timeout: Some(timeout),
+            ..
+        }) => *timeout,
+        _ => config.timeout().unwrap_or(DEFAULT_TIMEOUT),
+    };
+    let upstream = Udp::new(addr, timeout, config.dns0x20().unwrap_or(true));
+    let server = Server::with_upstream(Config::new(), upstream);
+    let answer = server
+        .resolve(domain, qtype)
+        .await
+        .and_then(|answer| console::decode(&answer));
+    match answer {
+        Ok(mut lines) => {
+            lines[0] = format!("upstream {}: {}", addr, lines[0]);
+            lines
+        }
+        Err(err) => vec![format!("upstream {}: {}", addr, err)],
+    }
+}
+
+pub fn origin(record: &Record) -> String {
+    let pattern = record.matcher.to_string();
+    match (record.source, record.line) {
+        (Some(source), Some(line)) => format!("{} at {}:{}", pattern, source, line),
+        (Some(source), None) => format!("{} in {}", pattern, source),
+        (None, Some(line)) => format!("{} at line {}", pattern, line),
+        _ => pattern,
+    }
+}
+
+// One line per domain
+pub fn describe(domain: &str, qtype: QueryType, decision: &Decision) -> String {
+    let name = format!("{} {}", domain, type_name(qtype));
+    match decision {
+        Decision::Local { record, ttl } => format!(
+            "{} -> {} ttl {}, matched {}",
+            name,
+            record.ip,
+            ttl,
+            origin(record)
+        ),
+        Decision::Forward { upstreams, skipped } => {
+            let upstreams = upstreams
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            match skipped {
+                Some(record) => format!(
+                    "{} -> forward to {}, {} has no {} address",
+                    name,
+                    upstreams,
+                    origin(record),
+                    type_name(qtype)
+                ),
+                None => format!("{} -> forward to {}", name, upstreams),
+            }
+        }
+    }
+}
+
+// A, AAAA, ..., TYPE65 or 65
+pub fn parse_type(text: &str) -> Option<QueryType> {
+    let text = text.to_ascii_uppercase();
+    let qtype = match text.as_str() {
+        "A" => QueryType::A,
+        "NS" => QueryType::NS,
+        "CNAME" => QueryType::CNAME,
+        "MX" => QueryType::MX,
+        "AAAA" => QueryType::AAAA,
+        _ => {
+            let num = text.strip_prefix("TYPE").unwrap_or(&text);
+            QueryType::from_num(num.parse().ok()?)
+        }
+    };
+    Some(qtype)
+}
+
+pub fn type_name(qtype: QueryType) -> String {
+    match qtype {
+        QueryType::UNKNOWN(num) => format!("TYPE{}", num),
+        qtype => format!("{:?}", qtype),
+    }
+}
+
+#[cfg(test)]
+mod test_dryrun {
+    use super::*;
+    use crate::parse_test_config;
+
+    #[test]
+    fn test_decide() {
+        let config = parse_test_config(
+            "
+            proxy 9.9.9.9:53
+            ttl_min 7200
+            a.com 1.1.1.1
+            *.a.com ::1
+            ",
+        );
+
+        let decision = decide(&config, "a.com", QueryType::A);
+        assert!(decision.is_local());
+        assert_eq!(
+            describe("a.com", QueryType::A, &decision),
+            "a.com A -> 1.1.1.1 ttl 7200, matched a.com at line 4"
+        );
+
+        let decision = decide(&config, "www.a.com", QueryType::AAAA);
+        assert!(decision.is_local());
+
+        let decision = decide(&config, "www.a.com", QueryType::A);
+        assert!(!decision.is_local());
+        assert_eq!(
+            describe("www.a.com", QueryType::A, &decision),
+            "www.a.com A -> forward to 9.9.9.9:53, *.a.com at line 5 has no A address"
+        );
+
+        let decision = decide(&config, "a.com", QueryType::MX);
+        assert!(!decision.is_local());
+
+        let empty = Config::new();
+        let decision = decide(&empty, "b.com", QueryType::A);
+        assert_eq!(
+            describe("b.com", QueryType::A, &decision),
+            format!("b.com A -> forward to {}", DEFAULT_PROXY.join(", "))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_upstream() {
+        use tokio::net::UdpSocket;
+        use updns::{BytePacketBuffer, DnsPacket, DnsRecord};
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buf.buf).await.unwrap();
+            let mut packet = DnsPacket::from_buffer(&mut buf).unwrap();
+            packet.header.response = true;
+            packet.answers.push(DnsRecord::A {
+                domain: packet.questions[0].name.clone(),
+                addr: "5.6.7.8".parse().unwrap(),
+                ttl: 300,
+            });
+            let mut res = BytePacketBuffer::new();
+            packet.write(&mut res).unwrap();
+            socket.send_to(&res.buf[..res.pos()], src).await.unwrap();
+        });
+
+        // The host record does not hide the upstream answer. The answer is
+        // rewritten in lowercase, which fails the case check of dns0x20
+        let config = parse_test_config(&format!(
+            "proxy {}\nproxy 9.9.9.9:53\ndns0x20 false\na.com 1.1.1.1",
+            addr
+        ));
+        assert_eq!(
+            ask_upstream(&config, "a.com", QueryType::A).await,
+            vec![
+                format!("upstream {}: NOERROR, 1 answers", addr),
+                "  a.com 300 A 5.6.7.8".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_type() {
+        assert_eq!(parse_type("aaaa"), Some(QueryType::AAAA));
+        assert_eq!(parse_type("MX"), Some(QueryType::MX));
+        assert_eq!(parse_type("TYPE65"), Some(QueryType::UNKNOWN(65)));
+        assert_eq!(parse_type("28"), Some(QueryType::AAAA));
+        assert_eq!(parse_type("TXTX"), None);
+        assert_eq!(type_name(QueryType::UNKNOWN(65)), "TYPE65");
+    }
+}
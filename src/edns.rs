@@ -0,0 +1,340 @@
+use crate::{BytePacketBuffer, QueryType, MAX_PACKET_SIZE};
+use std::net::IpAddr;
+use tokio::io::{Error, ErrorKind, Result};
+
+// EDNS Client Subnet option code (RFC 7871)
+pub const CLIENT_SUBNET: u16 = 8;
+// The ipv4 prefix of `ecs set` given only an ipv6 one (RFC 7871 section 11.1)
+const DEFAULT_V4_PREFIX: u8 = 24;
+// Requestor's payload size of a synthesized OPT record
+const UDP_PAYLOAD_SIZE: u16 = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    // Build a client subnet option from the client address truncated to `prefix` bits
+    pub fn client_subnet(addr: IpAddr, prefix: u8) -> EdnsOption {
+        let (family, octets, max) = match addr {
+            IpAddr::V4(ip) => (1_u16, ip.octets().to_vec(), 32),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => (1, ip.octets().to_vec(), 32),
+                None => (2, ip.octets().to_vec(), 128),
+            },
+        };
+        let prefix = prefix.min(max);
+
+        let len = (prefix as usize).div_ceil(8);
+        let mut address = octets[..len].to_vec();
+        if prefix % 8 != 0 {
+            address[len - 1] &= 0xFF << (8 - prefix % 8);
+        }
+
+        let mut data = Vec::with_capacity(4 + len);
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(prefix);
+        data.push(0);
+        data.extend(address);
+
+        EdnsOption {
+            code: CLIENT_SUBNET,
+            data,
+        }
+    }
+}
+
+pub fn read_options(mut data: &[u8]) -> Result<Vec<EdnsOption>> {
+    let mut options = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated EDNS option"));
+        }
+        let code = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data.len() < 4 + len {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated EDNS option"));
+        }
+        options.push(EdnsOption {
+            code,
+            data: data[4..4 + len].to_vec(),
+        });
+        data = &data[4 + len..];
+    }
+
+    Ok(options)
+}
+
+// The payload size the client of `query` takes over UDP, from the class of
+// its OPT record (RFC 6891), 512 without one and at most `MAX_PACKET_SIZE`
+pub fn udp_payload(query: &[u8]) -> usize {
+    let records = BytePacketBuffer::from_bytes(query)
+        .records()
+        .unwrap_or_default();
+    let size = records
+        .iter()
+        .find(|record| record.qtype == QueryType::OPT)
+        .and_then(|record| query.get(record.ttl - 2..record.ttl))
+        .map(|class| u16::from_be_bytes([class[0], class[1]]) as usize);
+    size.unwrap_or(0)
+        .clamp(UDP_PAYLOAD_SIZE as usize, MAX_PACKET_SIZE)
+}
+
+pub fn write_options(options: &[EdnsOption]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for option in options {
+        data.extend_from_slice(&option.code.to_be_bytes());
+        data.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+        data.extend_from_slice(&option.data);
+    }
+    data
+}
+
+// Handling of the client subnet option in forwarded queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecs {
+    // Remove the option
+    Strip,
+    // Pass the client's option through untouched
+    Forward,
+    // Replace the option with the client address truncated to the prefix (ipv4, ipv6)
+    Set(u8, u8),
+}
+
+impl Ecs {
+    // strip | forward | set <prefix> [ipv6 prefix], a single prefix over
+    // 32 is the ipv6 one
+    pub fn parse(text: &str) -> Option<Ecs> {
+        let mut words = text.split_ascii_whitespace();
+        let ecs = match words.next()? {
+            "strip" => Ecs::Strip,
+            "forward" => Ecs::Forward,
+            "set" => {
+                let first = Self::parse_prefix(words.next()?)?;
+                let (v4, v6) = match words.next() {
+                    Some(s) => (first, Self::parse_prefix(s)?),
+                    // Only an ipv6 prefix can be this long, like `set /56`
+                    None if first > 32 => (DEFAULT_V4_PREFIX, first),
+                    None => (first, first),
+                };
+                if v4 > 32 || v6 > 128 {
+                    return None;
+                }
+                Ecs::Set(v4, v6)
+            }
+            _ => return None,
+        };
+        match words.next() {
+            Some(_) => None,
+            None => Some(ecs),
+        }
+    }
+
+    fn parse_prefix(text: &str) -> Option<u8> {
+        text.trim_start_matches('/').parse().ok()
+    }
+
+    // The option sent to the upstream for `set`
+    pub fn subnet(&self, client: IpAddr) -> Option<EdnsOption> {
+        match *self {
+            Ecs::Set(v4, v6) => {
+                let prefix = match client {
+                    IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none() => v6,
+                    _ => v4,
+                };
+                Some(EdnsOption::client_subnet(client, prefix))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, packet: &mut Vec<u8>, client: IpAddr) -> Result<()> {
+        if *self == Ecs::Forward {
+            return Ok(());
+        }
+        let subnet = self.subnet(client);
+
+        let mut buffer = BytePacketBuffer::from_bytes(packet);
+        let opt = buffer
+            .records()?
+            .into_iter()
+            .find(|record| record.qtype == QueryType::OPT);
+
+        match opt {
+            Some(record) => {
+                let end = record.data + record.data_len;
+                if end > packet.len() {
+                    return Err(Error::new(ErrorKind::InvalidData, "Truncated OPT record"));
+                }
+                let mut options = read_options(&packet[record.data..end])?;
+                options.retain(|option| option.code != CLIENT_SUBNET);
+                options.extend(subnet);
+
+                let data = write_options(&options);
+                let mut rdata = (data.len() as u16).to_be_bytes().to_vec();
+                rdata.extend(data);
+                packet.splice(record.data - 2..end, rdata);
+            }
+            None => {
+                let subnet = match subnet {
+                    Some(subnet) => subnet,
+                    None => return Ok(()),
+                };
+                let data = write_options(&[subnet]);
+
+                // Root name, type, payload size, extended rcode and flags
+                packet.push(0);
+                packet.extend_from_slice(&QueryType::OPT.to_num().to_be_bytes());
+                packet.extend_from_slice(&UDP_PAYLOAD_SIZE.to_be_bytes());
+                packet.extend_from_slice(&[0; 4]);
+                packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                packet.extend(data);
+
+                let count = u16::from_be_bytes([packet[10], packet[11]]) + 1;
+                packet[10..12].copy_from_slice(&count.to_be_bytes());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_edns {
+    use super::*;
+    use crate::{DnsPacket, DnsQuestion};
+
+    fn query(options: Option<&[EdnsOption]>) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut data = buffer.buf[..buffer.pos()].to_vec();
+
+        if let Some(options) = options {
+            let rdata = write_options(options);
+            data.extend_from_slice(&[0, 0, 41, 2, 0, 0, 0, 0, 0]);
+            data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            data.extend(rdata);
+            data[11] = 1;
+        }
+        data
+    }
+
+    fn options(packet: &[u8]) -> Option<Vec<EdnsOption>> {
+        let mut buffer = BytePacketBuffer::from_bytes(packet);
+        let record = buffer
+            .records()
+            .unwrap()
+            .into_iter()
+            .find(|record| record.qtype == QueryType::OPT)?;
+        assert_eq!(record.data + record.data_len, packet.len());
+        Some(read_options(&packet[record.data..record.data + record.data_len]).unwrap())
+    }
+
+    fn cookie() -> EdnsOption {
+        EdnsOption {
+            code: 10,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ecs::parse("strip"), Some(Ecs::Strip));
+        assert_eq!(Ecs::parse("forward"), Some(Ecs::Forward));
+        assert_eq!(Ecs::parse("set /24"), Some(Ecs::Set(24, 24)));
+        assert_eq!(Ecs::parse("set 24 /56"), Some(Ecs::Set(24, 56)));
+        assert_eq!(Ecs::parse("set /56"), Some(Ecs::Set(24, 56)));
+        assert_eq!(Ecs::parse("set 33"), Some(Ecs::Set(24, 33)));
+        assert_eq!(Ecs::parse("set 129"), None);
+        assert_eq!(Ecs::parse("set 33 56"), None);
+        assert_eq!(Ecs::parse("set"), None);
+        assert_eq!(Ecs::parse("strip 24"), None);
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        let option = EdnsOption::client_subnet("192.168.1.77".parse().unwrap(), 24);
+        assert_eq!(option.data, vec![0, 1, 24, 0, 192, 168, 1]);
+
+        let option = EdnsOption::client_subnet("192.168.1.77".parse().unwrap(), 20);
+        assert_eq!(option.data, vec![0, 1, 20, 0, 192, 168, 0]);
+
+        let option = EdnsOption::client_subnet("2001:db8:aaaa:bbff::1".parse().unwrap(), 56);
+        assert_eq!(
+            option.data,
+            vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0xaa, 0xaa, 0xbb]
+        );
+
+        let option = EdnsOption::client_subnet("::ffff:10.1.2.3".parse().unwrap(), 16);
+        assert_eq!(option.data, vec![0, 1, 16, 0, 10, 1]);
+    }
+
+    #[test]
+    fn test_strip() {
+        let client = "10.0.0.1".parse().unwrap();
+        let subnet = EdnsOption::client_subnet("1.2.3.4".parse().unwrap(), 24);
+
+        let mut packet = query(Some(&[cookie(), subnet]));
+        Ecs::Strip.apply(&mut packet, client).unwrap();
+        assert_eq!(options(&packet), Some(vec![cookie()]));
+
+        let mut packet = query(None);
+        let raw = packet.clone();
+        Ecs::Strip.apply(&mut packet, client).unwrap();
+        assert_eq!(packet, raw);
+    }
+
+    #[test]
+    fn test_forward() {
+        let subnet = EdnsOption::client_subnet("1.2.3.4".parse().unwrap(), 24);
+        let mut packet = query(Some(&[subnet]));
+        let raw = packet.clone();
+        Ecs::Forward
+            .apply(&mut packet, "10.0.0.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(packet, raw);
+    }
+
+    #[test]
+    fn test_set() {
+        let client = "10.9.8.7".parse().unwrap();
+        let expect = EdnsOption::client_subnet(client, 24);
+
+        // Append an OPT record
+        let mut packet = query(None);
+        Ecs::Set(24, 56).apply(&mut packet, client).unwrap();
+        assert_eq!(options(&packet), Some(vec![expect.clone()]));
+        let mut buffer = BytePacketBuffer::from_bytes(&packet);
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.resources.len(), 1);
+
+        // Replace the existing option
+        let other = EdnsOption::client_subnet("1.2.3.4".parse().unwrap(), 32);
+        let mut packet = query(Some(&[other, cookie()]));
+        Ecs::Set(24, 56).apply(&mut packet, client).unwrap();
+        assert_eq!(options(&packet), Some(vec![cookie(), expect]));
+    }
+
+    #[test]
+    fn test_udp_payload() {
+        assert_eq!(udp_payload(&query(None)), 512);
+        let payload = |size: u16| {
+            // The class of the OPT record, before its ttl and rdata length
+            let mut packet = query(Some(&[]));
+            let class = packet.len() - 8;
+            packet[class..class + 2].copy_from_slice(&size.to_be_bytes());
+            udp_payload(&packet)
+        };
+        assert_eq!(payload(1232), 1232);
+        assert_eq!(payload(100), 512);
+        assert_eq!(payload(65535), 4096);
+    }
+}
@@ -0,0 +1,825 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    ops::RangeInclusive,
+    time::Duration,
+};
+use updns::{
+    cidr::Acl,
+    config::{AnyPolicy, Config, Invalid, Proxy},
+    edns::Ecs,
+    querylog::json_string,
+};
+
+// Directives written by `export`, in the order of the output. `acl` holds
+// the `allow` and `deny` lines, the order of the rules matters
+const SETTINGS: [&str; 39] = [
+    "bind",
+    "bind-dual-stack",
+    "workers",
+    "bind-device",
+    "bootstrap",
+    "proxy",
+    "timeout",
+    "retries",
+    "attempt-timeout",
+    "serve-stale-ttl",
+    "ttl_min",
+    "ttl_max",
+    "ecs",
+    "rebind_protection",
+    "rebind_protection_whitelist",
+    "dns0x20",
+    "bogus-nx",
+    "any-policy",
+    "dns64",
+    "special-domain",
+    "rpz",
+    "rate-limit",
+    "rate-limit-exempt",
+    "max-inflight",
+    "acl",
+    "acl-drop",
+    "shutdown-grace",
+    "import_timeout",
+    "parse-timeout",
+    "log_queries",
+    "log_format",
+    "log_file",
+    "log_rotate",
+    "log_level",
+    "dnstap",
+    "admin",
+    "user",
+    "group",
+    "files",
+];
+
+// The `proxy` lines, the SOCKS5 proxies where they were written
+fn proxies(config: &Config) -> Vec<String> {
+    let socks = |after: RangeInclusive<usize>| {
+        config
+            .socks5()
+            .iter()
+            .filter(|socks| after.contains(&socks.after))
+            .map(|socks| format!("{}{}", socks, socks.options))
+            .collect::<Vec<_>>()
+    };
+    let mut lines = Vec::new();
+    for (i, proxy) in config.proxy().iter().enumerate() {
+        lines.extend(socks(i..=i));
+        lines.push(match proxy {
+            Proxy::Host(host) => format!("{}{}", host, host.options),
+            Proxy::Addr(addr) => {
+                let options = config.proxy_options().get(addr).copied();
+                format!("{}{}", addr, options.unwrap_or_default())
+            }
+        });
+    }
+    lines.extend(socks(config.proxy().len()..=usize::MAX));
+    lines
+}
+
+// Output format of `updns config --export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Option<Format> {
+        match text {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+}
+
+// The data model shared by both formats, objects keep their key order
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    // As written, only integers are exported
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn string<T: ToString>(value: T) -> Value {
+        Value::String(value.to_string())
+    }
+
+    fn number<T: ToString>(value: T) -> Value {
+        Value::Number(value.to_string())
+    }
+
+    fn object(fields: Vec<(&str, Value)>) -> Value {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn is_table(&self) -> bool {
+        matches!(self, Value::Object(_))
+    }
+}
+
+// Values in the syntax of the config lines, so `import` can write them back
+fn duration(duration: Duration) -> Value {
+    Value::String(format!("{}ms", duration.as_millis()))
+}
+
+fn ecs(ecs: &Ecs) -> Value {
+    Value::String(match ecs {
+        Ecs::Strip => "strip".to_string(),
+        Ecs::Forward => "forward".to_string(),
+        Ecs::Set(v4, v6) => format!("set {} {}", v4, v6),
+    })
+}
+
+fn invalid(invalid: &Invalid) -> Value {
+    Value::object(vec![
+        ("file", Value::string(invalid.file.display())),
+        ("line", Value::number(invalid.line)),
+        ("message", Value::string(invalid.kind.description())),
+        ("source", Value::string(&invalid.source)),
+    ])
+}
+
+// The config after its imports are resolved. `admin-key` is left out,
+// the export is meant to be shared and compared
+fn export(config: &Config) -> Value {
+    let list = |values: Vec<String>| Value::Array(values.into_iter().map(Value::String).collect());
+    let option = |value: Option<Value>| value.unwrap_or(Value::Null);
+
+    let hosts = config
+        .hosts()
+        .records()
+        .map(|record| {
+            Value::object(vec![
+                ("pattern", Value::string(record.matcher)),
+                ("kind", Value::string(record.matcher.kind().as_str())),
+                ("ip", Value::string(record.ip)),
+                ("source", option(record.source.map(Value::string))),
+                ("line", option(record.line.map(Value::number))),
+            ])
+        })
+        .collect();
+    let acl = config
+        .acl()
+        .iter()
+        .map(|rule| match rule {
+            Acl::Allow(cidr) => Value::object(vec![("allow", Value::string(cidr))]),
+            Acl::Deny(cidr) => Value::object(vec![("deny", Value::string(cidr))]),
+        })
+        .collect();
+
+    let settings = vec![
+        list(config.bind().iter().map(|addr| addr.to_string()).collect()),
+        option(config.bind_dual_stack().map(Value::Bool)),
+        option(config.workers().map(Value::number)),
+        option(config.bind_device().as_ref().map(Value::string)),
+        option(config.bootstrap().map(Value::string)),
+        list(proxies(config)),
+        option(config.timeout().map(duration)),
+        option(config.retries().map(Value::number)),
+        option(config.attempt_timeout().map(duration)),
+        option(config.serve_stale().map(duration)),
+        option(config.ttl_min().map(Value::number)),
+        option(config.ttl_max().map(Value::number)),
+        option(config.ecs().as_ref().map(ecs)),
+        option(config.rebind_protection().map(Value::Bool)),
+        list(
+            config
+                .rebind_whitelist()
+                .iter()
+                .map(|matcher| matcher.to_string())
+                .collect(),
+        ),
+        option(config.dns0x20().map(Value::Bool)),
+        list(config.bogus_nx().iter().map(|ip| ip.to_string()).collect()),
+        option(config.any_policy().map(|policy| {
+            Value::string(match policy {
+                AnyPolicy::Forward => "forward",
+                AnyPolicy::Refuse => "refuse",
+                AnyPolicy::Hinfo => "hinfo",
+            })
+        })),
+        option(
+            config
+                .dns64()
+                .map(|prefix| Value::String(prefix.to_string())),
+        ),
+        list(
+            config
+                .special_domains()
+                .iter()
+                .map(|(suffix, policy)| format!(".{} {}", suffix, policy.as_str()))
+                .collect(),
+        ),
+        list(
+            config
+                .rpz()
+                .iter()
+                .map(|zone| zone.path().display().to_string())
+                .collect(),
+        ),
+        option(
+            config
+                .rate_limit()
+                .map(|(qps, burst)| Value::String(format!("{} {}", qps, burst))),
+        ),
+        list(
+            config
+                .rate_limit_exempt()
+                .iter()
+                .map(|cidr| cidr.to_string())
+                .collect(),
+        ),
+        option(config.max_inflight().map(Value::number)),
+        Value::Array(acl),
+        option(config.acl_drop().map(Value::Bool)),
+        option(config.shutdown_grace().map(duration)),
+        option(config.import_timeout().map(duration)),
+        option(config.parse_timeout().map(duration)),
+        option(config.log_queries().map(Value::Bool)),
+        option(
+            config
+                .log_format()
+                .map(|format| Value::string(format.as_str())),
+        ),
+        option(
+            config
+                .log_file()
+                .as_ref()
+                .map(|path| Value::string(path.display())),
+        ),
+        option(
+            config
+                .log_rotate()
+                .map(|rotate| Value::String(rotate.to_string())),
+        ),
+        option(
+            config
+                .log_level()
+                .map(|level| Value::string(level.as_str())),
+        ),
+        option(
+            config
+                .dnstap()
+                .as_ref()
+                .map(|path| Value::string(path.display())),
+        ),
+        option(config.admin().map(Value::string)),
+        option(config.user().as_ref().map(Value::string)),
+        option(config.group().as_ref().map(Value::string)),
+        list(
+            config
+                .files()
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        ),
+    ];
+
+    let mut fields = SETTINGS
+        .iter()
+        .zip(settings)
+        .map(|(key, value)| (key.to_string(), value))
+        .collect::<Vec<_>>();
+    fields.push(("hosts".to_string(), Value::Array(hosts)));
+    fields.push((
+        "invalid".to_string(),
+        Value::Array(config.invalid().iter().map(invalid).collect()),
+    ));
+    fields.push((
+        "warnings".to_string(),
+        Value::Array(config.warnings().iter().map(invalid).collect()),
+    ));
+    Value::Object(fields)
+}
+
+pub fn format(config: &Config, format: Format) -> String {
+    let value = export(config);
+    match format {
+        Format::Json => {
+            let mut out = String::new();
+            write_json(&value, 0, &mut out);
+            out + "\n"
+        }
+        Format::Toml => toml(&value),
+    }
+}
+
+// One member or element per line, empty containers inline
+fn write_json(value: &Value, indent: usize, out: &mut String) {
+    let pad = |n: usize| "  ".repeat(n);
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Number(n) => out.push_str(n),
+        Value::String(s) => out.push_str(&json_string(s)),
+        Value::Array(values) if values.is_empty() => out.push_str("[]"),
+        Value::Object(fields) if fields.is_empty() => out.push_str("{}"),
+        Value::Array(values) => {
+            out.push_str("[\n");
+            for (i, value) in values.iter().enumerate() {
+                out.push_str(&pad(indent + 1));
+                write_json(value, indent + 1, out);
+                out.push_str(if i + 1 < values.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&pad(indent));
+            out.push(']');
+        }
+        Value::Object(fields) => {
+            out.push_str("{\n");
+            for (i, (key, value)) in fields.iter().enumerate() {
+                out.push_str(&format!("{}{}: ", pad(indent + 1), json_string(key)));
+                write_json(value, indent + 1, out);
+                out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&pad(indent));
+            out.push('}');
+        }
+    }
+}
+
+// Top level keys, then the arrays of objects as arrays of tables.
+// TOML has no null, unset settings are left out
+fn toml(value: &Value) -> String {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => return String::new(),
+    };
+    let is_tables = |value: &Value| match value {
+        Value::Array(values) => !values.is_empty() && values.iter().all(Value::is_table),
+        _ => false,
+    };
+
+    let mut out = String::new();
+    for (key, value) in fields {
+        if *value != Value::Null && !is_tables(value) {
+            out += &format!("{} = {}\n", toml_key(key), toml_inline(value));
+        }
+    }
+    for (key, value) in fields.iter().filter(|(_, value)| is_tables(value)) {
+        if let Value::Array(tables) = value {
+            for table in tables {
+                out += &format!("\n[[{}]]\n", toml_key(key));
+                if let Value::Object(fields) = table {
+                    for (key, value) in fields.iter().filter(|(_, v)| *v != Value::Null) {
+                        out += &format!("{} = {}\n", toml_key(key), toml_inline(value));
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+    match bare {
+        true => key.to_string(),
+        false => json_string(key),
+    }
+}
+
+// JSON strings are valid TOML basic strings
+fn toml_inline(value: &Value) -> String {
+    match value {
+        Value::Null => "\"\"".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.clone(),
+        Value::String(s) => json_string(s),
+        Value::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(toml_inline)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Object(fields) => format!(
+            "{{ {} }}",
+            fields
+                .iter()
+                .filter(|(_, value)| *value != Value::Null)
+                .map(|(key, value)| format!("{} = {}", toml_key(key), toml_inline(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// The config lines of an export, settings first then the host records.
+// `files`, `invalid` and `warnings` are only informative
+pub fn import(json: &str) -> Result<String> {
+    let fields = match Reader::new(json).document()? {
+        Value::Object(fields) => fields,
+        _ => return Err(invalid_data("Expected a JSON object".to_string())),
+    };
+
+    let mut settings = Vec::new();
+    let mut hosts = Vec::new();
+    for (key, value) in fields {
+        match key.as_str() {
+            "files" | "invalid" | "warnings" => {}
+            "hosts" => {
+                for host in values(value) {
+                    let field = |name: &str| match &host {
+                        Value::Object(fields) => fields
+                            .iter()
+                            .find(|(key, _)| key == name)
+                            .and_then(|(_, value)| scalar(value)),
+                        _ => None,
+                    };
+                    match (field("pattern"), field("ip")) {
+                        (Some(pattern), Some(ip)) => hosts.push(format!("{} {}", pattern, ip)),
+                        _ => {
+                            return Err(invalid_data(
+                                "A host needs a 'pattern' and an 'ip'".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+            "acl" => {
+                for rule in values(value) {
+                    match rule {
+                        Value::Object(fields) if fields.len() == 1 => {
+                            let (kind, cidr) = &fields[0];
+                            match (kind.as_str(), scalar(cidr)) {
+                                ("allow" | "deny", Some(cidr)) => {
+                                    settings.push(format!("{} {}", kind, cidr))
+                                }
+                                _ => {
+                                    return Err(invalid_data(format!(
+                                        "Invalid acl rule '{}'",
+                                        kind
+                                    )))
+                                }
+                            }
+                        }
+                        _ => {
+                            return Err(invalid_data(
+                                "An acl rule is {\"allow\": cidr} or {\"deny\": cidr}".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+            key if SETTINGS.contains(&key) => {
+                for value in values(value) {
+                    match scalar(&value) {
+                        Some(value) => settings.push(format!("{} {}", key, value)),
+                        None => return Err(invalid_data(format!("Invalid value of '{}'", key))),
+                    }
+                }
+            }
+            key => return Err(invalid_data(format!("Unknown setting '{}'", key))),
+        }
+    }
+
+    let mut out = settings.join("\n");
+    if !settings.is_empty() && !hosts.is_empty() {
+        out += "\n\n";
+    }
+    out += &hosts.join("\n");
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// An array, one value or nothing for null
+fn values(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values,
+        Value::Null => Vec::new(),
+        value => vec![value],
+    }
+}
+
+// The text of the value in a config line, none for containers and
+// values spanning lines
+fn scalar(value: &Value) -> Option<String> {
+    let text = match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.clone(),
+        Value::String(s) => s.clone(),
+        _ => return None,
+    };
+    match text.is_empty() || text.contains(['\n', '\r', '#']) {
+        true => None,
+        false => Some(text),
+    }
+}
+
+// A JSON parser for `import`
+struct Reader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(text: &'a str) -> Reader<'a> {
+        Reader { text, pos: 0 }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        let line = self.text[..self.pos].matches('\n').count() + 1;
+        invalid_data(format!("{} at line {}", msg, line))
+    }
+
+    fn document(&mut self) -> Result<Value> {
+        let value = self.value()?;
+        self.skip_whitespace();
+        match self.pos == self.text.len() {
+            true => Ok(value),
+            false => Err(self.error("Unexpected text after the value")),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        match self.text[self.pos..].starts_with(token) {
+            true => {
+                self.pos += token.len();
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Value::String),
+            Some('-' | '0'..='9') => {
+                let rest = &self.text[self.pos..];
+                let len = rest
+                    .find(|ch: char| !matches!(ch, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+                    .unwrap_or(rest.len());
+                let number = &rest[..len];
+                if number.parse::<f64>().is_err() {
+                    return Err(self.error("Invalid number"));
+                }
+                self.pos += len;
+                Ok(Value::Number(number.to_string()))
+            }
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ if self.eat("null") => Ok(Value::Null),
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        self.eat("[");
+        let mut values = Vec::new();
+        if self.eat("]") {
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            if self.eat("]") {
+                return Ok(Value::Array(values));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value> {
+        self.eat("{");
+        let mut fields = Vec::new();
+        if self.eat("}") {
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("Expected a key"));
+            }
+            let key = self.string()?;
+            if !self.eat(":") {
+                return Err(self.error("Expected ':'"));
+            }
+            fields.push((key, self.value()?));
+            if self.eat("}") {
+                return Ok(Value::Object(fields));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let mut out = String::new();
+        let mut chars = self.text[self.pos + 1..].char_indices();
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    self.pos += i + 2;
+                    return Ok(out);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, ch)| ch) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let hex = (0..4)
+                                .filter_map(|_| chars.next().map(|(_, ch)| ch))
+                                .collect::<String>();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("Invalid unicode escape"))?
+                        }
+                        _ => return Err(self.error("Invalid escape")),
+                    };
+                    out.push(escaped);
+                }
+                ch => out.push(ch),
+            }
+        }
+        Err(self.error("Unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod test_export {
+    use super::*;
+    use crate::parse_test_config;
+    use futures_util::future::FutureExt;
+
+    const CONFIG: &str = "
+bind 0.0.0.0:53
+proxy 1.1.1.1:53
+proxy 9.9.9.9:53
+timeout 1.5s
+ttl_min 60
+ecs set 24 56
+dns0x20 false
+rate-limit 20 40
+allow 10.0.0.0/8
+deny 0.0.0.0/0
+admin-key secret
+a.com 1.2.3.4
+*.b.com ::1
+~^c[0-9]+\\.com$ 5.6.7.8
+bad line here
+";
+
+    #[test]
+    fn test_json() {
+        let json = format(&parse_test_config(CONFIG), Format::Json);
+        assert!(json.starts_with("{\n  \"bind\": [\n    \"0.0.0.0:53\"\n  ],\n"));
+        assert!(json.contains("\n  \"timeout\": \"1500ms\",\n"));
+        assert!(json.contains("\n  \"ttl_max\": null,\n"));
+        assert!(json.contains("\n  \"ecs\": \"set 24 56\",\n"));
+        assert!(json.contains("\n  \"dns0x20\": false,\n"));
+        assert!(json.contains("      \"allow\": \"10.0.0.0/8\"\n"));
+        assert!(json
+            .contains("      \"pattern\": \"~^c[0-9]+\\\\.com$\",\n      \"kind\": \"regex\",\n"));
+        assert!(json.contains("      \"line\": 16,\n      \"message\": \"Invalid line\",\n"));
+        assert!(!json.contains("secret"));
+        // Valid for the reader
+        Reader::new(&json).document().unwrap();
+    }
+
+    #[test]
+    fn test_toml() {
+        let toml = format(&parse_test_config(CONFIG), Format::Toml);
+        assert!(toml.starts_with("bind = [\"0.0.0.0:53\"]\n"));
+        assert!(toml.contains("\nproxy = [\"1.1.1.1:53\", \"9.9.9.9:53\"]\n"));
+        assert!(toml.contains("\nttl_min = 60\n"));
+        assert!(toml.contains("\nrate-limit = \"20 40\"\n"));
+        assert!(!toml.contains("ttl_max"));
+        assert!(toml.contains("\n[[acl]]\ndeny = \"0.0.0.0/0\"\n"));
+        assert!(toml.contains(
+            "\n[[hosts]]\npattern = \"a.com\"\nkind = \"text\"\nip = \"1.2.3.4\"\nline = 13\n"
+        ));
+        assert!(toml.contains("\n[[invalid]]\n"));
+    }
+
+    // The TOML export parses back into the same config, without the
+    // invalid lines of the original
+    #[test]
+    fn test_toml_round_trip() {
+        let settings = |toml: &str| {
+            let end = toml.find("\n[[invalid]]").unwrap_or(toml.len());
+            toml[..end]
+                .lines()
+                .filter(|line| !line.starts_with("line = ") && !line.starts_with("invalid = "))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let toml = format(&parse_test_config(CONFIG), Format::Toml);
+        let parsed = Config::parse_toml(&toml, |_, _| async { Ok(Config::new()) }.boxed())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(parsed.invalid().is_empty(), "{:?}", parsed.invalid());
+        assert_eq!(settings(&format(&parsed, Format::Toml)), settings(&toml));
+    }
+
+    #[test]
+    fn test_import() {
+        let exported = format(&parse_test_config(CONFIG), Format::Json);
+        let lines = import(&exported).unwrap();
+        assert_eq!(
+            lines,
+            "bind 0.0.0.0:53
+proxy 1.1.1.1:53
+proxy 9.9.9.9:53
+timeout 1500ms
+ttl_min 60
+ecs set 24 56
+dns0x20 false
+rate-limit 20 40
+allow 10.0.0.0/8
+deny 0.0.0.0/0
+
+a.com 1.2.3.4
+*.b.com ::1
+~^c[0-9]+\\.com$ 5.6.7.8
+"
+        );
+        // The same config, without the invalid line and the secret
+        let imported = parse_test_config(&lines);
+        assert!(imported.invalid().is_empty());
+        let strip = |json: String| {
+            json.lines()
+                .filter(|line| !line.contains("\"line\"") && !line.contains("\"source\""))
+                .map(|line| line.trim_end_matches(','))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let reexported = strip(format(&imported, Format::Json));
+        let without_invalid = {
+            let config = parse_test_config(&CONFIG.replace("bad line here\n", ""));
+            strip(format(&config, Format::Json))
+        };
+        assert_eq!(reexported, without_invalid);
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let err = |json: &str| import(json).unwrap_err().to_string();
+        assert_eq!(err("[]"), "Expected a JSON object");
+        assert_eq!(err("{\"proxi\": []}"), "Unknown setting 'proxi'");
+        assert_eq!(err("{\"bind\": [{}]}"), "Invalid value of 'bind'");
+        assert_eq!(err("{\"user\": \"a # b\"}"), "Invalid value of 'user'");
+        assert_eq!(
+            err("{\"acl\": [{\"block\": \"::/0\"}]}"),
+            "Invalid acl rule 'block'"
+        );
+        assert_eq!(
+            err("{\"hosts\": [{\"ip\": \"::1\"}]}"),
+            "A host needs a 'pattern' and an 'ip'"
+        );
+        assert_eq!(err("{\n\"bind\": [1,]}"), "Expected a value at line 2");
+        assert_eq!(err("{\"bind\": \"a} x"), "Unterminated string at line 1");
+        assert_eq!(import("{}").unwrap(), "");
+        assert_eq!(
+            import(r#"{"workers": 4, "log_queries": true, "user": "nobody", "group": null}"#)
+                .unwrap(),
+            "workers 4\nlog_queries true\nuser nobody\n"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    net::{IpAddr, UdpSocket},
+    path::Path,
+};
+use tokio::{
+    fs,
+    io::{Error, ErrorKind, Result},
+};
+
+// Address of the interface routing to the internet, connecting
+// a UDP socket only selects the route and sends nothing
+pub fn lan_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    if ip.is_unspecified() {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+// Initial config file with an example of every directive
+pub fn scaffold(lan: Option<IpAddr>) -> String {
+    let bind = match lan {
+        Some(ip) => format!("# bind     {}:53", ip),
+        None => "# bind     0.0.0.0:53".to_string(),
+    };
+
+    format!(
+        "\
+# updns config, uncomment a line to use it
+
+# Listening address, only local clients are served without one
+# (default: 127.0.0.1:53)
+{}
+
+# Upstream servers (default: 8.8.8.8:53, 1.1.1.1:53)
+# proxy    8.8.8.8:53
+
+# Upstream timeout, format: 1ms, 1s, 1m, 1h, 1d (default: 2s)
+# timeout  2s
+
+# Records from other files
+# import   /etc/updns/hosts
+
+# Domain matching, plain text, wildcard or regular expression starting with `~`
+# example.com              1.1.1.1
+# *.example.com            2.2.2.2
+# ~^\\w+\\.example\\.[a-z]+$  3.3.3.3
+# test.com                 ::1
+",
+        bind
+    )
+}
+
+pub async fn init(path: &Path, force: bool) -> Result<()> {
+    if !force && fs::metadata(path).await.is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} already exists, use --force to overwrite it", path),
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(path, scaffold(lan_ip())).await
+}
+
+#[cfg(test)]
+mod test_init {
+    use super::*;
+    use crate::parse_test_config;
+    use updns::config::Proxy;
+
+    #[test]
+    fn test_scaffold() {
+        let text = scaffold(Some("192.168.1.2".parse().unwrap()));
+        assert!(text.contains("# bind     192.168.1.2:53\n"));
+        assert!(text.contains("# proxy    8.8.8.8:53\n"));
+        assert!(scaffold(None).contains("# bind     0.0.0.0:53\n"));
+
+        // Everything is commented out
+        let config = parse_test_config(&text);
+        assert!(config.bind().is_empty());
+        assert!(config.invalid().is_empty());
+
+        // And every example is valid
+        let uncommented = text
+            .lines()
+            .filter(|line| line.starts_with("# ") && line.contains("  "))
+            .map(|line| &line[2..])
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config = parse_test_config(&uncommented);
+        assert!(config.invalid().is_empty(), "{:?}", config.invalid());
+        assert_eq!(config.bind(), vec!["192.168.1.2:53".parse().unwrap()]);
+        assert_eq!(
+            config.proxy(),
+            vec![Proxy::Addr("8.8.8.8:53".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init() {
+        let path = std::env::temp_dir()
+            .join(format!("updns-init-{}", std::process::id()))
+            .join("config");
+
+        init(&path, false).await.unwrap();
+        assert_eq!(
+            init(&path, false).await.unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        init(&path, true).await.unwrap();
+
+        fs::remove_dir_all(path.parent().unwrap()).await.unwrap();
+    }
+}
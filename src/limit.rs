@@ -0,0 +1,131 @@
+use crate::cidr::Cidr;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// Token bucket per client address, refilled at `qps` tokens per second
+// and holding at most `burst` tokens
+pub struct RateLimit {
+    qps: f64,
+    burst: f64,
+    exempt: Vec<Cidr>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    dropped: AtomicU64,
+}
+
+impl RateLimit {
+    pub fn new(qps: u32, burst: u32, exempt: Vec<Cidr>) -> Self {
+        RateLimit {
+            qps: qps as f64,
+            burst: burst.max(1) as f64,
+            exempt,
+            buckets: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Take a token of the client, `false` when the query is over the limit
+    pub fn check(&self, client: IpAddr, now: Instant) -> bool {
+        if self.exempt.iter().any(|cidr| cidr.contains(client)) {
+            return true;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.last = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        (bucket.tokens + elapsed * self.qps).min(self.burst)
+    }
+
+    // Forget the clients whose bucket has filled up again
+    pub fn cleanup(&self, now: Instant) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test_limit {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_and_refill() {
+        let limit = RateLimit::new(10, 5, Vec::new());
+        let client = "192.168.1.10".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert!(limit.check(client, now));
+        }
+        assert!(!limit.check(client, now));
+        assert!(!limit.check(client, now));
+        assert_eq!(limit.dropped(), 2);
+
+        // Other clients have their own bucket
+        assert!(limit.check("192.168.1.11".parse().unwrap(), now));
+
+        // 10 qps gives a token back every 100ms
+        let now = now + Duration::from_millis(100);
+        assert!(limit.check(client, now));
+        assert!(!limit.check(client, now));
+    }
+
+    #[test]
+    fn test_exempt() {
+        let limit = RateLimit::new(1, 1, vec![Cidr::parse("10.0.0.0/8").unwrap()]);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(limit.check("10.0.0.1".parse().unwrap(), now));
+        }
+        assert!(limit.check("192.168.1.10".parse().unwrap(), now));
+        assert!(!limit.check("192.168.1.10".parse().unwrap(), now));
+        assert_eq!(limit.dropped(), 1);
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let limit = RateLimit::new(10, 10, Vec::new());
+        let now = Instant::now();
+        limit.check("192.168.1.10".parse().unwrap(), now);
+
+        limit.cleanup(now);
+        assert_eq!(limit.buckets.lock().unwrap().len(), 1);
+
+        limit.cleanup(now + Duration::from_secs(1));
+        assert!(limit.buckets.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    config::{LogFormat, LogLevel},
+    querylog::{json_string, rfc3339},
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+    time::SystemTime,
+};
+
+// Chosen once at startup, text until then
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+// A bit for each enabled level, all of them until the command line is read
+static LEVELS: AtomicU8 = AtomicU8::new(ALL);
+
+const ALL: u8 = 0b11111;
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logger::enabled($crate::config::LogLevel::Error) {
+            $crate::logger::log($crate::config::LogLevel::Error, format_args!($($arg)*))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::logger::enabled($crate::config::LogLevel::Warn) {
+            $crate::logger::log($crate::config::LogLevel::Warn, format_args!($($arg)*))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logger::enabled($crate::config::LogLevel::Info) {
+            $crate::logger::log($crate::config::LogLevel::Info, format_args!($($arg)*))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::logger::enabled($crate::config::LogLevel::Trace) {
+            $crate::logger::log($crate::config::LogLevel::Trace, format_args!($($arg)*))
+        }
+    };
+}
+
+// The format of the lines, only the first call sets it
+pub fn init(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+// Log this level and the ones above, with trace when `trace` is set. Only
+// the levels change, the format stays
+pub fn set_level(level: LogLevel, trace: bool) {
+    let mut levels = bit(LogLevel::Error);
+    for other in [LogLevel::Warn, LogLevel::Info, LogLevel::Debug] {
+        if level >= other {
+            levels |= bit(other);
+        }
+    }
+    if trace || level >= LogLevel::Debug {
+        levels |= bit(LogLevel::Trace);
+    }
+    LEVELS.store(levels, Ordering::Relaxed);
+}
+
+// Enable the levels of a `--log` value such as `all,!trace,!debug`, the
+// unknown item is the error
+pub fn set_filter(value: &str) -> Result<(), String> {
+    LEVELS.store(parse_filter(value)?, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    LEVELS.load(Ordering::Relaxed) & bit(level) != 0
+}
+
+fn bit(level: LogLevel) -> u8 {
+    1 << level as u8
+}
+
+fn parse_filter(value: &str) -> Result<u8, String> {
+    let mut levels = 0;
+    for item in value.to_lowercase().split(',') {
+        let item = item.trim();
+        let (enable, name) = match item.strip_prefix('!') {
+            Some(name) => (false, name),
+            None => (true, item),
+        };
+        let mask = match name {
+            "all" => ALL,
+            _ => match LogLevel::parse(name) {
+                Some(level) => bit(level),
+                None => return Err(item.to_string()),
+            },
+        };
+        if enable {
+            levels |= mask;
+        } else {
+            levels &= !mask;
+        }
+    }
+    Ok(levels)
+}
+
+pub fn log(level: LogLevel, args: fmt::Arguments) {
+    let line = format(*FORMAT.get().unwrap_or(&LogFormat::Text), level, args);
+    match level {
+        LogLevel::Error => eprintln!("{}", line),
+        _ => println!("{}", line),
+    }
+}
+
+fn format(format: LogFormat, level: LogLevel, args: fmt::Arguments) -> String {
+    let label = match level {
+        LogLevel::Error => "ERROR",
+        LogLevel::Warn => "WARN ",
+        LogLevel::Info => "INFO ",
+        LogLevel::Debug => "DEBUG",
+        LogLevel::Trace => "TRACE",
+    };
+    match format {
+        LogFormat::Text => format!("[{}] [{}] {}", rfc3339(SystemTime::now()), label, args),
+        LogFormat::Compact => format!("[{}] {}", label, args),
+        LogFormat::Json => format!(
+            r#"{{"time":"{}","level":"{}","message":{}}}"#,
+            rfc3339(SystemTime::now()),
+            level.as_str(),
+            json_string(&args.to_string())
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test_logger {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let text = format(LogFormat::Text, LogLevel::Warn, format_args!("a {}", 1));
+        assert!(text.starts_with('['));
+        assert!(text.ends_with("] [WARN ] a 1"));
+        assert_eq!(
+            format(LogFormat::Compact, LogLevel::Info, format_args!("a {}", 1)),
+            "[INFO ] a 1"
+        );
+        let json = format(
+            LogFormat::Json,
+            LogLevel::Error,
+            format_args!("a\n{:?}", "b"),
+        );
+        assert!(json.starts_with(r#"{"time":""#));
+        assert!(json.ends_with(r#"","level":"error","message":"a\n\"b\""}"#));
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let levels = parse_filter("all,!trace,!debug").unwrap();
+        assert_eq!(levels, ALL & !bit(LogLevel::Trace) & !bit(LogLevel::Debug));
+        assert_eq!(parse_filter("Error, warn").unwrap(), 0b11);
+        assert_eq!(parse_filter("error,!all,info").unwrap(), 0b100);
+        assert_eq!(parse_filter("all,!loud").unwrap_err(), "!loud");
+    }
+}
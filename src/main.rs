@@ -1,47 +1,94 @@
+mod admin;
+mod bench;
+mod check;
 mod cli;
-mod config;
-mod lib;
-mod matcher;
+mod console;
+mod daemon;
+mod dryrun;
+mod export;
+mod init;
+mod man;
+mod privilege;
+mod records;
+#[cfg(windows)]
+mod service;
+mod shutdown;
+mod socket;
+mod systemd;
 mod watch;
 
+use admin::Admin;
 use cli::{parse_args, AppRunType};
-use config::{Config, Hosts, MultipleInvalid, Parser};
-use futures_util::StreamExt;
 use lazy_static::lazy_static;
-use lib::*;
-use logs::{error, info, warn};
+use regex::Regex;
+use shutdown::Signal;
+use socket::{bind_tcp, bind_udp, BindOptions, REUSE_PORT};
 use std::{
     env,
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
     time::Duration,
 };
+use systemd::Listen;
 use tokio::{
-    io::{Error, ErrorKind, Result},
-    net::UdpSocket,
+    io::{ErrorKind, Result},
+    net::{TcpListener, UdpSocket},
     sync::RwLock,
-    time::timeout,
+};
+use updns::{
+    config::{Config, ConfigFormat, InvalidType, LogFormat, MultipleInvalid, Parser, DEFAULT_BIND},
+    error, info, logger,
+    remote::RemoteImports,
+    server::DEFAULT_PROXY,
+    warn, Server,
 };
 use watch::Watch;
 
-const CONFIG_FILE: [&str; 2] = [".updns", "config"];
 const WATCH_INTERVAL: Duration = Duration::from_millis(5000);
-const DEFAULT_BIND: &str = "0.0.0.0:53";
-const DEFAULT_PROXY: [&str; 2] = ["8.8.8.8:53", "1.1.1.1:53"];
-const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+// Exit codes of `add`, `rm` and `check`, sysexits EX_DATAERR and EX_IOERR
+const EXIT_INVALID: i32 = 65;
+const EXIT_IO: i32 = 74;
+// Exit codes of `test`, 0 when every domain is answered locally
+const EXIT_FORWARDED: i32 = 1;
+const EXIT_PARSE: i32 = 2;
 
 lazy_static! {
-    static ref PROXY: RwLock<Vec<SocketAddr>> = RwLock::new(Vec::new());
-    static ref HOSTS: RwLock<Hosts> = RwLock::new(Hosts::new());
-    static ref TIMEOUT: RwLock<Duration> = RwLock::new(DEFAULT_TIMEOUT);
+    // Configured by `update_config`
+    static ref SERVER: Server = Server::new(Config::new());
+    // Bearer token of the mutating admin endpoints
+    static ref ADMIN_KEY: RwLock<Option<String>> = RwLock::new(None);
 }
 
+// Log the queries whatever the config says, set by `-v`
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+// Never log the queries, set by `--no-query-log`
+static NO_QUERY_LOG: AtomicBool = AtomicBool::new(false);
+// The log levels of the command line over `log_level`, set by `--log`,
+// `--log-level` or `RUST_LOG`
+static LOG_OVERRIDE: AtomicBool = AtomicBool::new(false);
+// `--log-format`, over the `log_format` of the config
+static LOG_FORMAT: Mutex<Option<LogFormat>> = Mutex::new(None);
+// Fail instead of binding `DEFAULT_BIND`, set by `--no-default-bind`
+static NO_DEFAULT_BIND: AtomicBool = AtomicBool::new(false);
+// Exit when a proxy host name cannot be resolved at startup, set by `--strict`
+static STRICT: AtomicBool = AtomicBool::new(false);
+// Format of the config file over its extension, set by `--format`
+static CONFIG_FORMAT: Mutex<Option<ConfigFormat>> = Mutex::new(None);
+// `parse-timeout` of the running config, used by the next parses
+static PARSE_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+// `--user` and `--group`, over the `user` and `group` of the config
+static RUN_AS: Mutex<(Option<String>, Option<String>)> = Mutex::new((None, None));
+
 #[macro_export]
 macro_rules! exit {
     ($($arg:tt)*) => {
         {
-            logs::error!($($arg)*);
+            updns::error!($($arg)*);
             std::process::exit(1)
         }
     };
@@ -50,35 +97,200 @@ macro_rules! exit {
 #[tokio::main]
 async fn main() {
     match parse_args() {
-        AppRunType::AddRecord { path, ip, host } => {
-            let mut parser = Parser::new(&path)
-                .await
-                .unwrap_or_else(|err| exit!("Failed to read config file {:?}\n{:?}", &path, err));
-
-            if let Err(err) = parser.add(&host, &ip).await {
-                exit!("Add record failed\n{:?}", err);
+        AppRunType::AddRecord {
+            path,
+            ip,
+            host,
+            remote,
+        } => {
+            let added = match open_config(&path).await {
+                Ok(mut parser) => parser.add(&host, &ip).await,
+                Err(err) => Err(err),
+            };
+            match added {
+                Ok(_) => println!("Added '{}' to {}", host, path.display()),
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    error!("{}", err);
+                    process::exit(EXIT_INVALID);
+                }
+                Err(err) => {
+                    error!("Failed to write config file {:?}\n{:?}", &path, err);
+                    process::exit(EXIT_IO);
+                }
+            }
+            notify_reload(&path, remote).await;
+        }
+        AppRunType::RemoveRecord {
+            path,
+            pattern,
+            remote,
+        } => {
+            let (removed, read_only) = match records::remove(&path, remote, &pattern).await {
+                Ok(r) => r,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    error!("{}", err);
+                    process::exit(EXIT_INVALID);
+                }
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => exit!("{}", err),
+                Err(err) => {
+                    error!("Failed to remove '{}'\n{:?}", pattern, err);
+                    process::exit(EXIT_IO);
+                }
+            };
+            for file in removed {
+                match file.lines {
+                    1 => println!("Removed '{}' from {}", pattern, file.source),
+                    n => println!("Removed {} lines of '{}' from {}", n, pattern, file.source),
+                }
+            }
+            for source in read_only {
+                warn!("'{}' is still in the read-only {}", pattern, source);
             }
+            notify_reload(&path, remote).await;
         }
-        AppRunType::PrintRecord { path } => {
-            let mut config = force_get_config(&path).await;
-            let n = config
-                .hosts
-                .iter()
-                .map(|(m, _)| m.to_string().len())
-                .fold(0, |a, b| a.max(b));
-
-            for (host, ip) in config.hosts.iter() {
-                println!("{:domain$}    {}", host.to_string(), ip, domain = n);
+        AppRunType::Check {
+            path,
+            remote,
+            json,
+            no_default_bind,
+        } => {
+            let config = match open_config(&path).await {
+                Ok(parser) => parser.allow_remote(remote).parse().await,
+                Err(err) => Err(err),
+            };
+            let mut config = config.unwrap_or_else(|err| {
+                error!("Failed to read config file {:?}\n{:?}", path, err);
+                process::exit(EXIT_IO)
+            });
+            config.resolve_proxies().await;
+            let findings = check::check(&config, !no_default_bind);
+            print!("{}", check::format(&findings, json));
+            if check::has_errors(&findings) {
+                process::exit(EXIT_INVALID);
             }
         }
-        AppRunType::EditConfig { path } => {
+        AppRunType::Test {
+            path,
+            remote,
+            domains,
+            qtype,
+            upstream,
+        } => {
+            let config = match open_config(&path).await {
+                Ok(parser) => parser.allow_remote(remote).parse().await,
+                Err(err) => Err(err),
+            };
+            let mut config = config.unwrap_or_else(|err| {
+                error!("Parsing config file failed\n{:?}", err);
+                process::exit(EXIT_PARSE)
+            });
+            config.resolve_proxies().await;
+            config.invalid().print();
+            config.warnings().warn();
+
+            let mut forwarded = false;
+            for domain in &domains {
+                let decision = dryrun::decide(&config, domain, qtype);
+                forwarded |= !decision.is_local();
+                println!("{}", dryrun::describe(domain, qtype, &decision));
+                if upstream {
+                    for line in dryrun::ask_upstream(&config, domain, qtype).await {
+                        println!("  {}", line);
+                    }
+                }
+            }
+            if !config.invalid().is_empty() {
+                process::exit(EXIT_PARSE);
+            }
+            if forwarded {
+                process::exit(EXIT_FORWARDED);
+            }
+        }
+        AppRunType::Console { path, remote } => console::run(&path, remote).await,
+        AppRunType::ExportConfig {
+            path,
+            remote,
+            format,
+        } => {
+            // Invalid lines are part of the export
+            let config = match open_config(&path).await {
+                Ok(parser) => parser.allow_remote(remote).parse().await,
+                Err(err) => Err(err),
+            };
+            let config = config.unwrap_or_else(|err| {
+                error!("Failed to read config file {:?}\n{:?}", path, err);
+                process::exit(EXIT_IO)
+            });
+            print!("{}", export::format(&config, format));
+        }
+        AppRunType::ImportConfig { file } => {
+            let json = match file.to_str() {
+                Some("-") => {
+                    let mut json = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut json).map(|_| json)
+                }
+                _ => std::fs::read_to_string(&file),
+            };
+            let json = json.unwrap_or_else(|err| {
+                error!("Failed to read {:?}\n{:?}", file, err);
+                process::exit(EXIT_IO)
+            });
+            match export::import(&json) {
+                Ok(lines) => print!("{}", lines),
+                Err(err) => {
+                    error!("Cannot import {:?}: {}", file, err);
+                    process::exit(EXIT_INVALID)
+                }
+            }
+        }
+        AppRunType::Bench {
+            path,
+            remote,
+            queries,
+            concurrency,
+            hit_ratio,
+            baseline,
+        } => {
+            // Read first, a missing file would waste the run
+            let baseline = baseline.map(|path| {
+                std::fs::read_to_string(&path)
+                    .unwrap_or_else(|err| exit!("Failed to read {:?}\n{:?}", path, err))
+            });
+            let config = force_get_config(&path, remote).await;
+            let report = bench::run(config, queries, concurrency, hit_ratio)
+                .await
+                .unwrap_or_else(|err| exit!("Benchmark failed\n{:?}", err));
+            println!("{}", report.to_json());
+            if let Some(baseline) = baseline {
+                for line in report.compare(&baseline) {
+                    eprintln!("{}", line);
+                }
+            }
+        }
+        AppRunType::PrintRecord {
+            path,
+            remote,
+            format,
+            filter,
+        } => {
+            let filter = filter.map(|filter| {
+                Regex::new(&filter)
+                    .unwrap_or_else(|err| exit!("Invalid filter '{}'\n{}", filter, err))
+            });
+            let config = force_get_config(&path, remote).await;
+            print!(
+                "{}",
+                records::format(config.hosts(), format, filter.as_ref())
+            );
+        }
+        AppRunType::EditConfig { path, remote } => {
             let status = Command::new("vim")
                 .arg(&path)
                 .status()
                 .unwrap_or_else(|err| exit!("Call 'vim' command failed\n{:?}", err));
 
             if status.success() {
-                force_get_config(&path).await;
+                force_get_config(&path, remote).await;
             } else {
                 exit!("'vim' exits with a non-zero status code: {:?}", status);
             }
@@ -89,197 +301,467 @@ async fn main() {
 
             println!("Binary: {}\nConfig: {}", binary.display(), path.display());
         }
-        AppRunType::Run { path, duration } => {
-            let mut config = force_get_config(&path).await;
-            if config.bind.is_empty() {
+        AppRunType::Completions { shell } => cli::print_completions(shell),
+        AppRunType::Man => cli::print_man(),
+        AppRunType::Init { path, force } => {
+            if let Err(err) = init::init(&path, force).await {
+                exit!("Failed to create config file\n{}", err);
+            }
+            println!("Config: {}", path.display());
+        }
+        AppRunType::Run {
+            path,
+            duration,
+            remote,
+            verbose,
+            no_query_log,
+            daemon,
+            pid_file,
+            no_default_bind,
+            strict,
+            user,
+            group,
+            log_override,
+            log_format,
+        } => {
+            if daemon && !daemon::is_child() {
+                // Refuse before starting, with the error on this terminal
+                if let Some(file) = &pid_file {
+                    daemon::check(file).unwrap_or_else(|err| exit!("{}", err));
+                }
+                match daemon::spawn() {
+                    Ok(pid) => println!("Started in the background with pid {}", pid),
+                    Err(err) => exit!("{}", err),
+                }
+                std::process::exit(0);
+            }
+
+            VERBOSE.store(verbose, Ordering::Relaxed);
+            NO_QUERY_LOG.store(no_query_log, Ordering::Relaxed);
+            LOG_OVERRIDE.store(log_override, Ordering::Relaxed);
+            *LOG_FORMAT.lock().unwrap() = log_format;
+            NO_DEFAULT_BIND.store(no_default_bind, Ordering::Relaxed);
+            STRICT.store(strict, Ordering::Relaxed);
+            *RUN_AS.lock().unwrap() = (user, group);
+            let mut pid = None;
+            run(path, duration, remote, || {
+                if let Some(file) = &pid_file {
+                    pid = Some(daemon::PidFile::create(file).unwrap_or_else(|err| {
+                        exit!("Failed to write pid file {:?}\n{}", file, err)
+                    }));
+                }
+                if let Err(err) = systemd::notify("READY=1") {
+                    warn!("Failed to notify systemd\n{:?}", err);
+                }
+                if daemon {
+                    if let Err(err) = daemon::detach() {
+                        exit!("Failed to run in the background\n{:?}", err);
+                    }
+                }
+            })
+            .await;
+            if let Some(pid) = pid {
+                pid.remove();
+            }
+            std::process::exit(0);
+        }
+        AppRunType::Stop { pid_file } => match daemon::stop(&pid_file) {
+            Ok(pid) => println!("Stopped updns with pid {}", pid),
+            Err(err) => exit!("{}", err),
+        },
+        #[cfg(windows)]
+        AppRunType::Service {
+            command,
+            path,
+            duration,
+            remote,
+        } => match command {
+            cli::ServiceCommand::Install => {
+                let mut args = format!("-d {}ms ", duration.as_millis());
+                match remote {
+                    RemoteImports::Deny => {}
+                    RemoteImports::Https => args += "--allow-remote-imports ",
+                    RemoteImports::Http => args += "--allow-remote-imports --allow-http-imports ",
+                }
+                if let Some(ConfigFormat::Toml) = *CONFIG_FORMAT.lock().unwrap() {
+                    args += "--format toml ";
+                }
+                if let Err(err) = service::install(&path, &args).await {
+                    exit!("Failed to install the service\n{:?}", err);
+                }
+                println!("Installed the service\nConfig: {}", path.display());
+            }
+            cli::ServiceCommand::Uninstall => {
+                if let Err(err) = service::uninstall() {
+                    exit!("Failed to uninstall the service\n{:?}", err);
+                }
+                println!("Uninstalled the service");
+            }
+            cli::ServiceCommand::Run => {
+                if let Err(err) = service::dispatch(path, duration, remote) {
+                    exit!("Failed to start the service\n{:?}", err);
+                }
+                std::process::exit(0);
+            }
+        },
+    }
+}
+
+// Serve until a stop signal, `ready` is called once the sockets are bound,
+// the user is switched and the config is applied
+async fn run<F: FnOnce()>(path: PathBuf, duration: Duration, remote: RemoteImports, ready: F) {
+    let mut config = force_get_config(&path, remote).await;
+    if STRICT.load(Ordering::Relaxed) {
+        let unresolved = config
+            .warnings()
+            .iter()
+            .any(|warning| matches!(warning.kind, InvalidType::ProxyResolve));
+        if unresolved {
+            exit!("A proxy host name cannot be resolved and --strict is set");
+        }
+    }
+    // Before the first lines of the server, the format is kept by the reloads
+    logger::init(config.log_format().unwrap_or(LogFormat::Text));
+    set_log_level(&config);
+    if remote == RemoteImports::Http {
+        warn!("Imports over plain http are allowed, anyone on the network path can alter them");
+    }
+    info!(
+        "{}, parsed in {:?}",
+        config.summary(),
+        config.parse_duration()
+    );
+    if config.proxy_count() == 0 {
+        warn!(
+            "Will use the default proxy address '{}'",
+            DEFAULT_PROXY.join(", ")
+        );
+    }
+    let identity = run_as(&config);
+
+    let (sockets, listeners) = match systemd::listen_fds() {
+        Some(fds) => {
+            if !config.bind().is_empty() {
+                warn!("Sockets are passed by systemd, ignore the 'bind' addresses");
+            }
+            inherit_sockets(fds)
+        }
+        None => {
+            if config.bind().is_empty() {
+                if NO_DEFAULT_BIND.load(Ordering::Relaxed) {
+                    exit!("No 'bind' address in the config and --no-default-bind is set");
+                }
                 warn!("Will bind the default address '{}'", DEFAULT_BIND);
-                config.bind.push(DEFAULT_BIND.parse().unwrap());
+                config.validate();
             }
-            if config.proxy.is_empty() {
-                warn!(
-                    "Will use the default proxy address '{}'",
-                    DEFAULT_PROXY.join(", ")
-                );
+            let (mut sockets, mut listeners) = (Vec::new(), Vec::new());
+            for bind in config.bind() {
+                if bind.protocol.udp() {
+                    sockets.extend(bind_sockets(bind.addr, &config, bind_udp, "UDP"));
+                }
+                if bind.protocol.tcp() {
+                    listeners.extend(bind_sockets(bind.addr, &config, bind_tcp, "TCP"));
+                }
             }
+            (sockets, listeners)
+        }
+    };
+    let admin = config.admin().map(|addr| bind_admin(addr, &config));
+    if let Some(identity) = identity {
+        privilege::switch(&identity)
+            .unwrap_or_else(|err| exit!("Failed to switch to user {}\n{}", identity, err));
+        info!("Running as user {}", identity);
+    }
+    let files = config.files().to_vec();
+    update_config(config).await;
+    ready();
+
+    let mut signal =
+        Signal::new().unwrap_or_else(|err| exit!("Failed to handle signals\n{:?}", err));
+
+    tokio::spawn(reopen_query_log());
+    if let Some(listener) = admin {
+        tokio::spawn(Admin::new(path.clone(), remote).serve(listener));
+    }
+    // watch config
+    tokio::spawn(watch_config(path, files, duration, remote));
+
+    // Run server
+    let stop = async {
+        if let Err(err) = signal.recv().await {
+            exit!("Failed to handle signals\n{:?}", err);
+        }
+    };
+    SERVER.serve_listeners(sockets, listeners, stop).await;
+    shutdown(signal).await;
+}
 
-            update_config(config.proxy, config.hosts, config.timeout).await;
+// The identity to switch to, after checking it can still read the config
+// and write the logs
+fn run_as(config: &Config) -> Option<privilege::Identity> {
+    let (user, group) = RUN_AS.lock().unwrap().clone();
+    let user = user.or_else(|| config.user().map(String::from));
+    let group = group.or_else(|| config.group().map(String::from));
+    let identity = privilege::resolve(user.as_deref(), group.as_deref())
+        .unwrap_or_else(|err| exit!("Cannot switch user\n{}", err))?;
+
+    let writes = config
+        .log_file()
+        .iter()
+        // The collector creates its socket
+        .chain(config.dnstap().iter().filter(|path| path.exists()))
+        .map(|path| path.to_path_buf())
+        .collect::<Vec<_>>();
+    if let Err(err) = privilege::check_access(&identity, config.files(), &writes) {
+        exit!("{}, change its permissions or run as another user", err);
+    }
+    Some(identity)
+}
 
-            // Run server
-            for addr in config.bind {
-                tokio::spawn(run_server(addr));
+// Wait for the pending queries to be answered, a second signal stops waiting
+async fn shutdown(mut signal: Signal) {
+    info!("Shutting down");
+    tokio::select! {
+        answered = SERVER.drain() => {
+            if !answered {
+                warn!("Exit with unanswered queries after {:?}", SERVER.shutdown_grace());
             }
-            // watch config
-            watch_config(path, duration).await;
         }
+        _ = signal.recv() => {}
     }
 }
 
-async fn update_config(mut proxy: Vec<SocketAddr>, hosts: Hosts, timeout: Option<Duration>) {
-    if proxy.is_empty() {
-        proxy = DEFAULT_PROXY
-            .iter()
-            .map(|p| p.parse().unwrap())
-            .collect::<Vec<SocketAddr>>();
+async fn update_config(mut config: Config) {
+    set_log_level(&config);
+    if VERBOSE.load(Ordering::Relaxed) {
+        config.set_log_queries(true);
     }
+    if NO_QUERY_LOG.load(Ordering::Relaxed) {
+        config.set_log_queries(false);
+    }
+    if let Some(format) = *LOG_FORMAT.lock().unwrap() {
+        config.set_log_format(format);
+    }
+    *ADMIN_KEY.write().await = config.take_admin_key();
+    *PARSE_TIMEOUT.lock().unwrap() = config.parse_timeout();
+    SERVER.update(config).await;
+}
 
-    {
-        let mut w = PROXY.write().await;
-        *w = proxy;
+// The `log_level` of the config, unless the command line sets the levels
+fn set_log_level(config: &Config) {
+    match config.log_level() {
+        Some(level) if !LOG_OVERRIDE.load(Ordering::Relaxed) => {
+            logger::set_level(level, VERBOSE.load(Ordering::Relaxed))
+        }
+        _ => {}
     }
-    {
-        let mut w = HOSTS.write().await;
-        *w = hosts;
+}
+
+// logrotate moves the query log away and sends SIGHUP
+async fn reopen_query_log() {
+    let mut hangup = match Signal::hangup() {
+        Ok(signal) => signal,
+        Err(err) => return error!("Failed to handle SIGHUP\n{:?}", err),
+    };
+    while hangup.recv().await.is_ok() {
+        SERVER.reopen_log().await;
     }
-    {
-        let mut w = TIMEOUT.write().await;
-        *w = timeout.unwrap_or(DEFAULT_TIMEOUT);
+}
+
+// `Parser::new` with the `--format` of the command line. It's the format
+// of the config file, the imports keep the one of their extension
+async fn open_config<P: AsRef<Path>>(path: P) -> Result<Parser> {
+    let mut parser = Parser::new(path).await?;
+    if let Some(timeout) = *PARSE_TIMEOUT.lock().unwrap() {
+        parser = parser.parse_timeout(timeout);
     }
+    Ok(match *CONFIG_FORMAT.lock().unwrap() {
+        Some(format) => parser.format(format),
+        None => parser,
+    })
 }
 
-async fn force_get_config(file: &Path) -> Config {
-    let parser = Parser::new(file)
+async fn force_get_config(file: &Path, remote: RemoteImports) -> Config {
+    let parser = open_config(file)
         .await
         .unwrap_or_else(|err| exit!("Failed to read config file {:?}\n{:?}", file, err));
 
-    let config: Config = parser
+    let mut config: Config = parser
+        .allow_remote(remote)
         .parse()
         .await
         .unwrap_or_else(|err| exit!("Parsing config file failed\n{:?}", err));
+    config.resolve_proxies().await;
 
-    config.invalid.print();
+    config.invalid().print();
+    config.warnings().warn();
     config
 }
 
-async fn watch_config(p: PathBuf, d: Duration) {
-    let mut watch = Watch::new(&p, d).await;
-    while watch.next().await.is_some() {
-        info!("Reload the configuration file: {:?}", &p);
-        if let Ok(parser) = Parser::new(&p).await {
-            if let Ok(config) = parser.parse().await {
-                update_config(config.proxy, config.hosts, config.timeout).await;
-                config.invalid.print();
-            }
-        }
+// Ask a running server to reload through its admin api, without one
+// the server reloads when it notices the change
+async fn notify_reload(path: &Path, remote: RemoteImports) {
+    let config = match open_config(path).await {
+        Ok(parser) => parser.allow_remote(remote).parse().await,
+        Err(err) => Err(err),
+    };
+    let (addr, key) = match &config {
+        Ok(config) => match config.admin() {
+            Some(addr) => (addr, config.admin_key()),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    match admin::request_reload(addr, key).await {
+        Ok(()) => println!("Reloaded the server at '{}'", addr),
+        // Not running
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {}
+        Err(err) => warn!("Failed to reload the server at '{}'\n{}", addr, err),
     }
 }
 
-async fn run_server(addr: SocketAddr) {
-    let socket = match UdpSocket::bind(&addr).await {
-        Ok(socket) => {
-            info!("Start listening to '{}'", addr);
-            socket
-        }
-        Err(err) => {
-            exit!("Binding '{}' failed\n{:?}", addr, err)
-        }
-    };
+// Parse the config again and apply it, returns the files it reads
+async fn reload_config(path: &Path, remote: RemoteImports) -> Result<Vec<PathBuf>> {
+    let mut config = open_config(path)
+        .await?
+        .allow_remote(remote)
+        .parse()
+        .await?;
+    config.resolve_proxies().await;
+    config.invalid().print();
+    config.warnings().warn();
+    let files = config.files().to_vec();
+    update_config(config).await;
+    Ok(files)
+}
 
+// Reload when the config file or one of its imports changes
+async fn watch_config(p: PathBuf, files: Vec<PathBuf>, d: Duration, remote: RemoteImports) {
+    let mut watch = Watch::new(files, d).await;
     loop {
-        let mut req = BytePacketBuffer::new();
-
-        let (len, src) = match socket.recv_from(&mut req.buf).await {
-            Ok(r) => r,
-            Err(err) => {
-                error!("Failed to receive message {:?}", err);
-                continue;
-            }
-        };
-
-        let res = match handle(req, len).await {
-            Ok(data) => data,
-            Err(err) => {
-                error!("Processing request failed {:?}", err);
-                continue;
-            }
-        };
-
-        if let Err(err) = socket.send_to(&res, &src).await {
-            error!("Replying to '{}' failed {:?}", &src, err);
+        watch.changed().await;
+        info!("Reload the configuration file: {:?}", &p);
+        if let Ok(files) = reload_config(&p, remote).await {
+            // Pick up added or removed imports
+            watch.watch(files).await;
         }
     }
 }
 
-async fn proxy(buf: &[u8]) -> Result<Vec<u8>> {
-    let proxy = PROXY.read().await;
-    let duration = *TIMEOUT.read().await;
-
-    for addr in proxy.iter() {
-        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-
-        let data: Result<Vec<u8>> = timeout(duration, async {
-            socket.send_to(buf, addr).await?;
-            let mut res = [0; 512];
-            let len = socket.recv(&mut res).await?;
-            Ok(res[..len].to_vec())
+// The admin address is only read at startup
+fn bind_admin(addr: SocketAddr, config: &Config) -> TcpListener {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
         })
-        .await?;
+        .unwrap_or_else(|err| exit!("Binding admin '{}' failed\n{:?}", addr, err));
+    if config.admin_key().is_none() && !addr.ip().is_loopback() {
+        warn!(
+            "Admin '{}' is reachable from the network without 'admin-key'",
+            addr
+        );
+    }
+    info!("Admin api listening to '{}'", addr);
+    listener
+}
 
-        match data {
-            Ok(data) => {
-                return Ok(data);
-            }
-            Err(err) => {
-                error!("Agent request to {} {:?}", addr, err);
-            }
+// Open the sockets of a bind address, with `bind-dual-stack` the IPv6 wildcard
+// also serves IPv4, or is paired with the IPv4 wildcard where it cannot.
+// Each worker gets its own socket sharing the address through SO_REUSEPORT
+fn bind_sockets<S>(
+    addr: SocketAddr,
+    config: &Config,
+    bind: fn(SocketAddr, &BindOptions) -> Result<S>,
+    protocol: &str,
+) -> Vec<S> {
+    let fail = |addr, err| exit!("Binding '{}' over {} failed\n{:?}", addr, protocol, err);
+
+    let mut workers = config.workers().unwrap_or(1);
+    if workers > 1 && !REUSE_PORT {
+        warn!("SO_REUSEPORT is not supported, use a single socket");
+        workers = 1;
+    }
+    let open = |addr, v6only| {
+        let options = BindOptions {
+            v6only,
+            reuse_port: workers > 1,
+            device: config.bind_device().map(String::from),
+        };
+        (0..workers)
+            .map(|_| bind(addr, &options))
+            .collect::<Result<Vec<_>>>()
+    };
+    let log = |addr: SocketAddr, family: &str| match workers {
+        1 => info!("Start listening to '{}' over {}{}", addr, protocol, family),
+        n => info!(
+            "Start listening to '{}' over {}{} with {} sockets",
+            addr, protocol, family, n
+        ),
+    };
+
+    let dual_stack = config.bind_dual_stack().unwrap_or(false);
+    if dual_stack && addr.ip() == IpAddr::from([0_u16; 8]) {
+        if let Ok(sockets) = open(addr, false) {
+            log(addr, " (IPv4 and IPv6)");
+            return sockets;
         }
+        let v4_addr = SocketAddr::from(([0, 0, 0, 0], addr.port()));
+        warn!(
+            "Dual-stack '{}' is not supported, bind '{}' too",
+            addr, v4_addr
+        );
+
+        let mut sockets = open(addr, true).unwrap_or_else(|err| fail(addr, err));
+        log(addr, " (IPv6 only)");
+        sockets.extend(open(v4_addr, true).unwrap_or_else(|err| fail(v4_addr, err)));
+        log(v4_addr, "");
+        return sockets;
     }
 
-    Err(Error::new(
-        ErrorKind::Other,
-        "Proxy server failed to proxy request",
-    ))
+    let sockets = open(addr, true).unwrap_or_else(|err| fail(addr, err));
+    match addr {
+        SocketAddr::V4(_) => log(addr, ""),
+        SocketAddr::V6(_) => log(addr, " (IPv6 only)"),
+    }
+    sockets
 }
 
-async fn get_answer(domain: &str, query: QueryType) -> Option<DnsRecord> {
-    if let Some(ip) = HOSTS.read().await.get(domain) {
-        match query {
-            QueryType::A => {
-                if let IpAddr::V4(addr) = ip {
-                    return Some(DnsRecord::A {
-                        domain: domain.to_string(),
-                        addr: *addr,
-                        ttl: 3600,
-                    });
+// Socket activation, the sockets are bound by systemd
+fn inherit_sockets<I: IntoIterator<Item = i32>>(fds: I) -> (Vec<UdpSocket>, Vec<TcpListener>) {
+    let (mut sockets, mut listeners) = (Vec::new(), Vec::new());
+    for fd in fds {
+        match systemd::adopt(fd) {
+            Ok(Listen::Udp(socket)) => {
+                match socket.local_addr() {
+                    Ok(addr) => info!("Start listening to inherited '{}' over UDP", addr),
+                    Err(_) => info!("Start listening to inherited socket {}", fd),
                 }
+                sockets.push(socket);
             }
-            QueryType::AAAA => {
-                if let IpAddr::V6(addr) = ip {
-                    return Some(DnsRecord::AAAA {
-                        domain: domain.to_string(),
-                        addr: *addr,
-                        ttl: 3600,
-                    });
+            Ok(Listen::Tcp(listener)) => {
+                match listener.local_addr() {
+                    Ok(addr) => info!("Start listening to inherited '{}' over TCP", addr),
+                    Err(_) => info!("Start listening to inherited socket {}", fd),
                 }
+                listeners.push(listener);
             }
-            _ => {}
+            Err(err) => exit!("Inherited socket {} is not usable\n{:?}", fd, err),
         }
     }
-    None
+    if sockets.is_empty() && listeners.is_empty() {
+        exit!("No socket is passed by systemd");
+    }
+    (sockets, listeners)
 }
 
-async fn handle(mut req: BytePacketBuffer, len: usize) -> Result<Vec<u8>> {
-    let mut request = DnsPacket::from_buffer(&mut req)?;
-
-    let query = match request.questions.get(0) {
-        Some(q) => q,
-        None => return proxy(&req.buf[..len]).await,
-    };
-
-    info!("{} {:?}", query.name, query.qtype);
-
-    // Whether to proxy
-    let answer = match get_answer(&query.name, query.qtype).await {
-        Some(record) => record,
-        None => return proxy(&req.buf[..len]).await,
-    };
-
-    request.header.recursion_desired = true;
-    request.header.recursion_available = true;
-    request.header.response = true;
-    request.answers.push(answer);
-    let mut res_buffer = BytePacketBuffer::new();
-    request.write(&mut res_buffer)?;
-
-    let data = res_buffer.get_range(0, res_buffer.pos())?;
-    Ok(data.to_vec())
+// A config without imports, `config::parse_test_config` of the library
+// isn't built for the tests of the binary
+#[cfg(test)]
+fn parse_test_config(content: &str) -> Config {
+    use futures_util::future::FutureExt;
+    Config::parse_str(content, |_, _| async { Ok(Config::new()) }.boxed())
+        .now_or_never()
+        .unwrap()
+        .unwrap()
 }
@@ -0,0 +1,297 @@
+use clap::{App, AppSettings, ErrorKind};
+use std::iter;
+
+// Of the help lines under an argument, its term is indented less
+const HELP_INDENT: &str = "            ";
+
+// One `.TP` paragraph of the options list
+struct Entry {
+    term: String,
+    help: String,
+}
+
+// The parts of a help text: the name line, the about lines, and the
+// sections such as `USAGE:` with their lines
+struct Help {
+    name: String,
+    about: Vec<String>,
+    sections: Vec<(String, Vec<String>)>,
+}
+
+// A roff man page from the help clap prints for the binary and each
+// subcommand, the same arguments parse the command line and generate the
+// completions
+pub fn render(app: &App<'static, 'static>, description: &str) -> String {
+    let help = help(app, &[]);
+    let mut words = help.name.split_whitespace();
+    let name = words.next().unwrap_or_default().to_string();
+    let mut out = format!(
+        ".TH {} 1 \"\" \"{} {}\"\n",
+        name.to_uppercase(),
+        name,
+        words.next().unwrap_or_default()
+    );
+    out += &format!(".SH NAME\n{} \\- {}\n", name, escape(description));
+    let usage = usage_args(&name, help.section("USAGE").first());
+    out += &format!(".SH SYNOPSIS\n\\fB{}\\fR{}\n", escape(&name), usage);
+    if !help.about.is_empty() {
+        out += &format!(".SH DESCRIPTION\n{}\n", escape(&help.about.join("\n")));
+    }
+
+    let options = entries(&help);
+    if !options.is_empty() {
+        out += ".SH OPTIONS\n";
+        out += &paragraphs(&options);
+    }
+
+    let subcommands = subcommands(&help);
+    if !subcommands.is_empty() {
+        out += ".SH SUBCOMMANDS\n";
+    }
+    for sub in subcommands {
+        out += &subcommand(app, &[sub.as_str()]);
+    }
+    out
+}
+
+fn subcommand(app: &App<'static, 'static>, path: &[&str]) -> String {
+    let help = help(app, path);
+    let name = iter::once(app.get_name())
+        .chain(path.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let usage = usage_args(&name, help.section("USAGE").first());
+    let mut out = format!(".SS {}{}\n", escape(&name), usage);
+    if !help.about.is_empty() {
+        out += &format!("{}\n", escape(&help.about.join("\n")));
+    }
+    out += &paragraphs(&entries(&help));
+    for sub in subcommands(&help) {
+        let path = path
+            .iter()
+            .copied()
+            .chain(iter::once(sub.as_str()))
+            .collect::<Vec<_>>();
+        out += &subcommand(app, &path);
+    }
+    out
+}
+
+// What `--help` prints for the subcommands of `path`, one argument per
+// line with its help on the next ones, without colors or wrapping
+fn help(app: &App<'static, 'static>, path: &[&str]) -> Help {
+    let app = app
+        .clone()
+        .global_setting(AppSettings::NextLineHelp)
+        .global_setting(AppSettings::ColorNever)
+        .set_term_width(0);
+    let args = iter::once(app.get_name().to_string())
+        .chain(path.iter().map(|name| name.to_string()))
+        .chain(iter::once("--help".to_string()));
+    let text = match app.get_matches_from_safe(args) {
+        Err(err) if err.kind == ErrorKind::HelpDisplayed => err.message,
+        _ => String::new(),
+    };
+    Help::parse(&text)
+}
+
+impl Help {
+    fn parse(text: &str) -> Help {
+        let mut lines = text.lines();
+        let name = lines.next().unwrap_or_default().trim().to_string();
+        let mut help = Help {
+            name,
+            about: Vec::new(),
+            sections: Vec::new(),
+        };
+        for line in lines {
+            if !line.starts_with(' ') && line.ends_with(':') {
+                let title = line.trim_end_matches(':').to_string();
+                help.sections.push((title, Vec::new()));
+            } else if let Some((_, lines)) = help.sections.last_mut() {
+                if !line.trim().is_empty() {
+                    lines.push(line.to_string());
+                }
+            } else if !line.trim().is_empty() {
+                help.about.push(line.trim().to_string());
+            }
+        }
+        help
+    }
+
+    fn section(&self, title: &str) -> &[String] {
+        self.sections
+            .iter()
+            .find(|(name, _)| name == title)
+            .map_or(&[], |(_, lines)| lines)
+    }
+}
+
+// Flags, options and then the positional arguments
+fn entries(help: &Help) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for title in &["FLAGS", "OPTIONS", "ARGS"] {
+        for line in help.section(title) {
+            match line.strip_prefix(HELP_INDENT) {
+                Some(text) => {
+                    if let Some(entry) = entries.last_mut() {
+                        if !entry.help.is_empty() {
+                            entry.help.push('\n');
+                        }
+                        // Each default and possible values on its own line
+                        entry.help += &text
+                            .replace(" [default: ", "\n[default: ")
+                            .replace(" [possible values: ", "\n[possible values: ");
+                    }
+                }
+                None => entries.push(Entry {
+                    term: term(line.trim()),
+                    help: String::new(),
+                }),
+            }
+        }
+    }
+    entries
+}
+
+// The names of the listed subcommands but `help`
+fn subcommands(help: &Help) -> Vec<String> {
+    help.section("SUBCOMMANDS")
+        .iter()
+        .filter(|line| !line.starts_with(HELP_INDENT))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "help")
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Switches in bold and value names in italics, as in `-c, --config <FILE>`
+fn term(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            let (word, comma) = match word.strip_suffix(',') {
+                Some(word) => (word, ","),
+                None => (word, ""),
+            };
+            let word = if word.starts_with('-') {
+                format!("\\fB{}\\fR", escape(word))
+            } else if let Some(value) = word.strip_prefix('<') {
+                match value.split_once('>') {
+                    Some((value, rest)) => format!("\\fI{}\\fR{}", escape(value), escape(rest)),
+                    None => escape(word),
+                }
+            } else {
+                escape(word)
+            };
+            word + comma
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The arguments after the command of a usage line as terms, with a
+// leading space
+fn usage_args(name: &str, usage: Option<&String>) -> String {
+    let args = usage.and_then(|usage| usage.trim().strip_prefix(name));
+    match args.map(str::trim) {
+        Some(args) if !args.is_empty() => format!(" {}", term(args)),
+        _ => String::new(),
+    }
+}
+
+fn paragraphs(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        out += &format!(".TP\n{}\n", entry.term);
+        for line in entry.help.lines() {
+            out += &format!("{}\n.br\n", escape(line));
+        }
+        if out.ends_with(".br\n") {
+            out.truncate(out.len() - ".br\n".len());
+        }
+    }
+    out
+}
+
+// Backslashes and dashes, and a leading dot or quote would start a request
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod test_man {
+    use super::*;
+    use clap::{Arg, SubCommand};
+
+    #[test]
+    fn test_render() {
+        let app = App::new("tool")
+            .version("1.0")
+            .setting(AppSettings::VersionlessSubcommands)
+            .arg(
+                Arg::with_name("config")
+                    .short("c")
+                    .long("config")
+                    .value_name("FILE")
+                    .help("Config file"),
+            )
+            .arg(Arg::with_name("quiet").long("quiet").help("No\noutput"))
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about(".hosts records")
+                    .arg(
+                        Arg::with_name("ip")
+                            .value_name("IP")
+                            .required(true)
+                            .help("Address"),
+                    )
+                    .arg(Arg::with_name("hidden").long("hidden").hidden(true)),
+            )
+            .subcommand(SubCommand::with_name("secret").setting(AppSettings::Hidden));
+
+        assert_eq!(
+            render(&app, "A tool"),
+            r#".TH TOOL 1 "" "tool 1.0"
+.SH NAME
+tool \- A tool
+.SH SYNOPSIS
+\fBtool\fR [FLAGS] [OPTIONS] [SUBCOMMAND]
+.SH OPTIONS
+.TP
+\fB\-h\fR, \fB\-\-help\fR
+Prints help information
+.TP
+\fB\-\-quiet\fR
+No
+.br
+output
+.TP
+\fB\-V\fR, \fB\-\-version\fR
+Prints version information
+.TP
+\fB\-c\fR, \fB\-\-config\fR \fIFILE\fR
+Config file
+.SH SUBCOMMANDS
+.SS tool add \fIIP\fR
+\&.hosts records
+.TP
+\fB\-h\fR, \fB\-\-help\fR
+Prints help information
+.TP
+\fIIP\fR
+Address
+"#
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r"~^\w+-a"), r"~^\ew+\-a");
+        assert_eq!(escape("'quoted'"), r"\&'quoted'");
+    }
+}
@@ -1,31 +1,115 @@
-use regex::{Error, Regex};
+use regex::Regex;
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Matcher(MatchMode);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum MatchMode {
     Static(String),
     Wildcard(WildcardMatch),
-    Regex(Regex),
+    Regex(Box<Regex>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern<'a> {
+    // example.com
+    Text(&'a str),
+    // *.example.com
+    Wildcard(&'a str),
+    // The regex without the leading `~`
+    Regex(&'a str),
+}
+
+// How a matcher compares domains
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchKind {
+    Text,
+    Wildcard,
+    Regex,
+}
+
+impl MatchKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchKind::Text => "text",
+            MatchKind::Wildcard => "wildcard",
+            MatchKind::Regex => "regex",
+        }
+    }
 }
 
 const REGEX_WORD: char = '~';
 const WILDCARD: char = '*';
 
+// Unrelated domains, a regex matching all of them catches every query
+const OVERBROAD_PROBES: [&str; 6] = [
+    "example.com",
+    "www.google.com",
+    "a.b.c.d.e.f",
+    "xn--fiqs8s.cn",
+    "4.3.2.1.in-addr.arpa",
+    "updns-9f2c.test",
+];
+
+// Labels put in place of `*` to sample the domains of a wildcard,
+// each starts with a different character
+const WILDCARD_FILLS: [&str; 4] = ["a", "z9", "0", "updns-probe"];
+
+// RFC 1035 limits, in the text form without the trailing dot
+const MAX_DOMAIN: usize = 253;
+const MAX_LABEL: usize = 63;
+
+#[derive(Debug)]
+pub enum Error {
+    Regex(regex::Error),
+    // The regex would override the upstream of every domain, like `.*`
+    Overbroad,
+    // A wildcard with an empty label, like `*..com`
+    Wildcard(String),
+    // Longer than a domain or one of its labels can be
+    TooLong,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Regex(err) => write!(f, "{}", err),
+            Error::Overbroad => write!(f, "The regular expression matches every domain"),
+            Error::Wildcard(raw) => write!(f, "The wildcard `{}` has an empty label", raw),
+            Error::TooLong => write!(
+                f,
+                "The domain is longer than {} characters, or a label than {}",
+                MAX_DOMAIN, MAX_LABEL
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl Matcher {
     pub fn new(raw: &str) -> Result<Self, Error> {
         // Use regex: ~^example\.com$
         if raw.starts_with(REGEX_WORD) {
             let reg = raw.replacen(REGEX_WORD, "", 1);
-            let mode = MatchMode::Regex(Regex::new(&reg)?);
-            return Ok(Matcher(mode));
+            let reg = Regex::new(&reg).map_err(Error::Regex)?;
+            if OVERBROAD_PROBES.iter().all(|domain| reg.is_match(domain)) {
+                return Err(Error::Overbroad);
+            }
+            return Ok(Matcher(MatchMode::Regex(Box::new(reg))));
+        }
+
+        if raw.len() > MAX_DOMAIN || raw.split('.').any(|label| label.len() > MAX_LABEL) {
+            return Err(Error::TooLong);
         }
 
         // Use wildcard match: *.example.com
         let find = raw.chars().any(|c| c == WILDCARD);
         if find {
+            if raw.split('.').any(str::is_empty) {
+                return Err(Error::Wildcard(raw.to_string()));
+            }
             let mode = MatchMode::Wildcard(WildcardMatch::new(raw));
             return Ok(Matcher(mode));
         }
@@ -34,90 +118,128 @@ impl Matcher {
         Ok(Matcher(MatchMode::Static(raw.to_string())))
     }
 
+    // The pattern as written in the config
+    pub fn as_pattern(&self) -> Pattern<'_> {
+        match &self.0 {
+            MatchMode::Static(raw) => Pattern::Text(raw),
+            MatchMode::Wildcard(raw) => Pattern::Wildcard(&raw.raw),
+            MatchMode::Regex(raw) => Pattern::Regex(raw.as_str()),
+        }
+    }
+
+    pub fn kind(&self) -> MatchKind {
+        match &self.0 {
+            MatchMode::Static(_) => MatchKind::Text,
+            MatchMode::Wildcard(_) => MatchKind::Wildcard,
+            MatchMode::Regex(_) => MatchKind::Regex,
+        }
+    }
+
+    // A fully qualified domain with a trailing dot is the same domain
     pub fn is_match(&self, domain: &str) -> bool {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
         match &self.0 {
             MatchMode::Static(raw) => raw == domain,
             MatchMode::Wildcard(raw) => raw.is_match(domain),
             MatchMode::Regex(raw) => raw.is_match(domain),
         }
     }
+
+    // Whether every domain matched by `other` is matched by this one.
+    // Exact for text and wildcards, a regex covering a wildcard is judged
+    // on sample domains, and regexes are only compared by their source
+    pub fn covers(&self, other: &Matcher) -> bool {
+        match &other.0 {
+            MatchMode::Static(domain) => self.is_match(domain),
+            MatchMode::Wildcard(wildcard) => {
+                if let MatchMode::Static(_) = self.0 {
+                    return false;
+                }
+                let samples = WILDCARD_FILLS
+                    .iter()
+                    .map(|fill| wildcard.raw.replace(WILDCARD, fill))
+                    .filter(|domain| wildcard.is_match(domain))
+                    .collect::<Vec<_>>();
+                !samples.is_empty() && samples.iter().all(|domain| self.is_match(domain))
+            }
+            MatchMode::Regex(regex) => match &self.0 {
+                MatchMode::Regex(own) => own.as_str() == regex.as_str(),
+                _ => false,
+            },
+        }
+    }
 }
 
-#[derive(Debug)]
+// `*` stands for one or more characters inside a label, so the domain
+// has as many labels as the pattern
+#[derive(Debug, Clone)]
 struct WildcardMatch {
-    chars: Vec<char>,
+    raw: String,
+    labels: Vec<Vec<u8>>,
 }
 
 impl WildcardMatch {
     fn new(raw: &str) -> Self {
-        let mut chars = Vec::with_capacity(raw.len());
-        for c in raw.chars() {
-            chars.push(c);
+        Self {
+            raw: raw.to_string(),
+            labels: raw
+                .split('.')
+                .map(|label| label.as_bytes().to_vec())
+                .collect(),
         }
-        Self { chars }
     }
 
     fn is_match(&self, text: &str) -> bool {
-        let mut chars = text.chars();
-        let mut dot = false;
-
-        for cur in &self.chars {
-            match cur {
-                '*' => {
-                    match chars.next() {
-                        Some(c) => {
-                            if c == '.' {
-                                return false;
-                            }
-                        }
-                        None => return false,
-                    }
-                    while let Some(n) = chars.next() {
-                        if n == '.' {
-                            dot = true;
-                            break;
-                        }
-                    }
-                }
-                word => {
-                    if dot {
-                        if word == &'.' {
-                            dot = false;
-                            continue;
-                        } else {
-                            return false;
-                        }
-                    }
-                    match chars.next() {
-                        Some(c) => {
-                            if word != &c {
-                                return false;
-                            }
-                        }
-                        None => return false,
-                    }
-                }
+        let mut labels = text.split('.');
+        for pattern in &self.labels {
+            match labels.next() {
+                Some(label) if label_match(pattern, label.as_bytes()) => {}
+                _ => return false,
             }
         }
-        if dot {
-            return false;
+        labels.next().is_none()
+    }
+}
+
+// UTF-8 is matched byte by byte, a literal after `*` starts on a character.
+// A mismatch only extends the last `*` instead of trying every split of
+// the label between the stars, so a query can't make it exponential
+fn label_match(pattern: &[u8], label: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the pattern goes on after the last `*`, and where its match ends
+    let mut star = None;
+    loop {
+        if pattern.get(p) == Some(&b'*') {
+            // One character at least
+            if t == label.len() {
+                return false;
+            }
+            p += 1;
+            t += 1;
+            star = Some((p, t));
+        } else if p < pattern.len() && pattern.get(p) == label.get(t) {
+            p += 1;
+            t += 1;
+        } else if p == pattern.len() && t == label.len() {
+            return true;
+        } else {
+            match star {
+                Some((after, end)) if end < label.len() => {
+                    star = Some((after, end + 1));
+                    p = after;
+                    t = end + 1;
+                }
+                _ => return false,
+            }
         }
-        chars.next().is_none()
     }
 }
 
 impl fmt::Display for Matcher {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.0 {
-            MatchMode::Static(raw) => write!(f, "{}", raw),
-            MatchMode::Wildcard(raw) => {
-                let mut s = String::new();
-                for ch in raw.chars.clone() {
-                    s.push(ch);
-                }
-                write!(f, "{}", s)
-            }
-            MatchMode::Regex(raw) => write!(f, "~{}", raw.as_str()),
+        match self.as_pattern() {
+            Pattern::Text(raw) | Pattern::Wildcard(raw) => write!(f, "{}", raw),
+            Pattern::Regex(raw) => write!(f, "{}{}", REGEX_WORD, raw),
         }
     }
 }
@@ -129,6 +251,45 @@ mod test_matcher {
     #[test]
     fn test_create() {}
 
+    #[test]
+    fn test_overbroad() {
+        for raw in &["~.*", "~.+", "~.*\\..*", "~^.*$", "~.", "~\\w", "~(com|.*)"] {
+            assert!(
+                matches!(Matcher::new(raw), Err(Error::Overbroad)),
+                "{}",
+                raw
+            );
+        }
+        for raw in &["~.*\\.com$", "~^www\\.", "~google", "*.*", "*"] {
+            assert!(Matcher::new(raw).is_ok(), "{}", raw);
+        }
+        assert!(matches!(Matcher::new("~("), Err(Error::Regex(_))));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for raw in &["*..com", ".*.com", "*.com.", "*."] {
+            assert!(
+                matches!(Matcher::new(raw), Err(Error::Wildcard(_))),
+                "{}",
+                raw
+            );
+        }
+        let label = "a".repeat(63);
+        assert!(Matcher::new(&format!("{}.com", label)).is_ok());
+        assert!(matches!(
+            Matcher::new(&format!("{}a.com", label)),
+            Err(Error::TooLong)
+        ));
+        let domain = [label.as_str(); 4].join(".");
+        assert_eq!(domain.len(), 255);
+        assert!(matches!(Matcher::new(&domain), Err(Error::TooLong)));
+        assert!(matches!(
+            Matcher::new(&format!("*.{}", &domain[2..])),
+            Err(Error::TooLong)
+        ));
+    }
+
     #[test]
     fn test_text() {
         let matcher = Matcher::new("example.com").unwrap();
@@ -142,7 +303,8 @@ mod test_matcher {
         let matcher = Matcher::new("*").unwrap();
         assert!(matcher.is_match("localhost"));
         assert!(!matcher.is_match(".localhost"));
-        assert!(!matcher.is_match("localhost."));
+        // Fully qualified
+        assert!(matcher.is_match("localhost."));
         assert!(!matcher.is_match("local.host"));
 
         let matcher = Matcher::new("*.com").unwrap();
@@ -150,13 +312,13 @@ mod test_matcher {
         assert!(matcher.is_match("example.com"));
         assert!(!matcher.is_match("test.test"));
         assert!(!matcher.is_match(".test.com"));
-        assert!(!matcher.is_match("test.com."));
+        assert!(matcher.is_match("test.com."));
         assert!(!matcher.is_match("test.test.com"));
 
         let matcher = Matcher::new("*.*").unwrap();
         assert!(matcher.is_match("test.test"));
         assert!(!matcher.is_match(".test.test"));
-        assert!(!matcher.is_match("test.test."));
+        assert!(matcher.is_match("test.test."));
         assert!(!matcher.is_match("test.test.test"));
 
         let matcher = Matcher::new("*.example.com").unwrap();
@@ -170,6 +332,32 @@ mod test_matcher {
         assert!(matcher.is_match("example.example.com"));
         assert!(!matcher.is_match("test.test.example.test"));
         assert!(!matcher.is_match("test.example.test.test"));
+        assert!(matcher.is_match("a.example.b"));
+        assert!(!matcher.is_match("a.example."));
+
+        // Inside a label
+        let matcher = Matcher::new("ab*c.example.com").unwrap();
+        assert!(matcher.is_match("abxc.example.com"));
+        assert!(matcher.is_match("abcxc.example.com"));
+        assert!(!matcher.is_match("abc.example.com"));
+        assert!(!matcher.is_match("abx.c.example.com"));
+        assert!(!matcher.is_match("xabxc.example.com"));
+    }
+
+    #[test]
+    fn test_wildcard_backtracking() {
+        let label = "a".repeat(5000);
+        let matcher = Matcher::new("*a*a*a*b.com").unwrap();
+        let start = std::time::Instant::now();
+        assert!(!matcher.is_match(&format!("{}.com", label)));
+        assert!(matcher.is_match(&format!("{}b.com", label)));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+        let matcher = Matcher::new("a*b*c").unwrap();
+        assert!(matcher.is_match("axbxc"));
+        assert!(matcher.is_match("abbbcbc"));
+        assert!(!matcher.is_match("abc"));
+        assert!(!matcher.is_match("axbxcx"));
     }
 
     #[test]
@@ -180,5 +368,203 @@ mod test_matcher {
     }
 
     #[test]
-    fn test_to_string() {}
+    fn test_trailing_dot() {
+        for pattern in &[
+            "example.com",
+            "*.example.*",
+            "*.com",
+            "*",
+            "~^example\\.com$",
+            "~\\.example\\.",
+        ] {
+            let matcher = Matcher::new(pattern).unwrap();
+            for domain in &["example.com", "www.example.com", "www.example.org", "com"] {
+                assert_eq!(
+                    matcher.is_match(domain),
+                    matcher.is_match(&format!("{}.", domain)),
+                    "{} {}",
+                    pattern,
+                    domain
+                );
+            }
+        }
+        // Not the apex, with or without the dot
+        let matcher = Matcher::new("*.example.*").unwrap();
+        assert!(!matcher.is_match("example.com."));
+        assert!(matcher.is_match("www.example.com."));
+    }
+
+    #[test]
+    fn test_as_pattern() {
+        let matcher = Matcher::new("example.com").unwrap();
+        assert_eq!(matcher.as_pattern(), Pattern::Text("example.com"));
+
+        let matcher = Matcher::new("*.example.com").unwrap();
+        assert_eq!(matcher.as_pattern(), Pattern::Wildcard("*.example.com"));
+
+        let matcher = Matcher::new("~^\\w+\\.com$").unwrap();
+        assert_eq!(matcher.as_pattern(), Pattern::Regex("^\\w+\\.com$"));
+    }
+
+    #[test]
+    fn test_kind() {
+        let kind = |raw| Matcher::new(raw).unwrap().kind();
+        assert_eq!(kind("example.com"), MatchKind::Text);
+        assert_eq!(kind("*.example.com"), MatchKind::Wildcard);
+        assert_eq!(kind("~^\\w+\\.com$"), MatchKind::Regex);
+        assert_eq!(kind("~^\\w+\\.com$").as_str(), "regex");
+    }
+
+    #[test]
+    fn test_covers() {
+        let covers = |a: &str, b: &str| Matcher::new(a).unwrap().covers(&Matcher::new(b).unwrap());
+
+        assert!(covers("a.com", "a.com"));
+        assert!(!covers("a.com", "b.com"));
+        assert!(covers("*.com", "a.com"));
+        assert!(covers("~^\\w+\\.com$", "a.com"));
+
+        assert!(covers("*.com", "*.com"));
+        assert!(covers("*.*", "*.com"));
+        assert!(covers("ab*.com", "abc*.com"));
+        assert!(!covers("abc*.com", "ab*.com"));
+        assert!(!covers("*.com", "*.*.com"));
+        assert!(!covers("*.com", "*.org"));
+        assert!(!covers("a.com", "*.com"));
+
+        assert!(covers("~\\.example\\.com$", "*.example.com"));
+        assert!(!covers("~^[a-z]\\.example\\.com$", "*.example.com"));
+        assert!(covers("~^a\\.", "~^a\\."));
+        assert!(!covers("~^a", "~^a\\."));
+        assert!(!covers("*.com", "~^a\\.com$"));
+    }
+
+    #[test]
+    fn test_to_string() {
+        for raw in &["example.com", "*.example.*", "~^\\w+\\.com$"] {
+            assert_eq!(Matcher::new(raw).unwrap().to_string(), *raw);
+        }
+    }
+
+    // Property checks over generated domain names, the xorshift generator
+    // runs from fixed seeds so failures are reproducible
+    const CASES: usize = 1000;
+    const LABEL_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789-";
+
+    struct Gen(u64);
+
+    impl Gen {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn range(&mut self, min: usize, max: usize) -> usize {
+            min + (self.next() % (max - min + 1) as u64) as usize
+        }
+
+        fn label(&mut self) -> String {
+            (0..self.range(1, 10))
+                .map(|_| LABEL_CHARS[self.range(0, LABEL_CHARS.len() - 1)] as char)
+                .collect()
+        }
+
+        fn domain(&mut self) -> String {
+            (0..self.range(1, 4))
+                .map(|_| self.label())
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    }
+
+    fn check<F: FnMut(&mut Gen)>(mut f: F) {
+        for seed in 1..=CASES as u64 {
+            f(&mut Gen(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15)));
+        }
+    }
+
+    #[test]
+    fn test_prop_text_matches_itself() {
+        check(|gen| {
+            let domain = gen.domain();
+            let other = gen.domain();
+            let matcher = Matcher::new(&domain).unwrap();
+            assert!(matcher.is_match(&domain), "{}", domain);
+            assert_eq!(
+                matcher.is_match(&other),
+                domain == other,
+                "{} {}",
+                domain,
+                other
+            );
+        });
+    }
+
+    #[test]
+    fn test_prop_wildcard_prefix() {
+        check(|gen| {
+            let prefix = gen.label();
+            let matcher = Matcher::new(&format!("{}.*", prefix)).unwrap();
+
+            let label = gen.label();
+            assert!(matcher.is_match(&format!("{}.{}", prefix, label)));
+
+            let domain = gen.domain();
+            if !domain.starts_with(&format!("{}.", prefix)) {
+                assert!(!matcher.is_match(&domain), "{}.* {}", prefix, domain);
+            }
+        });
+    }
+
+    #[test]
+    fn test_prop_regex_agrees() {
+        check(|gen| {
+            let domain = gen.domain();
+            let pattern = match gen.range(0, 2) {
+                0 => format!("^{}$", regex::escape(&domain)),
+                1 => format!("{}$", regex::escape(&gen.label())),
+                _ => format!("^[a-z]+\\.{}", regex::escape(&gen.label())),
+            };
+            let regex = Regex::new(&pattern).unwrap();
+            let matcher = Matcher::new(&format!("~{}", pattern)).unwrap();
+
+            for text in &[domain, gen.domain()] {
+                assert_eq!(
+                    matcher.is_match(text),
+                    regex.is_match(text),
+                    "{} {}",
+                    pattern,
+                    text
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_prop_display_round_trip() {
+        check(|gen| {
+            let raw = match gen.range(0, 2) {
+                0 => gen.domain(),
+                1 => format!("*.{}", gen.domain()),
+                _ => format!("~^{}$", regex::escape(&gen.domain())),
+            };
+            let matcher = Matcher::new(&raw).unwrap();
+            let parsed = Matcher::new(&matcher.to_string()).unwrap();
+            assert_eq!(matcher.to_string(), raw);
+            assert_eq!(parsed.to_string(), raw);
+
+            let samples = [gen.domain(), format!("{}.{}", gen.label(), gen.domain())];
+            for text in samples.iter().chain(Some(&raw)) {
+                assert_eq!(
+                    matcher.is_match(text),
+                    parsed.is_match(text),
+                    "{} {}",
+                    raw,
+                    text
+                );
+            }
+        });
+    }
 }
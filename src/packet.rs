@@ -0,0 +1,1086 @@
+// From   : EmilHernvall/dnsguide
+// GitHub : https://github.com/EmilHernvall/dnsguide
+
+#![allow(clippy::all)]
+#![allow(dead_code)]
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Of a packet read or written, also the largest EDNS payload size of the
+// answers relayed over UDP
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+pub struct BytePacketBuffer {
+    pub buf: [u8; MAX_PACKET_SIZE],
+    pub pos: usize,
+    // Of the received bytes, reads past them fail
+    len: usize,
+}
+
+// Of a name on the wire, with the length bytes, which also bounds
+// the labels to 127
+const MAX_NAME_LEN: usize = 255;
+// Label pointers followed to read one name
+const MAX_JUMPS: usize = 16;
+
+impl BytePacketBuffer {
+    pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: [0; MAX_PACKET_SIZE],
+            pos: 0,
+            len: MAX_PACKET_SIZE,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::new();
+        let len = data.len().min(MAX_PACKET_SIZE);
+        buffer.buf[..len].copy_from_slice(&data[..len]);
+        buffer.len = len;
+        buffer
+    }
+
+    // After receiving `len` bytes into `buf`
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(MAX_PACKET_SIZE);
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.pos += steps;
+
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        self.pos = pos;
+
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        if self.pos >= self.len {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        let res = self.buf[self.pos];
+        self.pos += 1;
+
+        Ok(res)
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.len {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        Ok(self.buf[pos])
+    }
+
+    pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.len {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        Ok(&self.buf[start..start + len as usize])
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
+
+        Ok(res)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let res = ((self.read()? as u32) << 24)
+            | ((self.read()? as u32) << 16)
+            | ((self.read()? as u32) << 8)
+            | ((self.read()? as u32) << 0);
+
+        Ok(res)
+    }
+
+    fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        let mut pos = self.pos();
+        let mut jumped = false;
+        // Pointers must point before the labels read so far, which
+        // rules out forward pointers and loops
+        let mut limit = pos;
+        let mut name_len = 0;
+        let mut jumps = 0;
+
+        let mut delim = "";
+        loop {
+            let len = self.get(pos)?;
+
+            // A two byte sequence, where the two highest bits of the first byte is
+            // set, represents a offset relative to the start of the buffer. We
+            // handle this by jumping to the offset, setting a flag to indicate
+            // that we shouldn't update the shared buffer position once done.
+            if (len & 0xC0) == 0xC0 {
+                // When a jump is performed, we only modify the shared buffer
+                // position once, and avoid making the change later on.
+                if !jumped {
+                    self.seek(pos + 2)?;
+                }
+
+                let b2 = self.get(pos + 1)? as u16;
+                let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+                if offset as usize >= limit {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Label pointer does not point backward",
+                    ));
+                }
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Too many label pointers",
+                    ));
+                }
+                pos = offset as usize;
+                limit = pos;
+                jumped = true;
+                continue;
+            }
+            // The 0x40 and 0x80 label types are obsolete
+            if len & 0xC0 != 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "Unknown label type"));
+            }
+
+            pos += 1;
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "Name is too long"));
+            }
+
+            // Names are terminated by an empty label of length 0
+            if len == 0 {
+                break;
+            }
+
+            outstr.push_str(delim);
+
+            let str_buffer = self.get_range(pos, len as usize)?;
+            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+
+            delim = ".";
+
+            pos += len as usize;
+        }
+
+        if !jumped {
+            self.seek(pos)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= MAX_PACKET_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        self.buf[self.pos] = val;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn write_u8(&mut self, val: u8) -> Result<()> {
+        self.write(val)?;
+
+        Ok(())
+    }
+
+    fn write_u16(&mut self, val: u16) -> Result<()> {
+        self.write((val >> 8) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    fn write_u32(&mut self, val: u32) -> Result<()> {
+        self.write(((val >> 24) & 0xFF) as u8)?;
+        self.write(((val >> 16) & 0xFF) as u8)?;
+        self.write(((val >> 8) & 0xFF) as u8)?;
+        self.write(((val >> 0) & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    fn write_qname(&mut self, qname: &str) -> Result<()> {
+        let split_str = qname.split('.').collect::<Vec<&str>>();
+
+        for label in split_str {
+            let len = label.len();
+            if len > 0x34 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Single label exceeds 63 characters of length",
+                ));
+            }
+
+            self.write_u8(len as u8)?;
+            for b in label.as_bytes() {
+                self.write_u8(*b)?;
+            }
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.len {
+            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        }
+        self.buf[pos] = val;
+
+        Ok(())
+    }
+
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    pub fn get_u32(&mut self, pos: usize) -> Result<u32> {
+        let res = ((self.get(pos)? as u32) << 24)
+            | ((self.get(pos + 1)? as u32) << 16)
+            | ((self.get(pos + 2)? as u32) << 8)
+            | ((self.get(pos + 3)? as u32) << 0);
+
+        Ok(res)
+    }
+
+    pub fn set_u32(&mut self, pos: usize, val: u32) -> Result<()> {
+        self.set_u16(pos, (val >> 16) as u16)?;
+        self.set_u16(pos + 2, (val & 0xFFFF) as u16)?;
+
+        Ok(())
+    }
+
+    fn skip_qname(&mut self) -> Result<()> {
+        loop {
+            let len = self.read()?;
+
+            // A pointer ends the name
+            if (len & 0xC0) == 0xC0 {
+                self.step(1)?;
+                return Ok(());
+            }
+            if len == 0 {
+                return Ok(());
+            }
+
+            self.step(len as usize)?;
+        }
+    }
+
+    // Position after the question section
+    pub fn questions_end(&mut self) -> Result<usize> {
+        self.seek(4)?;
+        let questions = self.read_u16()?;
+        self.seek(12)?;
+
+        for _ in 0..questions {
+            self.skip_qname()?;
+            self.step(4)?;
+        }
+
+        Ok(self.pos())
+    }
+
+    // Locate every resource record of the packet without decoding it,
+    // so the raw bytes can be patched in place
+    pub fn records(&mut self) -> Result<Vec<RecordPos>> {
+        self.seek(6)?;
+        let total =
+            self.read_u16()? as usize + self.read_u16()? as usize + self.read_u16()? as usize;
+        self.questions_end()?;
+
+        let mut records = Vec::with_capacity(total);
+        for _ in 0..total {
+            self.skip_qname()?;
+            let qtype = QueryType::from_num(self.read_u16()?);
+            self.step(2)?;
+            let ttl = self.pos();
+            self.step(4)?;
+            let data_len = self.read_u16()? as usize;
+            let data = self.pos();
+            self.step(data_len)?;
+
+            records.push(RecordPos {
+                qtype,
+                ttl,
+                data,
+                data_len,
+            });
+        }
+
+        Ok(records)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RecordPos {
+    pub qtype: QueryType,
+    pub ttl: usize,
+    pub data: usize,
+    pub data_len: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResultCode {
+    NOERROR = 0,
+    FORMERR = 1,
+    SERVFAIL = 2,
+    NXDOMAIN = 3,
+    NOTIMP = 4,
+    REFUSED = 5,
+}
+
+impl ResultCode {
+    pub fn from_num(num: u8) -> ResultCode {
+        match num {
+            1 => ResultCode::FORMERR,
+            2 => ResultCode::SERVFAIL,
+            3 => ResultCode::NXDOMAIN,
+            4 => ResultCode::NOTIMP,
+            5 => ResultCode::REFUSED,
+            0 | _ => ResultCode::NOERROR,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DnsHeader {
+    pub id: u16, // 16 bits
+
+    pub recursion_desired: bool,    // 1 bit
+    pub truncated_message: bool,    // 1 bit
+    pub authoritative_answer: bool, // 1 bit
+    pub opcode: u8,                 // 4 bits
+    pub response: bool,             // 1 bit
+
+    pub rescode: ResultCode,       // 4 bits
+    pub checking_disabled: bool,   // 1 bit
+    pub authed_data: bool,         // 1 bit
+    pub z: bool,                   // 1 bit
+    pub recursion_available: bool, // 1 bit
+
+    pub questions: u16,             // 16 bits
+    pub answers: u16,               // 16 bits
+    pub authoritative_entries: u16, // 16 bits
+    pub resource_entries: u16,      // 16 bits
+}
+
+impl DnsHeader {
+    pub fn new() -> DnsHeader {
+        DnsHeader {
+            id: 0,
+
+            recursion_desired: false,
+            truncated_message: false,
+            authoritative_answer: false,
+            opcode: 0,
+            response: false,
+
+            rescode: ResultCode::NOERROR,
+            checking_disabled: false,
+            authed_data: false,
+            z: false,
+            recursion_available: false,
+
+            questions: 0,
+            answers: 0,
+            authoritative_entries: 0,
+            resource_entries: 0,
+        }
+    }
+
+    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.id = buffer.read_u16()?;
+
+        let flags = buffer.read_u16()?;
+        let a = (flags >> 8) as u8;
+        let b = (flags & 0xFF) as u8;
+        self.recursion_desired = (a & (1 << 0)) > 0;
+        self.truncated_message = (a & (1 << 1)) > 0;
+        self.authoritative_answer = (a & (1 << 2)) > 0;
+        self.opcode = (a >> 3) & 0x0F;
+        self.response = (a & (1 << 7)) > 0;
+
+        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.checking_disabled = (b & (1 << 4)) > 0;
+        self.authed_data = (b & (1 << 5)) > 0;
+        self.z = (b & (1 << 6)) > 0;
+        self.recursion_available = (b & (1 << 7)) > 0;
+
+        self.questions = buffer.read_u16()?;
+        self.answers = buffer.read_u16()?;
+        self.authoritative_entries = buffer.read_u16()?;
+        self.resource_entries = buffer.read_u16()?;
+
+        // Return the constant header size
+        Ok(())
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16(self.id)?;
+
+        (buffer.write_u8(
+            (self.recursion_desired as u8)
+                | ((self.truncated_message as u8) << 1)
+                | ((self.authoritative_answer as u8) << 2)
+                | (self.opcode << 3)
+                | ((self.response as u8) << 7) as u8,
+        ))?;
+
+        (buffer.write_u8(
+            (self.rescode.clone() as u8)
+                | ((self.checking_disabled as u8) << 4)
+                | ((self.authed_data as u8) << 5)
+                | ((self.z as u8) << 6)
+                | ((self.recursion_available as u8) << 7),
+        ))?;
+
+        buffer.write_u16(self.questions)?;
+        buffer.write_u16(self.answers)?;
+        buffer.write_u16(self.authoritative_entries)?;
+        buffer.write_u16(self.resource_entries)?;
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+pub enum QueryType {
+    UNKNOWN(u16),
+    A,     // 1
+    NS,    // 2
+    CNAME, // 5
+    MX,    // 15
+    AAAA,  // 28
+    OPT,   // 41
+}
+
+impl QueryType {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            QueryType::UNKNOWN(x) => x,
+            QueryType::A => 1,
+            QueryType::NS => 2,
+            QueryType::CNAME => 5,
+            QueryType::MX => 15,
+            QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+        }
+    }
+
+    pub fn from_num(num: u16) -> QueryType {
+        match num {
+            1 => QueryType::A,
+            2 => QueryType::NS,
+            5 => QueryType::CNAME,
+            15 => QueryType::MX,
+            28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            _ => QueryType::UNKNOWN(num),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: QueryType,
+    // 1 for IN
+    pub qclass: u16,
+}
+
+impl DnsQuestion {
+    pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name: name,
+            qtype: qtype,
+            qclass: 1,
+        }
+    }
+
+    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.read_qname(&mut self.name)?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
+        self.qclass = buffer.read_u16()?;
+
+        Ok(())
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_qname(&self.name)?;
+
+        let typenum = self.qtype.to_num();
+        buffer.write_u16(typenum)?;
+        buffer.write_u16(self.qclass)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[allow(dead_code)]
+pub enum DnsRecord {
+    UNKNOWN {
+        domain: String,
+        qtype: u16,
+        data_len: u16,
+        ttl: u32,
+    }, // 0
+    A {
+        domain: String,
+        addr: Ipv4Addr,
+        ttl: u32,
+    }, // 1
+    NS {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 2
+    CNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 5
+    MX {
+        domain: String,
+        priority: u16,
+        host: String,
+        ttl: u32,
+    }, // 15
+    AAAA {
+        domain: String,
+        addr: Ipv6Addr,
+        ttl: u32,
+    }, // 28
+}
+
+impl DnsRecord {
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+
+        let qtype_num = buffer.read_u16()?;
+        let qtype = QueryType::from_num(qtype_num);
+        let _ = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+
+        match qtype {
+            QueryType::A => {
+                let raw_addr = buffer.read_u32()?;
+                let addr = Ipv4Addr::new(
+                    ((raw_addr >> 24) & 0xFF) as u8,
+                    ((raw_addr >> 16) & 0xFF) as u8,
+                    ((raw_addr >> 8) & 0xFF) as u8,
+                    ((raw_addr >> 0) & 0xFF) as u8,
+                );
+
+                Ok(DnsRecord::A {
+                    domain: domain,
+                    addr: addr,
+                    ttl: ttl,
+                })
+            }
+            QueryType::AAAA => {
+                let raw_addr1 = buffer.read_u32()?;
+                let raw_addr2 = buffer.read_u32()?;
+                let raw_addr3 = buffer.read_u32()?;
+                let raw_addr4 = buffer.read_u32()?;
+                let addr = Ipv6Addr::new(
+                    ((raw_addr1 >> 16) & 0xFFFF) as u16,
+                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    ((raw_addr2 >> 16) & 0xFFFF) as u16,
+                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    ((raw_addr3 >> 16) & 0xFFFF) as u16,
+                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    ((raw_addr4 >> 16) & 0xFFFF) as u16,
+                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                );
+
+                Ok(DnsRecord::AAAA {
+                    domain: domain,
+                    addr: addr,
+                    ttl: ttl,
+                })
+            }
+            QueryType::NS => {
+                let mut ns = String::new();
+                buffer.read_qname(&mut ns)?;
+
+                Ok(DnsRecord::NS {
+                    domain: domain,
+                    host: ns,
+                    ttl: ttl,
+                })
+            }
+            QueryType::CNAME => {
+                let mut cname = String::new();
+                buffer.read_qname(&mut cname)?;
+
+                Ok(DnsRecord::CNAME {
+                    domain: domain,
+                    host: cname,
+                    ttl: ttl,
+                })
+            }
+            QueryType::MX => {
+                let priority = buffer.read_u16()?;
+                let mut mx = String::new();
+                buffer.read_qname(&mut mx)?;
+
+                Ok(DnsRecord::MX {
+                    domain: domain,
+                    priority: priority,
+                    host: mx,
+                    ttl: ttl,
+                })
+            }
+            QueryType::UNKNOWN(_) | QueryType::OPT => {
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::UNKNOWN {
+                    domain: domain,
+                    qtype: qtype_num,
+                    data_len: data_len,
+                    ttl: ttl,
+                })
+            }
+        }
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        let start_pos = buffer.pos();
+
+        match *self {
+            DnsRecord::A {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::A.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4)?;
+
+                let octets = addr.octets();
+                buffer.write_u8(octets[0])?;
+                buffer.write_u8(octets[1])?;
+                buffer.write_u8(octets[2])?;
+                buffer.write_u8(octets[3])?;
+            }
+            DnsRecord::NS {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CNAME {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CNAME.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::MX {
+                ref domain,
+                priority,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::MX.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::AAAA {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
+
+                for octet in &addr.segments() {
+                    buffer.write_u16(*octet)?;
+                }
+            }
+            DnsRecord::UNKNOWN { .. } => {
+                crate::warn!("Skipping record: {:?}", self);
+            }
+        }
+
+        Ok(buffer.pos() - start_pos)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DnsPacket {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub resources: Vec<DnsRecord>,
+}
+
+impl DnsPacket {
+    pub fn new() -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader::new(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            resources: Vec::new(),
+        }
+    }
+
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+        let mut result = DnsPacket::new();
+        result.header.read(buffer)?;
+
+        for _ in 0..result.header.questions {
+            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            question.read(buffer)?;
+            result.questions.push(question);
+        }
+
+        for _ in 0..result.header.answers {
+            let rec = DnsRecord::read(buffer)?;
+            result.answers.push(rec);
+        }
+        for _ in 0..result.header.authoritative_entries {
+            let rec = DnsRecord::read(buffer)?;
+            result.authorities.push(rec);
+        }
+        for _ in 0..result.header.resource_entries {
+            let rec = DnsRecord::read(buffer)?;
+            result.resources.push(rec);
+        }
+
+        Ok(result)
+    }
+
+    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+
+        self.header.write(buffer)?;
+
+        for question in &self.questions {
+            question.write(buffer)?;
+        }
+        for rec in &self.answers {
+            rec.write(buffer)?;
+        }
+        for rec in &self.authorities {
+            rec.write(buffer)?;
+        }
+        for rec in &self.resources {
+            rec.write(buffer)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_random_a(&self) -> Option<String> {
+        if !self.answers.is_empty() {
+            let a_record = &self.answers[0];
+            if let DnsRecord::A { ref addr, .. } = *a_record {
+                return Some(addr.to_string());
+            }
+        }
+
+        None
+    }
+
+    pub fn get_resolved_ns(&self, qname: &str) -> Option<String> {
+        let mut new_authorities = Vec::new();
+        for auth in &self.authorities {
+            if let DnsRecord::NS {
+                ref domain,
+                ref host,
+                ..
+            } = *auth
+            {
+                if !qname.ends_with(domain) {
+                    continue;
+                }
+
+                for rsrc in &self.resources {
+                    if let DnsRecord::A {
+                        ref domain,
+                        ref addr,
+                        ttl,
+                    } = *rsrc
+                    {
+                        if domain != host {
+                            continue;
+                        }
+
+                        let rec = DnsRecord::A {
+                            domain: host.clone(),
+                            addr: *addr,
+                            ttl: ttl,
+                        };
+
+                        new_authorities.push(rec);
+                    }
+                }
+            }
+        }
+
+        if !new_authorities.is_empty() {
+            if let DnsRecord::A { addr, .. } = new_authorities[0] {
+                return Some(addr.to_string());
+            }
+        }
+
+        None
+    }
+
+    pub fn get_unresolved_ns(&self, qname: &str) -> Option<String> {
+        let mut new_authorities = Vec::new();
+        for auth in &self.authorities {
+            if let DnsRecord::NS {
+                ref domain,
+                ref host,
+                ..
+            } = *auth
+            {
+                if !qname.ends_with(domain) {
+                    continue;
+                }
+
+                new_authorities.push(host);
+            }
+        }
+
+        if !new_authorities.is_empty() {
+            return Some(new_authorities[0].clone());
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_packet {
+    use super::*;
+
+    // Header of a query with one question and `answers` records
+    fn header(answers: u8) -> Vec<u8> {
+        vec![0, 1, 0x01, 0, 0, 1, 0, answers, 0, 0, 0, 0]
+    }
+
+    fn parse(data: &[u8]) -> Result<DnsPacket> {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data))
+    }
+
+    fn message(data: &[u8]) -> String {
+        parse(data).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_compressed_name() {
+        let mut data = header(1);
+        data.extend_from_slice(b"\x01a\x03com\x00\x00\x01\x00\x01");
+        // Pointer to the question, then a label and a pointer to "com"
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 1, 1, 1]);
+        data.extend_from_slice(&[0x01, b'b', 0xC0, 0x0E, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        data.extend_from_slice(&[2, 2, 2, 2]);
+        data[7] = 2;
+
+        let packet = parse(&data).unwrap();
+        assert_eq!(packet.questions[0].name, "a.com");
+        assert_eq!(packet.questions[0].qclass, 1);
+        let names = packet
+            .answers
+            .iter()
+            .map(|record| match record {
+                DnsRecord::A { domain, .. } => domain.as_str(),
+                _ => "",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a.com", "b.com"]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(message(&[0, 1, 0x01, 0]), "End of buffer");
+
+        // Truncated names, in the label and before the end
+        for name in &[&b"\x03ab"[..], b"\x03abc", b"\x03abc\x03com"] {
+            let mut data = header(0);
+            data.extend_from_slice(name);
+            assert_eq!(message(&data), "End of buffer");
+        }
+        // Without the type and class
+        let mut data = header(0);
+        data.extend_from_slice(b"\x01a\x00\x00");
+        assert_eq!(message(&data), "End of buffer");
+
+        // A pointer to itself, to the end of the packet and to a later name
+        let mut data = header(0);
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+        assert_eq!(message(&data), "Label pointer does not point backward");
+        let mut data = header(0);
+        data.extend_from_slice(&[0xC0, 0x30, 0, 1, 0, 1]);
+        assert_eq!(message(&data), "Label pointer does not point backward");
+        let mut data = header(1);
+        data.extend_from_slice(&[0xC0, 0x12, 0, 1, 0, 1]);
+        data.extend_from_slice(&[0x01, b'a', 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 1, 1, 1]);
+        assert_eq!(message(&data), "Label pointer does not point backward");
+
+        // Loops: two pointers to each other, and a pointer back to
+        // the start of its own name
+        let mut data = header(1);
+        data.extend_from_slice(&[0xC0, 0x0E, 0xC0, 0x0C, 0, 1, 0, 1]);
+        assert_eq!(message(&data), "Label pointer does not point backward");
+        let mut data = header(1);
+        data.extend_from_slice(b"\x01a\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0x01, b'b', 0xC0, 0x13, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        data.extend_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(message(&data), "Label pointer does not point backward");
+
+        // Obsolete label types
+        let mut data = header(0);
+        data.extend_from_slice(&[0x41, b'a', 0, 0, 1, 0, 1]);
+        assert_eq!(message(&data), "Unknown label type");
+
+        // Over 255 bytes
+        let mut data = header(0);
+        for _ in 0..5 {
+            data.push(63);
+            data.extend_from_slice(&[b'a'; 63]);
+        }
+        data.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert_eq!(message(&data), "Name is too long");
+
+        // Record data shorter than its address, or than its length
+        let mut data = header(1);
+        data.extend_from_slice(b"\x01a\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 1]);
+        assert_eq!(message(&data), "End of buffer");
+        let mut data = header(1);
+        data.extend_from_slice(b"\x01a\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 99, 0, 1, 0, 0, 0, 60, 0, 9, 1]);
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 99, 0, 1, 0, 0, 0, 60, 0, 0]);
+        data[7] = 2;
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_name_limits() {
+        // 127 labels fill the 255 bytes
+        for (labels, ok) in &[(127, true), (128, false)] {
+            let mut data = header(0);
+            for _ in 0..*labels {
+                data.extend_from_slice(b"\x01a");
+            }
+            data.extend_from_slice(&[0, 0, 1, 0, 1]);
+            match parse(&data) {
+                Ok(packet) => assert!(*ok && packet.questions[0].name.len() == 253),
+                Err(err) => assert!(!*ok && err.to_string() == "Name is too long"),
+            }
+        }
+
+        // Each record name is a label and a pointer to the one before,
+        // the last of `records` takes as many jumps
+        for (records, ok) in &[(16, true), (17, false)] {
+            let mut data = header(*records);
+            data.extend_from_slice(b"\x01a\x00\x00\x01\x00\x01");
+            let mut previous = 12_u16;
+            for _ in 0..*records {
+                let start = data.len() as u16;
+                data.extend_from_slice(&[1, b'x']);
+                data.extend_from_slice(&(0xC000 | previous).to_be_bytes());
+                data.extend_from_slice(&[0, 99, 0, 1, 0, 0, 0, 60, 0, 0]);
+                previous = start;
+            }
+            match parse(&data) {
+                Ok(packet) => assert!(*ok && packet.answers.len() == 16),
+                Err(err) => assert!(!*ok && err.to_string() == "Too many label pointers"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_garbage() {
+        // Every prefix of a valid packet, then pseudo random bytes
+        let mut data = header(1);
+        data.extend_from_slice(b"\x01a\x03com\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 1, 1, 1]);
+        for len in 0..data.len() {
+            assert!(parse(&data[..len]).is_err(), "{}", len);
+        }
+        assert!(parse(&data).is_ok());
+
+        let mut state = 0x2545_F491_4F6C_DD1D_u64;
+        for _ in 0..10_000 {
+            let mut data = [0; 64];
+            for byte in data.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *byte = state as u8;
+            }
+            // A question and an answer, some with an additional record
+            let additional = data[11] & 1;
+            data[4..12].copy_from_slice(&[0, 1, 0, 1, 0, 0, 0, additional]);
+            let mut buffer = BytePacketBuffer::from_bytes(&data);
+            let _ = DnsPacket::from_buffer(&mut buffer);
+            let _ = BytePacketBuffer::from_bytes(&data).records();
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+// The user and group the server switches to after binding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' (uid {}, gid {})", self.name, self.uid, self.gid)
+    }
+}
+
+// Whether the permission bits let the identity read or write,
+// its only group is `gid` once switched
+fn permits(mode: u32, owner: u32, group: u32, id: &Identity, bits: u32) -> bool {
+    if id.uid == 0 {
+        return true;
+    }
+    let shift = if id.uid == owner {
+        6
+    } else if id.gid == group {
+        3
+    } else {
+        0
+    };
+    (mode >> shift) & bits == bits
+}
+
+const READ: u32 = 4;
+const WRITE: u32 = 2;
+const SEARCH: u32 = 1;
+
+// Files read and written after switching, checked before so a mistake shows
+// up as a clear error rather than a failed reload or a silent query log
+pub fn check_access(id: &Identity, reads: &[PathBuf], writes: &[PathBuf]) -> Result<()> {
+    for path in reads {
+        check_path(id, path, READ)?;
+    }
+    for path in writes {
+        if path.exists() {
+            check_path(id, path, WRITE)?;
+        } else if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            // Created at startup
+            check_path(id, dir, WRITE | SEARCH)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_path(id: &Identity, path: &Path, bits: u32) -> Result<()> {
+    let denied = |path: &Path, what: &str| {
+        Error::new(
+            ErrorKind::PermissionDenied,
+            format!("User {} cannot {} {:?}", id, what, path),
+        )
+    };
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    for dir in path.ancestors().skip(1) {
+        if !imp::permits_path(id, dir, SEARCH)? {
+            return Err(denied(dir, "enter the directory"));
+        }
+    }
+    if !imp::permits_path(id, &path, bits)? {
+        let what = if bits & WRITE != 0 { "write" } else { "read" };
+        return Err(denied(&path, what));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::{ffi::CString, os::unix::fs::MetadataExt};
+
+    pub fn permits_path(id: &Identity, path: &Path, bits: u32) -> Result<bool> {
+        let meta = std::fs::metadata(path)?;
+        Ok(permits(meta.mode(), meta.uid(), meta.gid(), id, bits))
+    }
+
+    fn c_string(name: &str) -> Result<CString> {
+        CString::new(name).map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid name"))
+    }
+
+    // A name or a numeric id, the user's group unless `group` is given
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        let (mut name, mut uid, mut gid) =
+            unsafe { (String::new(), libc::getuid() as u32, libc::getgid() as u32) };
+        if let Some(user) = user {
+            let (id, group) = lookup_user(user)?;
+            name = user.to_string();
+            uid = id;
+            gid = group;
+        }
+        if let Some(group) = group {
+            gid = lookup_group(group)?;
+        }
+        if name.is_empty() {
+            name = uid.to_string();
+        }
+        Ok(Some(Identity { name, uid, gid }))
+    }
+
+    fn lookup_user(user: &str) -> Result<(u32, u32)> {
+        let c_user = c_string(user)?;
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                c_user.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if ret == 0 && !found.is_null() {
+            return Ok((pwd.pw_uid as u32, pwd.pw_gid as u32));
+        }
+        match user.parse::<u32>() {
+            Ok(uid) => Ok((uid, uid)),
+            Err(_) => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No user '{}'", user),
+            )),
+        }
+    }
+
+    fn lookup_group(group: &str) -> Result<u32> {
+        let c_group = c_string(group)?;
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                c_group.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if ret == 0 && !found.is_null() {
+            return Ok(grp.gr_gid as u32);
+        }
+        group
+            .parse::<u32>()
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("No group '{}'", group)))
+    }
+
+    // setgroups, setgid then setuid, all of them or an error. Switching
+    // away from root also drops every capability
+    pub fn switch(id: &Identity) -> Result<()> {
+        let (uid, gid) = unsafe { (libc::geteuid() as u32, libc::getegid() as u32) };
+        if (uid, gid) == (id.uid, id.gid) {
+            return Ok(());
+        }
+        if uid != 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Switching to user {} needs to start as root", id),
+            ));
+        }
+
+        let gid = id.gid as libc::gid_t;
+        unsafe {
+            if libc::setgroups(1, &gid) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(id.uid as libc::uid_t) != 0
+            {
+                return Err(Error::last_os_error());
+            }
+            let switched = libc::getuid() as u32 == id.uid
+                && libc::geteuid() as u32 == id.uid
+                && libc::getgid() == gid
+                && libc::getegid() == gid;
+            if !switched || (id.uid != 0 && libc::setuid(0) == 0) {
+                return Err(Error::other("Root privileges could be regained"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn permits_path(_id: &Identity, _path: &Path, _bits: u32) -> Result<bool> {
+        Ok(true)
+    }
+
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>> {
+        match (user, group) {
+            (None, None) => Ok(None),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Switching the user is only supported on unix",
+            )),
+        }
+    }
+
+    pub fn switch(_id: &Identity) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub use imp::{resolve, switch};
+
+#[cfg(test)]
+mod test_privilege {
+    use super::*;
+
+    fn id(uid: u32, gid: u32) -> Identity {
+        Identity {
+            name: "test".to_string(),
+            uid,
+            gid,
+        }
+    }
+
+    #[test]
+    fn test_permits() {
+        let nobody = id(65534, 65534);
+        assert!(permits(0o644, 0, 0, &nobody, READ));
+        assert!(!permits(0o644, 0, 0, &nobody, WRITE));
+        assert!(!permits(0o640, 0, 0, &nobody, READ));
+        assert!(permits(0o640, 0, 65534, &nobody, READ));
+        assert!(permits(0o600, 65534, 0, &nobody, READ | WRITE));
+        // The owner bits apply to the owner even when the others allow more
+        assert!(!permits(0o066, 65534, 0, &nobody, READ));
+        assert!(permits(0o000, 5, 5, &id(0, 0), READ | WRITE));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(None, None).unwrap(), None);
+        let root = resolve(Some("0"), Some("0")).unwrap().unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(
+            resolve(Some("no-such-user-updns"), None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            resolve(None, Some("no-such-group-updns"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_access() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("updns-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config");
+        std::fs::write(&config, "").unwrap();
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let other = id(54321, 54321);
+        let err = check_access(&other, std::slice::from_ref(&config), &[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("cannot read"));
+
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o644)).unwrap();
+        check_access(&other, std::slice::from_ref(&config), &[]).unwrap();
+        // A log file created in a directory the user cannot write
+        let err = check_access(&other, &[], &[dir.join("queries.log")]).unwrap_err();
+        assert!(err.to_string().contains("cannot write"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
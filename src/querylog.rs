@@ -0,0 +1,577 @@
+use crate::{config::LogFormat, QueryType};
+use crate::{error, warn};
+use futures_util::future::FutureExt;
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Result, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task,
+};
+
+// Lines waiting for the writer, newer ones are dropped when it's full
+const QUEUE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // Answered by a host record
+    Hosts,
+    Forwarded,
+    // Refused by the ACL, an answer removed by rebind protection or
+    // rewritten by a response policy zone
+    Blocked,
+    NxDomain,
+    Timeout,
+    Failed,
+    // The upstreams failed, answered with an expired answer
+    Stale,
+}
+
+impl Outcome {
+    // In the order of the variants
+    pub const ALL: [Outcome; 7] = [
+        Outcome::Hosts,
+        Outcome::Forwarded,
+        Outcome::Blocked,
+        Outcome::NxDomain,
+        Outcome::Timeout,
+        Outcome::Failed,
+        Outcome::Stale,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Outcome::Hosts => "hosts",
+            Outcome::Forwarded => "forwarded",
+            Outcome::Blocked => "blocked",
+            Outcome::NxDomain => "nxdomain",
+            Outcome::Timeout => "timeout",
+            Outcome::Failed => "failed",
+            Outcome::Stale => "stale",
+        }
+    }
+}
+
+// One line of the query log
+#[derive(Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: IpAddr,
+    pub qname: String,
+    pub qtype: QueryType,
+    pub outcome: Outcome,
+    // The answered address of a host record, else the upstream used or
+    // the rule answering
+    pub result: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl Entry {
+    // `time client_ip domain query_type result_ip_or_upstream latency_ms`,
+    // the outcome is only a field of the JSON line
+    pub fn format(&self, format: LogFormat) -> String {
+        let qtype = format!("{:?}", self.qtype);
+        let latency = self.elapsed.as_secs_f64() * 1000.0;
+        match format {
+            LogFormat::Text => format!(
+                "{} {} {} {} {} {:.3}",
+                rfc3339(self.time),
+                self.client,
+                self.qname,
+                qtype,
+                self.result.as_deref().unwrap_or("-"),
+                latency
+            ),
+            LogFormat::Compact => format!(
+                "{} {} {} {} {:.3}",
+                self.client,
+                self.qname,
+                qtype,
+                self.result.as_deref().unwrap_or("-"),
+                latency
+            ),
+            LogFormat::Json => format!(
+                r#"{{"time":"{}","client":"{}","qname":{},"qtype":"{}","outcome":"{}","result":{},"latency_ms":{:.3}}}"#,
+                rfc3339(self.time),
+                self.client,
+                json_string(&self.qname),
+                qtype,
+                self.outcome.as_str(),
+                match &self.result {
+                    Some(result) => json_string(result),
+                    None => "null".to_string(),
+                },
+                latency
+            ),
+        }
+    }
+}
+
+// When the log file is renamed aside for a new one, never without any
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rotate {
+    // At the first line of a new day (UTC)
+    pub daily: bool,
+    // Before a line would make the file larger, in bytes
+    pub max_size: Option<u64>,
+}
+
+impl Rotate {
+    // `daily`, a size such as `100M`, or both
+    pub fn parse(text: &str) -> Option<Rotate> {
+        let mut rotate = Rotate::default();
+        for word in text.split_ascii_whitespace() {
+            match word {
+                "daily" if !rotate.daily => rotate.daily = true,
+                _ if rotate.max_size.is_none() => rotate.max_size = Some(parse_size(word)?),
+                _ => return None,
+            }
+        }
+        match rotate == Rotate::default() {
+            true => None,
+            false => Some(rotate),
+        }
+    }
+}
+
+impl fmt::Display for Rotate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let daily = self.daily.then_some("daily");
+        let size = self.max_size.map(|size| size.to_string());
+        let words = daily.into_iter().chain(size.as_deref());
+        write!(f, "{}", words.collect::<Vec<_>>().join(" "))
+    }
+}
+
+// Bytes with an optional K, M or G suffix (powers of 1024)
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, unit) = match text.char_indices().find(|(_, ch)| !ch.is_ascii_digit()) {
+        Some((i, _)) => text.split_at(i),
+        None => (text, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|n| *n > 0)
+}
+
+enum Output {
+    Stdout(BufWriter<io::Stdout>),
+    File(LogFile),
+}
+
+impl Output {
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::File(file) => file.file.flush(),
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    // Days since the epoch of the first line
+    day: u64,
+}
+
+impl LogFile {
+    // The day of a file kept from before is the one it was last written
+    fn open(path: PathBuf, now: SystemTime) -> Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let day = match metadata.len() {
+            0 => days(now),
+            _ => days(metadata.modified().unwrap_or(now)),
+        };
+        Ok(LogFile {
+            path,
+            file: BufWriter::new(file),
+            size: metadata.len(),
+            day,
+        })
+    }
+
+    fn write(&mut self, line: &str, rotate: Rotate, now: SystemTime) -> Result<()> {
+        let len = line.len() as u64;
+        let full = matches!(rotate.max_size, Some(max) if self.size > 0 && self.size + len > max);
+        if full || (rotate.daily && self.size > 0 && days(now) != self.day) {
+            self.file.flush()?;
+            let target = rotated_path(&self.path, self.day);
+            fs::rename(&self.path, target)?;
+            *self = LogFile::open(self.path.clone(), now)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    // The file was moved away by logrotate
+    fn reopen(&mut self) -> Result<()> {
+        self.file.flush()?;
+        *self = LogFile::open(self.path.clone(), SystemTime::now())?;
+        Ok(())
+    }
+}
+
+fn days(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+// `queries.log.2021-03-14`, then `.1`, `.2` for the next ones of the day
+fn rotated_path(path: &Path, day: u64) -> PathBuf {
+    let date = rfc3339(UNIX_EPOCH + Duration::from_secs(day * 86400));
+    let name = format!("{}.{}", path.display(), &date[..10]);
+    let mut target = PathBuf::from(&name);
+    let mut n = 1;
+    while target.exists() {
+        target = PathBuf::from(format!("{}.{}", name, n));
+        n += 1;
+    }
+    target
+}
+
+enum Command {
+    Line(String, SystemTime),
+    Reopen,
+    Flush(oneshot::Sender<()>),
+}
+
+// The lines are written by a single writer off the query tasks, a query
+// never waits for the disk
+pub struct QueryLog {
+    format: LogFormat,
+    sender: mpsc::Sender<Command>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    // Write to stdout without a path, which is never rotated
+    pub fn new(format: LogFormat, path: Option<PathBuf>, rotate: Rotate) -> Result<QueryLog> {
+        QueryLog::with_queue(format, path, rotate, QUEUE)
+    }
+
+    fn with_queue(
+        format: LogFormat,
+        path: Option<PathBuf>,
+        rotate: Rotate,
+        queue: usize,
+    ) -> Result<QueryLog> {
+        let output = match path {
+            Some(path) => Output::File(LogFile::open(path, SystemTime::now())?),
+            None => Output::Stdout(BufWriter::new(io::stdout())),
+        };
+        let (sender, receiver) = mpsc::channel(queue);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = Writer {
+            output,
+            rotate,
+            dropped: dropped.clone(),
+            reported: 0,
+        };
+        task::spawn_blocking(move || writer.run(receiver));
+        Ok(QueryLog {
+            format,
+            sender,
+            dropped,
+        })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        self.write_at(entry, SystemTime::now())
+    }
+
+    // Dropped and counted when the writer is behind
+    fn write_at(&self, entry: &Entry, now: SystemTime) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        if self.sender.try_send(Command::Line(line, now)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // The file was moved away by logrotate, after the lines sent before
+    pub async fn reopen(&self) {
+        let _ = self.sender.send(Command::Reopen).await;
+    }
+
+    // Wait for the lines sent before to be written
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Command::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    #[cfg(test)]
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct Writer {
+    output: Output,
+    rotate: Rotate,
+    dropped: Arc<AtomicU64>,
+    reported: u64,
+}
+
+impl Writer {
+    // Flushed each time the queue is empty, lines are written in batches
+    // under load. Ends once the `QueryLog` is gone
+    fn run(mut self, mut receiver: mpsc::Receiver<Command>) {
+        while let Some(command) = receiver.blocking_recv() {
+            self.handle(command);
+            while let Some(Some(command)) = receiver.recv().now_or_never() {
+                self.handle(command);
+            }
+            if let Err(err) = self.output.flush() {
+                error!("Failed to write query log {:?}", err);
+            }
+            let dropped = self.dropped.load(Ordering::Relaxed);
+            if dropped > self.reported {
+                warn!("Dropped {} query log lines", dropped - self.reported);
+                self.reported = dropped;
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Line(line, now) => {
+                let written = match &mut self.output {
+                    Output::Stdout(out) => out.write_all(line.as_bytes()),
+                    Output::File(file) => file.write(&line, self.rotate, now),
+                };
+                if let Err(err) = written {
+                    error!("Failed to write query log {:?}", err);
+                }
+            }
+            Command::Reopen => {
+                if let Output::File(file) = &mut self.output {
+                    if let Err(err) = file.reopen() {
+                        error!("Failed to reopen query log\n{:?}", err);
+                    }
+                }
+            }
+            Command::Flush(done) => {
+                if let Err(err) = self.output.flush() {
+                    error!("Failed to write query log {:?}", err);
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", ch as u32);
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// UTC with microseconds: 2021-03-14T01:59:26.535897Z
+pub fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from the days since 1970-01-01, Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod test_querylog {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            time: UNIX_EPOCH + Duration::from_micros(1_615_687_166_535_897),
+            client: "192.168.1.2".parse().unwrap(),
+            qname: "example.com".to_string(),
+            qtype: QueryType::A,
+            outcome: Outcome::Hosts,
+            result: Some("1.2.3.4".to_string()),
+            elapsed: Duration::from_micros(1042),
+        }
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000000Z"
+        );
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_micros(1_615_687_166_535_897)),
+            "2021-03-14T01:59:26.535897Z"
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let mut entry = entry();
+        assert_eq!(
+            entry.format(LogFormat::Text),
+            "2021-03-14T01:59:26.535897Z 192.168.1.2 example.com A 1.2.3.4 1.042"
+        );
+        assert_eq!(
+            entry.format(LogFormat::Compact),
+            "192.168.1.2 example.com A 1.2.3.4 1.042"
+        );
+        assert_eq!(
+            entry.format(LogFormat::Json),
+            r#"{"time":"2021-03-14T01:59:26.535897Z","client":"192.168.1.2","qname":"example.com","qtype":"A","outcome":"hosts","result":"1.2.3.4","latency_ms":1.042}"#
+        );
+
+        entry.qname = "a\"b\\c\u{1}".to_string();
+        entry.result = None;
+        let json = entry.format(LogFormat::Json);
+        assert!(json.contains(r#""qname":"a\"b\\c\u0001""#));
+        assert!(json.contains(r#""result":null"#));
+    }
+
+    #[test]
+    fn test_parse_rotate() {
+        let rotate = |daily, max_size| Some(Rotate { daily, max_size });
+        assert_eq!(Rotate::parse("daily"), rotate(true, None));
+        assert_eq!(Rotate::parse("10k daily"), rotate(true, Some(10240)));
+        assert_eq!(Rotate::parse("500"), rotate(false, Some(500)));
+        assert_eq!(Rotate::parse("2G").unwrap().to_string(), "2147483648");
+        assert_eq!(
+            Rotate::parse("daily 1M").unwrap().to_string(),
+            "daily 1048576"
+        );
+        for text in &["", "0", "1T", "M", "daily daily", "1M 2M", "weekly"] {
+            assert_eq!(Rotate::parse(text), None, "{}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("updns-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let line = entry().format(LogFormat::Text).len() as u64 + 1;
+        let now = SystemTime::now();
+        let date = &rfc3339(now)[..10];
+        let rotated = |suffix: &str| dir.join(format!("queries.log.{}{}", date, suffix));
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+
+        // Two lines per file
+        let rotate = Rotate {
+            daily: true,
+            max_size: Some(line * 2),
+        };
+        let log = QueryLog::new(LogFormat::Text, Some(path.clone()), rotate).unwrap();
+        for _ in 0..5 {
+            log.write_at(&entry(), now);
+        }
+        log.flush().await;
+        assert_eq!(lines(&rotated("")), 2);
+        assert_eq!(lines(&rotated(".1")), 2);
+        assert_eq!(lines(&path), 1);
+
+        // The next day starts a new file
+        log.write_at(&entry(), now + Duration::from_secs(86400));
+        log.flush().await;
+        assert_eq!(lines(&rotated(".2")), 1);
+        assert_eq!(lines(&path), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let dir = std::env::temp_dir().join(format!("updns-querylog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let rotated = dir.join("queries.log.1");
+
+        let log = QueryLog::new(LogFormat::Text, Some(path.clone()), Rotate::default()).unwrap();
+        log.write(&entry());
+        log.flush().await;
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().await;
+        log.write(&entry());
+        log.flush().await;
+
+        let lines = |path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&rotated), 1);
+        assert_eq!(lines(&path), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped() {
+        let dir = std::env::temp_dir().join(format!("updns-dropped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+
+        // Every line is written or counted
+        let log = QueryLog::with_queue(LogFormat::Text, Some(path.clone()), Rotate::default(), 1)
+            .unwrap();
+        for _ in 0..1000 {
+            log.write(&entry());
+        }
+        log.flush().await;
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count() as u64;
+        assert_eq!(lines + log.dropped(), 1000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+use regex::Regex;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+use tokio::fs;
+use updns::{
+    config::{Hosts, Parser, Record},
+    querylog::json_string,
+    remote::RemoteImports,
+};
+
+// A file which had records of a removed pattern
+#[derive(Debug, PartialEq, Eq)]
+pub struct Removed {
+    pub source: String,
+    pub lines: usize,
+}
+
+pub fn to_json(record: &Record) -> String {
+    format!(
+        r#"{{"pattern":{},"kind":"{}","ip":"{}","source":{},"line":{}}}"#,
+        json_string(&record.matcher.to_string()),
+        record.matcher.kind().as_str(),
+        record.ip,
+        match record.source {
+            Some(source) => json_string(source),
+            None => "null".to_string(),
+        },
+        match record.line {
+            Some(line) => line.to_string(),
+            None => "null".to_string(),
+        }
+    )
+}
+
+// Output format of `updns ls`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Aligned columns with a header
+    Table,
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Option<Format> {
+        match text {
+            "table" => Some(Format::Table),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+// Output of `updns ls` sorted by pattern, the records of a pattern in the
+// order they apply. `filter` keeps the patterns it matches
+pub fn format(hosts: &Hosts, format: Format, filter: Option<&Regex>) -> String {
+    let mut records = hosts
+        .records()
+        .map(|r| (r.matcher.to_string(), r))
+        .filter(|(pattern, _)| filter.is_none_or(|filter| filter.is_match(pattern)))
+        .collect::<Vec<_>>();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    match format {
+        Format::Json => {
+            let records = records.iter().map(|(_, r)| to_json(r)).collect::<Vec<_>>();
+            format!("[{}]\n", records.join(","))
+        }
+        Format::Csv => {
+            let mut out = "pattern,kind,ip,source,line\n".to_string();
+            for (pattern, r) in &records {
+                let line = r.line.map(|line| line.to_string());
+                let fields = [
+                    pattern.as_str(),
+                    r.matcher.kind().as_str(),
+                    &r.ip.to_string(),
+                    r.source.unwrap_or_default(),
+                    line.as_deref().unwrap_or_default(),
+                ];
+                let fields = fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>();
+                out += &fields.join(",");
+                out += "\n";
+            }
+            out
+        }
+        Format::Table => {
+            let mut rows = vec![[
+                "PATTERN".to_string(),
+                "KIND".to_string(),
+                "IP".to_string(),
+                "SOURCE".to_string(),
+            ]];
+            rows.extend(records.into_iter().map(|(pattern, r)| {
+                let origin = match (r.source, r.line) {
+                    (Some(source), Some(line)) => format!("{}:{}", source, line),
+                    (Some(source), None) => source.to_string(),
+                    _ => "-".to_string(),
+                };
+                [
+                    pattern,
+                    r.matcher.kind().as_str().to_string(),
+                    r.ip.to_string(),
+                    origin,
+                ]
+            }));
+            let width = |i: usize| rows.iter().map(|row| row[i].len()).max().unwrap_or(0);
+            let (pattern, kind, ip) = (width(0), width(1), width(2));
+
+            let mut out = String::new();
+            for [a, b, c, d] in &rows {
+                out += &format!(
+                    "{:pattern$}    {:kind$}    {:ip$}    {}\n",
+                    a,
+                    b,
+                    c,
+                    d,
+                    pattern = pattern,
+                    kind = kind,
+                    ip = ip
+                );
+            }
+            out
+        }
+    }
+}
+
+// Quoted when it has a comma, a quote or a line break (RFC 4180)
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// Remove the lines of `pattern` from the config and the imports defining it.
+// Remote imports and read-only files are left alone and returned, it fails
+// when the pattern is only found there
+pub async fn remove(
+    path: &Path,
+    remote: RemoteImports,
+    pattern: &str,
+) -> Result<(Vec<Removed>, Vec<String>)> {
+    let config = crate::open_config(path)
+        .await?
+        .allow_remote(remote)
+        .parse()
+        .await?;
+    let mut sources: Vec<String> = Vec::new();
+    for record in config.hosts().records() {
+        if let Some(source) = record.source {
+            if record.matcher.to_string() == pattern && !sources.iter().any(|s| s == source) {
+                sources.push(source.to_string());
+            }
+        }
+    }
+    if sources.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No host record '{}' in {:?} or its imports", pattern, path),
+        ));
+    }
+
+    let mut writable = Vec::new();
+    let mut read_only = Vec::new();
+    for source in sources {
+        if is_read_only(&source).await {
+            read_only.push(source);
+            continue;
+        }
+        let parser = match Path::new(&source) == path {
+            true => crate::open_config(path).await,
+            false => Parser::new(&source).await,
+        };
+        match parser {
+            Ok(parser) => writable.push((source, parser)),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => read_only.push(source),
+            Err(err) => return Err(err),
+        }
+    }
+    if writable.is_empty() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "'{}' only comes from the read-only {}, add a record for the domain \
+                 before the import in {:?} to override it instead",
+                pattern,
+                read_only.join(", "),
+                path
+            ),
+        ));
+    }
+
+    let mut removed = Vec::new();
+    for (source, mut parser) in writable {
+        let lines = parser.remove(pattern).await?;
+        removed.push(Removed { source, lines });
+    }
+    Ok((removed, read_only))
+}
+
+async fn is_read_only(source: &str) -> bool {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        return true;
+    }
+    match fs::metadata(source).await {
+        Ok(meta) => meta.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test_records {
+    use super::*;
+    use std::path::PathBuf;
+
+    async fn nested(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("updns-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("hosts")).await.unwrap();
+        fs::write(
+            dir.join("config"),
+            "# main\na.com 1.1.1.1\nimport hosts/a\n*.b.com 2.2.2.2\n",
+        )
+        .await
+        .unwrap();
+        fs::write(
+            dir.join("hosts/a"),
+            "b.com 3.3.3.3\nimport c\na.com 4.4.4.4\n",
+        )
+        .await
+        .unwrap();
+        fs::write(dir.join("hosts/c"), "\n~^c\\d\\.com$ 5.5.5.5 # c\n")
+            .await
+            .unwrap();
+        dir
+    }
+
+    async fn hosts(dir: &Path) -> Hosts {
+        let config = Parser::new(dir.join("config"))
+            .await
+            .unwrap()
+            .parse()
+            .await
+            .unwrap();
+        config.hosts().clone()
+    }
+
+    #[tokio::test]
+    async fn test_format() {
+        let dir = nested("ls").await;
+        let hosts = hosts(&dir).await;
+        let (config, a, c) = (
+            dir.join("config").display().to_string(),
+            dir.join("hosts/a").display().to_string(),
+            dir.join("hosts/c").display().to_string(),
+        );
+
+        let text = format(&hosts, Format::Table, None);
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "PATTERN        KIND        IP         SOURCE");
+        assert_eq!(
+            lines[1],
+            format!("*.b.com        wildcard    2.2.2.2    {}:4", config)
+        );
+        // Sorted by pattern, then in the order they apply
+        assert_eq!(
+            lines[2],
+            format!("a.com          text        1.1.1.1    {}:2", config)
+        );
+        assert_eq!(
+            lines[3],
+            format!("a.com          text        4.4.4.4    {}:3", a)
+        );
+        assert_eq!(
+            lines[5],
+            format!("~^c\\d\\.com$    regex       5.5.5.5    {}:2", c)
+        );
+
+        let json = format(&hosts, Format::Json, None);
+        assert!(json.starts_with(&format!(
+            r#"[{{"pattern":"*.b.com","kind":"wildcard","ip":"2.2.2.2","source":{},"line":4}},"#,
+            json_string(&config)
+        )));
+        assert!(json.contains(&format!(
+            r#"{{"pattern":"a.com","kind":"text","ip":"4.4.4.4","source":{},"line":3}}"#,
+            json_string(&a)
+        )));
+
+        let filter = Regex::new(r"^a\.").unwrap();
+        assert_eq!(
+            format(&hosts, Format::Csv, Some(&filter)),
+            format!(
+                "pattern,kind,ip,source,line\na.com,text,1.1.1.1,{},2\na.com,text,4.4.4.4,{},3\n",
+                csv_field(&config),
+                csv_field(&a)
+            )
+        );
+        assert_eq!(
+            format(&hosts, Format::Json, Some(&Regex::new("none").unwrap())),
+            "[]\n"
+        );
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("a.com"), "a.com");
+        assert_eq!(csv_field("~^(a|b),c$"), "\"~^(a|b),c$\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let dir = nested("rm").await;
+        let path = dir.join("config");
+
+        let (removed, read_only) = remove(&path, RemoteImports::Deny, "a.com").await.unwrap();
+        assert!(read_only.is_empty());
+        assert_eq!(
+            removed,
+            vec![
+                Removed {
+                    source: path.display().to_string(),
+                    lines: 1
+                },
+                Removed {
+                    source: dir.join("hosts/a").display().to_string(),
+                    lines: 1
+                },
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&path).await.unwrap(),
+            "# main\nimport hosts/a\n*.b.com 2.2.2.2\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("hosts/a")).await.unwrap(),
+            "b.com 3.3.3.3\nimport c\n"
+        );
+
+        remove(&path, RemoteImports::Deny, "~^c\\d\\.com$")
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("hosts/c")).await.unwrap(), "\n");
+
+        let err = remove(&path, RemoteImports::Deny, "a.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_read_only() {
+        let dir = nested("rm-ro").await;
+        let path = dir.join("config");
+        let blocklist = dir.join("hosts/a");
+        let mut permissions = fs::metadata(&blocklist).await.unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&blocklist, permissions).await.unwrap();
+
+        let err = remove(&path, RemoteImports::Deny, "b.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("override"));
+
+        // Still removed from the writable files
+        let (removed, read_only) = remove(&path, RemoteImports::Deny, "a.com").await.unwrap();
+        assert_eq!(read_only, vec![blocklist.display().to_string()]);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].source, path.display().to_string());
+        assert!(fs::read_to_string(&blocklist)
+            .await
+            .unwrap()
+            .contains("a.com 4.4.4.4"));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
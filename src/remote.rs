@@ -0,0 +1,375 @@
+use crate::warn;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
+    net::TcpStream,
+    time::timeout,
+};
+
+// Largest accepted config fragment
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+pub fn is_remote(value: &str) -> bool {
+    value.starts_with("http://") || value.starts_with("https://")
+}
+
+// The remote imports of the command line. Anyone on the network path can
+// alter a plain HTTP one, so it takes its own flag over HTTPS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemoteImports {
+    #[default]
+    Deny,
+    Https,
+    // HTTPS as well
+    Http,
+}
+
+impl RemoteImports {
+    // The error names the flag `url` lacks
+    pub fn check(self, url: &str) -> Result<()> {
+        let flag = match self {
+            RemoteImports::Deny => "--allow-remote-imports",
+            RemoteImports::Https if url.starts_with("http://") => "--allow-http-imports",
+            _ => return Ok(()),
+        };
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Importing '{}' requires {}", url, flag),
+        ))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url<'a> {
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> Result<Url<'a>> {
+        if url.starts_with("https://") {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "HTTPS imports are not supported, TLS is not available",
+            ));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Not an http url"))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with(']') => {
+                let port = authority[i + 1..]
+                    .parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid port"))?;
+                (&authority[..i], port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Missing host"));
+        }
+
+        Ok(Url { host, port, path })
+    }
+}
+
+// Private to the user, a cached copy is loaded as the config when the
+// download fails
+fn cache_dir() -> PathBuf {
+    match dirs::cache_dir() {
+        Some(dir) => dir.join("updns"),
+        #[cfg(unix)]
+        None => env::temp_dir().join(format!("updns-{}", unsafe { libc::geteuid() })),
+        #[cfg(not(unix))]
+        None => env::temp_dir().join("updns"),
+    }
+}
+
+// Local copy of the last download
+fn cache_name(url: &str) -> String {
+    // FNV-1a, stable across builds
+    let hash = url.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("import-{:016x}", hash)
+}
+
+// Create the directory if needed, then check no one else can write to it
+async fn private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(dir).await?;
+
+    let denied = |msg: &str| {
+        Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Cache directory '{}' {}", dir.display(), msg),
+        )
+    };
+    let meta = fs::symlink_metadata(dir).await?;
+    if !meta.is_dir() {
+        return Err(denied("is not a directory"));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        if meta.uid() != unsafe { libc::geteuid() } {
+            return Err(denied("is owned by another user"));
+        }
+        if meta.mode() & 0o077 != 0 {
+            fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700)).await?;
+        }
+    }
+    Ok(())
+}
+
+// Written to a new file renamed over the cache, a symlink planted in its
+// place is replaced rather than followed
+async fn save(dir: &Path, cache: &Path, content: &str) -> Result<()> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    private_dir(dir).await?;
+    let tmp = cache.with_extension(format!(
+        "{}.{}.tmp",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    let written = match file.write_all(content.as_bytes()).await {
+        Ok(()) => file.sync_all().await,
+        Err(err) => Err(err),
+    };
+    drop(file);
+    match written {
+        Ok(()) => fs::rename(&tmp, cache).await,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp).await;
+            Err(err)
+        }
+    }
+}
+
+// The cached copy and when it was written, a symlink isn't followed
+async fn load(dir: &Path, cache: &Path) -> Result<(String, SystemTime)> {
+    private_dir(dir).await?;
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let mut file = options.open(cache).await?;
+    let modified = file.metadata().await?.modified()?;
+    let mut content = String::new();
+    file.read_to_string(&mut content).await?;
+    Ok((content, modified))
+}
+
+// Download the url, falling back to the cached copy when it fails
+pub async fn fetch(url: &str, duration: Duration) -> Result<String> {
+    fetch_in(&cache_dir(), url, duration).await
+}
+
+async fn fetch_in(dir: &Path, url: &str, duration: Duration) -> Result<String> {
+    let cache = dir.join(cache_name(url));
+
+    match timeout(duration, get(url)).await {
+        Ok(Ok(content)) => {
+            // The download is used all the same
+            if let Err(err) = save(dir, &cache, &content).await {
+                warn!("Caching '{}' failed {:?}", url, err);
+            }
+            Ok(content)
+        }
+        Ok(Err(err)) => cached(dir, &cache, url, err).await,
+        Err(err) => cached(dir, &cache, url, err.into()).await,
+    }
+}
+
+async fn cached(dir: &Path, cache: &Path, url: &str, err: Error) -> Result<String> {
+    match load(dir, cache).await {
+        Ok((content, modified)) => {
+            warn!(
+                "Use the copy of '{}' cached at {}s, download failed {:?}",
+                url,
+                modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                err
+            );
+            Ok(content)
+        }
+        Err(_) => Err(Error::new(
+            err.kind(),
+            format!("Failed to download '{}': {}", url, err),
+        )),
+    }
+}
+
+// Plain HTTP/1.0 GET, which rules out chunked responses
+async fn get(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    let mut stream = TcpStream::connect((url.host, url.port)).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: updns\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_SIZE).read_to_end(&mut response).await?;
+
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("Incomplete HTTP response"))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head
+        .split_ascii_whitespace()
+        .nth(1)
+        .ok_or_else(|| invalid("Invalid HTTP response"))?;
+    if status != "200" {
+        return Err(invalid(&format!("HTTP status {}", status)));
+    }
+
+    String::from_utf8(response[end + 4..].to_vec()).map_err(|_| invalid("Config is not UTF-8"))
+}
+
+#[cfg(test)]
+mod test_remote {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Read the request headers
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).await.unwrap();
+                buf.push(byte[0]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://{}/updns/{}.conf", addr, addr.port())
+    }
+
+    #[test]
+    fn test_check() {
+        let denied = |imports: RemoteImports, url| imports.check(url).unwrap_err().to_string();
+        assert_eq!(
+            denied(RemoteImports::Deny, "https://a.com/hosts"),
+            "Importing 'https://a.com/hosts' requires --allow-remote-imports"
+        );
+        assert_eq!(
+            denied(RemoteImports::Https, "http://a.com/hosts"),
+            "Importing 'http://a.com/hosts' requires --allow-http-imports"
+        );
+        assert!(RemoteImports::Https.check("https://a.com/hosts").is_ok());
+        assert!(RemoteImports::Http.check("http://a.com/hosts").is_ok());
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            Url::parse("http://example.com:8080/a/b.conf").unwrap(),
+            Url {
+                host: "example.com",
+                port: 8080,
+                path: "/a/b.conf"
+            }
+        );
+        assert_eq!(
+            Url::parse("http://example.com").unwrap(),
+            Url {
+                host: "example.com",
+                port: 80,
+                path: "/"
+            }
+        );
+        assert_eq!(
+            Url::parse("https://example.com/").unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(Url::parse("http://example.com:x/").is_err());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("updns-remote-{}-{}", name, process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let dir = temp_dir("fetch");
+        let url = serve("HTTP/1.0 200 OK\r\nContent-Length: 20\r\n\r\nexample.com 1.1.1.1\n").await;
+        let content = fetch_in(&dir, &url, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(content, "example.com 1.1.1.1\n");
+
+        // Served from the cache once the server is gone
+        let content = fetch_in(&dir, &url, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(content, "example.com 1.1.1.1\n");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cache_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("symlink");
+        let victim = env::temp_dir().join(format!("updns-remote-victim-{}", process::id()));
+        fs::write(&victim, "untouched").await.unwrap();
+        let url = serve("HTTP/1.0 200 OK\r\n\r\nexample.com 1.1.1.1\n").await;
+
+        // Planted before the first download, world writable
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777))
+            .await
+            .unwrap();
+        let cache = dir.join(cache_name(&url));
+        fs::symlink(&victim, &cache).await.unwrap();
+        // Not loaded through the link either
+        assert!(load(&dir, &cache).await.is_err());
+
+        fetch_in(&dir, &url, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(fs::read_to_string(&victim).await.unwrap(), "untouched");
+        let meta = fs::symlink_metadata(&cache).await.unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        let meta = fs::metadata(&dir).await.unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o700);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+        fs::remove_file(&victim).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_error() {
+        let url = serve("HTTP/1.0 404 Not Found\r\n\r\n").await;
+        let err = fetch(&url, Duration::from_secs(1)).await.unwrap_err();
+        assert!(err.to_string().contains("HTTP status 404"), "{}", err);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::Result};
+
+// What a response policy zone does with the queries of a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpzPolicy {
+    // CNAME .
+    NxDomain,
+    // CNAME *.
+    NoData,
+    // CNAME rpz-passthru., forwarded whatever the later zones say
+    Passthru,
+    // A and AAAA local data, answered instead of the upstream
+    Redirect(Vec<IpAddr>),
+}
+
+// A record of the zone file which is not applied
+#[derive(Debug, PartialEq, Eq)]
+pub struct Skipped {
+    pub line: usize,
+    pub source: String,
+}
+
+// The QNAME triggers of a BIND response policy zone file. The IP, NSDNAME
+// and NSIP triggers, rpz-drop and CNAME redirects are not supported
+#[derive(Debug)]
+pub struct RpzZone {
+    path: PathBuf,
+    // Of the `rpz` directive in the config
+    line: usize,
+    loaded: bool,
+    names: HashMap<String, RpzPolicy>,
+    // `*.example.com` by `example.com`
+    wildcards: HashMap<String, RpzPolicy>,
+}
+
+impl RpzZone {
+    // Empty until `load` or `parse`
+    pub fn new(path: PathBuf, line: usize) -> RpzZone {
+        RpzZone {
+            path,
+            line,
+            loaded: false,
+            names: HashMap::new(),
+            wildcards: HashMap::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn set_path(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    // Number of triggers
+    pub fn len(&self) -> usize {
+        self.names.len() + self.wildcards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub async fn load(&mut self) -> Result<Vec<Skipped>> {
+        let text = fs::read_to_string(&self.path).await?;
+        Ok(self.parse(&text))
+    }
+
+    // Replace the triggers with those of the zone text
+    pub fn parse(&mut self, text: &str) -> Vec<Skipped> {
+        self.names.clear();
+        self.wildcards.clear();
+        self.loaded = true;
+
+        let mut skipped = Vec::new();
+        let mut origin: Option<String> = None;
+        let mut owner: Option<String> = None;
+        for (line, record) in records(text) {
+            let mut skip = || {
+                skipped.push(Skipped {
+                    line,
+                    source: record.clone(),
+                })
+            };
+            let mut words = record.split_ascii_whitespace().peekable();
+
+            if record.starts_with('$') {
+                match (words.next(), words.next()) {
+                    (Some("$ORIGIN"), Some(name)) => origin = Some(absolute(name, &origin)),
+                    (Some("$TTL"), Some(_)) => {}
+                    _ => skip(),
+                }
+                continue;
+            }
+            // A record starting with a blank has the owner of the previous one
+            if !record.starts_with([' ', '\t']) {
+                owner = words.next().map(|name| absolute(name, &origin));
+            }
+            let owner = match &owner {
+                Some(owner) => owner,
+                None => {
+                    skip();
+                    continue;
+                }
+            };
+            // TTL and class in any order
+            while let Some(word) = words.peek() {
+                let class = ["IN", "CH", "HS"].contains(&word.to_ascii_uppercase().as_str());
+                if !class && !word.bytes().all(|b| b.is_ascii_digit()) {
+                    break;
+                }
+                words.next();
+            }
+            let rtype = words.next().unwrap_or_default().to_ascii_uppercase();
+            let rdata = words.collect::<Vec<_>>();
+
+            // The SOA and NS records of the zone
+            if Some(owner) == origin.as_ref() {
+                continue;
+            }
+            let trigger = match trigger(owner, &origin) {
+                Some(trigger) => trigger,
+                None => {
+                    skip();
+                    continue;
+                }
+            };
+            if trigger.split('.').any(|label| label.starts_with("rpz-")) {
+                skip();
+                continue;
+            }
+            let policy = match (rtype.as_str(), &rdata[..]) {
+                ("CNAME", ["."]) => RpzPolicy::NxDomain,
+                ("CNAME", ["*."]) => RpzPolicy::NoData,
+                ("CNAME", [target]) if target.eq_ignore_ascii_case("rpz-passthru.") => {
+                    RpzPolicy::Passthru
+                }
+                ("A" | "AAAA", [ip]) => match ip.parse::<IpAddr>() {
+                    Ok(ip) if ip.is_ipv4() == (rtype == "A") => RpzPolicy::Redirect(vec![ip]),
+                    _ => {
+                        skip();
+                        continue;
+                    }
+                },
+                _ => {
+                    skip();
+                    continue;
+                }
+            };
+            self.insert(trigger, policy);
+        }
+        skipped
+    }
+
+    fn insert(&mut self, trigger: String, policy: RpzPolicy) {
+        let map = match trigger.strip_prefix("*.") {
+            Some(parent) => {
+                let parent = parent.to_string();
+                return Self::insert_into(&mut self.wildcards, parent, policy);
+            }
+            None => &mut self.names,
+        };
+        Self::insert_into(map, trigger, policy)
+    }
+
+    // Local data of a name adds up, the first other policy stays
+    fn insert_into(map: &mut HashMap<String, RpzPolicy>, key: String, policy: RpzPolicy) {
+        match (map.get_mut(&key), policy) {
+            (Some(RpzPolicy::Redirect(ips)), RpzPolicy::Redirect(more)) => ips.extend(more),
+            (Some(_), _) => {}
+            (None, policy) => {
+                map.insert(key, policy);
+            }
+        }
+    }
+
+    // The trigger and policy of the name, an exact trigger first and then
+    // the closest wildcard
+    pub fn find(&self, domain: &str) -> Option<(String, &RpzPolicy)> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(policy) = self.names.get(&domain) {
+            return Some((domain, policy));
+        }
+        let mut parent = domain.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(policy) = self.wildcards.get(rest) {
+                return Some((format!("*.{}", rest), policy));
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+// Records with their first line, without comments and with the lines
+// between parentheses joined
+fn records(text: &str) -> Vec<(usize, String)> {
+    let mut out = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut depth = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.find(';') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        depth += line.matches('(').count() as i32 - line.matches(')').count() as i32;
+        let line = line.replace(['(', ')'], " ");
+        match &mut current {
+            Some((_, record)) => {
+                record.push(' ');
+                record.push_str(line.trim());
+            }
+            None if line.trim().is_empty() => continue,
+            None => current = Some((i + 1, line.trim_end().to_string())),
+        }
+        if depth <= 0 {
+            depth = 0;
+            out.extend(current.take());
+        }
+    }
+    out.extend(current);
+    out
+}
+
+// Lowercase with a trailing dot, relative names are under the origin
+fn absolute(name: &str, origin: &Option<String>) -> String {
+    let name = name.to_ascii_lowercase();
+    if name == "@" {
+        return origin.clone().unwrap_or_default();
+    }
+    if name.ends_with('.') {
+        return name;
+    }
+    match origin {
+        Some(origin) if !origin.is_empty() => format!("{}.{}", name, origin),
+        _ => format!("{}.", name),
+    }
+}
+
+// The domain a record applies to, `None` outside of the origin. Without an
+// origin the names are taken as written
+fn trigger(owner: &str, origin: &Option<String>) -> Option<String> {
+    let name = match origin {
+        Some(origin) => owner.strip_suffix(origin.as_str())?.strip_suffix('.')?,
+        None => owner.trim_end_matches('.'),
+    };
+    match name.is_empty() {
+        true => None,
+        false => Some(name.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test_rpz {
+    use super::*;
+
+    const ZONE: &str = "
+$TTL 300
+$ORIGIN rpz.example.
+@   IN SOA localhost. admin.localhost. (
+        1 ; serial
+        3600 600 86400 60 )
+    IN NS  localhost.
+
+bad.com          CNAME .
+*.bad.com        CNAME .
+ok.bad.com       CNAME rpz-passthru.
+empty.com 60 IN  CNAME *.
+walled.com       A     10.0.0.1
+                 AAAA  fd00::1
+walled.com.rpz.example. A 10.0.0.2
+32.1.0.0.10.rpz-ip CNAME .
+drop.com         CNAME rpz-drop.
+other.com        TXT   \"note\"
+outside.com.     CNAME .
+";
+
+    #[test]
+    fn test_parse() {
+        let mut zone = RpzZone::new(PathBuf::from("rpz.zone"), 1);
+        let skipped = zone.parse(ZONE);
+        assert_eq!(
+            skipped
+                .iter()
+                .map(|skipped| skipped.line)
+                .collect::<Vec<_>>(),
+            vec![16, 17, 18, 19]
+        );
+        assert_eq!(skipped[1].source, "drop.com         CNAME rpz-drop.");
+        assert_eq!(zone.len(), 5);
+
+        let find = |domain: &str| {
+            zone.find(domain)
+                .map(|(trigger, policy)| (trigger, policy.clone()))
+        };
+        assert_eq!(
+            find("Bad.com."),
+            Some(("bad.com".to_string(), RpzPolicy::NxDomain))
+        );
+        assert_eq!(
+            find("a.b.bad.com"),
+            Some(("*.bad.com".to_string(), RpzPolicy::NxDomain))
+        );
+        assert_eq!(
+            find("ok.bad.com"),
+            Some(("ok.bad.com".to_string(), RpzPolicy::Passthru))
+        );
+        assert_eq!(find("empty.com").unwrap().1, RpzPolicy::NoData);
+        assert_eq!(
+            find("walled.com").unwrap().1,
+            RpzPolicy::Redirect(vec![
+                "10.0.0.1".parse().unwrap(),
+                "fd00::1".parse().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ])
+        );
+        assert_eq!(find("outside.com"), None);
+        assert_eq!(find("good.com"), None);
+    }
+
+    #[test]
+    fn test_without_origin() {
+        let mut zone = RpzZone::new(PathBuf::from("rpz.zone"), 1);
+        assert!(zone
+            .parse("bad.com CNAME .\n*.ads.net. CNAME .\nbad A 1.2.3")
+            .iter()
+            .any(|skipped| skipped.line == 3));
+        assert!(zone.find("bad.com").is_some());
+        assert!(zone.find("x.ads.net").is_some());
+        assert!(zone.find("ads.net").is_none());
+    }
+}
@@ -0,0 +1,2473 @@
+use crate::{
+    cidr::{is_allowed, Acl},
+    coalesce::Coalesce,
+    config::{
+        AnyPolicy, BindSpec, Config, Hosts, LogFormat, Proxy, ProxyHost, Socks5Proxy,
+        SpecialPolicy, DEFAULT_BIND,
+    },
+    dns64::Prefix,
+    dnstap::{Dnstap, Kind, Message},
+    edns::{udp_payload, Ecs},
+    limit::RateLimit,
+    matcher::Matcher,
+    querylog::{Entry, QueryLog},
+    rpz::{RpzPolicy, RpzZone},
+    stale::Stale,
+    swap::Swap,
+    tasks::Tasks,
+    upstream::{restore_question, Failover, Resolved, Retry, Socks5, Udp, Upstream, Weighted},
+    utils::{is_private_ip, random},
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode,
+};
+use crate::{error, info, warn};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream, UdpSocket},
+    sync::{watch, Mutex},
+    time::{sleep, timeout},
+};
+
+pub use crate::{querylog::Outcome, stats::Stats};
+
+// Used without a `proxy` line
+pub const DEFAULT_PROXY: [&str; 2] = ["8.8.8.8:53", "1.1.1.1:53"];
+// Of the answers from host records
+pub const DEFAULT_TTL: u32 = 3600;
+// Of the upstream queries
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+// Queries answered at once without `max-inflight`
+pub const DEFAULT_MAX_INFLIGHT: usize = 4096;
+const RATE_LIMIT_CLEANUP: Duration = Duration::from_secs(60);
+// Between the queries asking again for a stale answer
+const STALE_REFRESH: Duration = Duration::from_secs(10);
+// Proxy host names are looked up again to follow address changes
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+// TCP connections without a query for this long are closed (RFC 7766)
+const TCP_IDLE: Duration = Duration::from_secs(10);
+// Special-use suffixes answered NXDOMAIN without a `special-domain` line
+const SPECIAL_DOMAINS: [&str; 5] = ["local", "onion", "home.arpa", "invalid", "test"];
+
+// Query types of ANY and HINFO, not in `QueryType`
+const QTYPE_ANY: u16 = 255;
+const QTYPE_HINFO: u16 = 13;
+
+// The response of a query and how it was answered
+#[derive(Debug, Clone)]
+pub struct Answer {
+    data: Vec<u8>,
+    outcome: Outcome,
+    // The matched pattern or the upstream used
+    source: Option<String>,
+}
+
+impl Answer {
+    fn new(data: Vec<u8>, outcome: Outcome, source: Option<String>) -> Answer {
+        Answer {
+            data,
+            outcome,
+            source,
+        }
+    }
+
+    // The response as sent to the client
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn packet(&self) -> Result<DnsPacket> {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&self.data))
+    }
+}
+
+// Where the answer of a query is sent
+#[derive(Clone)]
+enum Reply {
+    Udp(Arc<UdpSocket>),
+    // Shared by the queries of a connection
+    Tcp(Arc<Mutex<OwnedWriteHalf>>),
+}
+
+impl Reply {
+    async fn send(&self, data: &[u8], dst: SocketAddr) -> Result<()> {
+        match self {
+            Reply::Udp(socket) => socket.send_to(data, dst).await.map(|_| ()),
+            Reply::Tcp(stream) => {
+                let len = (data.len() as u16).to_be_bytes();
+                stream.lock().await.write_all(&[&len, data].concat()).await
+            }
+        }
+    }
+}
+
+// The config without the host records, replaced as a whole by `update`
+struct Settings {
+    bind: Vec<BindSpec>,
+    upstream: Arc<dyn Upstream>,
+    ttl: (Option<u32>, Option<u32>),
+    ecs: Ecs,
+    // Whitelist of the rebinding protection, `None` when disabled
+    rebind: Option<Vec<Matcher>>,
+    // Addresses a lying upstream puts in place of NXDOMAIN
+    bogus_nx: Vec<IpAddr>,
+    rpz: Vec<RpzZone>,
+    any_policy: AnyPolicy,
+    dns64: Option<Prefix>,
+    special_domains: HashMap<String, SpecialPolicy>,
+    // Window of the stale answers, `None` when they are not kept
+    serve_stale: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    max_inflight: usize,
+    acl: Vec<Acl>,
+    // Drop the queries of denied clients instead of refusing them
+    acl_drop: bool,
+    shutdown_grace: Duration,
+    // `None` when queries are not logged
+    query_log: Option<Arc<QueryLog>>,
+    dnstap: Option<Arc<Dnstap>>,
+}
+
+impl Settings {
+    // `dnstap` is the connection of the previous settings, `upstream`
+    // replaces the `proxy` lines of the config
+    fn new(
+        config: Config,
+        dnstap: Option<Arc<Dnstap>>,
+        upstream: Option<Arc<dyn Upstream>>,
+    ) -> Settings {
+        let query_log = match config.log_queries {
+            Some(true) => {
+                let format = config.log_format.unwrap_or(LogFormat::Text);
+                let rotate = config.log_rotate.unwrap_or_default();
+                match QueryLog::new(format, config.log_file.clone(), rotate) {
+                    Ok(log) => Some(Arc::new(log)),
+                    Err(err) => {
+                        error!("Failed to open query log {:?}\n{:?}", config.log_file, err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // Keep the connection when the collector is the same
+        let dnstap = match (dnstap, config.dnstap) {
+            (Some(tap), Some(path)) if *tap.path() == path => Some(tap),
+            (_, path) => path.map(|path| Arc::new(Dnstap::new(path))),
+        };
+
+        let upstream = upstream.unwrap_or_else(|| {
+            let mut proxy = config.proxy;
+            if proxy.is_empty() && config.socks5.is_empty() {
+                proxy = DEFAULT_PROXY
+                    .iter()
+                    .map(|p| Proxy::Addr(p.parse().unwrap()))
+                    .collect();
+            }
+            // `timeout` is the budget of all the tries, shared without an
+            // `attempt-timeout`
+            let budget = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+            let retries = config.retries.unwrap_or(0);
+            let timeout = config.attempt_timeout.unwrap_or(budget / (retries + 1));
+            let dns0x20 = config.dns0x20.unwrap_or(true);
+            let options = &config.proxy_options;
+            let resolved = |host: &ProxyHost| {
+                let timeout = host.options.timeout.unwrap_or(timeout);
+                let resolved = Resolved::new(
+                    &host.host,
+                    host.port,
+                    host.bootstrap,
+                    host.addrs.clone(),
+                    timeout,
+                    dns0x20,
+                );
+                let resolved = Arc::new(resolved.tap(dnstap.clone()));
+                // Outside of a runtime the addresses stay those of the config
+                if tokio::runtime::Handle::try_current().is_ok() {
+                    resolved.refresh_every(RESOLVE_INTERVAL);
+                }
+                (Box::new(resolved) as Box<dyn Upstream>, host.options.weight)
+            };
+
+            // Without the address of its proxy, when the config was not
+            // resolved, a SOCKS5 upstream is left out
+            let socks5 = &config.socks5;
+            let socks = |socks: &Socks5Proxy| {
+                let timeout = socks.options.timeout.unwrap_or(timeout);
+                let upstream = Socks5::new(
+                    socks.proxy_addr()?,
+                    socks.upstream,
+                    socks.auth.clone(),
+                    timeout,
+                );
+                Some((
+                    Box::new(upstream) as Box<dyn Upstream>,
+                    socks.options.weight,
+                ))
+            };
+
+            // The addresses of a host name are one upstream
+            let mut upstreams = Vec::new();
+            let count = proxy.len();
+            for (i, proxy) in proxy.iter().enumerate() {
+                let before = socks5.iter().filter(|socks| socks.after == i);
+                upstreams.extend(before.filter_map(socks));
+                match proxy {
+                    Proxy::Host(host) => upstreams.push(resolved(host)),
+                    Proxy::Addr(addr) => {
+                        let options = options.get(addr).copied().unwrap_or_default();
+                        let udp = Udp::new(*addr, options.timeout.unwrap_or(timeout), dns0x20);
+                        let udp = Box::new(udp.tap(dnstap.clone())) as Box<dyn Upstream>;
+                        upstreams.push((udp, options.weight));
+                    }
+                }
+            }
+            let last = socks5.iter().filter(|socks| socks.after >= count);
+            upstreams.extend(last.filter_map(socks));
+            let upstreams = upstreams.into_iter();
+
+            // Round-robin once a proxy has a weight, the others weigh 1
+            let upstream: Box<dyn Upstream> =
+                match options.values().any(|options| options.weight.is_some())
+                    || proxy.iter().any(|proxy| match proxy {
+                        Proxy::Host(host) => host.options.weight.is_some(),
+                        Proxy::Addr(_) => false,
+                    })
+                    || socks5.iter().any(|socks| socks.options.weight.is_some())
+                {
+                    true => Box::new(Weighted::new(
+                        upstreams
+                            .map(|(udp, weight)| (udp, weight.unwrap_or(1)))
+                            .collect(),
+                    )),
+                    false => Box::new(Failover::new(upstreams.map(|(udp, _)| udp).collect())),
+                };
+            match retries {
+                0 => Arc::from(upstream),
+                _ => Arc::new(Retry::new(upstream, retries, budget)),
+            }
+        });
+
+        Settings {
+            bind: config.bind,
+            upstream,
+            ttl: (config.ttl_min, config.ttl_max),
+            ecs: config.ecs.unwrap_or(Ecs::Forward),
+            serve_stale: config.serve_stale,
+            rebind: match config.rebind_protection {
+                Some(true) => Some(config.rebind_whitelist),
+                _ => None,
+            },
+            bogus_nx: config.bogus_nx,
+            rpz: config.rpz,
+            any_policy: config.any_policy.unwrap_or(AnyPolicy::Forward),
+            dns64: config.dns64,
+            special_domains: SPECIAL_DOMAINS
+                .iter()
+                .map(|suffix| (suffix.to_string(), SpecialPolicy::NxDomain))
+                .chain(config.special_domains)
+                .collect(),
+            rate_limit: config
+                .rate_limit
+                .map(|(qps, burst)| RateLimit::new(qps, burst, config.rate_limit_exempt)),
+            max_inflight: config.max_inflight.unwrap_or(DEFAULT_MAX_INFLIGHT),
+            acl: config.acl,
+            acl_drop: config.acl_drop.unwrap_or(false),
+            shutdown_grace: config.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE),
+            query_log,
+            dnstap,
+        }
+    }
+}
+
+// Name, type, client subnet, and the DO and CD bits so a validating client
+// never gets the answer of a query without signatures
+type QueryKey = (String, QueryType, Vec<u8>, [bool; 2]);
+
+// Replaced together, a query sees the settings and the hosts of one config
+#[derive(Clone)]
+struct Live {
+    settings: Arc<Settings>,
+    hosts: Arc<Hosts>,
+}
+
+struct State {
+    // Read for every query, a snapshot is taken instead of holding a lock
+    live: Swap<Live>,
+    // Queries received and not answered yet
+    tasks: Arc<Tasks>,
+    // In-flight upstream queries by name, type and client subnet
+    inflight: Coalesce<QueryKey, Answer>,
+    // The last upstream answers by the same key, with `serve-stale-ttl`
+    stale: Stale<QueryKey>,
+    stats: Stats,
+    // Used instead of the `proxy` lines of every config
+    upstream: Option<Arc<dyn Upstream>>,
+}
+
+// A resolver answering from the host records of a config and forwarding
+// the other queries to its upstreams. Clones share the same state
+#[derive(Clone)]
+pub struct Server {
+    state: Arc<State>,
+}
+
+impl Server {
+    // Must be called within a tokio runtime when the config has `dnstap`
+    pub fn new(config: Config) -> Server {
+        Server::build(config, None)
+    }
+
+    // Forward to `upstream` whatever the `proxy` lines of the config
+    pub fn with_upstream<U: Upstream + 'static>(config: Config, upstream: U) -> Server {
+        Server::build(config, Some(Arc::new(upstream)))
+    }
+
+    fn build(mut config: Config, upstream: Option<Arc<dyn Upstream>>) -> Server {
+        let hosts = std::mem::take(&mut config.hosts);
+        let settings = Settings::new(config, None, upstream.clone());
+        Server {
+            state: Arc::new(State {
+                live: Swap::new(Live {
+                    settings: Arc::new(settings),
+                    hosts: Arc::new(hosts),
+                }),
+                tasks: Arc::new(Tasks::new()),
+                inflight: Coalesce::new(),
+                stale: Stale::new(),
+                stats: Stats::new(),
+                upstream,
+            }),
+        }
+    }
+
+    // Apply a new config, the bind addresses are only read by `serve`.
+    // Everything is built before the swap, queries keep the old config
+    // until then
+    pub async fn update(&self, mut config: Config) {
+        let hosts = std::mem::take(&mut config.hosts);
+        let dnstap = self.settings().dnstap.clone();
+        let settings = Settings::new(config, dnstap, self.state.upstream.clone());
+        self.state.live.store(Live {
+            settings: Arc::new(settings),
+            hosts: Arc::new(hosts),
+        });
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.state.live.load().settings.clone()
+    }
+
+    // A snapshot of the live host records
+    pub fn hosts(&self) -> Arc<Hosts> {
+        self.state.live.load().hosts.clone()
+    }
+
+    // Edit a copy of the live host records, swapped in once done. Changes
+    // are lost on the next `update`
+    pub fn edit_hosts<R, F: FnOnce(&mut Hosts) -> R>(&self, f: F) -> R {
+        self.state
+            .live
+            .update(|live| f(Arc::make_mut(&mut live.hosts)))
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.state.stats
+    }
+
+    // Queries received and not answered yet
+    pub fn inflight(&self) -> usize {
+        self.state.tasks.count()
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        self.settings().shutdown_grace
+    }
+
+    // logrotate moved the query log away
+    pub async fn reopen_log(&self) {
+        if let Some(log) = &self.settings().query_log {
+            log.reopen().await;
+        }
+    }
+
+    // Answer a query as if it came from 127.0.0.1, counted in the stats
+    pub async fn resolve(&self, name: &str, qtype: QueryType) -> Result<Answer> {
+        let (req, len) = build_query(name, qtype)?;
+        let res = self.handle(req, len, IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        self.state.stats.record(outcome(&res).0);
+        res
+    }
+
+    // Serve the `bind` addresses of the config, `DEFAULT_BIND` without any,
+    // until `shutdown` completes, then wait for the queries being answered
+    pub async fn serve<F: Future<Output = ()>>(&self, shutdown: F) -> Result<()> {
+        let mut binds = self.settings().bind.clone();
+        if binds.is_empty() {
+            binds.push(DEFAULT_BIND.parse().unwrap());
+        }
+        let (mut sockets, mut listeners) = (Vec::new(), Vec::new());
+        for bind in binds {
+            if bind.protocol.udp() {
+                sockets.push(UdpSocket::bind(bind.addr).await?);
+            }
+            if bind.protocol.tcp() {
+                listeners.push(TcpListener::bind(bind.addr).await?);
+            }
+            info!("Start listening to '{}'", bind);
+        }
+        self.serve_listeners(sockets, listeners, shutdown).await;
+        if !self.drain().await {
+            warn!(
+                "Exit with unanswered queries after {:?}",
+                self.shutdown_grace()
+            );
+        }
+        Ok(())
+    }
+
+    // Serve bound sockets until `shutdown` completes, the queries being
+    // answered are left to `drain`
+    pub async fn serve_sockets<F>(&self, sockets: Vec<UdpSocket>, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        self.serve_listeners(sockets, Vec::new(), shutdown).await
+    }
+
+    // `serve_sockets` with TCP listeners too
+    pub async fn serve_listeners<F>(
+        &self,
+        sockets: Vec<UdpSocket>,
+        listeners: Vec<TcpListener>,
+        shutdown: F,
+    ) where
+        F: Future<Output = ()>,
+    {
+        // Dropped at shutdown, which closes the idle TCP connections
+        let (closed, closing) = watch::channel(());
+        let mut tasks =
+            sockets
+                .into_iter()
+                .map(|socket| tokio::spawn(self.clone().listen(socket)))
+                .chain(listeners.into_iter().map(|listener| {
+                    tokio::spawn(self.clone().listen_tcp(listener, closing.clone()))
+                }))
+                .collect::<Vec<_>>();
+        tasks.push(tokio::spawn(self.clone().clean_rate_limit()));
+        shutdown.await;
+        for task in tasks {
+            task.abort();
+        }
+        drop(closed);
+    }
+
+    // Wait for the queries being answered, false when some are left after
+    // the shutdown grace time
+    pub async fn drain(&self) -> bool {
+        let drained = timeout(self.shutdown_grace(), self.state.tasks.wait())
+            .await
+            .is_ok();
+        // The lines of the last queries
+        if let Some(log) = &self.settings().query_log {
+            log.flush().await;
+        }
+        drained
+    }
+
+    async fn listen(self, socket: UdpSocket) {
+        let local = socket
+            .local_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let socket = Arc::new(socket);
+
+        loop {
+            let mut req = BytePacketBuffer::new();
+
+            let (len, src) = match socket.recv_from(&mut req.buf).await {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("Failed to receive message {:?}", err);
+                    continue;
+                }
+            };
+            // Shorter than a header, there is no id to answer to
+            if len < 12 {
+                continue;
+            }
+            req.set_len(len);
+            self.receive(req, len, src, local, Reply::Udp(socket.clone()))
+                .await;
+        }
+    }
+
+    async fn listen_tcp(self, listener: TcpListener, closing: watch::Receiver<()>) {
+        let local = listener
+            .local_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, src)) => {
+                    let closing = closing.clone();
+                    tokio::spawn(self.clone().connection(stream, src, local, closing));
+                }
+                Err(err) => error!("Failed to accept connection {:?}", err),
+            }
+        }
+    }
+
+    // Length-prefixed queries, answered in the order they complete. Closed
+    // between two queries once `closing` is, the answers being written keep
+    // the write half open
+    async fn connection(
+        self,
+        stream: TcpStream,
+        src: SocketAddr,
+        local: SocketAddr,
+        mut closing: watch::Receiver<()>,
+    ) {
+        let (mut read, write) = stream.into_split();
+        let reply = Reply::Tcp(Arc::new(Mutex::new(write)));
+
+        loop {
+            let mut len = [0; 2];
+            // Closed by the client, failed, idle or shut down
+            let read_len = tokio::select! {
+                read = timeout(TCP_IDLE, read.read_exact(&mut len)) => read,
+                _ = closing.changed() => return,
+            };
+            if !matches!(read_len, Ok(Ok(_))) {
+                return;
+            }
+            let len = u16::from_be_bytes(len) as usize;
+            let mut req = BytePacketBuffer::new();
+            // Larger than a buffer or shorter than a header, the stream
+            // cannot be followed
+            if len < 12 || len > req.buf.len() {
+                return;
+            }
+            let read = timeout(TCP_IDLE, read.read_exact(&mut req.buf[..len])).await;
+            if !matches!(read, Ok(Ok(_))) {
+                return;
+            }
+            req.set_len(len);
+            self.receive(req, len, src, local, reply.clone()).await;
+        }
+    }
+
+    // Check the client of a query, then answer it in its own task. Over
+    // `max-inflight` a UDP query is dropped, a TCP one gets SERVFAIL, and
+    // likewise over the rate limit a TCP one gets REFUSED
+    async fn receive(
+        &self,
+        req: BytePacketBuffer,
+        len: usize,
+        src: SocketAddr,
+        local: SocketAddr,
+        reply: Reply,
+    ) {
+        let settings = self.settings();
+        let client = client_ip(src);
+        let allowed = is_allowed(&settings.acl, client);
+        if !allowed && settings.acl_drop {
+            return;
+        }
+        // Drop the queries over the rate limit
+        if let Some(limit) = &settings.rate_limit {
+            if !limit.check(client, Instant::now()) {
+                self.state.stats.rate_limited();
+                if let (Reply::Tcp(_), Ok(data)) = (&reply, refuse(req)) {
+                    if let Err(err) = reply.send(&data, src).await {
+                        error!("Replying to '{}' failed {:?}", &src, err);
+                    }
+                }
+                return;
+            }
+        }
+        let task = match self.state.tasks.try_start(settings.max_inflight) {
+            Some(task) => task,
+            None => {
+                self.state.stats.overload();
+                if let (Reply::Tcp(_), Ok(data)) = (&reply, servfail(&req.buf[..len])) {
+                    if let Err(err) = reply.send(&data, src).await {
+                        error!("Replying to '{}' failed {:?}", &src, err);
+                    }
+                }
+                return;
+            }
+        };
+
+        let server = self.clone();
+        let tcp = matches!(reply, Reply::Tcp(_));
+        let (start, time) = (Instant::now(), SystemTime::now());
+        tokio::spawn(async move {
+            let _task = task;
+            let question = match settings.query_log {
+                Some(_) => first_question(&req.buf[..len]),
+                None => None,
+            };
+            let tap = settings.dnstap.as_deref();
+            let query = tap.map(|tap| {
+                let query = req.buf[..len].to_vec();
+                tap.send(&Message {
+                    kind: Kind::ClientQuery,
+                    query_address: src,
+                    response_address: local,
+                    tcp,
+                    query_time: time,
+                    query: Some(&query),
+                    response_time: None,
+                    response: None,
+                });
+                query
+            });
+
+            let raw = req.buf;
+            let res = if allowed {
+                server.handle(req, len, client).await
+            } else {
+                refuse(req).map(|data| Answer::new(data, Outcome::Blocked, Some("acl".into())))
+            };
+            let (outcome, source) = outcome(&res);
+            server.state.stats.record(outcome);
+            // The address of a host record, else the upstream or the rule
+            let result = match &res {
+                Ok(answer) if outcome == Outcome::Hosts && question.is_some() => {
+                    answered_ip(&answer.data)
+                        .map(|ip| ip.to_string())
+                        .or(source)
+                }
+                _ => source,
+            };
+            let mut data = match res {
+                Ok(answer) => Some(answer.data),
+                // Fail fast instead of leaving the client to its own timeout
+                Err(err) => {
+                    error!("Processing request failed {:?}", err);
+                    servfail(&raw[..len]).ok()
+                }
+            };
+            // Larger than the client takes over UDP
+            if let (Reply::Udp(_), Some(answer)) = (&reply, &data) {
+                if answer.len() > udp_payload(&raw[..len]) {
+                    data = truncate(answer).or_else(|_| servfail(&raw[..len])).ok();
+                }
+            }
+            if let Some(data) = data {
+                if let Err(err) = reply.send(&data, src).await {
+                    error!("Replying to '{}' failed {:?}", &src, err);
+                }
+                if let Some(tap) = tap {
+                    tap.send(&Message {
+                        kind: Kind::ClientResponse,
+                        query_address: src,
+                        response_address: local,
+                        tcp,
+                        query_time: time,
+                        query: query.as_deref(),
+                        response_time: Some(SystemTime::now()),
+                        response: Some(&data),
+                    });
+                }
+            }
+
+            if let (Some(log), Some((qname, qtype))) = (&settings.query_log, question) {
+                let entry = Entry {
+                    time,
+                    client,
+                    qname,
+                    qtype,
+                    outcome,
+                    result,
+                    elapsed: start.elapsed(),
+                };
+                log.write(&entry);
+            }
+        });
+    }
+
+    // Remove idle clients from the rate limiter and report dropped queries
+    async fn clean_rate_limit(self) {
+        let mut reported = 0;
+        loop {
+            sleep(RATE_LIMIT_CLEANUP).await;
+            if let Some(limit) = &self.settings().rate_limit {
+                limit.cleanup(Instant::now());
+
+                let dropped = limit.dropped();
+                if dropped > reported {
+                    warn!("Dropped {} queries over the rate limit", dropped - reported);
+                }
+                reported = dropped;
+            }
+        }
+    }
+
+    async fn handle(
+        &self,
+        mut req: BytePacketBuffer,
+        len: usize,
+        client: IpAddr,
+    ) -> Result<Answer> {
+        let request = match DnsPacket::from_buffer(&mut req) {
+            Ok(request) => request,
+            // FORMERR when the header is there, no answer without it
+            Err(err) => {
+                info!("Malformed query {}", err);
+                let data = header_reply(&req.buf[..len], ResultCode::FORMERR)?;
+                return Ok(Answer::new(data, Outcome::Failed, Some("formerr".into())));
+            }
+        };
+        // Only standard queries
+        if request.header.opcode != 0 {
+            let data = header_reply(&req.buf[..len], ResultCode::NOTIMP)?;
+            return Ok(Answer::new(data, Outcome::Failed, Some("notimp".into())));
+        }
+        let live = self.state.live.load();
+        let settings = &live.settings;
+
+        let query = match request.questions.first() {
+            Some(q) => q,
+            None => {
+                return self
+                    .forward(&request, &req.buf[..len], client, settings)
+                    .await
+            }
+        };
+
+        info!("{} {:?}", query.name, query.qtype);
+
+        // Only the IN class
+        if query.qclass != 1 {
+            let mut data = local_reply(&req.buf[..len], &[], 0)?;
+            // RCODE 4
+            data[3] |= 4;
+            return Ok(Answer::new(data, Outcome::Failed, Some("notimp".into())));
+        }
+
+        let ttl = clamp_ttl(DEFAULT_TTL, settings.ttl.0, None);
+        if let Some((ip, pattern)) = get_answer(&live.hosts, &query.name, query.qtype) {
+            if dnssec_ok(&req.buf[..len]) {
+                warn!(
+                    "DNSSEC client gets the host record of '{}', it fails validation",
+                    query.name
+                );
+            }
+            let data = local_reply(&req.buf[..len], &[ip], ttl)?;
+            return Ok(Answer::new(data, Outcome::Hosts, Some(pattern)));
+        }
+        if let Some((suffix, policy)) = special_policy(&settings.special_domains, &query.name) {
+            if policy != SpecialPolicy::Forward {
+                let mut data = local_reply(&req.buf[..len], &[], ttl)?;
+                // NODATA for a name with a host record of another type,
+                // whatever the policy
+                if live.hosts.find(&query.name).is_none() {
+                    // RCODE 3
+                    data[3] |= 3;
+                }
+                let source = Some(format!("special-domain .{}", suffix));
+                return Ok(Answer::new(data, Outcome::Blocked, source));
+            }
+        }
+        if let Some(answer) = rpz_answer(&settings.rpz, &req.buf[..len], query, ttl)? {
+            return Ok(answer);
+        }
+        if query.qtype == QueryType::UNKNOWN(QTYPE_ANY) {
+            let data = match settings.any_policy {
+                AnyPolicy::Forward => None,
+                AnyPolicy::Refuse => {
+                    let mut data = local_reply(&req.buf[..len], &[], 0)?;
+                    // RCODE 5
+                    data[3] |= 5;
+                    Some(data)
+                }
+                AnyPolicy::Hinfo => Some(hinfo_reply(&req.buf[..len], ttl)?),
+            };
+            if let Some(data) = data {
+                let source = Some("any-policy".to_string());
+                return Ok(Answer::new(data, Outcome::Blocked, source));
+            }
+        }
+        let answer = self
+            .forward(&request, &req.buf[..len], client, settings)
+            .await?;
+        match settings.dns64 {
+            // Names with host records are never synthesized
+            Some(prefix)
+                if query.qtype == QueryType::AAAA
+                    && request.questions.len() == 1
+                    && live.hosts.find(&query.name).is_none() =>
+            {
+                self.dns64(&request, &req.buf[..len], answer, client, settings, prefix)
+                    .await
+            }
+            _ => Ok(answer),
+        }
+    }
+
+    // RFC 6147, an AAAA answer without any AAAA record gets the A records
+    // of the name embedded into the prefix. It's kept as it is when the
+    // name has no A record or the A query fails
+    async fn dns64(
+        &self,
+        request: &DnsPacket,
+        buf: &[u8],
+        answer: Answer,
+        client: IpAddr,
+        settings: &Settings,
+        prefix: Prefix,
+    ) -> Result<Answer> {
+        // Also kept when its records cannot be read
+        let ipv6 = answer_addrs(&answer.data).map(|addrs| addrs.iter().any(IpAddr::is_ipv6));
+        if answer.outcome != Outcome::Forwarded || ipv6.unwrap_or(true) {
+            return Ok(answer);
+        }
+
+        // The same query with the A type, the qtype ends the question
+        let mut query = buf.to_vec();
+        let end = BytePacketBuffer::from_bytes(buf).questions_end()?;
+        query[end - 4..end - 2].copy_from_slice(&QueryType::A.to_num().to_be_bytes());
+        let mut request = request.clone();
+        request.questions[0].qtype = QueryType::A;
+
+        let res = self.forward(&request, &query, client, settings).await;
+        let a = match res {
+            Ok(a) if a.outcome == Outcome::Forwarded => a,
+            _ => return Ok(answer),
+        };
+        match dns64_reply(buf, &a.data, prefix)? {
+            Some(data) => Ok(Answer::new(data, Outcome::Forwarded, a.source)),
+            None => Ok(answer),
+        }
+    }
+
+    async fn forward(
+        &self,
+        request: &DnsPacket,
+        buf: &[u8],
+        client: IpAddr,
+        settings: &Settings,
+    ) -> Result<Answer> {
+        // Copied only to add the client subnet
+        let query = match settings.ecs {
+            Ecs::Forward => Cow::Borrowed(buf),
+            _ => {
+                let mut query = buf.to_vec();
+                settings.ecs.apply(&mut query, client)?;
+                Cow::Owned(query)
+            }
+        };
+
+        let question = match request.questions.first() {
+            Some(question) => question,
+            None => return upstream(request, &query, settings).await,
+        };
+
+        // Identical queries share one upstream request
+        let subnet = settings.ecs.subnet(client).map(|option| option.data);
+        let key = (
+            question.name.clone(),
+            question.qtype,
+            subnet.unwrap_or_default(),
+            [dnssec_ok(buf), checking_disabled(buf)],
+        );
+        let res = self
+            .state
+            .inflight
+            .run(key.clone(), upstream(request, &query, settings))
+            .await;
+        let mut answer = match (res, settings.serve_stale) {
+            (Ok(answer), Some(window)) => {
+                self.keep_stale(key, &answer, window);
+                answer
+            }
+            (Ok(answer), None) => answer,
+            (Err(err), Some(_)) => match self.state.stale.get(&key, Instant::now()) {
+                Some(data) => {
+                    warn!("Upstreams failed, serve a stale answer of '{}'", key.0);
+                    self.refresh_stale(key, request.clone(), query.into_owned());
+                    Answer::new(data, Outcome::Stale, None)
+                }
+                None => return Err(err),
+            },
+            (Err(err), None) => return Err(err),
+        };
+
+        let data = &mut answer.data;
+        if data.len() >= 2 {
+            data[..2].copy_from_slice(&request.header.id.to_be_bytes());
+        }
+        // The shared answer carries the casing of another client
+        restore_question(data, buf);
+        Ok(answer)
+    }
+}
+
+impl Server {
+    // Keep the new answer of the upstreams, a blocked one drops the
+    // previous answer so it's never served again
+    fn keep_stale(&self, key: QueryKey, answer: &Answer, window: Duration) {
+        let stale = &self.state.stale;
+        let kept = match answer.outcome {
+            Outcome::Forwarded | Outcome::NxDomain => {
+                stale.insert(key.clone(), &answer.data, Instant::now(), window)
+            }
+            _ => Err(Error::other("Blocked answer")),
+        };
+        if kept.is_err() {
+            stale.remove(&key);
+        }
+    }
+
+    // Ask the upstreams again in the background until they answer or the
+    // stale answer is past its window
+    fn refresh_stale(&self, key: QueryKey, request: DnsPacket, query: Vec<u8>) {
+        if !self.state.stale.start_refresh(key.clone()) {
+            return;
+        }
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(STALE_REFRESH).await;
+                let settings = server.settings();
+                let window = match settings.serve_stale {
+                    Some(window) if server.state.stale.contains(&key, Instant::now()) => window,
+                    _ => break,
+                };
+                if let Ok(answer) = upstream(&request, &query, &settings).await {
+                    server.keep_stale(key.clone(), &answer, window);
+                    break;
+                }
+            }
+            server.state.stale.end_refresh(&key);
+        });
+    }
+}
+
+// Outcome and source of the query log and the stats
+// The address of the host record and its pattern
+fn get_answer(hosts: &Hosts, domain: &str, query: QueryType) -> Option<(IpAddr, String)> {
+    let (matcher, ip) = hosts.find(domain)?;
+    match answers_type(query, ip) {
+        true => Some((*ip, matcher.to_string())),
+        false => None,
+    }
+}
+
+fn outcome(res: &Result<Answer>) -> (Outcome, Option<String>) {
+    match res {
+        Ok(answer) => (answer.outcome, answer.source.clone()),
+        Err(err) if err.kind() == ErrorKind::TimedOut => (Outcome::Timeout, None),
+        Err(_) => (Outcome::Failed, None),
+    }
+}
+
+fn build_query(name: &str, qtype: QueryType) -> Result<(BytePacketBuffer, usize)> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = random() as u16;
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(name.to_string(), qtype));
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let len = buffer.pos();
+    Ok((BytePacketBuffer::from_bytes(&buffer.buf[..len]), len))
+}
+
+// IPv4 clients of a dual-stack socket arrive as v4-mapped addresses
+fn client_ip(src: SocketAddr) -> IpAddr {
+    match src.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+async fn upstream(request: &DnsPacket, query: &[u8], settings: &Settings) -> Result<Answer> {
+    let (mut data, upstream) = settings.upstream.query(query).await?;
+    let upstream = Some(upstream);
+
+    // Without readable records the answer is relayed as it is, but for the
+    // rebinding protection which fails what it cannot check
+    let readable = BytePacketBuffer::from_bytes(&data).records().is_ok();
+    if readable && is_bogus(&data, &settings.bogus_nx)? {
+        warn!("Rewrite bogus answer into NXDOMAIN");
+        let data = reply(request.clone(), ResultCode::NXDOMAIN)?;
+        return Ok(Answer::new(data, Outcome::NxDomain, upstream));
+    }
+
+    if let Some(question) = request.questions.first() {
+        if is_rebinding(&question.name, &data, &settings.rebind)? {
+            warn!("Block private address answer of '{}'", question.name);
+            let data = reply(request.clone(), ResultCode::NXDOMAIN)?;
+            return Ok(Answer::new(data, Outcome::Blocked, upstream));
+        }
+    }
+
+    let (min, max) = settings.ttl;
+    if readable && (min.is_some() || max.is_some()) {
+        clamp_records_ttl(&mut data, min, max)?;
+    }
+    // RCODE 3
+    let outcome = match data.get(3) {
+        Some(flags) if flags & 0x0F == 3 => Outcome::NxDomain,
+        _ => Outcome::Forwarded,
+    };
+    Ok(Answer::new(data, outcome, upstream))
+}
+
+// Clamp ttl into [min, max], no limit for `None`. Also used by
+// `updns test`, not part of the api
+#[doc(hidden)]
+pub fn clamp_ttl(ttl: u32, min: Option<u32>, max: Option<u32>) -> u32 {
+    let ttl = match min {
+        Some(min) => ttl.max(min),
+        None => ttl,
+    };
+    match max {
+        Some(max) => ttl.min(max),
+        None => ttl,
+    }
+}
+
+// Rewrite the ttl of every answer, authority and additional record
+fn clamp_records_ttl(data: &mut [u8], min: Option<u32>, max: Option<u32>) -> Result<()> {
+    let mut buffer = BytePacketBuffer::from_bytes(data);
+    for record in buffer.records()? {
+        // The ttl field of OPT carries EDNS flags
+        if record.qtype == QueryType::OPT {
+            continue;
+        }
+        let ttl = buffer.get_u32(record.ttl)?;
+        buffer.set_u32(record.ttl, clamp_ttl(ttl, min, max))?;
+    }
+    // The buffer holds the first `MAX_PACKET_SIZE` bytes of a TCP answer
+    let len = data.len().min(buffer.buf.len());
+    data[..len].copy_from_slice(&buffer.buf[..len]);
+
+    Ok(())
+}
+
+// Whether the upstream answers the domain with a private address,
+// `rebind` is the whitelist when the protection is enabled
+fn is_rebinding(domain: &str, data: &[u8], rebind: &Option<Vec<Matcher>>) -> Result<bool> {
+    let whitelist = match rebind {
+        Some(whitelist) => whitelist,
+        None => return Ok(false),
+    };
+    if whitelist.iter().any(|host| host.is_match(domain)) {
+        return Ok(false);
+    }
+
+    Ok(answer_addrs(data)?.into_iter().any(is_private_ip))
+}
+
+// Whether any answer record is one of the bogus addresses
+fn is_bogus(data: &[u8], bogus: &[IpAddr]) -> Result<bool> {
+    if bogus.is_empty() {
+        return Ok(false);
+    }
+
+    Ok(answer_addrs(data)?.iter().any(|ip| bogus.contains(ip)))
+}
+
+// The addresses of the A and AAAA records of the answer section, read in
+// place without decoding the names
+fn answer_addrs(data: &[u8]) -> Result<Vec<IpAddr>> {
+    let answers = match data.get(6..8) {
+        Some(count) => u16::from_be_bytes([count[0], count[1]]) as usize,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Answer is too short")),
+    };
+    let mut addrs = Vec::new();
+    for record in BytePacketBuffer::from_bytes(data)
+        .records()?
+        .into_iter()
+        .take(answers)
+    {
+        let rdata = data
+            .get(record.data..record.data + record.data_len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated record"))?;
+        match (
+            record.qtype,
+            <[u8; 4]>::try_from(rdata),
+            <[u8; 16]>::try_from(rdata),
+        ) {
+            (QueryType::A, Ok(octets), _) => addrs.push(IpAddr::from(octets)),
+            (QueryType::AAAA, _, Ok(octets)) => addrs.push(IpAddr::from(octets)),
+            _ => {}
+        }
+    }
+    Ok(addrs)
+}
+
+// Build a reply without any records, never authenticated
+fn reply(mut request: DnsPacket, rescode: ResultCode) -> Result<Vec<u8>> {
+    request.header.response = true;
+    request.header.recursion_available = true;
+    request.header.authed_data = false;
+    request.header.rescode = rescode;
+    request.answers.clear();
+    request.authorities.clear();
+    request.resources.clear();
+
+    let mut buffer = BytePacketBuffer::new();
+    request.write(&mut buffer)?;
+    Ok(buffer.buf[..buffer.pos()].to_vec())
+}
+
+fn refuse(mut req: BytePacketBuffer) -> Result<Vec<u8>> {
+    reply(DnsPacket::from_buffer(&mut req)?, ResultCode::REFUSED)
+}
+
+// The question echoed with the id of the query, RA and SERVFAIL. The
+// header alone when the question cannot be read
+fn servfail(query: &[u8]) -> Result<Vec<u8>> {
+    let mut data = match local_reply(query, &[], 0) {
+        Ok(data) => data,
+        Err(_) => return header_reply(query, ResultCode::SERVFAIL),
+    };
+    // RCODE 2
+    data[3] |= 2;
+    Ok(data)
+}
+
+// The header and question of an answer, with TC the client asks again
+// over TCP (RFC 2181 section 9)
+fn truncate(answer: &[u8]) -> Result<Vec<u8>> {
+    let end = BytePacketBuffer::from_bytes(answer).questions_end()?;
+    let mut data = match answer.get(..end) {
+        Some(data) => data.to_vec(),
+        None => return Err(Error::new(ErrorKind::InvalidData, "Answer is too short")),
+    };
+    data[2] |= 0x02;
+    // No answer, authority and additional records
+    data[6..12].copy_from_slice(&[0; 6]);
+    Ok(data)
+}
+
+// The header of the query with QR, RA and `rcode`, without any section
+fn header_reply(query: &[u8], rcode: ResultCode) -> Result<Vec<u8>> {
+    if query.len() < 12 {
+        return Err(Error::new(ErrorKind::InvalidData, "Query is too short"));
+    }
+    let mut data = query[..12].to_vec();
+    // QR, keep the opcode and RD
+    data[2] = (data[2] & 0x79) | 0x80;
+    data[3] = 0x80 | rcode as u8;
+    data[4..12].copy_from_slice(&[0; 8]);
+    Ok(data)
+}
+
+// The RFC 8482 answer to ANY queries, one HINFO record with the CPU
+// "RFC8482" and an empty OS
+fn hinfo_reply(query: &[u8], ttl: u32) -> Result<Vec<u8>> {
+    let mut data = local_reply(query, &[], ttl)?;
+    data[6..8].copy_from_slice(&1_u16.to_be_bytes());
+    let rdata = b"\x07RFC8482\x00";
+    data.extend_from_slice(&[0xC0, 0x0C]);
+    data.extend_from_slice(&QTYPE_HINFO.to_be_bytes());
+    data.extend_from_slice(&1_u16.to_be_bytes());
+    data.extend_from_slice(&ttl.to_be_bytes());
+    data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    data.extend_from_slice(rdata);
+    Ok(data)
+}
+
+// Host records answer A and AAAA queries of their address family, and
+// ANY queries of both. The other types are forwarded, a wildcard never
+// answers the NS, DS or SOA queries of a resolver minimizing the names
+// (RFC 7816). Also used by `updns test`, not part of the api
+#[doc(hidden)]
+pub fn answers_type(query: QueryType, ip: &IpAddr) -> bool {
+    matches!(
+        (query, ip),
+        (QueryType::A, IpAddr::V4(_))
+            | (QueryType::AAAA, IpAddr::V6(_))
+            | (QueryType::UNKNOWN(QTYPE_ANY), _)
+    )
+}
+
+// The answer of the first response policy zone with a trigger for the
+// name, `None` to forward the query
+fn rpz_answer(
+    zones: &[RpzZone],
+    query: &[u8],
+    question: &DnsQuestion,
+    ttl: u32,
+) -> Result<Option<Answer>> {
+    let (trigger, policy) = match zones.iter().find_map(|zone| zone.find(&question.name)) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let data = match policy {
+        RpzPolicy::Passthru => return Ok(None),
+        RpzPolicy::NxDomain => {
+            let mut data = local_reply(query, &[], ttl)?;
+            // RCODE 3
+            data[3] |= 3;
+            data
+        }
+        RpzPolicy::NoData => local_reply(query, &[], ttl)?,
+        // NODATA when none of the addresses has the queried type
+        RpzPolicy::Redirect(ips) => {
+            let ips = ips
+                .iter()
+                .copied()
+                .filter(|ip| answers_type(question.qtype, ip))
+                .collect::<Vec<_>>();
+            local_reply(query, &ips, ttl)?
+        }
+    };
+    let source = format!("rpz {}", trigger);
+    Ok(Some(Answer::new(data, Outcome::Blocked, Some(source))))
+}
+
+// The answer to `query` with the A records of `answer` embedded into
+// `prefix` as AAAA records, each with its ttl. `None` without any A record
+fn dns64_reply(query: &[u8], answer: &[u8], prefix: Prefix) -> Result<Option<Vec<u8>>> {
+    let count = match answer.get(6..8) {
+        Some(count) => u16::from_be_bytes([count[0], count[1]]) as usize,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Answer is too short")),
+    };
+    let mut buffer = BytePacketBuffer::from_bytes(answer);
+    let mut records = Vec::new();
+    for record in buffer.records()?.into_iter().take(count) {
+        let rdata = answer.get(record.data..record.data + record.data_len);
+        if let (QueryType::A, Some(&[a, b, c, d])) = (record.qtype, rdata) {
+            let ip = prefix.embed(Ipv4Addr::new(a, b, c, d));
+            records.push((ip, buffer.get_u32(record.ttl)?));
+        }
+    }
+    if records.is_empty() {
+        return Ok(None);
+    }
+
+    let mut data = local_reply(query, &[], 0)?;
+    data[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    for (ip, ttl) in records {
+        data.extend_from_slice(&[0xC0, 0x0C]);
+        data.extend_from_slice(&QueryType::AAAA.to_num().to_be_bytes());
+        data.extend_from_slice(&1_u16.to_be_bytes());
+        data.extend_from_slice(&ttl.to_be_bytes());
+        data.extend_from_slice(&16_u16.to_be_bytes());
+        data.extend_from_slice(&ip.octets());
+    }
+    Ok(Some(data))
+}
+
+// The longest special-use suffix of the name and its policy
+fn special_policy<'a>(
+    domains: &'a HashMap<String, SpecialPolicy>,
+    name: &str,
+) -> Option<(&'a str, SpecialPolicy)> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let mut suffix = name.as_str();
+    loop {
+        if let Some((suffix, policy)) = domains.get_key_value(suffix) {
+            return Some((suffix, *policy));
+        }
+        suffix = suffix.split_once('.')?.1;
+    }
+}
+
+// Answer the query with local records, the owner name of the records
+// points at the question (offset 12) so it echoes the name as asked
+fn local_reply(query: &[u8], ips: &[IpAddr], ttl: u32) -> Result<Vec<u8>> {
+    let end = BytePacketBuffer::from_bytes(query).questions_end()?;
+    if end > query.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "Query is too short"));
+    }
+
+    // A record takes at most 28 bytes
+    let mut data = Vec::with_capacity(end + ips.len() * 28);
+    data.extend_from_slice(&query[..end]);
+    // QR and RD, keep the opcode
+    data[2] = (data[2] & 0x78) | 0x81;
+    // RA and NOERROR
+    data[3] = 0x80;
+    // The answers, no authority and additional records
+    data[6..8].copy_from_slice(&(ips.len() as u16).to_be_bytes());
+    data[8..12].copy_from_slice(&[0, 0, 0, 0]);
+
+    for ip in ips {
+        let (qtype, rdata) = match ip {
+            IpAddr::V4(ip) => (QueryType::A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (QueryType::AAAA, ip.octets().to_vec()),
+        };
+        data.extend_from_slice(&[0xC0, 0x0C]);
+        data.extend_from_slice(&qtype.to_num().to_be_bytes());
+        data.extend_from_slice(&1_u16.to_be_bytes());
+        data.extend_from_slice(&ttl.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+    }
+
+    Ok(data)
+}
+
+// The DO bit of the EDNS OPT record, the client validates the answers
+// (RFC 3225)
+fn dnssec_ok(query: &[u8]) -> bool {
+    let records = match BytePacketBuffer::from_bytes(query).records() {
+        Ok(records) => records,
+        Err(_) => return false,
+    };
+    records.iter().any(|record| {
+        // Extended rcode, version, then the flags
+        record.qtype == QueryType::OPT && query.get(record.ttl + 2).is_some_and(|f| f & 0x80 != 0)
+    })
+}
+
+// The CD bit of the header, the client wants the answers not validated
+fn checking_disabled(query: &[u8]) -> bool {
+    query.get(3).is_some_and(|flags| flags & 0x10 != 0)
+}
+
+// Name and type of the first question, for the query log
+fn first_question(buf: &[u8]) -> Option<(String, QueryType)> {
+    let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(buf)).ok()?;
+    let question = packet.questions.into_iter().next()?;
+    Some((question.name, question.qtype))
+}
+
+// The address of the first A or AAAA record of an answer
+fn answered_ip(buf: &[u8]) -> Option<IpAddr> {
+    let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(buf)).ok()?;
+    packet.answers.into_iter().find_map(|record| match record {
+        DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod test_server {
+    use super::*;
+    use crate::{config::parse_test_config, stale::STALE_TTL, upstream::Static};
+    use futures_util::future::{BoxFuture, FutureExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn query(id: u16, name: &str) -> (BytePacketBuffer, usize) {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let len = buffer.pos();
+        (BytePacketBuffer::from_bytes(&buffer.buf[..len]), len)
+    }
+
+    fn client() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn test_clamp_records_ttl() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "ttl.example.com".to_string(),
+            host: "cdn.example.com".to_string(),
+            ttl: 10,
+        });
+        packet.answers.push(DnsRecord::A {
+            domain: "cdn.example.com".to_string(),
+            addr: "1.1.1.1".parse().unwrap(),
+            ttl: 300,
+        });
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns.example.com".to_string(),
+            ttl: 172800,
+        });
+        packet.resources.push(DnsRecord::AAAA {
+            domain: "ns.example.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: 0,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut data = buffer.buf[..buffer.pos()].to_vec();
+
+        clamp_records_ttl(&mut data, Some(60), Some(3600)).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(&data);
+        let ttls = buffer
+            .records()
+            .unwrap()
+            .iter()
+            .map(|record| buffer.get_u32(record.ttl).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ttls, vec![60, 300, 3600, 60]);
+    }
+
+    // Answers every A query with `addr`
+    fn static_a(addr: &'static str) -> Static<impl Fn(&[u8]) -> Result<Vec<u8>>> {
+        Static::new("static", move |query: &[u8]| {
+            let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query))?;
+            packet.header.response = true;
+            packet.answers.push(DnsRecord::A {
+                domain: packet.questions[0].name.clone(),
+                addr: addr.parse().unwrap(),
+                ttl: 60,
+            });
+            let mut res = BytePacketBuffer::new();
+            packet.write(&mut res)?;
+            Ok(res.buf[..res.pos()].to_vec())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale() {
+        // Fails with 0, else answers 1.1.1.1 or 2.2.2.2
+        let mode = Arc::new(AtomicUsize::new(1));
+        let upstream = |mode: Arc<AtomicUsize>| {
+            Static::new("static", move |query: &[u8]| {
+                let addr = match mode.load(Ordering::Relaxed) {
+                    0 => return Err(Error::new(ErrorKind::TimedOut, "Lost")),
+                    1 => [1, 1, 1, 1],
+                    _ => [2, 2, 2, 2],
+                };
+                let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query))?;
+                packet.header.response = true;
+                packet.answers.push(DnsRecord::A {
+                    domain: packet.questions[0].name.clone(),
+                    addr: addr.into(),
+                    ttl: 60,
+                });
+                let mut res = BytePacketBuffer::new();
+                packet.write(&mut res)?;
+                Ok(res.buf[..res.pos()].to_vec())
+            })
+        };
+        let server = Server::with_upstream(
+            parse_test_config("serve-stale-ttl 1h"),
+            upstream(mode.clone()),
+        );
+        let resolve = |name: &'static str| {
+            let server = server.clone();
+            async move {
+                let answer = server.resolve(name, QueryType::A).await?;
+                let packet = answer.packet()?;
+                let (addr, ttl) = match packet.answers[..] {
+                    [DnsRecord::A { addr, ttl, .. }] => (addr.to_string(), ttl),
+                    _ => panic!("{:?}", packet.answers),
+                };
+                Ok::<_, Error>((answer.outcome(), addr, ttl))
+            }
+        };
+
+        let forwarded = (Outcome::Forwarded, "1.1.1.1".to_string(), 60);
+        assert_eq!(resolve("a.com").await.unwrap(), forwarded);
+        mode.store(0, Ordering::Relaxed);
+        let stale = (Outcome::Stale, "1.1.1.1".to_string(), STALE_TTL);
+        assert_eq!(resolve("a.com").await.unwrap(), stale);
+        assert!(resolve("b.com").await.is_err());
+
+        // A new answer replaces the stale one
+        mode.store(2, Ordering::Relaxed);
+        assert_eq!(resolve("a.com").await.unwrap().1, "2.2.2.2");
+        mode.store(0, Ordering::Relaxed);
+        let stale = (Outcome::Stale, "2.2.2.2".to_string(), STALE_TTL);
+        assert_eq!(resolve("a.com").await.unwrap(), stale);
+        assert_eq!(server.stats().outcome(Outcome::Stale), 2);
+        assert_eq!(server.stats().outcome(Outcome::Timeout), 1);
+
+        // Nothing is kept without `serve-stale-ttl`
+        let mode = Arc::new(AtomicUsize::new(1));
+        let server = Server::with_upstream(Config::new(), upstream(mode.clone()));
+        server.resolve("a.com", QueryType::A).await.unwrap();
+        mode.store(0, Ordering::Relaxed);
+        assert!(server.resolve("a.com", QueryType::A).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bogus_nx() {
+        // Upstream answering every name with the ad server address
+        let server = Server::with_upstream(
+            parse_test_config("bogus-nx 2001:db8::1\nbogus-nx 198.51.100.1"),
+            static_a("198.51.100.1"),
+        );
+        let (req, len) = query(7, "missing.example.com");
+        let answer = server.handle(req, len, client()).await.unwrap();
+        assert_eq!(answer.outcome(), Outcome::NxDomain);
+        assert_eq!(answer.source(), Some("static"));
+
+        let packet = answer.packet().unwrap();
+        assert_eq!(packet.header.id, 7);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert!(packet.header.response);
+        assert_eq!(packet.header.questions, 1);
+        assert_eq!(packet.header.answers, 0);
+        assert_eq!(packet.questions[0].name, "missing.example.com");
+
+        server
+            .update(parse_test_config("bogus-nx 2001:db8::1"))
+            .await;
+        let (req, len) = query(8, "missing.example.com");
+        let answer = server.handle(req, len, client()).await.unwrap();
+        assert_eq!(answer.outcome(), Outcome::Forwarded);
+        assert_eq!(answer.packet().unwrap().answers.len(), 1);
+    }
+
+    // An A query of `example.com` with CD, and DO in its OPT record
+    fn dnssec_query() -> Vec<u8> {
+        let mut data = vec![0, 5, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 1];
+        data.extend_from_slice(b"\x07example\x03com\x00");
+        data.extend_from_slice(&[0, 1, 0, 1]);
+        // Root, OPT, payload size 4096, extended rcode, version and DO
+        data.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0]);
+        data
+    }
+
+    // The signed answer to the query: AD, the A record, its RRSIG and OPT
+    // with DO
+    fn signed_answer(query: &[u8]) -> Vec<u8> {
+        let end = BytePacketBuffer::from_bytes(query).questions_end().unwrap();
+        let mut data = query[..end].to_vec();
+        // QR, RD, RA, AD and CD
+        data[2..4].copy_from_slice(&[0x81, 0xB0]);
+        data[6..12].copy_from_slice(&[0, 2, 0, 0, 0, 1]);
+        data.extend_from_slice(&[
+            0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 1, 0x2C, 0, 4, 93, 184, 216, 34,
+        ]);
+        // Type covered, algorithm, labels, original ttl, expiration,
+        // inception, key tag, signer and signature
+        let mut rrsig = vec![0, 1, 13, 2, 0, 0, 1, 0x2C];
+        rrsig.extend_from_slice(&[0x65, 0, 0, 0, 0x64, 0, 0, 0, 0x12, 0x34]);
+        rrsig.extend_from_slice(b"\x07example\x03com\x00");
+        // Larger than a 512 bytes answer takes
+        rrsig.extend_from_slice(&[0xAB; 1024]);
+        data.extend_from_slice(&[0xC0, 0x0C, 0, 46, 0, 1, 0, 0, 1, 0x2C]);
+        data.extend_from_slice(&(rrsig.len() as u16).to_be_bytes());
+        data.extend(rrsig);
+        data.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0]);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_dnssec() {
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let upstream = |sent: Arc<std::sync::Mutex<Vec<u8>>>| {
+            Static::new("static", move |query: &[u8]| {
+                *sent.lock().unwrap() = query.to_vec();
+                Ok(signed_answer(query))
+            })
+        };
+        let handle = |server: Server, query: Vec<u8>| async move {
+            let len = query.len();
+            let req = BytePacketBuffer::from_bytes(&query);
+            server.handle(req, len, client()).await.unwrap()
+        };
+        let query = dnssec_query();
+
+        // DO and CD are forwarded, the answer is relayed byte for byte
+        let server = Server::with_upstream(Config::new(), upstream(sent.clone()));
+        let answer = handle(server, query.clone()).await;
+        assert_eq!(*sent.lock().unwrap(), query);
+        assert_eq!(answer.data(), &signed_answer(&query)[..]);
+
+        // Local answers are never authenticated, even to a query with AD
+        let mut ad = query.clone();
+        ad[3] |= 0x20;
+        let server = Server::with_upstream(
+            parse_test_config("example.com 1.1.1.1"),
+            upstream(sent.clone()),
+        );
+        let answer = handle(server, ad.clone()).await;
+        assert_eq!(answer.outcome(), Outcome::Hosts);
+        assert_eq!(answer.data()[3] & 0x20, 0);
+        let server =
+            Server::with_upstream(parse_test_config("bogus-nx 93.184.216.34"), upstream(sent));
+        let answer = handle(server, ad).await;
+        assert_eq!(answer.outcome(), Outcome::NxDomain);
+        assert!(!answer.packet().unwrap().header.authed_data);
+    }
+
+    #[tokio::test]
+    async fn test_dns64() {
+        // A records for every name, an AAAA record for `v6.com` only
+        let upstream = Static::new("static", |query: &[u8]| {
+            let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query))?;
+            packet.header.response = true;
+            let domain = packet.questions[0].name.clone();
+            match packet.questions[0].qtype {
+                QueryType::A => packet.answers.push(DnsRecord::A {
+                    domain,
+                    addr: [192, 0, 2, 33].into(),
+                    ttl: 60,
+                }),
+                QueryType::AAAA if domain == "v6.com" => packet.answers.push(DnsRecord::AAAA {
+                    domain,
+                    addr: "2001:db8::1".parse().unwrap(),
+                    ttl: 300,
+                }),
+                _ => {}
+            }
+            let mut res = BytePacketBuffer::new();
+            packet.write(&mut res)?;
+            Ok(res.buf[..res.pos()].to_vec())
+        });
+        let server = Server::with_upstream(
+            parse_test_config("dns64 64:ff9b::/96\nv4.lan 10.0.0.1"),
+            upstream,
+        );
+        let aaaa = |name: &'static str| {
+            let server = server.clone();
+            async move {
+                let answer = server.resolve(name, QueryType::AAAA).await.unwrap();
+                assert_eq!(answer.outcome(), Outcome::Forwarded);
+                answer.packet().unwrap().answers
+            }
+        };
+
+        // NODATA, synthesized from the A record with its ttl
+        let synthesized = DnsRecord::AAAA {
+            domain: "v4.com".to_string(),
+            addr: "64:ff9b::192.0.2.33".parse().unwrap(),
+            ttl: 60,
+        };
+        assert_eq!(aaaa("v4.com").await, vec![synthesized]);
+        // The native AAAA record is kept
+        let native = DnsRecord::AAAA {
+            domain: "v6.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        };
+        assert_eq!(aaaa("v6.com").await, vec![native]);
+        // A host record of the name
+        assert!(aaaa("v4.lan").await.is_empty());
+
+        server.update(Config::new()).await;
+        assert!(aaaa("v4.com").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_special_domains() {
+        let server = Server::with_upstream(
+            parse_test_config("a.local 10.0.0.1\nspecial-domain .corp hosts-only\na.corp 10.0.0.2\nspecial-domain .onion forward"),
+            static_a("1.1.1.1"),
+        );
+        let resolve = |name: &'static str, qtype: QueryType| {
+            let server = server.clone();
+            async move {
+                let answer = server.resolve(name, qtype).await.unwrap();
+                let packet = answer.packet().unwrap();
+                (
+                    answer.outcome(),
+                    packet.header.rescode,
+                    packet.answers.len(),
+                )
+            }
+        };
+
+        let nxdomain = (Outcome::Blocked, ResultCode::NXDOMAIN, 0);
+        assert_eq!(resolve("printer.local", QueryType::A).await, nxdomain);
+        assert_eq!(resolve("x.Home.Arpa", QueryType::A).await, nxdomain);
+        // Host records are still answered
+        let hosts = (Outcome::Hosts, ResultCode::NOERROR, 1);
+        assert_eq!(resolve("a.local", QueryType::A).await, hosts);
+        // NODATA for a name with a host record of another type
+        let nodata = (Outcome::Blocked, ResultCode::NOERROR, 0);
+        assert_eq!(resolve("a.local", QueryType::AAAA).await, nodata);
+        assert_eq!(resolve("a.corp", QueryType::A).await, hosts);
+        assert_eq!(resolve("a.corp", QueryType::AAAA).await, nodata);
+        assert_eq!(resolve("b.corp", QueryType::AAAA).await, nxdomain);
+
+        let forwarded = (Outcome::Forwarded, ResultCode::NOERROR, 1);
+        assert_eq!(resolve("x.onion", QueryType::A).await, forwarded);
+        assert_eq!(resolve("local.com", QueryType::A).await, forwarded);
+    }
+
+    // ANY query of `Any.com` with the id 9
+    fn any_query() -> Vec<u8> {
+        let mut data = vec![0, 9, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"\x03Any\x03com\x00");
+        data.extend_from_slice(&[0, 255, 0, 1]);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_any_policy() {
+        let any = any_query();
+        let handle = |server: Server, raw: Vec<u8>| async move {
+            let len = raw.len();
+            server
+                .handle(BytePacketBuffer::from_bytes(&raw), len, client())
+                .await
+                .unwrap()
+        };
+
+        for config in &["", "any-policy forward"] {
+            let server = Server::with_upstream(parse_test_config(config), static_a("1.1.1.1"));
+            let answer = handle(server, any.clone()).await;
+            assert_eq!(answer.outcome(), Outcome::Forwarded);
+            assert_eq!(answer.packet().unwrap().answers.len(), 1);
+        }
+
+        let server =
+            Server::with_upstream(parse_test_config("any-policy refuse"), static_a("1.1.1.1"));
+        let answer = handle(server, any.clone()).await;
+        assert_eq!(answer.outcome(), Outcome::Blocked);
+        assert_eq!(answer.source(), Some("any-policy"));
+        let mut expected = any.clone();
+        // QR, RD, RA and REFUSED
+        expected[2..4].copy_from_slice(&[0x81, 0x85]);
+        assert_eq!(answer.data, expected);
+
+        let server =
+            Server::with_upstream(parse_test_config("any-policy hinfo"), static_a("1.1.1.1"));
+        let answer = handle(server, any.clone()).await;
+        assert_eq!(answer.outcome(), Outcome::Blocked);
+        let mut expected = any.clone();
+        expected[2..4].copy_from_slice(&[0x81, 0x80]);
+        expected[6..8].copy_from_slice(&[0, 1]);
+        // HINFO IN, ttl 3600, CPU "RFC8482" and an empty OS
+        expected.extend_from_slice(&[0xC0, 0x0C, 0, 13, 0, 1, 0, 0, 0x0E, 0x10, 0, 9]);
+        expected.extend_from_slice(b"\x07RFC8482\x00");
+        assert_eq!(answer.data, expected);
+
+        // Host records answer whatever the policy, for both address families
+        for policy in &["forward", "refuse", "hinfo"] {
+            let config = format!("any-policy {}\nany.com 2.2.2.2\n*.v6.com ::2", policy);
+            let server = Server::with_upstream(parse_test_config(&config), static_a("1.1.1.1"));
+            let answer = handle(server.clone(), any.clone()).await;
+            assert_eq!(answer.outcome(), Outcome::Hosts);
+            let packet = answer.packet().unwrap();
+            assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+            assert_eq!(packet.answers.len(), 1);
+            assert!(
+                matches!(packet.answers[0], DnsRecord::A { addr, .. } if addr.to_string() == "2.2.2.2")
+            );
+
+            let mut raw = vec![0, 9, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            raw.extend_from_slice(b"\x01a\x02v6\x03com\x00\x00\xFF\x00\x01");
+            let answer = handle(server, raw).await;
+            assert_eq!(answer.outcome(), Outcome::Hosts);
+            let packet = answer.packet().unwrap();
+            assert!(
+                matches!(packet.answers[0], DnsRecord::AAAA { addr, .. } if addr.to_string() == "::2")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rebind_protection() {
+        let server = Server::with_upstream(
+            parse_test_config(
+                "rebind_protection true\nrebind_protection_whitelist *.lan.example.com",
+            ),
+            static_a("192.168.1.10"),
+        );
+        let (req, len) = query(1, "evil.example.com");
+        let answer = server.handle(req, len, client()).await.unwrap();
+        assert_eq!(answer.outcome(), Outcome::Blocked);
+        assert_eq!(
+            answer.packet().unwrap().header.rescode,
+            ResultCode::NXDOMAIN
+        );
+
+        let (req, len) = query(2, "nas.lan.example.com");
+        let answer = server.handle(req, len, client()).await.unwrap();
+        assert_eq!(answer.outcome(), Outcome::Forwarded);
+    }
+
+    #[tokio::test]
+    async fn test_rpz() {
+        let mut config = parse_test_config("walled.com 9.9.9.9");
+        let mut zone = RpzZone::new("rpz.zone".into(), 1);
+        zone.parse("*.bad.com CNAME .\nok.bad.com CNAME rpz-passthru.\nwalled.com CNAME .");
+        config.rpz.push(zone);
+        let mut zone = RpzZone::new("more.zone".into(), 2);
+        zone.parse("x.bad.com CNAME *.\ngarden.com A 10.0.0.1\ngarden.com AAAA fd00::1");
+        config.rpz.push(zone);
+        let server = Server::with_upstream(config, static_a("5.5.5.5"));
+
+        let resolve = |name: &str| {
+            let (req, len) = query(3, name);
+            let server = server.clone();
+            async move { server.handle(req, len, client()).await.unwrap() }
+        };
+
+        let answer = resolve("Ads.Bad.com").await;
+        assert_eq!(answer.outcome(), Outcome::Blocked);
+        assert_eq!(answer.source(), Some("rpz *.bad.com"));
+        let packet = answer.packet().unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(packet.header.answers, 0);
+
+        // The first zone wins over the NODATA of the second
+        let answer = resolve("x.bad.com").await;
+        assert_eq!(
+            answer.packet().unwrap().header.rescode,
+            ResultCode::NXDOMAIN
+        );
+
+        let answer = resolve("ok.bad.com").await;
+        assert_eq!(answer.outcome(), Outcome::Forwarded);
+
+        let answer = resolve("garden.com").await;
+        assert_eq!(answer.outcome(), Outcome::Blocked);
+        assert_eq!(answer.source(), Some("rpz garden.com"));
+        let packet = answer.packet().unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 1);
+
+        // Host records are answered first
+        let answer = resolve("walled.com").await;
+        assert_eq!(answer.outcome(), Outcome::Hosts);
+
+        let answer = resolve("good.com").await;
+        assert_eq!(answer.outcome(), Outcome::Forwarded);
+    }
+
+    #[test]
+    fn test_refuse() {
+        let (req, _) = query(42, "denied.example.com");
+        let res = refuse(req).unwrap();
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&res)).unwrap();
+        assert_eq!(packet.header.id, 42);
+        assert_eq!(packet.header.rescode, ResultCode::REFUSED);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn test_local_reply() {
+        let hosts = parse_test_config("*.example.com 1.2.3.4").hosts;
+        let ip = *hosts.get("foo.example.com").unwrap();
+
+        let (req, len) = query(9, "Foo.Example.com");
+        let data = local_reply(&req.buf[..len], &[ip], 60).unwrap();
+
+        // The question as asked, then the answer pointing at it
+        assert_eq!(data[..2], [0, 9]);
+        assert_eq!(data[6..12], [0, 1, 0, 0, 0, 0]);
+        assert_eq!(data[12..len], req.buf[12..len]);
+        assert_eq!(&data[12..16], b"\x03Foo");
+        assert_eq!(
+            data[len..],
+            [0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4]
+        );
+
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data)).unwrap();
+        assert!(packet.header.response);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        for (src, ip) in &[
+            ("[::ffff:192.168.1.2]:53", "192.168.1.2"),
+            ("192.168.1.2:53", "192.168.1.2"),
+            ("[fd00::1]:53", "fd00::1"),
+        ] {
+            assert_eq!(client_ip(src.parse().unwrap()).to_string(), *ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let server = Server::new(parse_test_config("*.example.com 1.2.3.4"));
+        let answer = server.resolve("a.example.com", QueryType::A).await.unwrap();
+        assert_eq!(answer.outcome(), Outcome::Hosts);
+        assert_eq!(answer.source(), Some("*.example.com"));
+        match &answer.packet().unwrap().answers[..] {
+            [DnsRecord::A { domain, addr, ttl }] => {
+                assert_eq!(domain, "a.example.com");
+                assert_eq!(addr.to_string(), "1.2.3.4");
+                assert_eq!(*ttl, DEFAULT_TTL);
+            }
+            answers => panic!("{:?}", answers),
+        }
+
+        server
+            .update(parse_test_config("*.example.com 5.6.7.8\nttl_min 7200"))
+            .await;
+        let packet = server
+            .resolve("a.example.com", QueryType::A)
+            .await
+            .unwrap()
+            .packet()
+            .unwrap();
+        assert!(matches!(
+            packet.answers[..],
+            [DnsRecord::A { addr, ttl: 7200, .. }] if addr.to_string() == "5.6.7.8"
+        ));
+        assert_eq!(server.stats().queries(), 2);
+        assert_eq!(server.stats().outcome(Outcome::Hosts), 2);
+    }
+
+    #[tokio::test]
+    async fn test_infrastructure_types() {
+        let server = Server::with_upstream(
+            parse_test_config("*.com 1.1.1.1\n~^(\\w+\\.)*example\\.org$ 2.2.2.2"),
+            static_a("9.9.9.9"),
+        );
+        // The labels asked one at a time by a minimizing resolver
+        let types = [QueryType::NS, QueryType::UNKNOWN(43), QueryType::UNKNOWN(6)];
+        for name in &["example.com", "example.org", "a.example.org"] {
+            for qtype in &types {
+                let answer = server.resolve(name, *qtype).await.unwrap();
+                assert_eq!(answer.outcome(), Outcome::Forwarded, "{} {:?}", name, qtype);
+            }
+            let answer = server.resolve(name, QueryType::A).await.unwrap();
+            assert_eq!(answer.outcome(), Outcome::Hosts, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_edit_hosts() {
+        let server = Server::new(parse_test_config("a.example.com 1.2.3.4"));
+        let before = server.hosts();
+        server.edit_hosts(|hosts| {
+            hosts.insert(
+                (Matcher::new("b.example.com").unwrap(), [5, 6, 7, 8].into()),
+                None,
+            )
+        });
+        let answer = server.resolve("b.example.com", QueryType::A).await.unwrap();
+        assert_eq!(answer.source(), Some("b.example.com"));
+        // Snapshots are left as they were
+        assert!(before.find("b.example.com").is_none());
+
+        let removed = server.edit_hosts(|hosts| hosts.remove("a.example.com").len());
+        assert_eq!(removed, 1);
+        assert_eq!(server.hosts().records().count(), 1);
+
+        // Lost on update
+        server
+            .update(parse_test_config("c.example.com 1.2.3.4"))
+            .await;
+        assert!(server.hosts().find("b.example.com").is_none());
+        assert!(server.hosts().find("c.example.com").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_serve_sockets() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Server::new(parse_test_config("a.example.com 1.2.3.4"));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_sockets(vec![socket], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (req, len) = query(5, "a.example.com");
+        client.send_to(&req.buf[..len], addr).await.unwrap();
+        let mut res = BytePacketBuffer::new();
+        timeout(Duration::from_secs(1), client.recv_from(&mut res.buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = DnsPacket::from_buffer(&mut res).unwrap();
+        assert_eq!(packet.header.id, 5);
+        assert_eq!(packet.answers.len(), 1);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert!(server.drain().await);
+        assert_eq!(server.stats().queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_truncate() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let upstream = Static::new("static", |query: &[u8]| Ok(signed_answer(query)));
+        let server = Server::with_upstream(Config::new(), upstream);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_sockets(vec![socket], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exchange = |query: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send_to(&query, addr).await.unwrap();
+                let mut res = [0; 4096];
+                let (len, _) = timeout(Duration::from_secs(1), client.recv_from(&mut res))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                res[..len].to_vec()
+            }
+        };
+
+        // Whole to a client taking 4096 bytes
+        let query = dnssec_query();
+        assert_eq!(exchange(query.clone()).await, signed_answer(&query));
+
+        // Only the question with TC to one taking 512 bytes
+        let mut plain = query[..query.len() - 11].to_vec();
+        plain[11] = 0;
+        let answer = exchange(plain.clone()).await;
+        assert_eq!(answer.len(), plain.len());
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&answer)).unwrap();
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.questions[0].name, "example.com");
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_log() {
+        let path = std::env::temp_dir().join(format!("updns-queries-{}", std::process::id()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Server::new(parse_test_config(&format!(
+            "log_queries true\nlog_file {}\na.example.com 1.2.3.4",
+            path.display()
+        )));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_sockets(vec![socket], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (req, len) = query(5, "a.example.com");
+        client.send_to(&req.buf[..len], addr).await.unwrap();
+        let mut res = [0; 512];
+        timeout(Duration::from_secs(1), client.recv_from(&mut res))
+            .await
+            .unwrap()
+            .unwrap();
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert!(server.drain().await);
+
+        // The client address without its port and the answered address
+        let log = std::fs::read_to_string(&path).unwrap();
+        let fields = log.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(fields[1..5], ["127.0.0.1", "a.example.com", "A", "1.2.3.4"]);
+        assert!(fields[5].parse::<f64>().is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Length-prefixed over a TCP connection
+    async fn tcp_query(stream: &mut TcpStream, id: u16, name: &str) -> DnsPacket {
+        let (req, len) = query(id, name);
+        let len_prefix = (len as u16).to_be_bytes();
+        stream
+            .write_all(&[&len_prefix[..], &req.buf[..len]].concat())
+            .await
+            .unwrap();
+        let mut len = [0; 2];
+        timeout(Duration::from_secs(1), stream.read_exact(&mut len))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut res = BytePacketBuffer::new();
+        let len = u16::from_be_bytes(len) as usize;
+        stream.read_exact(&mut res.buf[..len]).await.unwrap();
+        DnsPacket::from_buffer(&mut res).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(parse_test_config("a.example.com 1.2.3.4"));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_listeners(Vec::new(), vec![listener], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        // Several queries on one connection
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for id in 1..3 {
+            let packet = tcp_query(&mut stream, id, "a.example.com").await;
+            assert_eq!(packet.header.id, id);
+            assert_eq!(packet.answers.len(), 1);
+        }
+
+        // A length past the buffer closes the connection
+        stream.write_all(&[0xff, 0xff]).await.unwrap();
+        let mut rest = Vec::new();
+        let closed = timeout(Duration::from_secs(1), stream.read_to_end(&mut rest)).await;
+        assert!(matches!(closed, Ok(Ok(0))));
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert!(server.drain().await);
+        assert_eq!(server.stats().queries(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(parse_test_config("a.example.com 1.2.3.4"));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_listeners(Vec::new(), vec![listener], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        tcp_query(&mut stream, 1, "a.example.com").await;
+
+        // The idle connection is closed well before `TCP_IDLE`
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        let mut rest = Vec::new();
+        let closed = timeout(Duration::from_secs(1), stream.read_to_end(&mut rest)).await;
+        assert!(matches!(closed, Ok(Ok(0))));
+        assert!(server.drain().await);
+        assert_eq!(server.stats().queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(parse_test_config("rate-limit 1 1\na.example.com 1.2.3.4"));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_listeners(Vec::new(), vec![listener], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        // Refused rather than left to time out
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let packet = tcp_query(&mut stream, 1, "a.example.com").await;
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        let packet = tcp_query(&mut stream, 2, "a.example.com").await;
+        assert_eq!(packet.header.id, 2);
+        assert_eq!(packet.header.rescode, ResultCode::REFUSED);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_protocol() {
+        // A port free over UDP and TCP
+        let port = loop {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = socket.local_addr().unwrap().port();
+            if TcpListener::bind(("127.0.0.1", port)).await.is_ok() {
+                break port;
+            }
+        };
+        let server = Server::new(parse_test_config(&format!(
+            "bind udp://127.0.0.1:{}\na.example.com 1.2.3.4",
+            port
+        )));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve(async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (req, len) = query(5, "a.example.com");
+        client
+            .send_to(&req.buf[..len], ("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut res = BytePacketBuffer::new();
+        timeout(Duration::from_secs(1), client.recv_from(&mut res.buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(DnsPacket::from_buffer(&mut res).unwrap().header.id, 5);
+
+        // Nothing accepts connections on the port
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_servfail() {
+        // Never answers
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = format!(
+            "proxy {}\ntimeout 200ms\nretries 1",
+            upstream.local_addr().unwrap()
+        );
+        let server = Server::new(parse_test_config(&config));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_sockets(vec![socket], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (req, len) = query(7, "Lost.example.com");
+        client.send_to(&req.buf[..len], addr).await.unwrap();
+        let mut res = BytePacketBuffer::new();
+        let (n, _) = timeout(Duration::from_secs(1), client.recv_from(&mut res.buf))
+            .await
+            .unwrap()
+            .unwrap();
+        // The question as sent, with its case
+        let mut expected = req.buf[..len].to_vec();
+        // QR, RD, RA and SERVFAIL
+        expected[2..4].copy_from_slice(&[0x81, 0x82]);
+        assert_eq!(res.buf[..n], expected[..]);
+        let packet = DnsPacket::from_buffer(&mut res).unwrap();
+        assert_eq!(packet.header.id, 7);
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+        assert!(packet.header.response && packet.header.recursion_available);
+        assert!(packet.answers.is_empty());
+
+        // Exactly one response
+        let more = timeout(Duration::from_millis(300), client.recv_from(&mut res.buf)).await;
+        assert!(more.is_err());
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert_eq!(server.stats().outcome(Outcome::Timeout), 1);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_query() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Server::with_upstream(Config::new(), static_a("1.1.1.1"));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_sockets(vec![socket], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = |query: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send_to(&query, addr).await.unwrap();
+                let mut res = [0; 512];
+                let (n, _) = timeout(Duration::from_secs(1), client.recv_from(&mut res))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                res[..n].to_vec()
+            }
+        };
+
+        // Dropped without an answer, shorter than a header
+        client.send_to(&[0, 1, 0x01], addr).await.unwrap();
+
+        // FORMERR, the question ends early
+        let mut query = vec![0, 2, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x03abc");
+        assert_eq!(
+            ask(query).await,
+            vec![0, 2, 0x81, 0x81, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // NOTIMP, a STATUS query keeps its opcode
+        let query = vec![0, 3, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            ask(query).await,
+            vec![0, 3, 0x91, 0x84, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // NOTIMP, the CH class with its question
+        let mut query = vec![0, 4, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07version\x04bind\x00\x00\x10\x00\x03");
+        let mut expected = query.clone();
+        expected[2..4].copy_from_slice(&[0x81, 0x84]);
+        assert_eq!(ask(query).await, expected);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert_eq!(server.stats().outcome(Outcome::Failed), 3);
+    }
+
+    // Echoes the query as the answer after a delay, counting the queries
+    struct Slow {
+        count: AtomicUsize,
+    }
+
+    impl Upstream for Slow {
+        fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+            async move {
+                self.count.fetch_add(1, Ordering::SeqCst);
+                sleep(Duration::from_millis(300)).await;
+                let mut answer = query.to_vec();
+                answer[2] |= 0x80;
+                Ok((answer, "slow".to_string()))
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_upstream() {
+        let upstream = Arc::new(Slow {
+            count: AtomicUsize::new(0),
+        });
+        let server = Server::with_upstream(Config::new(), upstream.clone());
+
+        let tasks = (0..100)
+            .map(|id| {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let (req, len) = query(id, "coalesce.example.com");
+                    (id, server.handle(req, len, client()).await)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            let (id, res) = task.await.unwrap();
+            let answer = res.unwrap();
+            assert_eq!(answer.outcome, Outcome::Forwarded);
+            let packet = answer.packet().unwrap();
+            assert_eq!(packet.header.id, id);
+            assert!(packet.header.response);
+            assert_eq!(packet.questions[0].name, "coalesce.example.com");
+        }
+        assert_eq!(upstream.count.load(Ordering::SeqCst), 1);
+    }
+
+    // A flood against a slow upstream never has more than `max-inflight`
+    // queries waiting
+    #[tokio::test]
+    async fn test_max_inflight() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (udp, tcp) = (socket.local_addr().unwrap(), listener.local_addr().unwrap());
+        let upstream = Slow {
+            count: AtomicUsize::new(0),
+        };
+        let server = Server::with_upstream(parse_test_config("max-inflight 8"), upstream);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .serve_listeners(vec![socket], vec![listener], async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 0..1000 {
+            let (req, len) = query(id, &format!("{}.flood.example.com", id));
+            client.send_to(&req.buf[..len], udp).await.unwrap();
+            assert!(server.inflight() <= 8);
+        }
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.inflight(), 8);
+
+        // Answered with SERVFAIL over TCP
+        let mut stream = TcpStream::connect(tcp).await.unwrap();
+        let packet = tcp_query(&mut stream, 1, "tcp.example.com").await;
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+
+        // Only the queries let in are answered
+        let mut res = [0; 512];
+        for _ in 0..8 {
+            timeout(Duration::from_secs(1), client.recv_from(&mut res))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(
+            timeout(Duration::from_millis(100), client.recv_from(&mut res))
+                .await
+                .is_err()
+        );
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+        assert!(server.drain().await);
+        assert_eq!(server.inflight(), 0);
+        assert_eq!(server.stats().queries(), 8);
+        // Some of the flood may be lost before the socket
+        assert!(server.stats().overloaded() > 0);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{run, shutdown, SERVER};
+use lazy_static::lazy_static;
+use std::{
+    env,
+    ffi::{c_void, OsStr},
+    fs::{self, OpenOptions},
+    io::{Error, Result},
+    os::windows::{ffi::OsStrExt, io::IntoRawHandle},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicIsize, AtomicU32, Ordering},
+        mpsc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::runtime::Handle;
+use updns::remote::RemoteImports;
+
+const NAME: &str = "updns";
+const DISPLAY_NAME: &str = "updns DNS proxy";
+
+const SC_MANAGER_CREATE_SERVICE: u32 = 0x0002;
+const SERVICE_ALL_ACCESS: u32 = 0xF01FF;
+const DELETE: u32 = 0x10000;
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_AUTO_START: u32 = 0x2;
+const SERVICE_ERROR_NORMAL: u32 = 0x1;
+
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_FAILED_SERVICE_CONTROLLER_CONNECT: i32 = 1063;
+
+const EVENTLOG_ERROR_TYPE: u16 = 0x1;
+const EVENTLOG_WARNING_TYPE: u16 = 0x2;
+const EVENTLOG_INFORMATION_TYPE: u16 = 0x4;
+
+const STD_OUTPUT_HANDLE: u32 = -11_i32 as u32;
+
+type ServiceMain = unsafe extern "system" fn(u32, *mut *mut u16);
+type HandlerEx = unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *const u16,
+    service_proc: Option<ServiceMain>,
+}
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: HandlerEx,
+        ctx: *mut c_void,
+    ) -> isize;
+    fn SetServiceStatus(handle: isize, status: *const ServiceStatus) -> i32;
+    fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> isize;
+    fn CreateServiceW(
+        manager: isize,
+        name: *const u16,
+        display_name: *const u16,
+        access: u32,
+        service_type: u32,
+        start_type: u32,
+        error_control: u32,
+        binary_path: *const u16,
+        load_order_group: *const u16,
+        tag_id: *mut u32,
+        dependencies: *const u16,
+        start_name: *const u16,
+        password: *const u16,
+    ) -> isize;
+    fn OpenServiceW(manager: isize, name: *const u16, access: u32) -> isize;
+    fn DeleteService(service: isize) -> i32;
+    fn CloseServiceHandle(handle: isize) -> i32;
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> isize;
+    fn ReportEventW(
+        source: isize,
+        kind: u16,
+        category: u16,
+        event_id: u32,
+        sid: *mut c_void,
+        num_strings: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *mut c_void,
+    ) -> i32;
+    fn DeregisterEventSource(source: isize) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn SetStdHandle(std_handle: u32, handle: *mut c_void) -> i32;
+}
+
+// Arguments of the service entry, set before the dispatcher starts it
+struct Args {
+    path: PathBuf,
+    duration: Duration,
+    remote: RemoteImports,
+    runtime: Handle,
+}
+
+lazy_static! {
+    static ref ARGS: Mutex<Option<Args>> = Mutex::new(None);
+}
+
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+static CHECK_POINT: AtomicU32 = AtomicU32::new(0);
+
+// Closed when dropped
+struct ScHandle(isize);
+
+impl ScHandle {
+    fn new(handle: isize) -> Result<ScHandle> {
+        match handle {
+            0 => Err(Error::last_os_error()),
+            h => Ok(ScHandle(h)),
+        }
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+fn wide<S: AsRef<OsStr>>(s: S) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+// %ProgramData%\updns\config, the service runs as LocalSystem
+pub fn default_path() -> PathBuf {
+    env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+        .join("updns")
+        .join("config")
+}
+
+// Register the service to start with the system,
+// `args` are passed to `updns service run`
+pub async fn install(path: &Path, args: &str) -> Result<()> {
+    if !path.exists() {
+        crate::init::init(path, false).await?;
+    }
+    let exe = env::current_exe()?;
+    let command = format!(
+        "\"{}\" -c \"{}\" {}service run",
+        exe.display(),
+        path.display(),
+        args
+    );
+
+    let manager = ScHandle::new(unsafe {
+        OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CREATE_SERVICE)
+    })?;
+    ScHandle::new(unsafe {
+        CreateServiceW(
+            manager.0,
+            wide(NAME).as_ptr(),
+            wide(DISPLAY_NAME).as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            wide(command).as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        )
+    })?;
+    Ok(())
+}
+
+pub fn uninstall() -> Result<()> {
+    let manager = ScHandle::new(unsafe {
+        OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CREATE_SERVICE)
+    })?;
+    let service = ScHandle::new(unsafe { OpenServiceW(manager.0, wide(NAME).as_ptr(), DELETE) })?;
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Hand the main thread to the service control manager,
+// returns once the service is stopped
+pub fn dispatch(path: PathBuf, duration: Duration, remote: RemoteImports) -> Result<()> {
+    *ARGS.lock().unwrap() = Some(Args {
+        path,
+        duration,
+        remote,
+        runtime: Handle::current(),
+    });
+
+    let name = wide(NAME);
+    let table = [
+        ServiceTableEntry {
+            name: name.as_ptr(),
+            service_proc: Some(service_main),
+        },
+        ServiceTableEntry {
+            name: ptr::null(),
+            service_proc: None,
+        },
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT) {
+            return Err(Error::other(
+                "Only the service control manager starts 'service run', run 'updns' in a console",
+            ));
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let args = match ARGS.lock().unwrap().take() {
+        Some(args) => args,
+        None => return,
+    };
+
+    let handle = RegisterServiceCtrlHandlerExW(wide(NAME).as_ptr(), control, ptr::null_mut());
+    if handle == 0 {
+        event_log(
+            EVENTLOG_ERROR_TYPE,
+            &format!(
+                "Failed to register the service\n{:?}",
+                Error::last_os_error()
+            ),
+        );
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+    set_state(SERVICE_START_PENDING);
+
+    // There is no console, the logs are written next to the config
+    let log = args.path.with_file_name("updns.log");
+    if let Err(err) = redirect_stdout(&log) {
+        event_log(
+            EVENTLOG_WARNING_TYPE,
+            &format!("Failed to open the log file {:?}\n{:?}", log, err),
+        );
+    }
+
+    // The main thread is blocked in the dispatcher, serve on the runtime workers
+    let config = args.path.display().to_string();
+    let (done, stopped) = mpsc::channel();
+    args.runtime.spawn(async move {
+        run(args.path, args.duration, args.remote, move || {
+            set_state(SERVICE_RUNNING);
+            event_log(
+                EVENTLOG_INFORMATION_TYPE,
+                &format!("Started with the config {}", config),
+            );
+        })
+        .await;
+        let _ = done.send(());
+    });
+    let _ = stopped.recv();
+
+    event_log(EVENTLOG_INFORMATION_TYPE, "Stopped");
+    set_state(SERVICE_STOPPED);
+}
+
+// Stop and shutdown drain the pending queries like SIGTERM
+unsafe extern "system" fn control(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_state(SERVICE_STOP_PENDING);
+            shutdown::stop();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn set_state(state: u32) {
+    let (controls_accepted, check_point, wait_hint) = match state {
+        SERVICE_RUNNING => (SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN, 0, 0),
+        SERVICE_START_PENDING | SERVICE_STOP_PENDING => {
+            // Longer than the shutdown grace time
+            let grace = SERVER.shutdown_grace();
+            let hint = (grace + Duration::from_secs(5)).as_millis() as u32;
+            (0, CHECK_POINT.fetch_add(1, Ordering::SeqCst) + 1, hint)
+        }
+        _ => (0, 0, 0),
+    };
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        win32_exit_code: NO_ERROR,
+        service_specific_exit_code: 0,
+        check_point,
+        wait_hint,
+    };
+    unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &status) };
+}
+
+fn event_log(kind: u16, message: &str) {
+    unsafe {
+        let source = RegisterEventSourceW(ptr::null(), wide(NAME).as_ptr());
+        if source == 0 {
+            return;
+        }
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        ReportEventW(
+            source,
+            kind,
+            0,
+            0,
+            ptr::null_mut(),
+            1,
+            0,
+            strings.as_ptr(),
+            ptr::null_mut(),
+        );
+        DeregisterEventSource(source);
+    }
+}
+
+// The logs are printed to stdout, which is looked up on every write
+fn redirect_stdout(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    if unsafe { SetStdHandle(STD_OUTPUT_HANDLE, file.into_raw_handle()) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use tokio::io::Result;
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::{
+        os::unix::{io::IntoRawFd, net},
+        sync::atomic::{AtomicI32, Ordering},
+    };
+    use tokio::{io::AsyncReadExt, net::UnixStream};
+
+    // Write ends of the self-pipes, the handler may only use signal safe calls
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    static HANGUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let fd = match signal {
+            libc::SIGHUP => HANGUP_PIPE.load(Ordering::Relaxed),
+            _ => PIPE.load(Ordering::Relaxed),
+        };
+        if fd >= 0 {
+            unsafe { libc::write(fd, [1_u8].as_ptr() as *const libc::c_void, 1) };
+        }
+    }
+
+    // SIGTERM and SIGINT, or SIGHUP
+    pub struct Signal(UnixStream);
+
+    impl Signal {
+        pub fn new() -> Result<Signal> {
+            Signal::handle(&PIPE, &[libc::SIGTERM, libc::SIGINT])
+        }
+
+        // Reopen the log files, sent by logrotate
+        pub fn hangup() -> Result<Signal> {
+            Signal::handle(&HANGUP_PIPE, &[libc::SIGHUP])
+        }
+
+        fn handle(pipe: &AtomicI32, signals: &[libc::c_int]) -> Result<Signal> {
+            let (read, write) = net::UnixStream::pair()?;
+            read.set_nonblocking(true)?;
+            write.set_nonblocking(true)?;
+            pipe.store(write.into_raw_fd(), Ordering::Relaxed);
+
+            let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            for signal in signals {
+                unsafe { libc::signal(*signal, handler) };
+            }
+            Ok(Signal(UnixStream::from_std(read)?))
+        }
+
+        pub async fn recv(&mut self) -> Result<()> {
+            self.0.read_exact(&mut [0]).await?;
+            Ok(())
+        }
+    }
+}
+
+// Outside of unix the Windows service control manager stops the server,
+// and Ctrl-C or Ctrl-Break when running in a console
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+    use lazy_static::lazy_static;
+    use tokio::sync::Notify;
+
+    lazy_static! {
+        static ref STOP: Notify = Notify::new();
+    }
+
+    #[cfg(windows)]
+    const CTRL_C_EVENT: u32 = 0;
+    #[cfg(windows)]
+    const CTRL_BREAK_EVENT: u32 = 1;
+
+    #[cfg(windows)]
+    type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    #[cfg(windows)]
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+
+    // Called on its own thread, TRUE keeps the process alive until the
+    // server has stopped
+    #[cfg(windows)]
+    unsafe extern "system" fn on_console(event: u32) -> i32 {
+        match event {
+            CTRL_C_EVENT | CTRL_BREAK_EVENT => {
+                stop();
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    // Whether it's the stop signal, there is no SIGHUP
+    pub struct Signal(bool);
+
+    impl Signal {
+        pub fn new() -> Result<Signal> {
+            #[cfg(windows)]
+            if unsafe { SetConsoleCtrlHandler(Some(on_console), 1) } == 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Signal(true))
+        }
+
+        pub fn hangup() -> Result<Signal> {
+            Ok(Signal(false))
+        }
+
+        pub async fn recv(&mut self) -> Result<()> {
+            if !self.0 {
+                std::future::pending::<()>().await;
+            }
+            STOP.notified().await;
+            Ok(())
+        }
+    }
+
+    // Same as SIGTERM, may be called from any thread, the console handler too
+    pub fn stop() {
+        STOP.notify_one();
+    }
+}
+
+#[cfg(not(unix))]
+pub use imp::stop;
+pub use imp::Signal;
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hangup() {
+        let mut hangup = Signal::hangup().unwrap();
+        unsafe { libc::raise(libc::SIGHUP) };
+        timeout(Duration::from_secs(1), hangup.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_signal() {
+        let mut signal = Signal::new().unwrap();
+        unsafe { libc::raise(libc::SIGTERM) };
+        timeout(Duration::from_secs(1), signal.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
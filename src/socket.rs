@@ -0,0 +1,278 @@
+use std::net::{self, SocketAddr};
+use tokio::{
+    io::Result,
+    net::{TcpListener, UdpSocket},
+};
+
+// Several sockets can share an address only with SO_REUSEPORT
+pub const REUSE_PORT: bool = cfg!(unix);
+
+#[derive(Debug, Default, Clone)]
+pub struct BindOptions {
+    // IPV6_V6ONLY of IPv6 sockets
+    pub v6only: bool,
+    // SO_REUSEPORT, lets the kernel spread the queries over the sockets
+    pub reuse_port: bool,
+    // SO_BINDTODEVICE, only receive from this interface
+    pub device: Option<String>,
+}
+
+pub fn bind_udp(addr: SocketAddr, options: &BindOptions) -> Result<UdpSocket> {
+    let socket = bind_std::<net::UdpSocket>(addr, options)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+pub fn bind_tcp(addr: SocketAddr, options: &BindOptions) -> Result<TcpListener> {
+    let listener = bind_std::<net::TcpListener>(addr, options)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+// A bound UDP socket, or a TCP socket listening
+#[cfg(unix)]
+trait Kind: std::os::unix::io::FromRawFd {
+    const TYPE: libc::c_int;
+}
+
+#[cfg(unix)]
+impl Kind for net::UdpSocket {
+    const TYPE: libc::c_int = libc::SOCK_DGRAM;
+}
+
+#[cfg(unix)]
+impl Kind for net::TcpListener {
+    const TYPE: libc::c_int = libc::SOCK_STREAM;
+}
+
+#[cfg(unix)]
+fn bind_std<S: Kind>(addr: SocketAddr, options: &BindOptions) -> Result<S> {
+    use std::{io::Error, mem};
+
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, S::TYPE, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Closes the descriptor on error
+    let socket = unsafe { S::from_raw_fd(fd) };
+
+    if addr.is_ipv6() {
+        set_option(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            options.v6only as libc::c_int,
+        )?;
+    }
+    // Restarts don't wait for the connections in TIME_WAIT
+    if S::TYPE == libc::SOCK_STREAM {
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    }
+    if options.reuse_port {
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    }
+    if let Some(device) = &options.device {
+        bind_device(fd, device)?;
+    }
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = addr.port().to_be();
+            sockaddr.sin6_flowinfo = addr.flowinfo();
+            sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr.sin6_scope_id = addr.scope_id();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    if S::TYPE == libc::SOCK_STREAM && unsafe { libc::listen(fd, 1024) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn set_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(fd: libc::c_int, device: &str) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn bind_device(_fd: libc::c_int, _device: &str) -> Result<()> {
+    Err(unsupported_device())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unsupported_device() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Binding to a device is only supported on Linux",
+    )
+}
+
+// No socket options outside of unix, the system defaults are kept
+#[cfg(not(unix))]
+trait Kind: Sized {
+    fn bind(addr: SocketAddr) -> Result<Self>;
+}
+
+#[cfg(not(unix))]
+impl Kind for net::UdpSocket {
+    fn bind(addr: SocketAddr) -> Result<Self> {
+        net::UdpSocket::bind(addr)
+    }
+}
+
+#[cfg(not(unix))]
+impl Kind for net::TcpListener {
+    fn bind(addr: SocketAddr) -> Result<Self> {
+        net::TcpListener::bind(addr)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_std<S: Kind>(addr: SocketAddr, options: &BindOptions) -> Result<S> {
+    if options.device.is_some() {
+        return Err(unsupported_device());
+    }
+    S::bind(addr)
+}
+
+#[cfg(test)]
+mod test_socket {
+    use super::*;
+
+    fn options(v6only: bool, reuse_port: bool) -> BindOptions {
+        BindOptions {
+            v6only,
+            reuse_port,
+            device: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let socket = bind_udp("[::]:0".parse().unwrap(), &options(false, false)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"v4", ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0; 8];
+        let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"v4");
+        assert_eq!(src.ip().to_string(), "::ffff:127.0.0.1");
+
+        // Replies to the mapped address reach the IPv4 client
+        socket.send_to(b"ok", src).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ok");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_tcp() {
+        let listener = bind_tcp("[::]:0".parse().unwrap(), &options(false, false)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = tokio::net::TcpStream::connect(("127.0.0.1", port));
+        let (accepted, connected) = tokio::join!(listener.accept(), client);
+        connected.unwrap();
+        let (_, src) = accepted.unwrap();
+        assert_eq!(src.ip().to_string(), "::ffff:127.0.0.1");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_v6only() {
+        let socket = bind_udp("[::]:0".parse().unwrap(), &options(true, false)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        // The IPv4 port is still free
+        let v4 = bind_udp(
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            &options(true, false),
+        );
+        assert!(v4.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), &options(true, true)).unwrap();
+        let addr = first.local_addr().unwrap();
+
+        assert!(bind_udp(addr, &options(true, true)).is_ok());
+        assert!(bind_udp(addr, &options(true, false)).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_device() {
+        let mut options = options(true, false);
+        options.device = Some("updns-none0".to_string());
+        assert!(bind_udp("127.0.0.1:0".parse().unwrap(), &options).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{BytePacketBuffer, QueryType};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::Result;
+
+// Ttl of the records of a stale answer, as suggested by RFC 8767
+pub const STALE_TTL: u32 = 30;
+// Inserts between the removals of the entries past their window
+const CLEANUP_EVERY: usize = 1024;
+
+// The last answer of the upstreams to each query, kept a window past its
+// ttl to answer when the upstreams fail (RFC 8767 serve-stale)
+pub struct Stale<K> {
+    entries: Mutex<HashMap<K, Entry>>,
+    inserts: AtomicUsize,
+    // Keys asked again in the background
+    refreshing: Mutex<HashSet<K>>,
+}
+
+struct Entry {
+    data: Vec<u8>,
+    // End of the window
+    until: Instant,
+}
+
+impl<K: Hash + Eq + Clone> Stale<K> {
+    pub fn new() -> Self {
+        Stale {
+            entries: Mutex::new(HashMap::new()),
+            inserts: AtomicUsize::new(0),
+            refreshing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Replace the answer of the key, kept `window` past its smallest ttl
+    pub fn insert(&self, key: K, data: &[u8], now: Instant, window: Duration) -> Result<()> {
+        let expires = now + Duration::from_secs(min_ttl(data)? as u64);
+        let mut entries = self.entries.lock().unwrap();
+        if self.inserts.fetch_add(1, Ordering::Relaxed) % CLEANUP_EVERY == CLEANUP_EVERY - 1 {
+            entries.retain(|_, entry| entry.until >= now);
+        }
+        entries.insert(
+            key,
+            Entry {
+                data: data.to_vec(),
+                until: expires + window,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    // Whether an answer is kept for the key
+    pub fn contains(&self, key: &K, now: Instant) -> bool {
+        matches!(self.entries.lock().unwrap().get(key), Some(entry) if entry.until >= now)
+    }
+
+    // The kept answer with every ttl at `STALE_TTL`, also before it expires
+    // since the upstreams just failed. `None` past the window
+    pub fn get(&self, key: &K, now: Instant) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.until < now {
+            entries.remove(key);
+            return None;
+        }
+        let mut data = entry.data.clone();
+        set_ttl(&mut data, STALE_TTL).ok()?;
+        Some(data)
+    }
+
+    // False while the key is being refreshed, `end_refresh` once done
+    pub fn start_refresh(&self, key: K) -> bool {
+        self.refreshing.lock().unwrap().insert(key)
+    }
+
+    pub fn end_refresh(&self, key: &K) {
+        self.refreshing.lock().unwrap().remove(key);
+    }
+}
+
+// Of the records, 0 without any. The ttl field of OPT carries EDNS flags
+fn min_ttl(data: &[u8]) -> Result<u32> {
+    let mut buffer = BytePacketBuffer::from_bytes(data);
+    let mut ttl = None;
+    for record in buffer.records()? {
+        if record.qtype != QueryType::OPT {
+            let record = buffer.get_u32(record.ttl)?;
+            ttl = Some(ttl.map_or(record, |ttl: u32| ttl.min(record)));
+        }
+    }
+    Ok(ttl.unwrap_or(0))
+}
+
+fn set_ttl(data: &mut [u8], ttl: u32) -> Result<()> {
+    let mut buffer = BytePacketBuffer::from_bytes(data);
+    for record in buffer.records()? {
+        if record.qtype != QueryType::OPT {
+            buffer.set_u32(record.ttl, ttl)?;
+        }
+    }
+    let len = data.len().min(buffer.buf.len());
+    data[..len].copy_from_slice(&buffer.buf[..len]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_stale {
+    use super::*;
+    use crate::{DnsPacket, DnsQuestion, DnsRecord};
+
+    fn answer(ttls: &[u32]) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("a.com".to_string(), QueryType::A));
+        for ttl in ttls {
+            packet.answers.push(DnsRecord::A {
+                domain: "a.com".to_string(),
+                addr: [1, 1, 1, 1].into(),
+                ttl: *ttl,
+            });
+        }
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos()].to_vec()
+    }
+
+    #[test]
+    fn test_window() {
+        let stale = Stale::new();
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        stale
+            .insert("a", &answer(&[300, 60]), now, secs(10))
+            .unwrap();
+
+        // Kept until the smallest ttl and the window are over
+        assert_eq!(stale.get(&"a", now + secs(69)), Some(answer(&[30, 30])));
+        assert!(stale.contains(&"a", now + secs(70)));
+        assert_eq!(stale.get(&"a", now + secs(71)), None);
+        assert!(!stale.contains(&"a", now));
+
+        assert!(stale.start_refresh("a"));
+        assert!(!stale.start_refresh("a"));
+        stale.end_refresh(&"a");
+        assert!(stale.start_refresh("a"));
+    }
+}
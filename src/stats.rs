@@ -0,0 +1,63 @@
+use crate::querylog::Outcome;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters of the queries answered by a server
+pub struct Stats {
+    queries: AtomicU64,
+    outcomes: [AtomicU64; 7],
+    rate_limited: AtomicU64,
+    overloaded: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) const fn new() -> Stats {
+        Stats {
+            queries: AtomicU64::new(0),
+            outcomes: [const { AtomicU64::new(0) }; 7],
+            rate_limited: AtomicU64::new(0),
+            overloaded: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, outcome: Outcome) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.outcomes[outcome as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn overload(&self) {
+        self.overloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn outcome(&self, outcome: Outcome) -> u64 {
+        self.outcomes[outcome as usize].load(Ordering::Relaxed)
+    }
+
+    // Queries dropped over the rate limit, not counted in `queries`
+    pub fn dropped(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    // Queries over `max-inflight`, not counted in `queries`
+    pub fn overloaded(&self) -> u64 {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    // `12 queries, hosts 3, forwarded 9, ..., rate limited 0, overloaded 0`
+    pub fn summary(&self) -> String {
+        let mut out = format!("{} queries", self.queries());
+        for outcome in Outcome::ALL.iter() {
+            out += &format!(", {} {}", outcome.as_str(), self.outcome(*outcome));
+        }
+        out += &format!(", rate limited {}", self.dropped());
+        out += &format!(", overloaded {}", self.overloaded());
+        out
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+// A value replaced as a whole. Readers take a snapshot, the lock is only
+// held to clone the `Arc`, so a new value is built without stalling them
+pub struct Swap<T> {
+    current: RwLock<Arc<T>>,
+    // Writers one at a time, an edit isn't lost to a concurrent one
+    writer: Mutex<()>,
+}
+
+impl<T> Swap<T> {
+    pub fn new(value: T) -> Self {
+        Swap {
+            current: RwLock::new(Arc::new(value)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        let _writer = self.writer.lock().unwrap();
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+
+    // Edit a copy of the current value, then swap it in
+    pub fn update<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let mut value = T::clone(&self.load());
+        let res = f(&mut value);
+        *self.current.write().unwrap() = Arc::new(value);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test_swap {
+    use super::*;
+
+    #[test]
+    fn test_swap() {
+        let swap = Swap::new(vec![1]);
+        let before = swap.load();
+
+        swap.update(|value| value.push(2));
+        swap.update(|value| value.push(3));
+        // Snapshots are left as they were
+        assert_eq!(*before, vec![1]);
+        assert_eq!(*swap.load(), vec![1, 2, 3]);
+
+        swap.store(Vec::new());
+        assert!(swap.load().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        let swap = Arc::new(Swap::new(0));
+        let threads = (0..4)
+            .map(|_| {
+                let swap = swap.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        swap.update(|value| *value += 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*swap.load(), 400);
+    }
+}
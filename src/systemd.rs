@@ -0,0 +1,178 @@
+use tokio::{
+    io::Result,
+    net::{TcpListener, UdpSocket},
+};
+
+// A socket passed by systemd socket activation
+pub enum Listen {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+// LISTEN_PID must be this process, LISTEN_FDS is the number of sockets
+#[cfg_attr(not(unix), allow(dead_code))]
+fn listen_count(pid: Option<&str>, fds: Option<&str>, own: u32) -> Option<usize> {
+    if pid?.parse::<u32>().ok()? != own {
+        return None;
+    }
+    match fds?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(n) => Some(n),
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::{
+        env,
+        io::{Error, ErrorKind},
+        net,
+        os::unix::{
+            io::{FromRawFd, RawFd},
+            net::UnixDatagram,
+        },
+        process,
+    };
+
+    // SD_LISTEN_FDS_START
+    const LISTEN_FDS_START: RawFd = 3;
+
+    // The descriptors passed by systemd, None without socket activation
+    pub fn listen_fds() -> Option<Vec<RawFd>> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        let count = listen_count(pid.as_deref(), fds.as_deref(), process::id())?;
+        Some((LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd).collect())
+    }
+
+    pub fn adopt(fd: RawFd) -> Result<Listen> {
+        let mut kind: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut kind as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        // Not passed on to child processes
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        match kind {
+            libc::SOCK_DGRAM => {
+                let socket = unsafe { net::UdpSocket::from_raw_fd(fd) };
+                socket.set_nonblocking(true)?;
+                Ok(Listen::Udp(UdpSocket::from_std(socket)?))
+            }
+            libc::SOCK_STREAM => {
+                let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Ok(Listen::Tcp(TcpListener::from_std(listener)?))
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Not a UDP or TCP socket",
+            )),
+        }
+    }
+
+    // sd_notify, does nothing when not started by systemd
+    pub fn notify(state: &str) -> Result<()> {
+        match env::var("NOTIFY_SOCKET") {
+            Ok(path) => notify_to(&path, state),
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub(super) fn notify_to(path: &str, state: &str) -> Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn listen_fds() -> Option<Vec<i32>> {
+        None
+    }
+
+    pub fn adopt(_fd: i32) -> Result<Listen> {
+        unreachable!()
+    }
+
+    pub fn notify(_state: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub use imp::{adopt, listen_fds, notify};
+
+#[cfg(test)]
+mod test_systemd {
+    use super::*;
+
+    #[test]
+    fn test_listen_count() {
+        assert_eq!(listen_count(Some("42"), Some("2"), 42), Some(2));
+        assert_eq!(listen_count(Some("42"), Some("2"), 43), None);
+        assert_eq!(listen_count(Some("42"), Some("0"), 42), None);
+        assert_eq!(listen_count(Some("42"), Some("x"), 42), None);
+        assert_eq!(listen_count(None, Some("2"), 42), None);
+        assert_eq!(listen_count(Some("42"), None, 42), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_adopt() {
+        use std::{net, os::unix::io::IntoRawFd};
+
+        let udp = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        match adopt(udp.into_raw_fd()).unwrap() {
+            Listen::Udp(socket) => assert_eq!(socket.local_addr().unwrap(), addr),
+            Listen::Tcp(_) => panic!("expected a UDP socket"),
+        }
+
+        let tcp = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        match adopt(tcp.into_raw_fd()).unwrap() {
+            Listen::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            Listen::Udp(_) => panic!("expected a TCP listener"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("updns-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        imp::notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+
+// Number of queries being processed, to drain them before exiting
+pub struct Tasks {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+// Moved into the task it counts
+pub struct TaskGuard(Arc<Tasks>);
+
+impl Tasks {
+    pub fn new() -> Self {
+        Tasks {
+            count: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+
+    // `None` when `limit` tasks are already running
+    pub fn try_start(self: &Arc<Self>, limit: usize) -> Option<TaskGuard> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        Some(TaskGuard(self.clone()))
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Wait until no task is running
+    pub async fn wait(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tasks {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn test_wait_tasks() {
+        let tasks = Arc::new(Tasks::new());
+        timeout(Duration::from_millis(10), tasks.wait())
+            .await
+            .unwrap();
+
+        for ms in &[50, 100, 200] {
+            let tasks = tasks.clone();
+            tokio::spawn(async move {
+                let _guard = tasks.try_start(usize::MAX).unwrap();
+                sleep(Duration::from_millis(*ms)).await;
+            });
+        }
+        sleep(Duration::from_millis(10)).await;
+
+        assert!(timeout(Duration::from_millis(100), tasks.wait())
+            .await
+            .is_err());
+        timeout(Duration::from_secs(1), tasks.wait()).await.unwrap();
+    }
+
+    #[test]
+    fn test_try_start() {
+        let tasks = Arc::new(Tasks::new());
+        let first = tasks.try_start(2).unwrap();
+        let _second = tasks.try_start(2).unwrap();
+        assert!(tasks.try_start(2).is_none());
+        assert_eq!(tasks.count(), 2);
+
+        drop(first);
+        assert!(tasks.try_start(2).is_some());
+        assert_eq!(tasks.count(), 1);
+    }
+}
@@ -0,0 +1,424 @@
+// The part of TOML used by the config: key/value pairs, tables and arrays
+// of tables, strings, numbers, booleans, arrays and inline tables.
+// Dotted keys, multi-line strings and dates are not supported
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    // As written, without the underscores
+    Number(String),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub line: usize,
+    pub key: String,
+    pub value: Value,
+}
+
+// `[name]`, or `[[name]]` when `array`
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub name: String,
+    pub array: bool,
+    // Of the header and of the end of the last entry
+    pub line: usize,
+    pub end: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Document {
+    // Before the first table
+    pub entries: Vec<Entry>,
+    pub tables: Vec<Table>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+pub fn parse(text: &str) -> Result<Document, Error> {
+    let mut reader = Reader { text, pos: 0 };
+    let mut document = Document::default();
+    loop {
+        reader.skip_blank();
+        if reader.pos == text.len() {
+            return Ok(document);
+        }
+        let line = reader.line();
+        if reader.peek() == Some('[') {
+            let array = reader.eat("[[");
+            if !array {
+                reader.eat("[");
+            }
+            reader.skip_spaces();
+            let name = reader.key()?;
+            reader.skip_spaces();
+            if !reader.eat(if array { "]]" } else { "]" }) {
+                return Err(reader.error("Expected the end of the table header"));
+            }
+            reader.end_of_line()?;
+            document.tables.push(Table {
+                name,
+                array,
+                line,
+                end: line,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let key = reader.key()?;
+        reader.skip_spaces();
+        if !reader.eat("=") {
+            return Err(reader.error("Expected '=' after the key"));
+        }
+        let value = reader.value()?;
+        let end = reader.line();
+        reader.end_of_line()?;
+
+        let entries = match document.tables.last_mut() {
+            Some(table) => {
+                table.end = end;
+                &mut table.entries
+            }
+            None => &mut document.entries,
+        };
+        if entries.iter().any(|entry| entry.key == key) {
+            return Err(Error {
+                line,
+                column: 1,
+                message: format!("Duplicate key '{}'", key),
+            });
+        }
+        entries.push(Entry { line, key, value });
+    }
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn line(&self) -> usize {
+        self.text[..self.pos].matches('\n').count() + 1
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let start = self.text[..self.pos].rfind('\n').map_or(0, |i| i + 1);
+        Error {
+            line: self.line(),
+            column: self.text[start..self.pos].chars().count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.text[self.pos..].starts_with(token) {
+            true => {
+                self.pos += token.len();
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(' ' | '\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            self.pos += self.text[self.pos..]
+                .find('\n')
+                .unwrap_or(self.text.len() - self.pos);
+        }
+    }
+
+    // Whitespace, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            if !self.eat("\n") && !self.eat("\r\n") {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.pos == self.text.len() || self.eat("\n") || self.eat("\r\n") {
+            return Ok(());
+        }
+        Err(self.error("Expected the end of the line"))
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        let key = match self.peek() {
+            Some('"') => self.basic_string()?,
+            Some('\'') => self.literal_string()?,
+            _ => {
+                let rest = &self.text[self.pos..];
+                let len = rest
+                    .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'))
+                    .unwrap_or(rest.len());
+                if len == 0 {
+                    return Err(self.error("Expected a key"));
+                }
+                self.pos += len;
+                rest[..len].to_string()
+            }
+        };
+        self.skip_spaces();
+        match self.peek() {
+            Some('.') => Err(self.error("Dotted keys are not supported")),
+            _ => Ok(key),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            Some('0'..='9' | '+' | '-') => self.number(),
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|ch: char| !(ch.is_ascii_alphanumeric() || "+-._:".contains(ch)))
+            .unwrap_or(rest.len());
+        let number = rest[..len].replace('_', "");
+        if number.parse::<i64>().is_err() && number.parse::<f64>().is_err() {
+            return Err(self.error(match number.contains([':', 'T']) {
+                true => "Dates are not supported",
+                false => "Invalid number",
+            }));
+        }
+        self.pos += len;
+        Ok(Value::Number(number))
+    }
+
+    fn basic_string(&mut self) -> Result<String, Error> {
+        if self.text[self.pos..].starts_with("\"\"\"") {
+            return Err(self.error("Multi-line strings are not supported"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let ch = match self.peek() {
+                Some('\n') | None => return Err(self.error("Unterminated string")),
+                Some(ch) => ch,
+            };
+            self.pos += ch.len_utf8();
+            match ch {
+                '"' => return Ok(out),
+                '\\' => out.push(self.escape()?),
+                ch => out.push(ch),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, Error> {
+        let ch = self
+            .peek()
+            .ok_or_else(|| self.error("Unterminated string"))?;
+        self.pos += ch.len_utf8();
+        let digits = match ch {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'b' => return Ok('\u{8}'),
+            'f' => return Ok('\u{c}'),
+            '"' | '\\' => return Ok(ch),
+            'u' => 4,
+            'U' => 8,
+            _ => return Err(self.error("Invalid escape")),
+        };
+        let code = self
+            .text
+            .get(self.pos..self.pos + digits)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.pos += digits;
+        Ok(code)
+    }
+
+    fn literal_string(&mut self) -> Result<String, Error> {
+        if self.text[self.pos..].starts_with("'''") {
+            return Err(self.error("Multi-line strings are not supported"));
+        }
+        self.pos += 1;
+        let rest = &self.text[self.pos..];
+        match rest.find(['\'', '\n']) {
+            Some(len) if rest[len..].starts_with('\'') => {
+                self.pos += len + 1;
+                Ok(rest[..len].to_string())
+            }
+            _ => Err(self.error("Unterminated string")),
+        }
+    }
+
+    // May span lines, with comments and a trailing comma
+    fn array(&mut self) -> Result<Value, Error> {
+        self.pos += 1;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat("]") {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            if !self.eat(",") && self.peek() != Some(']') {
+                return Err(self.error("Expected ',' or ']'"));
+            }
+        }
+    }
+
+    // On one line, without a trailing comma
+    fn inline_table(&mut self) -> Result<Value, Error> {
+        self.pos += 1;
+        let mut fields: Vec<(String, Value)> = Vec::new();
+        self.skip_spaces();
+        if self.eat("}") {
+            return Ok(Value::Table(fields));
+        }
+        loop {
+            self.skip_spaces();
+            let key = self.key()?;
+            if fields.iter().any(|(field, _)| *field == key) {
+                return Err(self.error(&format!("Duplicate key '{}'", key)));
+            }
+            if !self.eat("=") {
+                return Err(self.error("Expected '=' after the key"));
+            }
+            fields.push((key, self.value()?));
+            self.skip_spaces();
+            if self.eat("}") {
+                return Ok(Value::Table(fields));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected ',' or '}'"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_toml {
+    use super::*;
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let document = parse(
+            "# updns\nbind = [\"0.0.0.0:53\", # first\n  '[::]:53',\n]\ntimeout = \"2s\"\n\
+             workers = 1_000\ndns0x20 = false\n\"admin-key\" = \"a\\\"\\u00e9\"\n\n\
+             [[hosts]]\npattern = \"*.example.com\"  # wildcard\nip = \"1.1.1.1\"\n\n\
+             [[hosts]]\npattern = 'a.com'\nip = \"::1\"\nacl = [{ allow = \"10.0.0.0/8\" }, {}]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            document.entries,
+            vec![
+                Entry {
+                    line: 2,
+                    key: "bind".to_string(),
+                    value: Value::Array(vec![string("0.0.0.0:53"), string("[::]:53")]),
+                },
+                Entry {
+                    line: 5,
+                    key: "timeout".to_string(),
+                    value: string("2s"),
+                },
+                Entry {
+                    line: 6,
+                    key: "workers".to_string(),
+                    value: Value::Number("1000".to_string()),
+                },
+                Entry {
+                    line: 7,
+                    key: "dns0x20".to_string(),
+                    value: Value::Bool(false),
+                },
+                Entry {
+                    line: 8,
+                    key: "admin-key".to_string(),
+                    value: string("a\"é"),
+                },
+            ]
+        );
+        assert_eq!(document.tables.len(), 2);
+        let table = &document.tables[0];
+        assert_eq!((table.name.as_str(), table.array), ("hosts", true));
+        assert_eq!((table.line, table.end), (10, 12));
+        assert_eq!(table.entries[0].value, string("*.example.com"));
+        let table = &document.tables[1];
+        assert_eq!((table.line, table.end), (14, 17));
+        assert_eq!(
+            table.entries[2].value,
+            Value::Array(vec![
+                Value::Table(vec![("allow".to_string(), string("10.0.0.0/8"))]),
+                Value::Table(Vec::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        for (text, line, column, message) in &[
+            ("bind 1", 1, 6, "Expected '=' after the key"),
+            ("a = 1\na = 2", 2, 1, "Duplicate key 'a'"),
+            ("a.b = 1", 1, 2, "Dotted keys are not supported"),
+            ("a = \"x", 1, 7, "Unterminated string"),
+            (
+                "a = \"\"\"x\"\"\"",
+                1,
+                5,
+                "Multi-line strings are not supported",
+            ),
+            ("a = 1979-05-27T07:32:00Z", 1, 5, "Dates are not supported"),
+            ("a = [1 2]", 1, 8, "Expected ',' or ']'"),
+            ("a = 1 b", 1, 7, "Expected the end of the line"),
+            ("[hosts\n", 1, 7, "Expected the end of the table header"),
+            ("a = yes", 1, 5, "Expected a value"),
+        ] {
+            let err = parse(text).unwrap_err();
+            assert_eq!(
+                (err.line, err.column, err.message.as_str()),
+                (*line, *column, *message),
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,1266 @@
+use crate::{
+    dnstap::{Dnstap, Kind, Message},
+    edns::udp_payload,
+    utils::random,
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType,
+};
+use crate::{error, info, warn};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
+    net::{self, TcpStream, UdpSocket},
+    time::{sleep, timeout},
+};
+
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(2);
+// Idle sockets kept by each upstream for its next queries
+const IDLE_SOCKETS: usize = 64;
+// Random ports tried before leaving the choice to the system
+const BIND_ATTEMPTS: usize = 8;
+// Between the attempts to resolve a proxy host name without any address
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Where the queries not answered by the host records go. `query` takes the
+// query as the client sent it and gives back the answer with the same id,
+// with the name of the upstream which gave it. The future is boxed to keep
+// the trait usable as `dyn Upstream`
+pub trait Upstream: Send + Sync {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>>;
+}
+
+// Shared with the caller, e.g. to look at a test upstream afterwards
+impl<U: Upstream + ?Sized> Upstream for Arc<U> {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        (**self).query(query)
+    }
+}
+
+// Sockets of the upstream queries, reused instead of binding one per query.
+// Each query has a socket of its own while it runs, a random one of the
+// idle sockets of the family of the upstream, each on a random port
+#[derive(Default)]
+struct Sockets {
+    idle: Mutex<Vec<UdpSocket>>,
+}
+
+impl Sockets {
+    async fn take(&self, addr: SocketAddr) -> Result<UdpSocket> {
+        let idle = {
+            let mut idle = self.idle.lock().unwrap();
+            let family = idle
+                .iter()
+                .enumerate()
+                .filter(|(_, socket)| {
+                    matches!(socket.local_addr(), Ok(local) if local.is_ipv4() == addr.is_ipv4())
+                })
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            match family.len() {
+                0 => None,
+                n => Some(idle.swap_remove(family[random() as usize % n])),
+            }
+        };
+        match idle {
+            Some(socket) => Ok(socket),
+            None => bind_random(addr).await,
+        }
+    }
+
+    // Only after the answer came, a late one would be left on the socket
+    fn put(&self, socket: UdpSocket) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < IDLE_SOCKETS {
+            idle.push(socket);
+        }
+    }
+}
+
+// On a random port rather than the next one of the system, which makes the
+// port of a query harder to guess for off-path spoofing
+async fn bind_random(addr: SocketAddr) -> Result<UdpSocket> {
+    let ip = match addr {
+        SocketAddr::V4(_) => IpAddr::from([0, 0, 0, 0]),
+        SocketAddr::V6(_) => IpAddr::from([0_u16; 8]),
+    };
+    for _ in 0..BIND_ATTEMPTS {
+        let port = 1024 + (random() % (65536 - 1024)) as u16;
+        if let Ok(socket) = UdpSocket::bind((ip, port)).await {
+            return Ok(socket);
+        }
+    }
+    UdpSocket::bind((ip, 0)).await
+}
+
+// A DNS server over UDP
+pub struct Udp {
+    addr: SocketAddr,
+    timeout: Duration,
+    // Randomize the case of the name and require it back in the answer
+    dns0x20: bool,
+    tap: Option<Arc<Dnstap>>,
+    sockets: Sockets,
+}
+
+impl Udp {
+    pub fn new(addr: SocketAddr, timeout: Duration, dns0x20: bool) -> Udp {
+        Udp {
+            addr,
+            timeout,
+            dns0x20,
+            tap: None,
+            sockets: Sockets::default(),
+        }
+    }
+
+    pub(crate) fn tap(mut self, tap: Option<Arc<Dnstap>>) -> Udp {
+        self.tap = tap;
+        self
+    }
+}
+
+impl Upstream for Udp {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let tap = self.tap.as_deref();
+            let sockets = &self.sockets;
+            match query_upstream(query, self.addr, self.timeout, self.dns0x20, tap, sockets).await {
+                Ok(data) => Ok((data, self.addr.to_string())),
+                Err(err) => {
+                    error!("Agent request to {} {:?}", self.addr, err);
+                    Err(err)
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+// The upstreams in order until one answers, the `proxy` lines of a config
+pub struct Failover {
+    upstreams: Vec<Box<dyn Upstream>>,
+}
+
+impl Failover {
+    pub fn new(upstreams: Vec<Box<dyn Upstream>>) -> Failover {
+        Failover { upstreams }
+    }
+}
+
+impl Upstream for Failover {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let mut kind = ErrorKind::Other;
+            for upstream in &self.upstreams {
+                match upstream.query(query).await {
+                    Ok(answer) => return Ok(answer),
+                    Err(err) => kind = err.kind(),
+                }
+            }
+            // Timed out when the last upstream did
+            Err(Error::new(kind, "Proxy server failed to proxy request"))
+        }
+        .boxed()
+    }
+}
+
+// Weighted round-robin, the upstream picked for a query gets it first and
+// the others are tried in order when it fails. Smooth like nginx: weights
+// 3 and 1 give the second upstream one query out of 4, not 4 in a row
+pub struct Weighted {
+    upstreams: Vec<(Box<dyn Upstream>, u32)>,
+    current: Mutex<Vec<i64>>,
+}
+
+impl Weighted {
+    pub fn new(upstreams: Vec<(Box<dyn Upstream>, u32)>) -> Weighted {
+        let current = vec![0; upstreams.len()];
+        Weighted {
+            upstreams,
+            current: Mutex::new(current),
+        }
+    }
+
+    fn pick(&self) -> usize {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut best = 0;
+        for (i, (_, weight)) in self.upstreams.iter().enumerate() {
+            current[i] += *weight as i64;
+            total += *weight as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        if let Some(best) = current.get_mut(best) {
+            *best -= total;
+        }
+        best
+    }
+}
+
+impl Upstream for Weighted {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let first = self.pick();
+            let order = (0..self.upstreams.len()).filter(|i| *i != first);
+            let mut kind = ErrorKind::Other;
+            for i in std::iter::once(first).chain(order) {
+                let (upstream, _) = match self.upstreams.get(i) {
+                    Some(upstream) => upstream,
+                    None => break,
+                };
+                match upstream.query(query).await {
+                    Ok(answer) => return Ok(answer),
+                    Err(err) => kind = err.kind(),
+                }
+            }
+            Err(Error::new(kind, "Proxy server failed to proxy request"))
+        }
+        .boxed()
+    }
+}
+
+// Sends the query again while the upstream times out, `retries` times at
+// most and within `budget`. Each query gets a fresh id from the upstream,
+// a `Failover` goes on with its next upstream
+pub struct Retry {
+    upstream: Box<dyn Upstream>,
+    retries: u32,
+    budget: Duration,
+}
+
+impl Retry {
+    pub fn new(upstream: Box<dyn Upstream>, retries: u32, budget: Duration) -> Retry {
+        Retry {
+            upstream,
+            retries,
+            budget,
+        }
+    }
+}
+
+impl Upstream for Retry {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let attempts = async {
+                let mut attempt = 0;
+                loop {
+                    match self.upstream.query(query).await {
+                        Err(err) if err.kind() == ErrorKind::TimedOut && attempt < self.retries => {
+                            attempt += 1;
+                            warn!("Retry a timed out query, attempt {}", attempt + 1);
+                        }
+                        res => return res,
+                    }
+                }
+            };
+            timeout(self.budget, attempts).await?
+        }
+        .boxed()
+    }
+}
+
+// Every address of a host name, from the A and AAAA records `bootstrap`
+// answers or from the system resolver without one
+pub async fn resolve(
+    host: &str,
+    port: u16,
+    bootstrap: Option<SocketAddr>,
+) -> Result<Vec<SocketAddr>> {
+    let addrs = match bootstrap {
+        Some(bootstrap) => {
+            let mut addrs = Vec::new();
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                let mut packet = DnsPacket::new();
+                packet.header.recursion_desired = true;
+                packet
+                    .questions
+                    .push(DnsQuestion::new(host.to_string(), qtype));
+                let mut buffer = BytePacketBuffer::new();
+                packet.write(&mut buffer)?;
+                let query = &buffer.buf[..buffer.pos()];
+                let sockets = Sockets::default();
+                let data =
+                    query_upstream(query, bootstrap, BOOTSTRAP_TIMEOUT, false, None, &sockets)
+                        .await?;
+                let answer = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data))?;
+                addrs.extend(answer.answers.iter().filter_map(|record| match record {
+                    DnsRecord::A { addr, .. } => Some(SocketAddr::new(IpAddr::V4(*addr), port)),
+                    DnsRecord::AAAA { addr, .. } => Some(SocketAddr::new(IpAddr::V6(*addr), port)),
+                    _ => None,
+                }));
+            }
+            addrs
+        }
+        None => net::lookup_host((host, port)).await?.collect(),
+    };
+    match addrs.is_empty() {
+        true => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No address for {}", host),
+        )),
+        false => Ok(addrs),
+    }
+}
+
+// A proxy given by host name, its addresses tried in order. They are
+// resolved again by `refresh`, the last ones are kept when it fails
+pub struct Resolved {
+    host: String,
+    port: u16,
+    bootstrap: Option<SocketAddr>,
+    addrs: Mutex<Vec<SocketAddr>>,
+    timeout: Duration,
+    dns0x20: bool,
+    tap: Option<Arc<Dnstap>>,
+    sockets: Sockets,
+}
+
+impl Resolved {
+    pub fn new(
+        host: &str,
+        port: u16,
+        bootstrap: Option<SocketAddr>,
+        addrs: Vec<SocketAddr>,
+        timeout: Duration,
+        dns0x20: bool,
+    ) -> Resolved {
+        Resolved {
+            host: host.to_string(),
+            port,
+            bootstrap,
+            addrs: Mutex::new(addrs),
+            timeout,
+            dns0x20,
+            tap: None,
+            sockets: Sockets::default(),
+        }
+    }
+
+    pub(crate) fn tap(mut self, tap: Option<Arc<Dnstap>>) -> Resolved {
+        self.tap = tap;
+        self
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.lock().unwrap().clone()
+    }
+
+    pub async fn refresh(&self) -> Result<()> {
+        let addrs = resolve(&self.host, self.port, self.bootstrap).await?;
+        let mut current = self.addrs.lock().unwrap();
+        if *current != addrs {
+            info!("Resolved proxy {}:{} to {:?}", self.host, self.port, addrs);
+            *current = addrs;
+        }
+        Ok(())
+    }
+
+    // Refresh in the background every `interval`, more often while the name
+    // has no address, until the upstream is dropped
+    pub fn refresh_every(self: &Arc<Self>, interval: Duration) {
+        let resolved = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let wait = match resolved.upgrade() {
+                    Some(resolved) if resolved.addrs().is_empty() => RETRY_INTERVAL,
+                    Some(_) => interval,
+                    None => return,
+                };
+                sleep(wait).await;
+                let resolved = match resolved.upgrade() {
+                    Some(resolved) => resolved,
+                    None => return,
+                };
+                if let Err(err) = resolved.refresh().await {
+                    warn!(
+                        "Failed to resolve proxy {}:{}: {}",
+                        resolved.host, resolved.port, err
+                    );
+                }
+            }
+        });
+    }
+}
+
+impl Upstream for Resolved {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let tap = self.tap.as_deref();
+            let mut kind = ErrorKind::NotFound;
+            for addr in self.addrs() {
+                let sockets = &self.sockets;
+                match query_upstream(query, addr, self.timeout, self.dns0x20, tap, sockets).await {
+                    Ok(data) => return Ok((data, addr.to_string())),
+                    Err(err) => {
+                        error!("Agent request to {} {:?}", addr, err);
+                        kind = err.kind();
+                    }
+                }
+            }
+            Err(Error::new(
+                kind,
+                format!("No address of {}:{} answered", self.host, self.port),
+            ))
+        }
+        .boxed()
+    }
+}
+
+// A DNS server over TCP through a SOCKS5 proxy (RFC 1928), with the
+// username and password authentication of RFC 1929 when `auth` is set.
+// A connection per query
+pub struct Socks5 {
+    proxy: SocketAddr,
+    upstream: SocketAddr,
+    auth: Option<(String, String)>,
+    timeout: Duration,
+}
+
+impl Socks5 {
+    pub fn new(
+        proxy: SocketAddr,
+        upstream: SocketAddr,
+        auth: Option<(String, String)>,
+        timeout: Duration,
+    ) -> Socks5 {
+        Socks5 {
+            proxy,
+            upstream,
+            auth,
+            timeout,
+        }
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(self.proxy).await?;
+        self.connect(&mut stream).await?;
+
+        // Over TCP a message comes after its length
+        let len = u16::try_from(query.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Query is too long"))?;
+        stream
+            .write_all(&[&len.to_be_bytes(), query].concat())
+            .await?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut answer = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut answer).await?;
+
+        if !is_answer(query, &answer, false) {
+            return Err(Error::new(ErrorKind::InvalidData, "Mismatched answer"));
+        }
+        Ok(answer)
+    }
+
+    // Authenticate and ask the proxy to connect to the upstream
+    async fn connect(&self, stream: &mut TcpStream) -> Result<()> {
+        let method = match self.auth {
+            Some(_) => 2,
+            None => 0,
+        };
+        stream.write_all(&[5, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply != [5, method] {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 proxy refused the authentication method",
+            ));
+        }
+
+        if let Some((user, password)) = &self.auth {
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "SOCKS5 authentication failed",
+                ));
+            }
+        }
+
+        // CONNECT
+        let mut request = vec![5, 1, 0];
+        match self.upstream.ip() {
+            IpAddr::V4(ip) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&self.upstream.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("SOCKS5 proxy failed to connect, reply {}", reply[1]),
+            ));
+        }
+        // The address the proxy bound, then its port
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 reply")),
+        };
+        stream.read_exact(&mut vec![0; len + 2]).await?;
+        Ok(())
+    }
+}
+
+impl Upstream for Socks5 {
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        async move {
+            let name = format!("{} via {}", self.upstream, self.proxy);
+            match timeout(self.timeout, self.exchange(query)).await {
+                Ok(Ok(data)) => Ok((data, name)),
+                Ok(Err(err)) => {
+                    error!("Agent request to {} {:?}", name, err);
+                    Err(err)
+                }
+                Err(_) => {
+                    error!("Agent request to {} timed out", name);
+                    Err(Error::new(ErrorKind::TimedOut, "SOCKS5 query timed out"))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+// Answers with a function of the query, without any network. For tests
+// and embedding, the function sets the id of the answer
+pub struct Static<F> {
+    name: String,
+    answer: F,
+}
+
+impl<F> Static<F>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync,
+{
+    pub fn new(name: &str, answer: F) -> Static<F> {
+        Static {
+            name: name.to_string(),
+            answer,
+        }
+    }
+}
+
+impl<F> Upstream for Static<F>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn query<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, Result<(Vec<u8>, String)>> {
+        let res = (self.answer)(query).map(|data| (data, self.name.clone()));
+        async move { res }.boxed()
+    }
+}
+
+// Send the query with a fresh transaction id and wait for the matching answer,
+// with `dns0x20` the answer must also echo the randomized case of the name,
+// the exchange is sent to `tap` as it's on the wire
+async fn query_upstream(
+    buf: &[u8],
+    addr: SocketAddr,
+    duration: Duration,
+    dns0x20: bool,
+    tap: Option<&Dnstap>,
+    sockets: &Sockets,
+) -> Result<Vec<u8>> {
+    if buf.len() < 12 {
+        return Err(Error::new(ErrorKind::InvalidData, "Query is too short"));
+    }
+    let mut query = buf.to_vec();
+    query[..2].copy_from_slice(&(random() as u16).to_be_bytes());
+    if dns0x20 {
+        randomize_case(&mut query)?;
+    }
+
+    // The system drops the datagrams of other sources
+    let socket = sockets.take(addr).await?;
+    socket.connect(addr).await?;
+    let local = socket.local_addr()?;
+    let query_time = SystemTime::now();
+
+    let answer = timeout(duration, async {
+        socket.send(&query).await?;
+        if let Some(tap) = tap {
+            tap.send(&Message {
+                kind: Kind::ResolverQuery,
+                query_address: local,
+                response_address: addr,
+                tcp: false,
+                query_time,
+                query: Some(&query),
+                response_time: None,
+                response: None,
+            });
+        }
+        // As large as the client takes, the upstream truncates past it
+        let mut res = vec![0; udp_payload(&query)];
+        loop {
+            let (len, src) = socket.recv_from(&mut res).await?;
+
+            // Ignore spoofed or stale packets, also checked where connected
+            // sockets still receive from other sources
+            if src != addr || !is_answer(&query, &res[..len], dns0x20) {
+                warn!("Drop mismatched answer from '{}'", src);
+                continue;
+            }
+            // Relayed as it is, the client may read what updns cannot
+            if let Err(err) = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&res[..len]))
+            {
+                warn!(
+                    "Relay an answer from '{}' which cannot be parsed: {}",
+                    src, err
+                );
+            }
+            if let Some(tap) = tap {
+                tap.send(&Message {
+                    kind: Kind::ResolverResponse,
+                    query_address: local,
+                    response_address: addr,
+                    tcp: false,
+                    query_time,
+                    query: Some(&query),
+                    response_time: Some(SystemTime::now()),
+                    response: Some(&res[..len]),
+                });
+            }
+
+            res[..2].copy_from_slice(&buf[..2]);
+            restore_question(&mut res[..len], buf);
+            return Ok(res[..len].to_vec());
+        }
+    })
+    .await?;
+    if answer.is_ok() {
+        sockets.put(socket);
+    }
+    answer
+}
+
+// Whether the packet answers the query: same id and question section,
+// the name is compared case-sensitively when `exact`
+fn is_answer(query: &[u8], answer: &[u8], exact: bool) -> bool {
+    if answer.len() < 12 || query[..2] != answer[..2] || answer[2] & 0x80 == 0 {
+        return false;
+    }
+    // Question count
+    if query[4..6] != answer[4..6] {
+        return false;
+    }
+
+    let end = match BytePacketBuffer::from_bytes(query).questions_end() {
+        Ok(end) => end,
+        Err(_) => return false,
+    };
+    match BytePacketBuffer::from_bytes(answer).questions_end() {
+        Ok(n) if n == end && end <= answer.len() && end <= query.len() => {
+            if exact {
+                query[12..end] == answer[12..end]
+            } else {
+                query[12..end].eq_ignore_ascii_case(&answer[12..end])
+            }
+        }
+        _ => false,
+    }
+}
+
+// Flip the case of every letter of the question names at random (dns 0x20)
+fn randomize_case(query: &mut [u8]) -> Result<()> {
+    let end = BytePacketBuffer::from_bytes(query).questions_end()?;
+    if end > query.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "Query is too short"));
+    }
+
+    let (mut bits, mut n) = (0, 0);
+    let mut pos = 12;
+    while pos < end {
+        let len = query[pos] as usize;
+        // End of the name or a pointer, skip the type and class
+        if len == 0 {
+            pos += 5;
+            continue;
+        }
+        if (len & 0xC0) == 0xC0 {
+            pos += 6;
+            continue;
+        }
+
+        for ch in &mut query[pos + 1..(pos + 1 + len).min(end)] {
+            if ch.is_ascii_alphabetic() {
+                if n % 64 == 0 {
+                    bits = random();
+                }
+                if bits & 1 == 1 {
+                    *ch ^= 0x20;
+                }
+                bits >>= 1;
+                n += 1;
+            }
+        }
+        pos += len + 1;
+    }
+    Ok(())
+}
+
+// Copy the question section of the query into its answer,
+// which gives the client back its own casing of the name
+pub(crate) fn restore_question(answer: &mut [u8], query: &[u8]) {
+    if let Ok(end) = BytePacketBuffer::from_bytes(query).questions_end() {
+        if end <= query.len() && is_answer(query, answer, false) {
+            answer[12..end].copy_from_slice(&query[12..end]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_upstream {
+    use super::*;
+    use crate::{DnsPacket, DnsQuestion, QueryType};
+    use tokio::time::sleep;
+
+    fn query(id: u16, name: &str) -> (BytePacketBuffer, usize) {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let len = buffer.pos();
+        (BytePacketBuffer::from_bytes(&buffer.buf[..len]), len)
+    }
+
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80;
+        answer
+    }
+
+    #[tokio::test]
+    async fn test_validate_answer() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let spoof = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            let real = answer(&buf[..len]);
+
+            // Wrong transaction id
+            let mut fake = real.clone();
+            fake[0] ^= 0xFF;
+            upstream.send_to(&fake, src).await.unwrap();
+            // Wrong question
+            let (fake, n) = query(u16::from_be_bytes([real[0], real[1]]), "evil.com");
+            upstream
+                .send_to(&answer(&fake.buf[..n]), src)
+                .await
+                .unwrap();
+            // Not a response
+            upstream.send_to(&buf[..len], src).await.unwrap();
+            // Wrong source address
+            spoof.send_to(&real, src).await.unwrap();
+
+            sleep(Duration::from_millis(50)).await;
+            upstream.send_to(&real, src).await.unwrap();
+        });
+
+        let (req, len) = query(1234, "valid.example.com");
+        let sockets = Sockets::default();
+        let data = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_secs(5),
+            false,
+            None,
+            &sockets,
+        )
+        .await
+        .unwrap();
+        assert_eq!(data, answer(&req.buf[..len]));
+        // Kept for the next query
+        assert_eq!(sockets.idle.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_large_answer() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        // A signature larger than 512 bytes, then a record name pointing at
+        // itself
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            // Without the OPT record
+            let mut large = answer(&buf[..len - 11]);
+            large[7] = 1;
+            large[11] = 0;
+            large.extend_from_slice(&[0xC0, 0x0C, 0, 46, 0, 1, 0, 0, 0, 60, 0x04, 0]);
+            large.extend_from_slice(&[0xAB; 1024]);
+            upstream.send_to(&large, src).await.unwrap();
+
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            let mut looped = answer(&buf[..len]);
+            looped[7] = 1;
+            looped.extend_from_slice(&(0xC000 | len as u16).to_be_bytes());
+            looped.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 6, 6, 6, 6]);
+            upstream.send_to(&looped, src).await.unwrap();
+        });
+
+        // With an OPT record, payload size 4096
+        let (req, len) = query(1, "large.example.com");
+        let mut edns = req.buf[..len].to_vec();
+        edns[11] = 1;
+        edns.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        let sockets = Sockets::default();
+        let data = query_upstream(&edns, addr, Duration::from_secs(5), false, None, &sockets)
+            .await
+            .unwrap();
+        assert_eq!(data.len(), len + 12 + 1024);
+        assert!(data.ends_with(&[0xAB; 1024]));
+
+        // Relayed as it is
+        let (req, len) = query(2, "loop.example.com");
+        let data = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_secs(5),
+            false,
+            None,
+            &sockets,
+        )
+        .await
+        .unwrap();
+        assert_eq!(data.len(), len + 16);
+        assert_eq!(
+            &data[len + 2..],
+            &[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 6, 6, 6, 6]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_source() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let spoof = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+                // A valid NXDOMAIN answer, but from another address
+                let mut fake = answer(&buf[..len]);
+                fake[3] |= 3;
+                spoof.send_to(&fake, src).await.unwrap();
+
+                sleep(Duration::from_millis(50)).await;
+                upstream.send_to(&answer(&buf[..len]), src).await.unwrap();
+            }
+        });
+
+        // Also once the socket comes back from the pool
+        let sockets = Sockets::default();
+        for id in 0..2 {
+            let (req, len) = query(id, "spoofed.example.com");
+            let data = query_upstream(
+                &req.buf[..len],
+                addr,
+                Duration::from_secs(5),
+                false,
+                None,
+                &sockets,
+            )
+            .await
+            .unwrap();
+            assert_eq!(data, answer(&req.buf[..len]));
+        }
+        assert_eq!(sockets.idle.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_bootstrap() {
+        let bootstrap = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = bootstrap.local_addr().unwrap();
+
+        // A new address for each A query, no AAAA record
+        tokio::spawn(async move {
+            let mut n = 0;
+            loop {
+                let mut req = BytePacketBuffer::new();
+                let (_, src) = bootstrap.recv_from(&mut req.buf).await.unwrap();
+                let mut packet = DnsPacket::from_buffer(&mut req).unwrap();
+                packet.header.response = true;
+                let question = packet.questions[0].clone();
+                if question.qtype == QueryType::A {
+                    n += 1;
+                    packet.answers.push(DnsRecord::A {
+                        domain: question.name,
+                        addr: [10, 0, 0, n].into(),
+                        ttl: 60,
+                    });
+                }
+                let mut res = BytePacketBuffer::new();
+                packet.write(&mut res).unwrap();
+                bootstrap.send_to(&res.buf[..res.pos()], src).await.unwrap();
+            }
+        });
+
+        let addrs = resolve("dns.example.com", 853, Some(addr)).await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.1:853".parse().unwrap()]);
+
+        let resolved = Resolved::new(
+            "dns.example.com",
+            853,
+            Some(addr),
+            Vec::new(),
+            Duration::from_secs(1),
+            false,
+        );
+        let (req, len) = query(1, "example.com");
+        let err = resolved.query(&req.buf[..len]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        resolved.refresh().await.unwrap();
+        assert_eq!(resolved.addrs(), vec!["10.0.0.2:853".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_answer_timeout() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            let mut fake = answer(&buf[..len]);
+            fake[1] ^= 0xFF;
+            upstream.send_to(&fake, src).await.unwrap();
+        });
+
+        let (req, len) = query(1, "timeout.example.com");
+        let err = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_millis(200),
+            false,
+            None,
+            &Sockets::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        // Lose the first query, answer the next ones
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let seen = ids.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+                let first = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push([buf[0], buf[1]]);
+                    seen.len() == 1
+                };
+                if !first {
+                    upstream.send_to(&answer(&buf[..len]), src).await.unwrap();
+                }
+            }
+        });
+
+        let udp = Udp::new(addr, Duration::from_millis(100), false);
+        let retry = Retry::new(Box::new(udp), 2, Duration::from_millis(500));
+        let (req, len) = query(1, "retry.example.com");
+        let start = std::time::Instant::now();
+        let (data, source) = retry.query(&req.buf[..len]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(source, addr.to_string());
+        assert_eq!(data[4..], answer(&req.buf[..len])[4..]);
+
+        let ids = ids.lock().unwrap().clone();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        // The budget is over before the retries
+        let lost = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = Udp::new(
+            lost.local_addr().unwrap(),
+            Duration::from_millis(100),
+            false,
+        );
+        let retry = Retry::new(Box::new(udp), 10, Duration::from_millis(250));
+        let start = std::time::Instant::now();
+        let err = retry.query(&req.buf[..len]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    // Answer with the case of every letter of the name inverted
+    fn invert_case(answer: &mut [u8]) {
+        let end = BytePacketBuffer::from_bytes(answer)
+            .questions_end()
+            .unwrap();
+        for ch in &mut answer[13..end - 4] {
+            if ch.is_ascii_alphabetic() {
+                *ch ^= 0x20;
+            }
+        }
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let (req, len) = query(1, "www.example.com");
+        let mut randomized = req.buf[..len].to_vec();
+        randomize_case(&mut randomized).unwrap();
+
+        assert!(is_answer(&req.buf[..len], &answer(&randomized), false));
+        assert_eq!(randomized[..12], req.buf[..12]);
+        assert_eq!(randomized[len - 4..], req.buf[len - 4..len]);
+
+        let mut inverted = answer(&randomized);
+        invert_case(&mut inverted);
+        assert!(is_answer(&randomized, &answer(&randomized), true));
+        assert!(!is_answer(&randomized, &inverted, true));
+        assert!(is_answer(&randomized, &inverted, false));
+    }
+
+    #[tokio::test]
+    async fn test_dns0x20_answer() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            let real = answer(&buf[..len]);
+
+            let mut fake = real.clone();
+            invert_case(&mut fake);
+            upstream.send_to(&fake, src).await.unwrap();
+
+            sleep(Duration::from_millis(50)).await;
+            upstream.send_to(&real, src).await.unwrap();
+        });
+
+        let (req, len) = query(1, "Case.Example.COM");
+        let data = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_secs(5),
+            true,
+            None,
+            &Sockets::default(),
+        )
+        .await
+        .unwrap();
+        // The client gets its own casing back
+        assert_eq!(data, answer(&req.buf[..len]));
+    }
+
+    #[tokio::test]
+    async fn test_dns0x20_mismatch() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+                let mut fake = answer(&buf[..len]);
+                invert_case(&mut fake);
+                upstream.send_to(&fake, src).await.unwrap();
+            }
+        });
+
+        let (req, len) = query(1, "mismatch.example.com");
+        let err = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_millis(200),
+            true,
+            None,
+            &Sockets::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        // Accepted when case randomization is disabled
+        let data = query_upstream(
+            &req.buf[..len],
+            addr,
+            Duration::from_secs(5),
+            false,
+            None,
+            &Sockets::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(data, answer(&req.buf[..len]));
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let failing = |kind| {
+            Box::new(Static::new("failing", move |_: &[u8]| {
+                Err(Error::from(kind))
+            })) as Box<dyn Upstream>
+        };
+        let (req, len) = query(3, "failover.example.com");
+        let upstream = Failover::new(vec![
+            failing(ErrorKind::ConnectionRefused),
+            Box::new(Static::new("second", |query: &[u8]| Ok(answer(query)))),
+            failing(ErrorKind::Other),
+        ]);
+        let (data, source) = upstream.query(&req.buf[..len]).await.unwrap();
+        assert_eq!(data, answer(&req.buf[..len]));
+        assert_eq!(source, "second");
+
+        let upstream = Failover::new(vec![
+            failing(ErrorKind::Other),
+            failing(ErrorKind::TimedOut),
+        ]);
+        let err = upstream.query(&req.buf[..len]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    // A SOCKS5 proxy for one connection to `upstream`, with the password
+    // `secret` when `auth`. Answers the DNS query itself
+    async fn socks5_proxy(upstream: SocketAddr, auth: bool) -> SocketAddr {
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 3];
+            stream.read_exact(&mut buf).await.unwrap();
+            let method = if auth { 2 } else { 0 };
+            assert_eq!(buf, [5, 1, method]);
+            stream.write_all(&[5, method]).await.unwrap();
+            if auth {
+                let mut buf = [0; 2];
+                stream.read_exact(&mut buf).await.unwrap();
+                let mut user = vec![0; buf[1] as usize];
+                stream.read_exact(&mut user).await.unwrap();
+                stream.read_exact(&mut buf[..1]).await.unwrap();
+                let mut password = vec![0; buf[0] as usize];
+                stream.read_exact(&mut password).await.unwrap();
+                if (&user[..], &password[..]) != (b"user", b"secret") {
+                    stream.write_all(&[1, 1]).await.unwrap();
+                    return;
+                }
+                stream.write_all(&[1, 0]).await.unwrap();
+            }
+
+            let mut request = vec![0; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut expected = vec![5, 1, 0, 1];
+            if let IpAddr::V4(ip) = upstream.ip() {
+                expected.extend_from_slice(&ip.octets());
+            }
+            expected.extend_from_slice(&upstream.port().to_be_bytes());
+            assert_eq!(request, expected);
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = answer(&query);
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&answer).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let upstream = "9.9.9.9:53".parse().unwrap();
+        let (req, len) = query(5, "socks.example.com");
+        let timeout = Duration::from_secs(1);
+
+        let proxy = socks5_proxy(upstream, false).await;
+        let socks = Socks5::new(proxy, upstream, None, timeout);
+        let (data, source) = socks.query(&req.buf[..len]).await.unwrap();
+        assert_eq!(data, answer(&req.buf[..len]));
+        assert_eq!(source, format!("9.9.9.9:53 via {}", proxy));
+
+        let auth = |password: &str| Some(("user".to_string(), password.to_string()));
+        let proxy = socks5_proxy(upstream, true).await;
+        let socks = Socks5::new(proxy, upstream, auth("secret"), timeout);
+        assert!(socks.query(&req.buf[..len]).await.is_ok());
+
+        // The next proxy is tried when the authentication fails
+        let proxy = socks5_proxy(upstream, true).await;
+        let upstream = Failover::new(vec![
+            Box::new(Socks5::new(proxy, upstream, auth("wrong"), timeout)),
+            Box::new(Static::new("second", |query: &[u8]| Ok(answer(query)))),
+        ]);
+        let (_, source) = upstream.query(&req.buf[..len]).await.unwrap();
+        assert_eq!(source, "second");
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let named = |name: &'static str| {
+            Box::new(Static::new(name, |query: &[u8]| Ok(answer(query)))) as Box<dyn Upstream>
+        };
+        let (req, len) = query(4, "weighted.example.com");
+        let upstream = Weighted::new(vec![(named("a"), 3), (named("b"), 1)]);
+        let mut sources = Vec::new();
+        for _ in 0..8 {
+            let (_, source) = upstream.query(&req.buf[..len]).await.unwrap();
+            sources.push(source);
+        }
+        assert_eq!(sources, ["a", "a", "b", "a", "a", "a", "b", "a"]);
+
+        // The others answer when the picked one fails
+        let failing = Box::new(Static::new("failing", |_: &[u8]| {
+            Err(Error::from(ErrorKind::TimedOut))
+        }));
+        let upstream = Weighted::new(vec![(failing, 5), (named("b"), 1)]);
+        for _ in 0..3 {
+            let (_, source) = upstream.query(&req.buf[..len]).await.unwrap();
+            assert_eq!(source, "b");
+        }
+    }
+}
@@ -1,37 +1,74 @@
-use futures_util::{future::Future, ready, stream::Stream};
 use std::{
-    path::{Path, PathBuf},
-    pin::Pin,
-    task::{Context, Poll},
+    path::PathBuf,
     time::{Duration, SystemTime},
 };
 use tokio::{
-    fs::File,
+    fs,
     io::Result,
-    time::{interval, Interval},
+    time::{interval, sleep, Interval},
 };
 
+// Quiet time after a change, editors may write a file several times per save
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+// Poll the modification time of the config file and its imports
 pub struct Watch {
-    path: PathBuf,
-    state: Option<Pin<Box<dyn Future<Output = Result<SystemTime>>>>>,
-    modified: Result<SystemTime>,
+    files: Vec<(PathBuf, Result<SystemTime>)>,
     timer: Interval,
 }
 
 impl Watch {
-    pub async fn new<P: AsRef<Path>>(path: P, duration: Duration) -> Watch {
-        let path = path.as_ref().to_path_buf();
-        Watch {
-            path: path.clone(),
-            state: None,
-            modified: Self::modified(path).await,
+    pub async fn new(paths: Vec<PathBuf>, duration: Duration) -> Watch {
+        let mut watch = Watch {
+            files: Vec::new(),
             timer: interval(duration),
+        };
+        watch.watch(paths).await;
+        watch
+    }
+
+    // Replace the watched files, the known ones keep their state
+    pub async fn watch(&mut self, paths: Vec<PathBuf>) {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let modified = match self.files.iter().position(|(p, _)| *p == path) {
+                Some(i) => self.files.swap_remove(i).1,
+                None => Self::modified(&path).await,
+            };
+            files.push((path, modified));
+        }
+        self.files = files;
+    }
+
+    // Wait until a file changes and then stays unchanged for `DEBOUNCE`
+    pub async fn changed(&mut self) {
+        loop {
+            self.timer.tick().await;
+            if self.update().await {
+                sleep(DEBOUNCE).await;
+                while self.update().await {
+                    sleep(DEBOUNCE).await;
+                }
+                return;
+            }
+        }
+    }
+
+    // Refresh the modification times, whether any of them changed
+    async fn update(&mut self) -> bool {
+        let mut changed = false;
+        for (path, modified) in &mut self.files {
+            let new = Self::modified(path).await;
+            if !Self::eq(modified, &new) {
+                *modified = new;
+                changed = true;
+            }
         }
+        changed
     }
 
-    async fn modified(p: PathBuf) -> Result<SystemTime> {
-        let file = File::open(p).await?;
-        file.metadata().await?.modified()
+    async fn modified(p: &PathBuf) -> Result<SystemTime> {
+        fs::metadata(p).await?.modified()
     }
 
     fn eq(a: &Result<SystemTime>, b: &Result<SystemTime>) -> bool {
@@ -50,22 +87,30 @@ impl Watch {
     }
 }
 
-impl Stream for Watch {
-    type Item = ();
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            if let Some(state) = &mut self.state {
-                let modified: Result<SystemTime> = ready!(Pin::new(state).poll(cx));
-                self.state = None;
+#[cfg(test)]
+mod test_watch {
+    use super::*;
+    use tokio::time::timeout;
 
-                if !Self::eq(&self.modified, &modified) {
-                    self.modified = modified;
-                    return Poll::Ready(Some(()));
-                }
-            } else {
-                ready!(self.timer.poll_tick(cx));
-                self.state = Some(Box::pin(Self::modified(self.path.clone())));
-            }
-        }
+    #[tokio::test]
+    async fn test_changed() {
+        let dir = std::env::temp_dir().join(format!("updns-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let (config, import) = (dir.join("config"), dir.join("import"));
+        fs::write(&config, "").await.unwrap();
+
+        let mut watch = Watch::new(vec![config.clone()], Duration::from_millis(20)).await;
+        watch.watch(vec![config, import.clone()]).await;
+        assert!(timeout(Duration::from_millis(100), watch.changed())
+            .await
+            .is_err());
+
+        // Creating an imported file
+        fs::write(&import, "example.com 1.1.1.1").await.unwrap();
+        assert!(timeout(Duration::from_secs(2), watch.changed())
+            .await
+            .is_ok());
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 }